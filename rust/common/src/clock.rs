@@ -0,0 +1,63 @@
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+
+/// Source of the current wall-clock time for everything judged or stamped
+/// against it: packet deadlines, flow idling, warm-up and cooldown,
+/// time-of-day windows, probe results and the events they trigger.
+/// Injected so tests can control it; durations are still timed with
+/// `Instant`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Local time of day, which time windows are judged against.
+    fn local_now(&self) -> NaiveDateTime {
+        self.now().with_timezone(&Local).naive_local()
+    }
+}
+
+/// The system's real-time clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use mock::MockClock;
+
+#[cfg(any(test, feature = "test-utils"))]
+mod mock {
+    use super::*;
+    use parking_lot::Mutex;
+
+    /// A clock that only moves when told to, for tests. Its local time is
+    /// its UTC time, so time windows don't depend on the host's zone.
+    pub struct MockClock {
+        now: Mutex<DateTime<Utc>>,
+    }
+
+    impl MockClock {
+        pub fn new(now: DateTime<Utc>) -> Self {
+            Self { now: Mutex::new(now) }
+        }
+
+        pub fn set(&self, now: DateTime<Utc>) {
+            *self.now.lock() = now;
+        }
+
+        pub fn advance(&self, by: chrono::Duration) {
+            *self.now.lock() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.now.lock()
+        }
+
+        fn local_now(&self) -> NaiveDateTime {
+            self.now().naive_utc()
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use tokio::sync::broadcast;
+
+/// Subscribers that fall this many events behind start missing the oldest.
+const EVENT_CAPACITY: usize = 256;
+
+/// Fans events out to every interested task, so features react to the same
+/// transitions instead of each re-deriving them. Clones publish to the same
+/// subscribers.
+#[derive(Clone)]
+pub struct EventBus<E> {
+    sender: broadcast::Sender<E>,
+}
+
+impl<E: Clone> EventBus<E> {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender }
+    }
+
+    /// Sends `event` to current subscribers; dropped if there are none.
+    pub fn publish(&self, event: E) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receives every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<E> {
+        self.sender.subscribe()
+    }
+}
+
+impl<E: Clone> Default for EventBus<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+/// Comment for each configuration field, keyed by its dotted path. List
+/// items share the path of their list.
+pub type FieldDocs = [(&'static str, &'static str)];
+
+/// Renders `config` as YAML under a `title` comment, with the comment from
+/// `docs` next to every field, as a starting point for a new configuration
+/// file.
+pub fn render_annotated<T: Serialize>(config: &T, title: &str, docs: &FieldDocs) -> Result<String> {
+    let value = serde_yaml::to_value(config)?;
+    let mapping = value.as_mapping()
+        .ok_or_else(|| anyhow::anyhow!("Config did not serialize to a mapping"))?;
+
+    let mut out = format!("# {}\n", title);
+    Renderer { docs }.mapping(&mut out, mapping, "", 0)?;
+    Ok(out)
+}
+
+/// Dotted paths of the fields of `config` that `docs` has no comment for.
+/// Fields under an undocumented one aren't listed separately.
+pub fn undocumented_fields<T: Serialize>(config: &T, docs: &FieldDocs) -> Result<Vec<String>> {
+    fn check(value: &Value, path: &str, docs: &FieldDocs, missing: &mut Vec<String>) {
+        match value {
+            Value::Mapping(mapping) => {
+                for (key, value) in mapping {
+                    let key = key.as_str().map(str::to_string).unwrap_or_else(|| format!("{:?}", key));
+                    let field = child_path(path, &key);
+                    if doc(docs, &field).is_none() {
+                        missing.push(field);
+                    } else {
+                        check(value, &field, docs, missing);
+                    }
+                }
+            }
+            Value::Sequence(items) => items.iter().for_each(|item| check(item, path, docs, missing)),
+            _ => {}
+        }
+    }
+
+    let mut missing = Vec::new();
+    check(&serde_yaml::to_value(config)?, "", docs, &mut missing);
+    Ok(missing)
+}
+
+fn doc(docs: &FieldDocs, path: &str) -> Option<&'static str> {
+    docs.iter().find(|(field, _)| *field == path).map(|(_, doc)| *doc)
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+struct Renderer<'a> {
+    docs: &'a FieldDocs,
+}
+
+impl Renderer<'_> {
+    fn mapping(&self, out: &mut String, mapping: &Mapping, path: &str, indent: usize) -> Result<()> {
+        let mut entries: Vec<(String, &Value)> = mapping.iter()
+            .map(|(key, value)| Ok((inline(key)?, value)))
+            .collect::<Result<_>>()?;
+        // Undocumented keys are data (e.g. a DSCP table), not fields; sort them
+        // so the output is stable
+        if !entries.iter().any(|(key, _)| doc(self.docs, &child_path(path, key)).is_some()) {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+
+        for (key, value) in entries {
+            let field = child_path(path, &key);
+            let comment = doc(self.docs, &field).map(|doc| format!("  # {}", doc)).unwrap_or_default();
+            let prefix = " ".repeat(indent);
+            match value {
+                Value::Mapping(children) if !children.is_empty() => {
+                    out.push_str(&format!("{}{}:{}\n", prefix, key, comment));
+                    self.mapping(out, children, &field, indent + 2)?;
+                }
+                Value::Sequence(items) if !items.is_empty() => {
+                    out.push_str(&format!("{}{}:{}\n", prefix, key, comment));
+                    self.sequence(out, items, &field, indent + 2)?;
+                }
+                _ => out.push_str(&format!("{}{}: {}{}\n", prefix, key, inline(value)?, comment)),
+            }
+        }
+        Ok(())
+    }
+
+    fn sequence(&self, out: &mut String, items: &[Value], path: &str, indent: usize) -> Result<()> {
+        let prefix = " ".repeat(indent);
+        for item in items {
+            match item {
+                Value::Mapping(children) if !children.is_empty() => {
+                    // Render the item two columns in, then hang its first line
+                    // off the list marker
+                    let mut rendered = String::new();
+                    self.mapping(&mut rendered, children, path, indent + 2)?;
+                    out.push_str(&prefix);
+                    out.push_str("- ");
+                    out.push_str(&rendered[indent + 2..]);
+                }
+                _ => out.push_str(&format!("{}- {}\n", prefix, inline(item)?)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn inline(value: &Value) -> Result<String> {
+    Ok(serde_yaml::to_string(value)?.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Site {
+        name: String,
+        uplinks: Vec<Uplink>,
+        tags: std::collections::BTreeMap<String, u8>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Uplink {
+        name: String,
+        enabled: bool,
+    }
+
+    const DOCS: &FieldDocs = &[
+        ("name", "site name"),
+        ("uplinks", "WAN uplinks"),
+        ("uplinks.name", "interface name"),
+        ("uplinks.enabled", "use this uplink"),
+        ("tags", "free-form tags"),
+    ];
+
+    fn site() -> Site {
+        Site {
+            name: "branch".to_string(),
+            uplinks: vec![Uplink { name: "eth0".to_string(), enabled: true }],
+            tags: [("zeta".to_string(), 1), ("alpha".to_string(), 2)].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn test_rendered_config_parses_back() {
+        let yaml = render_annotated(&site(), "Site configuration", DOCS).unwrap();
+        assert_eq!(serde_yaml::from_str::<Site>(&yaml).unwrap(), site());
+
+        assert!(yaml.starts_with("# Site configuration\n"));
+        assert!(yaml.contains("  - name: eth0  # interface name\n    enabled: true  # use this uplink\n"));
+        // Table entries are data: uncommented and sorted
+        assert!(yaml.contains("tags:  # free-form tags\n  alpha: 2\n  zeta: 1\n"));
+    }
+
+    #[test]
+    fn test_undocumented_fields_listed() {
+        assert!(undocumented_fields(&site(), DOCS).unwrap().iter().all(|field| field.starts_with("tags.")));
+        let missing = undocumented_fields(&site(), &DOCS[..3]).unwrap();
+        assert!(missing.contains(&"uplinks.enabled".to_string()) && missing.contains(&"tags".to_string()));
+    }
+}
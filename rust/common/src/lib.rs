@@ -0,0 +1,12 @@
+//! Pieces both the packet scheduler and the underlay manager need: task
+//! supervision, event fan-out, log throttling, runtime construction, config loading and
+//! units, and the annotated starter config renderer.
+
+pub mod clock;
+pub mod config_file;
+pub mod event_bus;
+pub mod init_config;
+pub mod log_limit;
+pub mod runtime;
+pub mod supervisor;
+pub mod units;
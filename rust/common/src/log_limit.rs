@@ -0,0 +1,95 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Throttles a repeating log message: the first occurrence is logged, then
+/// at most one per `interval`, each noting how many were suppressed since.
+/// Messages are told apart by a caller-chosen key.
+pub struct RateLimitedLogger {
+    interval: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    last_logged: Instant,
+    suppressed: u64,
+}
+
+/// Occurrences skipped since a message was last logged. Displays as a
+/// suffix for the message, empty when nothing was skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Suppressed(pub u64);
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => Ok(()),
+            count => write!(f, " ({} similar messages suppressed)", count),
+        }
+    }
+}
+
+impl RateLimitedLogger {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether to log this occurrence of `key`'s message, and if so the
+    /// suffix to append to it.
+    pub fn check(&self, key: &str) -> Option<Suppressed> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Option<Suppressed> {
+        let mut entries = self.entries.lock();
+        match entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.last_logged) < self.interval => {
+                entry.suppressed += 1;
+                None
+            }
+            Some(entry) => {
+                let suppressed = Suppressed(entry.suppressed);
+                entry.last_logged = now;
+                entry.suppressed = 0;
+                Some(suppressed)
+            }
+            None => {
+                entries.insert(key.to_string(), Entry { last_logged: now, suppressed: 0 });
+                Some(Suppressed(0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_suppressed_until_interval_passes() {
+        let logger = RateLimitedLogger::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert_eq!(logger.check_at("send", start), Some(Suppressed(0)));
+        for ms in [10, 20, 30] {
+            assert_eq!(logger.check_at("send", start + Duration::from_millis(ms)), None);
+        }
+        // Other messages are throttled separately
+        assert_eq!(logger.check_at("pcap", start), Some(Suppressed(0)));
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(logger.check_at("send", later), Some(Suppressed(3)));
+        assert_eq!(logger.check_at("send", later + Duration::from_secs(1)), None);
+        assert_eq!(logger.check_at("send", later + Duration::from_secs(10)), Some(Suppressed(1)));
+    }
+
+    #[test]
+    fn test_suppressed_suffix() {
+        assert_eq!(Suppressed(0).to_string(), "");
+        assert_eq!(Suppressed(4).to_string(), " (4 similar messages suppressed)");
+    }
+}
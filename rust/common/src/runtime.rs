@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use tokio::runtime::{Builder, Runtime};
+
+/// Which tokio scheduler the binary runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RuntimeFlavor {
+    /// Everything on the main thread, for the smallest footprint.
+    CurrentThread,
+    /// A pool of worker threads, one per core unless `worker_threads` says
+    /// otherwise.
+    MultiThread,
+}
+
+/// Builds the runtime `main` runs on. `worker_threads` only applies to the
+/// multi-threaded flavor; asking for it with the current-thread flavor is
+/// an error rather than silently ignored.
+pub fn build_runtime(flavor: RuntimeFlavor, worker_threads: Option<usize>) -> Result<Runtime> {
+    let mut builder = match flavor {
+        RuntimeFlavor::CurrentThread => {
+            if worker_threads.is_some() {
+                anyhow::bail!("worker_threads needs the multi-thread runtime; the current-thread runtime has no worker pool");
+            }
+            Builder::new_current_thread()
+        }
+        RuntimeFlavor::MultiThread => {
+            let mut builder = Builder::new_multi_thread();
+            if let Some(worker_threads) = worker_threads {
+                if worker_threads == 0 {
+                    anyhow::bail!("worker_threads must be at least 1");
+                }
+                builder.worker_threads(worker_threads);
+            }
+            builder
+        }
+    };
+    builder.enable_all().build().context("Failed to build the tokio runtime")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::RuntimeFlavor as TokioFlavor;
+
+    #[test]
+    fn test_runtime_built_with_requested_settings() {
+        let runtime = build_runtime(RuntimeFlavor::MultiThread, Some(2)).unwrap();
+        assert_eq!(runtime.handle().runtime_flavor(), TokioFlavor::MultiThread);
+        assert_eq!(runtime.metrics().num_workers(), 2);
+        assert_eq!(runtime.block_on(async { 1 + 1 }), 2);
+
+        let runtime = build_runtime(RuntimeFlavor::CurrentThread, None).unwrap();
+        assert_eq!(runtime.handle().runtime_flavor(), TokioFlavor::CurrentThread);
+        // Timers and IO are enabled
+        runtime.block_on(async { tokio::time::sleep(std::time::Duration::from_millis(1)).await });
+
+        assert!(build_runtime(RuntimeFlavor::MultiThread, Some(0)).is_err());
+        assert!(build_runtime(RuntimeFlavor::CurrentThread, Some(2)).is_err());
+    }
+}
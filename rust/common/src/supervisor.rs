@@ -0,0 +1,140 @@
+use anyhow::Result;
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+
+/// How a supervised background task is restarted.
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Delay before the first restart; doubles on each further restart.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive restarts allowed before the supervisor gives up. A task
+    /// that ran for at least `max_backoff` starts the count over.
+    pub max_restarts: u32,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 10,
+        }
+    }
+}
+
+/// Runs the task built by `start` in the background, restarting it with
+/// backoff whenever it exits, fails or panics. The returned handle resolves
+/// to `Ok` once `shutdown` is cancelled, or to an error when the task keeps
+/// failing past `max_restarts`.
+pub fn supervise<F, Fut>(
+    name: &'static str,
+    policy: RestartPolicy,
+    shutdown: CancellationToken,
+    mut start: F,
+) -> JoinHandle<Result<()>>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut restarts = 0;
+        let mut backoff = policy.initial_backoff;
+
+        loop {
+            let started = Instant::now();
+            let mut task = tokio::spawn(start());
+            let outcome = tokio::select! {
+                _ = shutdown.cancelled() => {
+                    task.abort();
+                    return Ok(());
+                }
+                outcome = &mut task => outcome,
+            };
+
+            match outcome {
+                Ok(Ok(())) => warn!("Background task {} exited", name),
+                Ok(Err(e)) => error!("Background task {} failed: {}", name, e),
+                Err(e) if e.is_panic() => error!("Background task {} panicked", name),
+                Err(e) => error!("Background task {} was cancelled: {}", name, e),
+            }
+
+            if started.elapsed() >= policy.max_backoff {
+                restarts = 0;
+                backoff = policy.initial_backoff;
+            }
+            if restarts >= policy.max_restarts {
+                return Err(anyhow::anyhow!(
+                    "Background task {} stopped {} times in a row, giving up", name, restarts + 1
+                ));
+            }
+            restarts += 1;
+
+            warn!("Restarting background task {} in {:?}", name, backoff);
+            tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            backoff = (backoff * 2).min(policy.max_backoff);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    fn fast_policy(max_restarts: u32) -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_secs(5),
+            max_restarts,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicked_task_is_restarted() {
+        let starts = Arc::new(AtomicU32::new(0));
+        let shutdown = CancellationToken::new();
+        let counter = starts.clone();
+        let handle = supervise("flaky", fast_policy(3), shutdown.clone(), move || {
+            let attempt = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 0 {
+                    panic!("first run crashes");
+                }
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while starts.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.unwrap();
+
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_restarts() {
+        let starts = Arc::new(AtomicU32::new(0));
+        let counter = starts.clone();
+        let handle = supervise("broken", fast_policy(2), CancellationToken::new(), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { Err(anyhow::anyhow!("cannot connect")) }
+        });
+
+        let result = tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap();
+        assert!(result.is_err());
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,130 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// A config value given either as a raw integer, as configs always could,
+/// or as a string with units.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RawValue {
+    Integer(u64),
+    Text(String),
+}
+
+/// Parses a duration such as `"500ms"`, `"5s"` or `"1m30s"` into
+/// milliseconds. Units are `ms`, `s`, `m`, `h` and `d`; a bare number is
+/// already milliseconds.
+pub fn parse_duration_ms(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if let Ok(ms) = s.parse() {
+        return Ok(ms);
+    }
+    if s.is_empty() {
+        return Err("Duration must not be empty".to_string());
+    }
+
+    let mut total: u64 = 0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (value, tail) = rest.split_at(digits);
+        let unit_len = tail.find(|c: char| c.is_ascii_digit()).unwrap_or(tail.len());
+        let (unit, next) = tail.split_at(unit_len);
+        let factor = match unit.trim() {
+            "ms" => 1,
+            "s" => 1_000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            _ => return Err(format!("Duration {:?} needs a unit after each number: ms, s, m, h or d", s)),
+        };
+        let value: u64 = value.parse()
+            .map_err(|_| format!("Duration {:?} needs a whole number before each unit", s))?;
+        total = value.checked_mul(factor)
+            .and_then(|ms| total.checked_add(ms))
+            .ok_or_else(|| format!("Duration {:?} is too long", s))?;
+        rest = next.trim_start();
+    }
+    Ok(total)
+}
+
+/// `#[serde(with)]` for millisecond fields that also accept `"5s"`-style
+/// strings. Values are written back as integers.
+pub mod duration_ms {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(ms: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(*ms)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        match RawValue::deserialize(deserializer)? {
+            RawValue::Integer(ms) => Ok(ms),
+            RawValue::Text(s) => parse_duration_ms(&s).map_err(D::Error::custom),
+        }
+    }
+}
+
+/// `duration_ms` for optional fields, which then also need `#[serde(default)]`.
+pub mod option_duration_ms {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(ms: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match ms {
+            Some(ms) => serializer.serialize_some(ms),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        match Option::<RawValue>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(RawValue::Integer(ms)) => Ok(Some(ms)),
+            Some(RawValue::Text(s)) => parse_duration_ms(&s).map(Some).map_err(D::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Settings {
+        #[serde(with = "duration_ms")]
+        interval: u64,
+        #[serde(default, with = "option_duration_ms")]
+        timeout: Option<u64>,
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration_ms("500ms"), Ok(500));
+        assert_eq!(parse_duration_ms("5s"), Ok(5_000));
+        assert_eq!(parse_duration_ms("1m30s"), Ok(90_000));
+        assert_eq!(parse_duration_ms("1h 15m"), Ok(4_500_000));
+        assert_eq!(parse_duration_ms("2000"), Ok(2_000));
+        assert!(parse_duration_ms("5").is_ok());
+        assert!(parse_duration_ms("5 sec").is_err());
+        assert!(parse_duration_ms("s").is_err());
+        assert!(parse_duration_ms("1m30").is_err());
+        assert!(parse_duration_ms("").is_err());
+    }
+
+    #[test]
+    fn test_string_and_integer_forms_parse_alike() {
+        let strings: Settings = serde_yaml::from_str("interval: 5s\ntimeout: \"250ms\"\n").unwrap();
+        let integers: Settings = serde_yaml::from_str("interval: 5000\ntimeout: 250\n").unwrap();
+        for settings in [&strings, &integers] {
+            assert_eq!(settings.interval, 5_000);
+            assert_eq!(settings.timeout, Some(250));
+        }
+
+        let unset: Settings = serde_yaml::from_str("interval: 1m\n").unwrap();
+        assert_eq!((unset.interval, unset.timeout), (60_000, None));
+
+        // Written back as plain integers
+        assert_eq!(serde_yaml::to_string(&strings).unwrap(), serde_yaml::to_string(&integers).unwrap());
+        assert!(serde_yaml::from_str::<Settings>("interval: 5 parsecs\n").is_err());
+    }
+} 
\ No newline at end of file
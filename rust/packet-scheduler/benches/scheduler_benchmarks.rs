@@ -0,0 +1,106 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use packet_scheduler::metrics_provider::StaticMetricsProvider;
+use packet_scheduler::scheduler::{Packet, PacketScheduler, ScheduledPacket};
+use packet_scheduler::transport::PacketTransport;
+use packet_scheduler::{Config, LinkMetrics, Protocol};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PACKETS: usize = 20_000;
+
+/// Transport that accepts everything, so the benchmark measures scheduling
+/// rather than socket I/O.
+struct NullTransport;
+
+#[async_trait]
+impl PacketTransport for NullTransport {
+    async fn send(&self, _packet: &ScheduledPacket) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn packet(id: usize) -> Packet {
+    Packet {
+        id: id as u64,
+        data: vec![0u8; 1200],
+        priority: 5,
+        source_ip: format!("192.168.{}.{}", (id / 250) % 250, id % 250),
+        dest_ip: "10.0.0.1".to_string(),
+        protocol: Protocol::Udp,
+        source_port: None,
+        dest_port: None,
+        dscp: None,
+        icmp_type: None,
+        icmp_code: None,
+        vlan_id: None,
+        inner_vlan_id: None,
+        timestamp: chrono::Utc::now(),
+    }
+}
+
+/// Time for `workers` workers to schedule `PACKETS` packets, excluding
+/// scheduler startup.
+async fn schedule_all(workers: usize) -> Duration {
+    let mut config = Config::default();
+    config.scheduler.workers = workers;
+    config.scheduler.max_queue_size = PACKETS;
+    let metrics = HashMap::from([("eth0", 10.0, 100.0), ("eth1", 15.0, 50.0)].map(|(link, latency_ms, bandwidth_mbps)| {
+        let metrics = LinkMetrics { latency_ms, bandwidth_mbps, ..LinkMetrics::new() };
+        (link.to_string(), metrics)
+    }));
+    let provider = Arc::new(StaticMetricsProvider::new(metrics));
+    let mut scheduler = PacketScheduler::with_metrics_provider(config, provider).await.unwrap();
+    scheduler.set_transport(Arc::new(NullTransport));
+    
+    let scheduler = Arc::new(scheduler);
+    let handle = tokio::spawn(scheduler.clone().run());
+    // Let the first metrics report arrive so workers have links to pick from
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    
+    let start = Instant::now();
+    for id in 0..PACKETS {
+        scheduler.enqueue(packet(id)).unwrap();
+    }
+    while scheduler.stats().snapshot().packets_scheduled < PACKETS as u64 {
+        tokio::task::yield_now().await;
+    }
+    let elapsed = start.elapsed();
+    
+    scheduler.stop();
+    handle.await.unwrap().unwrap();
+    elapsed
+}
+
+fn bench_worker_scaling(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(8)
+        .enable_all()
+        .build()
+        .unwrap();
+    
+    let mut group = c.benchmark_group("schedule_throughput");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+    group.sample_size(10);
+    
+    for workers in [1, 2, 4, 8] {
+        group.bench_with_input(BenchmarkId::from_parameter(workers), &workers, |b, &workers| {
+            b.iter_custom(|iterations| {
+                runtime.block_on(async {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iterations {
+                        total += schedule_all(workers).await;
+                    }
+                    total
+                })
+            });
+        });
+    }
+    
+    group.finish();
+}
+
+criterion_group!(benches, bench_worker_scaling);
+criterion_main!(benches); 
@@ -0,0 +1,623 @@
+use crate::config::{AdminConfig, AdminRole};
+use crate::proto::*;
+use crate::scheduler::{DrainMode, PacketScheduler};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader as FileReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+use x509_parser::extensions::GeneralName;
+
+/// Most admin clients served at once.
+const MAX_ADMIN_CONNECTIONS: usize = 16;
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The identity of an admin client, read from the certificate it presented
+/// once the TLS handshake verified it against `admin.client_ca_file`. Only
+/// the TLS layer creates one, so roles are granted to certified names and
+/// never to names a client merely claims.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    common_name: Option<String>,
+    subject_alt_names: Vec<String>,
+}
+
+impl ClientIdentity {
+    /// The CN and the DNS, email and URI SANs of a certificate the TLS
+    /// verifier accepted.
+    fn from_verified_cert(cert: &CertificateDer<'_>) -> Result<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref())
+            .map_err(|e| anyhow::anyhow!("Unreadable client certificate: {}", e))?;
+        let common_name = cert.subject().iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .map(str::to_string);
+        let subject_alt_names = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension.value.general_names.iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            Ok(None) => Vec::new(),
+            Err(e) => anyhow::bail!("Unreadable client certificate SANs: {}", e),
+        };
+        Ok(ClientIdentity { common_name, subject_alt_names })
+    }
+
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    pub fn subject_alt_names(&self) -> &[String] {
+        &self.subject_alt_names
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.common_name.iter().chain(&self.subject_alt_names).map(String::as_str)
+    }
+
+    fn describe(&self) -> String {
+        self.common_name.clone()
+            .or_else(|| self.subject_alt_names.first().cloned())
+            .unwrap_or_else(|| "<anonymous>".to_string())
+    }
+}
+
+/// Maps client certificate identities to admin roles.
+pub struct AdminAuthorizer {
+    roles: HashMap<String, AdminRole>,
+}
+
+impl AdminAuthorizer {
+    pub fn from_config(config: &AdminConfig) -> Self {
+        let roles = config.roles.iter()
+            .map(|mapping| (mapping.identity.clone(), mapping.role))
+            .collect();
+        AdminAuthorizer { roles }
+    }
+
+    /// The most privileged role granted to the certificate's CN or any of
+    /// its SANs, or `None` if none of them is mapped.
+    pub fn role_of(&self, identity: &ClientIdentity) -> Option<AdminRole> {
+        identity.names().filter_map(|name| self.roles.get(name).copied()).max()
+    }
+
+    /// Opens a session for a client, failing if its certificate maps to no
+    /// role.
+    pub fn session<'a>(&self, scheduler: &'a PacketScheduler, identity: &ClientIdentity) -> Result<AdminSession<'a>> {
+        match self.role_of(identity) {
+            Some(role) => Ok(AdminSession { scheduler, role, client: identity.describe() }),
+            None => {
+                warn!("Admin client {} has no role", identity.describe());
+                anyhow::bail!("Client {} is not authorized for the admin API", identity.describe())
+            }
+        }
+    }
+}
+
+/// The admin services as seen by one authenticated client: every request is
+/// checked against the client's role before reaching the scheduler. The
+/// scheduler's mutating methods are crate-private, so from outside the crate
+/// a session is the only way to reach them.
+pub struct AdminSession<'a> {
+    scheduler: &'a PacketScheduler,
+    role: AdminRole,
+    client: String,
+}
+
+impl AdminSession<'_> {
+    pub fn role(&self) -> AdminRole {
+        self.role
+    }
+
+    fn require(&self, required: AdminRole) -> Result<()> {
+        if self.role < required {
+            warn!("Admin client {} ({:?}) denied an {:?} request", self.client, self.role, required);
+            anyhow::bail!("Client {} needs the {:?} role", self.client, required);
+        }
+        Ok(())
+    }
+
+    pub fn drain_link(&self, link_name: &str, mode: DrainMode) -> Result<()> {
+        self.require(AdminRole::Operator)?;
+        self.scheduler.drain_link(link_name, mode);
+        Ok(())
+    }
+
+    pub fn undrain_link(&self, link_name: &str) -> Result<()> {
+        self.require(AdminRole::Operator)?;
+        self.scheduler.undrain_link(link_name);
+        Ok(())
+    }
+
+    /// Answers one admin API request, checked against the session's role.
+    pub async fn handle(&self, request: AdminRequest) -> RpcReply {
+        fn to_value(response: impl serde::Serialize) -> serde_json::Value {
+            serde_json::to_value(response).expect("responses serialize")
+        }
+        let result: Result<serde_json::Value, Box<dyn std::error::Error>> = match request {
+            AdminRequest::DrainLink(request) => self.drain_link(&request.link_name, request.mode)
+                .map(|()| to_value(DrainLinkResponse { link_name: request.link_name, status: "drained".to_string() }))
+                .map_err(Into::into),
+            AdminRequest::UndrainLink(request) => self.undrain_link(&request.link_name)
+                .map(|()| to_value(DrainLinkResponse { link_name: request.link_name, status: "undrained".to_string() }))
+                .map_err(Into::into),
+            AdminRequest::AddRule(request) => self.add_rule(request).await.map(to_value),
+            AdminRequest::UpdateRule(request) => self.update_rule(request).await.map(to_value),
+            AdminRequest::RemoveRule(request) => self.remove_rule(request).await.map(to_value),
+            AdminRequest::SetLinkWeight(request) => self.set_link_weight(request).await.map(to_value),
+            AdminRequest::GroupHealth(request) => self.get_group_health(request).await.map(to_value),
+            AdminRequest::ExplainSelection(request) => self.explain_last_selection(request).await.map(to_value),
+            AdminRequest::DebugState(request) => self.get_debug_state(request).await.map(to_value),
+        };
+        match result {
+            Ok(result) => RpcReply::Result(result),
+            Err(e) => RpcReply::Error(e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl QosRuleService for AdminSession<'_> {
+    async fn add_rule(&self, request: QosRuleRequest) -> Result<QosRuleResponse, Box<dyn std::error::Error>> {
+        self.require(AdminRole::Operator)?;
+        let name = request.rule.name.clone();
+        self.scheduler.add_qos_rule(request.rule)?;
+        Ok(QosRuleResponse { name, status: "added".to_string() })
+    }
+
+    async fn update_rule(&self, request: QosRuleRequest) -> Result<QosRuleResponse, Box<dyn std::error::Error>> {
+        self.require(AdminRole::Operator)?;
+        let name = request.rule.name.clone();
+        self.scheduler.update_qos_rule(request.rule)?;
+        Ok(QosRuleResponse { name, status: "updated".to_string() })
+    }
+
+    async fn remove_rule(&self, request: RemoveQosRuleRequest) -> Result<QosRuleResponse, Box<dyn std::error::Error>> {
+        self.require(AdminRole::Operator)?;
+        self.scheduler.remove_qos_rule(&request.name)?;
+        Ok(QosRuleResponse { name: request.name, status: "removed".to_string() })
+    }
+}
+
+#[async_trait]
+impl LinkWeightService for AdminSession<'_> {
+    async fn set_link_weight(&self, request: LinkWeightRequest) -> Result<LinkWeightResponse, Box<dyn std::error::Error>> {
+        self.require(AdminRole::Operator)?;
+        self.scheduler.set_link_multiplier(&request.link_name, request.multiplier)?;
+        Ok(LinkWeightResponse { link_name: request.link_name, multiplier: request.multiplier })
+    }
+}
+
+#[async_trait]
+impl GroupHealthService for AdminSession<'_> {
+    async fn get_group_health(&self, request: GroupHealthRequest) -> Result<GroupHealthResponse, Box<dyn std::error::Error>> {
+        self.require(AdminRole::ReadOnly)?;
+        self.scheduler.get_group_health(request).await
+    }
+}
+
+#[async_trait]
+impl SelectionExplanationService for AdminSession<'_> {
+    async fn explain_last_selection(&self, request: ExplainSelectionRequest) -> Result<ExplainSelectionResponse, Box<dyn std::error::Error>> {
+        self.require(AdminRole::ReadOnly)?;
+        SelectionExplanationService::explain_last_selection(self.scheduler, request).await
+    }
+}
+
+#[async_trait]
+impl DebugStateService for AdminSession<'_> {
+    async fn get_debug_state(&self, request: DebugStateRequest) -> Result<DebugStateResponse, Box<dyn std::error::Error>> {
+        self.require(AdminRole::ReadOnly)?;
+        self.scheduler.get_debug_state(request).await
+    }
+}
+
+/// The admin API: accepts TLS connections only from clients presenting a
+/// certificate that chains to `admin.client_ca_file`, and serves each
+/// through an `AdminSession` for the role its certificate maps to.
+pub struct AdminServer {
+    acceptor: TlsAcceptor,
+    authorizer: AdminAuthorizer,
+    scheduler: Arc<PacketScheduler>,
+}
+
+impl AdminServer {
+    /// Loads the server certificate and the client CAs named in `config`.
+    pub fn from_config(config: &AdminConfig, scheduler: Arc<PacketScheduler>) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&config.client_ca_file).context("admin.client_ca_file")? {
+            roots.add(cert).context("admin.client_ca_file")?;
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .context("admin.client_ca_file")?;
+        let certs = read_certs(&config.cert_file).context("admin.cert_file")?;
+        let key = read_key(&config.key_file).context("admin.key_file")?;
+        let tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .context("admin.cert_file doesn't match admin.key_file")?;
+        Ok(AdminServer {
+            acceptor: TlsAcceptor::from(Arc::new(tls)),
+            authorizer: AdminAuthorizer::from_config(config),
+            scheduler,
+        })
+    }
+
+    /// Answers admin clients on `listener` until the task is dropped.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        info!("Admin API listening on {}", listener.local_addr()?);
+        let mut connections = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("Failed to accept admin client: {}", e);
+                            continue;
+                        }
+                    };
+                    if connections.len() >= MAX_ADMIN_CONNECTIONS {
+                        warn!("Refusing admin client {}: {} connections open", peer, connections.len());
+                        continue;
+                    }
+                    connections.push(self.serve_connection(stream, peer));
+                }
+                Some(()) = connections.next(), if !connections.is_empty() => {}
+            }
+        }
+    }
+
+    /// Completes the handshake, which fails unless the client's certificate
+    /// verifies, then answers each request line with the role the
+    /// certificate maps to until the client disconnects.
+    async fn serve_connection(&self, stream: TcpStream, peer: SocketAddr) {
+        let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                warn!("Admin client {} failed the TLS handshake: {}", peer, e);
+                return;
+            }
+            Err(_) => {
+                warn!("Admin client {} didn't complete the TLS handshake within {:?}", peer, HANDSHAKE_TIMEOUT);
+                return;
+            }
+        };
+        let identity = match stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) {
+            Some(cert) => ClientIdentity::from_verified_cert(cert),
+            None => Err(anyhow::anyhow!("Admin client {} presented no certificate", peer)),
+        };
+        let session = identity.and_then(|identity| self.authorizer.session(&self.scheduler, &identity));
+        let (reader, mut writer) = tokio::io::split(stream);
+        let session = match session {
+            Ok(session) => session,
+            Err(e) => {
+                let reply = RpcReply::Error(e.to_string());
+                let _ = writer.write_all(format!("{}\n", serde_json::to_string(&reply).expect("RPC replies serialize")).as_bytes()).await;
+                let _ = writer.shutdown().await;
+                return;
+            }
+        };
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(e) => {
+                    debug!("Admin client {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let reply = match serde_json::from_str::<AdminRequest>(&line) {
+                Ok(request) => session.handle(request).await,
+                Err(e) => RpcReply::Error(format!("Invalid request: {}", e)),
+            };
+            let mut reply = serde_json::to_vec(&reply).expect("RPC replies serialize");
+            reply.push(b'\n');
+            if let Err(e) = writer.write_all(&reply).await {
+                debug!("Admin client {} failed: {}", peer, e);
+                return;
+            }
+        }
+    }
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut FileReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read certificates from {}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {}", path);
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::private_key(&mut FileReader::new(file))
+        .with_context(|| format!("Failed to read the private key from {}", path))?
+        .ok_or_else(|| anyhow::anyhow!("No private key in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AdminRoleMapping, Config};
+    use crate::test_utils::link_config;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair, SanType,
+    };
+    use rustls::pki_types::ServerName;
+    use std::fs;
+    use std::path::PathBuf;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::TlsConnector;
+
+    fn admin_config(cert_file: &str, key_file: &str, client_ca_file: &str) -> AdminConfig {
+        AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            cert_file: cert_file.to_string(),
+            key_file: key_file.to_string(),
+            client_ca_file: client_ca_file.to_string(),
+            roles: vec![
+                AdminRoleMapping { identity: "noc-dashboard".to_string(), role: AdminRole::ReadOnly },
+                AdminRoleMapping { identity: "ops@example.com".to_string(), role: AdminRole::Operator },
+            ],
+        }
+    }
+
+    fn authorizer() -> AdminAuthorizer {
+        AdminAuthorizer::from_config(&admin_config("admin.crt", "admin.key", "admin-ca.crt"))
+    }
+
+    /// A CA signing the certificates of a test.
+    struct TestCa {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl TestCa {
+        fn new(name: &str) -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+            let key = KeyPair::generate().unwrap();
+            TestCa { cert: params.self_signed(&key).unwrap(), key }
+        }
+
+        /// A client certificate with the given CN, if any, and email SANs.
+        fn client(&self, common_name: Option<&str>, emails: &[&str]) -> (Certificate, KeyPair) {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.distinguished_name = DistinguishedName::new();
+            if let Some(common_name) = common_name {
+                params.distinguished_name.push(DnType::CommonName, common_name);
+            }
+            params.subject_alt_names = emails.iter()
+                .map(|email| SanType::Rfc822Name((*email).try_into().unwrap()))
+                .collect();
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let key = KeyPair::generate().unwrap();
+            (params.signed_by(&key, &self.cert, &self.key).unwrap(), key)
+        }
+
+        fn server(&self) -> (Certificate, KeyPair) {
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+            let key = KeyPair::generate().unwrap();
+            (params.signed_by(&key, &self.cert, &self.key).unwrap(), key)
+        }
+    }
+
+    fn cert(common_name: &str, emails: &[&str]) -> ClientIdentity {
+        let (cert, _) = TestCa::new("test CA").client(Some(common_name), emails);
+        ClientIdentity::from_verified_cert(cert.der()).unwrap()
+    }
+
+    async fn scheduler() -> PacketScheduler {
+        let config = Config {
+            links: vec![link_config("eth0", None), link_config("eth1", None)],
+            ..Config::default()
+        };
+        PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap()
+    }
+
+    fn weight_request() -> LinkWeightRequest {
+        LinkWeightRequest { link_name: "eth0".to_string(), multiplier: 0.5 }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_cert_cannot_adjust_weights() {
+        let scheduler = scheduler().await;
+        let session = authorizer().session(&scheduler, &cert("noc-dashboard", &[])).unwrap();
+
+        assert!(session.set_link_weight(weight_request()).await.is_err());
+        assert!(session.drain_link("eth0", DrainMode::Hard).is_err());
+        assert_eq!(scheduler.link_multiplier("eth0"), 1.0);
+        assert!(session.get_debug_state(DebugStateRequest {}).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_operator_cert_adjusts_weights() {
+        let scheduler = scheduler().await;
+        // Matched by SAN; the CN isn't mapped
+        let session = authorizer().session(&scheduler, &cert("laptop-17", &["ops@example.com"])).unwrap();
+        assert_eq!(session.role(), AdminRole::Operator);
+
+        session.set_link_weight(weight_request()).await.unwrap();
+        assert_eq!(scheduler.link_multiplier("eth0"), 0.5);
+        assert!(session.get_debug_state(DebugStateRequest {}).await.is_ok());
+    }
+
+    fn rule(priority: u8) -> QosRuleRequest {
+        let rule = serde_yaml::from_str(&format!(
+            "name: voip\npriority: {}\nmatch_criteria: {{}}\naction:\n  link_preference: []\n",
+            priority
+        )).unwrap();
+        QosRuleRequest { rule }
+    }
+
+    #[tokio::test]
+    async fn test_qos_rules_changed_only_by_operators() {
+        let scheduler = scheduler().await;
+        let read_only = authorizer().session(&scheduler, &cert("noc-dashboard", &[])).unwrap();
+        let operator = authorizer().session(&scheduler, &cert("ops@example.com", &[])).unwrap();
+
+        assert!(read_only.add_rule(rule(6)).await.is_err());
+        assert_eq!(operator.add_rule(rule(6)).await.unwrap().status, "added");
+        assert!(operator.add_rule(rule(6)).await.is_err());
+        assert!(read_only.update_rule(rule(7)).await.is_err());
+        assert_eq!(operator.update_rule(rule(7)).await.unwrap().status, "updated");
+
+        let remove = || RemoveQosRuleRequest { name: "voip".to_string() };
+        assert!(read_only.remove_rule(remove()).await.is_err());
+        assert_eq!(operator.remove_rule(remove()).await.unwrap().status, "removed");
+        assert!(operator.remove_rule(remove()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_cert_rejected() {
+        let scheduler = scheduler().await;
+        assert!(authorizer().session(&scheduler, &cert("intruder", &["evil.example.com"])).is_err());
+        let (nameless, _) = TestCa::new("test CA").client(None, &[]);
+        let identity = ClientIdentity::from_verified_cert(nameless.der()).unwrap();
+        assert_eq!((identity.common_name(), identity.subject_alt_names()), (None, &[][..]));
+        assert!(authorizer().session(&scheduler, &identity).is_err());
+    }
+
+    #[test]
+    fn test_highest_matching_role_wins() {
+        let identity = cert("noc-dashboard", &["ops@example.com"]);
+        assert_eq!(authorizer().role_of(&identity), Some(AdminRole::Operator));
+    }
+
+    #[test]
+    fn test_identity_read_from_certificate() {
+        let identity = cert("laptop-17", &["ops@example.com"]);
+        assert_eq!(identity.common_name(), Some("laptop-17"));
+        assert_eq!(identity.subject_alt_names(), ["ops@example.com".to_string()]);
+        assert!(ClientIdentity::from_verified_cert(&CertificateDer::from(vec![0x30, 0x03, 0x02, 0x01, 0x00])).is_err());
+    }
+
+    /// Writes the server certificate, its key and the client CA for `ca`
+    /// into a fresh directory, returning the config naming them.
+    fn write_tls_files(ca: &TestCa) -> (AdminConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("admin-tls-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (server, key) = ca.server();
+        fs::write(dir.join("admin.crt"), server.pem()).unwrap();
+        fs::write(dir.join("admin.key"), key.serialize_pem()).unwrap();
+        fs::write(dir.join("admin-ca.crt"), ca.cert.pem()).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        (admin_config(&path("admin.crt"), &path("admin.key"), &path("admin-ca.crt")), dir)
+    }
+
+    async fn start_admin_server(config: &AdminConfig, scheduler: Arc<PacketScheduler>) -> SocketAddr {
+        let server = AdminServer::from_config(config, scheduler).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await });
+        addr
+    }
+
+    /// Connects trusting `ca` for the server, presenting `client` if given.
+    async fn connect(addr: SocketAddr, ca: &TestCa, client: Option<&(Certificate, KeyPair)>) -> std::io::Result<TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert.der().clone()).unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client {
+            Some((cert, key)) => {
+                let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+                builder.with_client_auth_cert(vec![cert.der().clone()], key).unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let stream = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), stream).await
+    }
+
+    /// Sends one request line and reads the reply, or `None` if the server
+    /// ended the connection instead.
+    async fn call(addr: SocketAddr, ca: &TestCa, client: Option<&(Certificate, KeyPair)>, request: &str) -> Option<serde_json::Value> {
+        let stream = connect(addr, ca, client).await.ok()?;
+        let (reader, mut writer) = tokio::io::split(stream);
+        writer.write_all(format!("{}\n", request).as_bytes()).await.ok()?;
+        let line = BufReader::new(reader).lines().next_line().await.ok()??;
+        Some(serde_json::from_str(&line).unwrap())
+    }
+
+    const SET_WEIGHT: &str = r#"{"method": "set_link_weight", "params": {"link_name": "eth0", "multiplier": 0.5}}"#;
+
+    #[tokio::test]
+    async fn test_mutating_request_over_mtls_needs_operator_cert() {
+        let ca = TestCa::new("admin CA");
+        let (config, dir) = write_tls_files(&ca);
+        let scheduler = Arc::new(scheduler().await);
+        let addr = start_admin_server(&config, scheduler.clone()).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        let read_only = ca.client(Some("noc-dashboard"), &[]);
+        let reply = call(addr, &ca, Some(&read_only), SET_WEIGHT).await.unwrap();
+        assert!(reply["error"].as_str().unwrap().contains("Operator"), "{}", reply);
+        assert_eq!(scheduler.link_multiplier("eth0"), 1.0);
+        let reply = call(addr, &ca, Some(&read_only), r#"{"method": "debug_state", "params": {}}"#).await.unwrap();
+        assert!(reply.get("result").is_some(), "{}", reply);
+
+        let operator = ca.client(Some("laptop-17"), &["ops@example.com"]);
+        let reply = call(addr, &ca, Some(&operator), SET_WEIGHT).await.unwrap();
+        assert_eq!(reply["result"]["multiplier"], 0.5);
+        assert_eq!(scheduler.link_multiplier("eth0"), 0.5);
+        let reply = call(addr, &ca, Some(&operator), r#"{"method": "drain_link", "params": {"link_name": "eth1", "mode": "Hard"}}"#).await.unwrap();
+        assert_eq!(reply["result"]["status"], "drained");
+    }
+
+    #[tokio::test]
+    async fn test_unverified_clients_rejected_by_tls() {
+        let ca = TestCa::new("admin CA");
+        let (config, dir) = write_tls_files(&ca);
+        let scheduler = Arc::new(scheduler().await);
+        let addr = start_admin_server(&config, scheduler.clone()).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Claiming the operator's names doesn't help without the admin CA's signature
+        let forged = TestCa::new("admin CA").client(Some("ops@example.com"), &["ops@example.com"]);
+        assert!(call(addr, &ca, Some(&forged), SET_WEIGHT).await.is_none());
+        assert!(call(addr, &ca, None, SET_WEIGHT).await.is_none());
+        assert_eq!(scheduler.link_multiplier("eth0"), 1.0);
+
+        // Verified but not mapped to a role
+        let unmapped = ca.client(Some("intruder"), &[]);
+        let reply = call(addr, &ca, Some(&unmapped), SET_WEIGHT).await.unwrap();
+        assert!(reply["error"].as_str().unwrap().contains("not authorized"), "{}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_missing_tls_files_fail_startup() {
+        let scheduler = Arc::new(scheduler().await);
+        let config = admin_config("/nonexistent/admin.crt", "/nonexistent/admin.key", "/nonexistent/admin-ca.crt");
+        let e = AdminServer::from_config(&config, scheduler).err().unwrap();
+        assert!(format!("{:#}", e).contains("admin.client_ca_file"), "{:#}", e);
+    }
+}
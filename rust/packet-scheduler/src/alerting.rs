@@ -0,0 +1,24 @@
+use crate::events::LinkEvent;
+use anyhow::Result;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{info, warn};
+
+/// Raises an operator alert for each link event until the bus closes.
+pub async fn alert_on_link_events(mut events: Receiver<LinkEvent>) -> Result<()> {
+    loop {
+        match events.recv().await {
+            Ok(event) => alert(&event),
+            Err(RecvError::Lagged(missed)) => warn!("Alerting fell behind, {} link events missed", missed),
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+fn alert(event: &LinkEvent) {
+    match event {
+        LinkEvent::Up(link_name) => info!("Link {} is up", link_name),
+        LinkEvent::Recovered(link_name) => info!("Link {} recovered", link_name),
+        LinkEvent::Degraded(link_name) => warn!("Link {} degraded: sudden latency/loss increase", link_name),
+        LinkEvent::Down(link_name) => warn!("Link {} is down", link_name),
+    }
+}
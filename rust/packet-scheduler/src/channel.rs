@@ -0,0 +1,146 @@
+use crossbeam_channel::{bounded, Receiver, SendError, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+
+/// What a sender does when its bounded channel is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for the receiver to make room.
+    #[default]
+    Block,
+    /// Evict the oldest queued item to make room for the new one.
+    DropOldest,
+    /// Discard the new item, keeping what's already queued.
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// Sent after evicting the oldest queued item.
+    DroppedOldest,
+    /// Not sent because the channel was full.
+    DroppedNewest,
+}
+
+/// Sending half of a bounded channel that applies an [`OverflowPolicy`].
+pub struct PolicySender<T> {
+    sender: Sender<T>,
+    /// Kept only under `DropOldest`, to evict from the front of the queue.
+    evictor: Option<Receiver<T>>,
+    policy: OverflowPolicy,
+}
+
+pub fn bounded_with_policy<T>(capacity: usize, policy: OverflowPolicy) -> (PolicySender<T>, Receiver<T>) {
+    let (sender, receiver) = bounded(capacity);
+    let evictor = (policy == OverflowPolicy::DropOldest).then(|| receiver.clone());
+    (PolicySender { sender, evictor, policy }, receiver)
+}
+
+impl<T> PolicySender<T> {
+    /// Sends `item` according to the channel's policy. Only fails if every
+    /// receiver has been dropped. Under `Block` this blocks the thread, so
+    /// async code uses `try_send` and waits for room itself.
+    pub fn send(&self, item: T) -> Result<SendOutcome, SendError<T>> {
+        match self.policy {
+            OverflowPolicy::Block => self.sender.send(item).map(|_| SendOutcome::Sent),
+            OverflowPolicy::DropNewest => match self.sender.try_send(item) {
+                Ok(()) => Ok(SendOutcome::Sent),
+                Err(TrySendError::Full(_)) => Ok(SendOutcome::DroppedNewest),
+                Err(TrySendError::Disconnected(item)) => Err(SendError(item)),
+            },
+            OverflowPolicy::DropOldest => {
+                let mut outcome = SendOutcome::Sent;
+                let mut item = item;
+                loop {
+                    match self.sender.try_send(item) {
+                        Ok(()) => return Ok(outcome),
+                        Err(TrySendError::Full(rejected)) => {
+                            // The receiver may drain concurrently, so just retry if there was nothing to evict
+                            if let Some(ref evictor) = self.evictor {
+                                if evictor.try_recv().is_ok() {
+                                    outcome = SendOutcome::DroppedOldest;
+                                }
+                            }
+                            item = rejected;
+                        }
+                        Err(TrySendError::Disconnected(rejected)) => return Err(SendError(rejected)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like `send`, but never waits: under `Block` a full channel hands the
+    /// item back as `TrySendError::Full` for the caller to retry once there
+    /// is room.
+    pub fn try_send(&self, item: T) -> Result<SendOutcome, TrySendError<T>> {
+        match self.policy {
+            OverflowPolicy::Block => self.sender.try_send(item).map(|_| SendOutcome::Sent),
+            _ => self.send(item).map_err(|SendError(item)| TrySendError::Disconnected(item)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_block_waits_for_room() {
+        let (sender, receiver) = bounded_with_policy(1, OverflowPolicy::Block);
+        sender.send(1).unwrap();
+
+        let handle = std::thread::spawn(move || sender.send(2).unwrap());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_finished());
+
+        assert_eq!(receiver.recv().unwrap(), 1);
+        assert_eq!(handle.join().unwrap(), SendOutcome::Sent);
+        assert_eq!(receiver.recv().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_try_send_returns_instead_of_blocking() {
+        let (sender, receiver) = bounded_with_policy(1, OverflowPolicy::Block);
+        assert_eq!(sender.try_send(1).unwrap(), SendOutcome::Sent);
+        assert!(matches!(sender.try_send(2), Err(TrySendError::Full(2))));
+
+        assert_eq!(receiver.recv().unwrap(), 1);
+        assert_eq!(sender.try_send(2).unwrap(), SendOutcome::Sent);
+    }
+
+    #[test]
+    fn test_drop_oldest_evicts_front() {
+        let (sender, receiver) = bounded_with_policy(2, OverflowPolicy::DropOldest);
+        assert_eq!(sender.send(1).unwrap(), SendOutcome::Sent);
+        assert_eq!(sender.send(2).unwrap(), SendOutcome::Sent);
+        assert_eq!(sender.send(3).unwrap(), SendOutcome::DroppedOldest);
+
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_drop_newest_keeps_queued() {
+        let (sender, receiver) = bounded_with_policy(2, OverflowPolicy::DropNewest);
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        assert_eq!(sender.send(3).unwrap(), SendOutcome::DroppedNewest);
+
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_disconnected_receiver_fails_send() {
+        let (sender, receiver) = bounded_with_policy(2, OverflowPolicy::DropNewest);
+        drop(receiver);
+        assert!(sender.send(1).is_err());
+    }
+
+    #[test]
+    fn test_policy_from_yaml() {
+        let policy: OverflowPolicy = serde_yaml::from_str("drop_oldest").unwrap();
+        assert_eq!(policy, OverflowPolicy::DropOldest);
+    }
+} 
@@ -0,0 +1,74 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network as `address/prefix_len`; a bare address is a
+/// host route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(cidr: &str) -> anyhow::Result<Self> {
+        let (address, prefix_len) = cidr.split_once('/').unwrap_or((cidr, ""));
+        let network: IpAddr = address.parse()
+            .with_context(|| format!("Invalid address in {}", cidr))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = if prefix_len.is_empty() {
+            max_len
+        } else {
+            prefix_len.parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| anyhow::anyhow!("Invalid prefix length in {}", cidr))?
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(cidr: String) -> Result<Self, Self::Error> {
+        cidr.parse().map_err(|e: anyhow::Error| e.to_string())
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
@@ -1,28 +1,206 @@
+use crate::channel::OverflowPolicy;
+use crate::cidr::Cidr;
+use crate::protocol::Protocol;
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::collections::HashMap;
+use sdwan_common::config_file::{load_yaml, process_env, EnvLookup};
 use std::path::Path;
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Every section and most settings may be left out, taking the value from
+/// `Config::default()`, so configs written before a setting existed keep
+/// parsing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub scheduler: SchedulerConfig,
     pub qos: QosConfig,
     pub links: Vec<LinkConfig>,
     pub failover: FailoverConfig,
+    pub tunnel: Option<TunnelConfig>,
+    /// Export flow records of scheduled traffic to an IPFIX collector.
+    pub ipfix: Option<IpfixConfig>,
+    /// Source subnets forced onto a link regardless of link health.
+    pub policy_routes: Vec<PolicyRoute>,
+    /// Per-link egress budgets shared by QoS classes.
+    pub shaping: Vec<LinkShaping>,
+    /// Client-certificate authentication and roles for the admin API.
+    pub admin: Option<AdminConfig>,
 }
 
+/// Algorithms `scheduler.algorithm` and per-class overrides may name.
+pub const SCHEDULER_ALGORITHMS: [&str; 3] = ["weighted_round_robin", "weighted_ecmp", "mos"];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SchedulerConfig {
     pub algorithm: String,
     pub batch_size: usize,
     pub max_queue_size: usize,
+    #[serde(with = "crate::units::duration_ms")]
     pub metrics_interval: u64,
+    /// Pin each flow to the link its first packet was scheduled on.
+    pub flow_affinity: bool,
+    /// Milliseconds without traffic after which a pinned flow is forgotten.
+    #[serde(with = "crate::units::duration_ms")]
+    pub flow_idle_timeout: u64,
+    /// Health score margin a challenger link must exceed the currently
+    /// selected link by before selection switches. 0 disables hysteresis.
+    pub selection_hysteresis: f64,
+    /// Algorithm evaluated alongside `algorithm` without affecting routing,
+    /// counting how often it would have picked a different link.
+    pub shadow_algorithm: Option<String>,
+    /// How many alternate links to try when sending on the selected link fails.
+    pub send_retries: usize,
+    /// Number of worker tasks scheduling packets in parallel. Idle workers
+    /// steal queued packets from busy ones.
+    pub workers: usize,
+    /// Capacity of the channel carrying metrics reports from the underlay
+    /// manager.
+    pub metrics_channel_capacity: usize,
+    /// Capacity of each link's scheduled-packet output queue; defaults to
+    /// `max_queue_size`.
+    pub packet_channel_capacity: Option<usize>,
+    /// What the metrics and packet channels do when full.
+    pub overflow_policy: OverflowPolicy,
+    /// How much a link's output queue filling up lowers its score: a full
+    /// queue scales it by `1 - queue_depth_penalty`. 0 ignores queue depth.
+    pub queue_depth_penalty: f64,
+    /// Seed for randomized selection (`weighted_ecmp`), for reproducible runs.
+    /// Unset seeds from OS entropy.
+    pub rng_seed: Option<u64>,
+    /// Bounds in ms on the reorder window, which otherwise follows the
+    /// latency spread across active links.
+    #[serde(with = "crate::units::duration_ms")]
+    pub reorder_window_min_ms: u64,
+    #[serde(with = "crate::units::duration_ms")]
+    pub reorder_window_max_ms: u64,
+    /// Degrade to cheap selection and drop low-priority traffic while the
+    /// packet queue is deep.
+    pub load_shedding: Option<LoadSheddingConfig>,
+    /// Deadline in ms for each metrics request to the underlay manager; on
+    /// timeout the last-known metrics stay in use.
+    #[serde(with = "crate::units::duration_ms")]
+    pub grpc_timeout_ms: u64,
+    /// Milliseconds a link may go unselected before the underlay manager is
+    /// told it's idle and probes it less often.
+    #[serde(with = "crate::units::duration_ms")]
+    pub idle_link_after: u64,
+    /// Milliseconds a link's metrics towards a destination prefix stay in
+    /// use after they were measured; older ones give way to the link's
+    /// overall metrics.
+    #[serde(with = "crate::units::duration_ms")]
+    pub destination_metrics_ttl: u64,
+}
+
+impl SchedulerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.grpc_timeout_ms == 0 {
+            anyhow::bail!("scheduler.grpc_timeout_ms must be positive; 0 would time out every metrics request");
+        }
+        if !(0.0..=1.0).contains(&self.queue_depth_penalty) {
+            anyhow::bail!("scheduler.queue_depth_penalty {} is outside 0.0-1.0", self.queue_depth_penalty);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadSheddingConfig {
+    /// Queue depth above which shedding starts.
+    pub high_watermark: usize,
+    /// Queue depth at or below which full behaviour resumes.
+    pub low_watermark: usize,
+    /// Packets at or below this priority are dropped while shedding.
+    #[serde(default = "default_shed_priority")]
+    pub shed_priority: u8,
+}
+
+fn default_shed_priority() -> u8 {
+    1
+}
+
+fn default_grpc_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_reorder_window_min_ms() -> u64 {
+    5
+}
+
+fn default_reorder_window_max_ms() -> u64 {
+    100
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_metrics_channel_capacity() -> usize {
+    100
+}
+
+fn default_send_retries() -> usize {
+    1
+}
+
+fn default_queue_depth_penalty() -> f64 {
+    1.0
+}
+
+fn default_flow_idle_timeout() -> u64 {
+    30000
+}
+
+fn default_idle_link_after() -> u64 {
+    60000
+}
+
+fn default_destination_metrics_ttl() -> u64 {
+    30000
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct QosConfig {
     pub rules: Vec<QosRule>,
     pub default_priority: u8,
+    /// Optional path to a YAML list of additional rules, resolved relative to
+    /// the main config file. Inline `rules` take precedence on name clashes.
+    pub rules_file: Option<String>,
+    /// Priority per protocol (e.g. `ICMP: 6`) for packets no rule matches,
+    /// consulted before `default_priority`.
+    pub protocol_defaults: HashMap<String, u8>,
+    /// Priority per DSCP class name (`EF`, `AF41`, `CS6`, ...) or decimal
+    /// codepoint for packets no rule matches, consulted before
+    /// `protocol_defaults`. Replaces the built-in RFC 4594 table when set.
+    pub dscp_priority_map: HashMap<String, u8>,
+    /// Sources whose DSCP markings are honored. Packets from anywhere else
+    /// are classified as if unmarked (DSCP 0), so clients can't mark their
+    /// own traffic into a better class. Unset trusts every source.
+    pub trust_dscp_from: Option<Vec<Cidr>>,
+}
+
+/// Service classes from RFC 4594, highest first. Default forwarding (`DF`)
+/// is left out so unmarked traffic falls through to the other defaults.
+fn default_dscp_priority_map() -> HashMap<String, u8> {
+    [
+        ("CS7", 7), ("CS6", 7),                 // network control
+        ("EF", 6),                              // telephony
+        ("CS5", 5),                             // signaling
+        ("AF41", 5), ("AF42", 5), ("AF43", 5),  // multimedia conferencing
+        ("CS4", 5),                             // real-time interactive
+        ("AF31", 4), ("AF32", 4), ("AF33", 4),  // multimedia streaming
+        ("CS3", 4),                             // broadcast video
+        ("AF21", 3), ("AF22", 3), ("AF23", 3),  // low-latency data
+        ("CS2", 3),                             // OAM
+        ("AF11", 2), ("AF12", 2), ("AF13", 2),  // high-throughput data
+        ("CS1", 1),                             // low-priority data
+    ]
+    .into_iter()
+    .map(|(class, priority)| (class.to_string(), priority))
+    .collect()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,25 +212,202 @@ pub struct QosRule {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "MatchCriteriaSpec")]
 pub struct MatchCriteria {
     pub source_ip: Option<String>,
     pub dest_ip: Option<String>,
-    pub protocol: Option<String>,
+    pub protocol: Option<Protocol>,
     pub port_range: Option<PortRange>,
     pub dscp: Option<u8>,
+    /// ICMP message type and code, e.g. 8/0 for echo request. Only valid in
+    /// rules matching protocol ICMP.
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    /// 802.1Q VLAN ID (1-4094) of the outer tag, the S-tag of a QinQ frame.
+    /// Untagged packets never match.
+    pub vlan_id: Option<u16>,
+    /// VLAN ID of the inner (C-) tag of a QinQ frame.
+    pub inner_vlan_id: Option<u16>,
+}
+
+/// `MatchCriteria` as written, before a service name in its port range is
+/// resolved for its protocol.
+#[derive(Deserialize)]
+struct MatchCriteriaSpec {
+    source_ip: Option<String>,
+    dest_ip: Option<String>,
+    protocol: Option<Protocol>,
+    port_range: Option<PortRangeSpec>,
+    dscp: Option<u8>,
+    icmp_type: Option<u8>,
+    icmp_code: Option<u8>,
+    vlan_id: Option<u16>,
+    inner_vlan_id: Option<u16>,
+}
+
+impl TryFrom<MatchCriteriaSpec> for MatchCriteria {
+    type Error = String;
+
+    fn try_from(spec: MatchCriteriaSpec) -> std::result::Result<Self, Self::Error> {
+        let port_range = match spec.port_range {
+            Some(PortRangeSpec::Range { start, end }) => Some(PortRange { start, end }),
+            Some(PortRangeSpec::Spec(port_spec)) => Some(crate::services::parse_port_spec(&port_spec, spec.protocol.as_ref())?),
+            None => None,
+        };
+        Ok(MatchCriteria {
+            source_ip: spec.source_ip,
+            dest_ip: spec.dest_ip,
+            protocol: spec.protocol,
+            port_range,
+            dscp: spec.dscp,
+            icmp_type: spec.icmp_type,
+            icmp_code: spec.icmp_code,
+            vlan_id: spec.vlan_id,
+            inner_vlan_id: spec.inner_vlan_id,
+        })
+    }
 }
 
+/// Inclusive port range. In YAML either `{start, end}` or a string: a port,
+/// a `"start-end"` range or a service name such as `"https"`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "PortRangeSpec")]
 pub struct PortRange {
     pub start: u16,
     pub end: u16,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeSpec {
+    Range { start: u16, end: u16 },
+    Spec(String),
+}
+
+impl TryFrom<PortRangeSpec> for PortRange {
+    type Error = String;
+
+    fn try_from(spec: PortRangeSpec) -> std::result::Result<Self, Self::Error> {
+        match spec {
+            PortRangeSpec::Range { start, end } => Ok(PortRange { start, end }),
+            PortRangeSpec::Spec(spec) => crate::services::parse_port_spec(&spec, None),
+        }
+    }
+}
+
+/// A rate written as a number and unit, e.g. `"10Mbps"`. `Kbps` and `Mbps`
+/// are decimal kilo- and megabits per second, `Bps` bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BandwidthLimit {
+    pub value: u64,
+    pub unit: BandwidthUnit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BandwidthUnit {
+    Bps,
+    Kbps,
+    Mbps,
+}
+
+impl BandwidthUnit {
+    const ALL: [BandwidthUnit; 3] = [BandwidthUnit::Bps, BandwidthUnit::Kbps, BandwidthUnit::Mbps];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            BandwidthUnit::Bps => "Bps",
+            BandwidthUnit::Kbps => "Kbps",
+            BandwidthUnit::Mbps => "Mbps",
+        }
+    }
+
+    fn bytes_per_sec(self) -> u64 {
+        match self {
+            BandwidthUnit::Bps => 1,
+            BandwidthUnit::Kbps => 1_000 / 8,
+            BandwidthUnit::Mbps => 1_000_000 / 8,
+        }
+    }
+}
+
+impl BandwidthLimit {
+    /// `bps` in the largest unit that represents it exactly, if any does.
+    pub fn from_bits_per_sec(bps: u64) -> Option<Self> {
+        [BandwidthUnit::Mbps, BandwidthUnit::Kbps, BandwidthUnit::Bps].into_iter()
+            .find(|unit| bps % (unit.bytes_per_sec() * 8) == 0)
+            .map(|unit| BandwidthLimit { value: bps / (unit.bytes_per_sec() * 8), unit })
+    }
+
+    /// The limit in bytes per second, the unit enforcement works in.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.value.saturating_mul(self.unit.bytes_per_sec())
+    }
+
+    pub fn bits_per_sec(&self) -> u64 {
+        self.bytes_per_sec().saturating_mul(8)
+    }
+}
+
+impl std::str::FromStr for BandwidthLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, suffix) = s.split_at(digits);
+        // Case matters: "Bps" is bytes, so "bps" can't be read as either
+        let unit = BandwidthUnit::ALL.into_iter()
+            .find(|unit| unit.suffix() == suffix.trim_start())
+            .ok_or_else(|| format!("Bandwidth limit {:?} needs a unit: Bps, Kbps or Mbps", s))?;
+        let value = value.parse()
+            .map_err(|_| format!("Bandwidth limit {:?} needs a whole number before the unit", s))?;
+        Ok(BandwidthLimit { value, unit })
+    }
+}
+
+impl TryFrom<String> for BandwidthLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BandwidthLimit> for String {
+    fn from(limit: BandwidthLimit) -> Self {
+        format!("{}{}", limit.value, limit.unit.suffix())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QosAction {
+    /// Links to try in order: the class uses the first healthy one, or the
+    /// best link overall when none of them is.
     pub link_preference: Vec<String>,
-    pub bandwidth_limit: Option<u64>,
+    /// Most this class may send on each link; past it, its packets wait in
+    /// the link's shaper.
+    pub bandwidth_limit: Option<BandwidthLimit>,
+    #[serde(default, with = "crate::units::option_duration_ms")]
     pub latency_threshold: Option<u64>,
+    /// Drop packets of this class queued for longer than this many ms
+    /// rather than sending them late.
+    #[serde(default, with = "crate::units::option_duration_ms")]
+    pub max_age_ms: Option<u64>,
+    /// Send the first this many packets of each new flow (handshakes, DNS
+    /// queries) over the most reliable link before normal selection takes
+    /// over.
+    #[serde(default)]
+    pub pin_first_packets: Option<u32>,
+    /// Selects links for this class with this algorithm instead of
+    /// `scheduler.algorithm`.
+    #[serde(default)]
+    pub scheduler_algorithm: Option<String>,
+    /// Bandwidth reserved for this class on every link, in bits per second,
+    /// written as a rate such as `"2Mbps"`. Under contention the class still
+    /// gets at least this much; other traffic only gets what's left.
+    #[serde(default, with = "crate::units::option_bandwidth_bps")]
+    pub min_bandwidth: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -60,52 +415,661 @@ pub struct LinkConfig {
     pub name: String,
     pub interface: String,
     pub weight: f64,
+    /// Bits per second, written as a rate such as `"100Mbps"`.
+    #[serde(with = "crate::units::bandwidth_bps")]
     pub max_bandwidth: u64,
+    #[serde(with = "crate::units::duration_ms")]
     pub min_latency: u64,
     pub failover_group: Option<String>,
+    /// Rank within the failover group, 0 being the most preferred. Traffic
+    /// only uses a tier while every better tier in the group is down.
+    #[serde(default)]
+    pub failover_tier: u32,
+    /// Local address the tunnel socket for this link binds to.
+    pub source_address: Option<String>,
+    /// Score multipliers applied on a schedule, e.g. to reserve a metered
+    /// link for off-peak hours. The first active window wins.
+    #[serde(default)]
+    pub time_multipliers: Vec<TimedMultiplier>,
+    /// Path MTU in bytes. Tunnel frames that would exceed it are sent as
+    /// fragments; unset sends every frame whole.
+    #[serde(default)]
+    pub mtu: Option<usize>,
+    /// Most packets sent on this link and not yet acknowledged by the far
+    /// end. A link at its cap is passed over while another has room; unset
+    /// leaves it uncapped.
+    #[serde(default)]
+    pub max_in_flight: Option<usize>,
+    /// Milliseconds after which an unacknowledged packet stops counting
+    /// against `max_in_flight`, so lost acks can't wedge the link.
+    #[serde(default = "default_in_flight_timeout", with = "crate::units::duration_ms")]
+    pub in_flight_timeout_ms: u64,
+}
+
+/// Smallest datagram every IPv4 host must accept.
+const MIN_MTU: usize = 576;
+
+impl LinkConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_in_flight == Some(0) {
+            anyhow::bail!("Link {}: max_in_flight must be at least 1", self.name);
+        }
+        if self.max_in_flight.is_some() && self.in_flight_timeout_ms == 0 {
+            anyhow::bail!("Link {}: in_flight_timeout_ms must be positive", self.name);
+        }
+        for timed in &self.time_multipliers {
+            if !(0.0..=1.0).contains(&timed.multiplier) {
+                anyhow::bail!("Link {}: time multiplier {} is outside 0.0-1.0", self.name, timed.multiplier);
+            }
+        }
+        if let Some(mtu) = self.mtu {
+            if mtu < MIN_MTU {
+                anyhow::bail!("Link {}: mtu {} is below the IPv4 minimum of {}", self.name, mtu, MIN_MTU);
+            }
+        }
+        Ok(())
+    }
+
+    /// Multiplier of the first window active at `at`, if any.
+    pub fn time_multiplier(&self, at: NaiveDateTime) -> Option<f64> {
+        self.time_multipliers.iter()
+            .find(|timed| timed.window.contains(at))
+            .map(|timed| timed.multiplier)
+    }
+}
+
+/// Scales a link's selection score by `multiplier` (0.0-1.0) while `window`
+/// is active.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimedMultiplier {
+    #[serde(flatten)]
+    pub window: TimeWindow,
+    pub multiplier: f64,
+}
+
+/// Daily window of local time, written `start: "22:00"`, `end: "06:00"`. An
+/// `end` before `start` runs past midnight. `days` limits the window to the
+/// days it opens on; empty means every day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let time = at.time();
+        let opened_on = if self.start <= self.end {
+            if time < self.start || time >= self.end {
+                return false;
+            }
+            at.date()
+        } else if time >= self.start {
+            at.date()
+        } else if time < self.end {
+            // Still in the window that opened yesterday evening
+            match at.date().pred_opt() {
+                Some(date) => date,
+                None => return false,
+            }
+        } else {
+            return false;
+        };
+        self.days.is_empty() || self.days.contains(&opened_on.weekday())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRoute {
+    /// Source subnet in CIDR notation, e.g. `10.1.0.0/16`.
+    pub source: String,
+    /// Link the subnet's traffic must use.
+    pub link: String,
+    /// What happens to the traffic while `link` is down.
+    #[serde(default)]
+    pub on_link_down: PolicyFallback,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyFallback {
+    /// Select a link by health as if there were no route.
+    #[default]
+    Fallback,
+    /// Drop the traffic rather than let it leave over another link.
+    Drop,
+}
+
+/// Hierarchical shaping of one link: the link's budget is shared by QoS
+/// classes, each guaranteed its own rate and able to borrow unused budget
+/// up to its ceiling.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkShaping {
+    pub link: String,
+    /// The link's total egress budget.
+    pub rate: BandwidthLimit,
+    pub classes: Vec<ShapingClass>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShapingClass {
+    /// QoS rule whose traffic this class shapes.
+    pub name: String,
+    /// Guaranteed rate.
+    pub rate: BandwidthLimit,
+    /// Most the class may send by borrowing; defaults to the link's rate.
+    #[serde(default)]
+    pub ceil: Option<BandwidthLimit>,
+}
+
+impl LinkShaping {
+    pub fn validate(&self, links: &[LinkConfig]) -> Result<()> {
+        if !links.iter().any(|link| link.name == self.link) {
+            anyhow::bail!("Shaping names unknown link {}", self.link);
+        }
+        let link_rate = self.rate.bytes_per_sec();
+        let guaranteed: u64 = self.classes.iter().map(|class| class.rate.bytes_per_sec()).sum();
+        if guaranteed > link_rate {
+            anyhow::bail!("Shaping for {}: class rates add up to more than the link's {}", self.link, String::from(self.rate));
+        }
+        for class in &self.classes {
+            let ceil = class.ceil.map_or(link_rate, |ceil| ceil.bytes_per_sec());
+            if ceil < class.rate.bytes_per_sec() || ceil > link_rate {
+                anyhow::bail!("Shaping for {}: class {} ceil must be between its rate and the link's", self.link, class.name);
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelConfig {
+    /// Encrypt tunnel payloads with ChaCha20-Poly1305. Requires the
+    /// `encryption` feature.
+    #[serde(default)]
+    pub encrypt: bool,
+    /// Pre-shared 256-bit key as 64 hex characters.
+    pub psk: Option<String>,
+    /// How long the receiving end waits for the missing fragments of a
+    /// fragmented frame before dropping it. Unset follows the scheduler's
+    /// reorder window when the receiver is attached to one, else 2s.
+    #[serde(default, with = "crate::units::option_duration_ms")]
+    pub reassembly_timeout_ms: Option<u64>,
+    /// Frames a fragmented frame may fall behind the newest one from its
+    /// sender before the receiving end gives up on it.
+    #[serde(default = "default_reassembly_window")]
+    pub reassembly_window: u64,
+    /// Far end of the tunnel as `ip:port`. Unset, the first peer the
+    /// underlay manager's discovery found is used, at `port`.
+    #[serde(default)]
+    pub peer: Option<String>,
+    /// UDP port tunnel ends receive on, for reaching discovered peers.
+    #[serde(default = "default_tunnel_port")]
+    pub port: u16,
+}
+
+/// Reassembly timeout of a receiver without `tunnel.reassembly_timeout_ms`
+/// until a scheduler adapts it.
+pub const DEFAULT_REASSEMBLY_TIMEOUT_MS: u64 = 2000;
+
+fn default_tunnel_port() -> u16 {
+    47192
+}
+
+fn default_reassembly_window() -> u64 {
+    crate::fragment::DEFAULT_REASSEMBLY_WINDOW
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            encrypt: false,
+            psk: None,
+            reassembly_timeout_ms: None,
+            reassembly_window: default_reassembly_window(),
+            peer: None,
+            port: default_tunnel_port(),
+        }
+    }
+}
+
+impl TunnelConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.reassembly_timeout_ms == Some(0) {
+            anyhow::bail!("tunnel.reassembly_timeout_ms must be positive; 0 would drop every fragmented frame");
+        }
+        if self.reassembly_window == 0 {
+            anyhow::bail!("tunnel.reassembly_window must be positive; 0 would drop every frame behind the newest");
+        }
+        if let Some(ref peer) = self.peer {
+            peer.parse::<std::net::SocketAddr>()
+                .map_err(|e| anyhow::anyhow!("tunnel.peer {:?} is not an ip:port address: {}", peer, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct FailoverConfig {
     pub enabled: bool,
+    #[serde(with = "crate::units::duration_ms")]
     pub health_check_interval: u64,
     pub failover_threshold: u64,
     pub recovery_threshold: u64,
+    /// Minimum health score for a metrics sample to count as healthy.
+    pub health_threshold: f64,
+    /// A link is flagged as anomalous when its latency or loss grows by more
+    /// than this factor relative to the previous `anomaly_window` samples.
+    pub anomaly_factor: f64,
+    pub anomaly_window: usize,
+    /// Keep traffic on one active link and, when it degrades, duplicate onto
+    /// a backup until the backup has delivered `recovery_threshold` packets
+    /// before cutting over.
+    pub make_before_break: bool,
+    /// After recovering from down, a link's score ramps linearly from 0 to
+    /// full over this many ms, so a still-stabilizing path isn't flooded.
+    /// 0 puts it back to full use at once.
+    #[serde(with = "crate::units::duration_ms")]
+    pub warmup_ms: u64,
+    /// For this many ms after recovering (warm-up included) a link's score
+    /// is scaled by `recovery_cooldown_penalty`, so a marginally stable link
+    /// isn't immediately preferred again and flapped back down. 0 disables.
+    #[serde(with = "crate::units::duration_ms")]
+    pub recovery_cooldown_ms: u64,
+    /// Score factor in 0.0-1.0 applied during the cooldown.
+    pub recovery_cooldown_penalty: f64,
+    /// Per-tier overrides of `failover_threshold` and `recovery_threshold`,
+    /// for links whose `failover_tier` matches.
+    pub tiers: Vec<FailoverTier>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailoverTier {
+    pub tier: u32,
+    #[serde(default)]
+    pub failover_threshold: Option<u64>,
+    #[serde(default)]
+    pub recovery_threshold: Option<u64>,
+}
+
+impl FailoverConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.recovery_cooldown_penalty) {
+            anyhow::bail!("failover.recovery_cooldown_penalty {} is outside 0.0-1.0", self.recovery_cooldown_penalty);
+        }
+        let mut seen = std::collections::HashSet::new();
+        for tier in &self.tiers {
+            if !seen.insert(tier.tier) {
+                anyhow::bail!("failover.tiers: tier {} is listed more than once", tier.tier);
+            }
+        }
+        Ok(())
+    }
+
+    /// Consecutive unhealthy samples that take a link in `tier` down.
+    pub fn failover_threshold_for(&self, tier: u32) -> u64 {
+        self.tiers.iter()
+            .find(|t| t.tier == tier)
+            .and_then(|t| t.failover_threshold)
+            .unwrap_or(self.failover_threshold)
+    }
+
+    /// Consecutive healthy samples that bring a link in `tier` back up.
+    pub fn recovery_threshold_for(&self, tier: u32) -> u64 {
+        self.tiers.iter()
+            .find(|t| t.tier == tier)
+            .and_then(|t| t.recovery_threshold)
+            .unwrap_or(self.recovery_threshold)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IpfixConfig {
+    /// Collector address as `host:port`, receiving IPFIX over UDP.
+    pub collector: String,
+    /// Milliseconds without packets after which a flow's record is exported.
+    #[serde(default = "default_ipfix_idle_timeout", with = "crate::units::duration_ms")]
+    pub idle_timeout_ms: u64,
+    /// Milliseconds after which a long-lived flow's record is exported and
+    /// a new one started.
+    #[serde(default = "default_ipfix_active_timeout", with = "crate::units::duration_ms")]
+    pub active_timeout_ms: u64,
+    #[serde(default)]
+    pub observation_domain_id: u32,
+}
+
+/// The admin API only accepts clients presenting a certificate signed by
+/// `client_ca_file`; the certificate's CN or a SAN must appear in `roles`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// `ip:port` the admin API listens on for TLS connections.
+    #[serde(default = "default_admin_listen")]
+    pub listen: String,
+    /// PEM certificate chain the admin API presents to clients.
+    pub cert_file: String,
+    /// PEM private key of `cert_file`.
+    pub key_file: String,
+    /// PEM bundle of the CAs client certificates must chain to.
+    pub client_ca_file: String,
+    #[serde(default)]
+    pub roles: Vec<AdminRoleMapping>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminRoleMapping {
+    /// Certificate common name or subject alternative name.
+    pub identity: String,
+    pub role: AdminRole,
+}
+
+/// Ordered by privilege, so a role satisfies any requirement below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// May read state, metrics and selection explanations.
+    ReadOnly,
+    /// May also drain links, push QoS rules and adjust link weights.
+    Operator,
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.listen.parse::<std::net::SocketAddr>().is_err() {
+            anyhow::bail!("admin.listen {} is not an ip:port", self.listen);
+        }
+        for (field, path) in [("cert_file", &self.cert_file), ("key_file", &self.key_file), ("client_ca_file", &self.client_ca_file)] {
+            if path.is_empty() {
+                anyhow::bail!("admin.{} must not be empty", field);
+            }
+        }
+        let mut seen = std::collections::HashSet::new();
+        for mapping in &self.roles {
+            if mapping.identity.is_empty() {
+                anyhow::bail!("admin.roles: identity must not be empty");
+            }
+            if !seen.insert(mapping.identity.as_str()) {
+                anyhow::bail!("admin.roles: identity {} mapped more than once", mapping.identity);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_admin_listen() -> String {
+    "127.0.0.1:9443".to_string()
+}
+
+fn default_in_flight_timeout() -> u64 {
+    2000
+}
+
+fn default_ipfix_idle_timeout() -> u64 {
+    15000
+}
+
+fn default_ipfix_active_timeout() -> u64 {
+    60000
+}
+
+fn default_recovery_cooldown_penalty() -> f64 {
+    0.5
+}
+
+fn default_health_threshold() -> f64 {
+    0.3
+}
+
+fn default_anomaly_factor() -> f64 {
+    2.0
+}
+
+fn default_anomaly_window() -> usize {
+    5
 }
 
 impl Config {
+    /// Loads and validates a config file. String values may reference
+    /// environment variables as `${NAME}`, see
+    /// `sdwan_common::config_file::expand_env_vars`.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&content)?;
+        Self::from_file_with_env(path, &process_env)
+    }
+
+    /// `from_file` with variables looked up in `env` instead of the process
+    /// environment.
+    pub fn from_file_with_env<P: AsRef<Path>>(path: P, env: EnvLookup) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config: Config = load_yaml(path, env)?;
+
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        config.qos.load_rules_file_with_env(base_dir, env)?;
+        config.scheduler.validate()?;
+        config.qos.validate()?;
+        config.failover.validate()?;
+        for link in &config.links {
+            link.validate()?;
+        }
+        ensure_unique_names("link", config.links.iter().map(|l| l.name.as_str()))?;
+        config.qos.validate_reservations(&config.links, &config.shaping)?;
+        if let Some(ref admin) = config.admin {
+            admin.validate()?;
+        }
+        if let Some(ref tunnel) = config.tunnel {
+            tunnel.validate()?;
+        }
+
         Ok(config)
     }
+}
+
+/// Fails on the first name used twice, which would otherwise silently
+/// shadow one of the entries wherever they are looked up by name.
+fn ensure_unique_names<'a>(kind: &str, names: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    let mut seen = std::collections::HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            anyhow::bail!("Duplicate {} name {}", kind, name);
+        }
+    }
+    Ok(())
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            algorithm: "weighted_round_robin".to_string(),
+            batch_size: 64,
+            max_queue_size: 10000,
+            metrics_interval: 1000,
+            flow_affinity: false,
+            flow_idle_timeout: default_flow_idle_timeout(),
+            selection_hysteresis: 0.0,
+            shadow_algorithm: None,
+            send_retries: default_send_retries(),
+            workers: default_workers(),
+            metrics_channel_capacity: default_metrics_channel_capacity(),
+            packet_channel_capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+            queue_depth_penalty: default_queue_depth_penalty(),
+            rng_seed: None,
+            reorder_window_min_ms: default_reorder_window_min_ms(),
+            reorder_window_max_ms: default_reorder_window_max_ms(),
+            load_shedding: None,
+            grpc_timeout_ms: default_grpc_timeout_ms(),
+            idle_link_after: default_idle_link_after(),
+            destination_metrics_ttl: default_destination_metrics_ttl(),
+        }
+    }
+}
 
-    pub fn default() -> Self {
-        Config {
-            scheduler: SchedulerConfig {
-                algorithm: "weighted_round_robin".to_string(),
-                batch_size: 64,
-                max_queue_size: 10000,
-                metrics_interval: 1000,
-            },
-            qos: QosConfig {
-                rules: vec![],
-                default_priority: 5,
-            },
-            links: vec![],
-            failover: FailoverConfig {
-                enabled: true,
-                health_check_interval: 5000,
-                failover_threshold: 3,
-                recovery_threshold: 5,
-            },
+impl Default for QosConfig {
+    fn default() -> Self {
+        QosConfig {
+            rules: vec![],
+            default_priority: 5,
+            rules_file: None,
+            protocol_defaults: HashMap::new(),
+            dscp_priority_map: default_dscp_priority_map(),
+            trust_dscp_from: None,
         }
     }
 }
 
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            enabled: true,
+            health_check_interval: 5000,
+            failover_threshold: 3,
+            recovery_threshold: 5,
+            health_threshold: default_health_threshold(),
+            anomaly_factor: default_anomaly_factor(),
+            anomaly_window: default_anomaly_window(),
+            make_before_break: false,
+            warmup_ms: 0,
+            recovery_cooldown_ms: 0,
+            recovery_cooldown_penalty: default_recovery_cooldown_penalty(),
+            tiers: Vec::new(),
+        }
+    }
+}
+
+impl QosConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (class, priority) in &self.dscp_priority_map {
+            if crate::qos::parse_dscp(class).is_none() {
+                anyhow::bail!("dscp_priority_map: unknown DSCP class {}", class);
+            }
+            if *priority > QosRule::MAX_PRIORITY {
+                anyhow::bail!("dscp_priority_map: priority {} for {} exceeds {}", priority, class, QosRule::MAX_PRIORITY);
+            }
+        }
+        ensure_unique_names("QoS rule", self.rules.iter().map(|r| r.name.as_str()))
+
+    }
+
+    /// Fails if the classes' `min_bandwidth` reservations add up to more
+    /// than a link can carry: its `max_bandwidth`, or its shaped rate less
+    /// the guarantees of classes shaped there explicitly.
+    pub fn validate_reservations(&self, links: &[LinkConfig], shaping: &[LinkShaping]) -> Result<()> {
+        let reservations: Vec<(&str, u64)> = self.rules.iter()
+            .filter_map(|rule| rule.action.min_bandwidth.map(|bps| (rule.name.as_str(), bps)))
+            .collect();
+        if reservations.is_empty() {
+            return Ok(());
+        }
+
+        let reserved: u64 = reservations.iter().map(|(_, bps)| bps).sum();
+        for link in links {
+            if reserved > link.max_bandwidth {
+                anyhow::bail!("min_bandwidth reservations add up to {} bps, more than link {}'s {} bps", reserved, link.name, link.max_bandwidth);
+            }
+        }
+
+        for link_shaping in shaping {
+            // A class shaped explicitly gets the larger of its rate and its
+            // reservation; compare in bytes/sec as the shaper does
+            let guaranteed_for = |name: &str| link_shaping.classes.iter()
+                .find(|class| class.name == name)
+                .map_or(0, |class| class.rate.bytes_per_sec());
+            let shaped: u64 = link_shaping.classes.iter()
+                .map(|class| class.rate.bytes_per_sec())
+                .sum();
+            let extra: u64 = reservations.iter()
+                .map(|(name, bps)| (bps / 8).saturating_sub(guaranteed_for(name)))
+                .sum();
+            if shaped + extra > link_shaping.rate.bytes_per_sec() {
+                anyhow::bail!("Shaping for {}: class rates and min_bandwidth reservations add up to more than the link's {}", link_shaping.link, String::from(link_shaping.rate));
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges the rules from `rules_file` (if any) after the inline rules.
+    /// Rules are evaluated first-match, so inline rules keep their precedence
+    /// and a file rule sharing a name with an inline rule is skipped.
+    pub fn load_rules_file<P: AsRef<Path>>(&mut self, base_dir: P) -> Result<()> {
+        self.load_rules_file_with_env(base_dir, &process_env)
+    }
+
+    /// `load_rules_file`, expanding `${NAME}` references in the rules file
+    /// from `env` like the main config.
+    pub fn load_rules_file_with_env<P: AsRef<Path>>(&mut self, base_dir: P, env: EnvLookup) -> Result<()> {
+        let rules_file = match self.rules_file {
+            Some(ref rules_file) => Path::new(rules_file),
+            None => return Ok(()),
+        };
+
+        let path = if rules_file.is_relative() {
+            base_dir.as_ref().join(rules_file)
+        } else {
+            rules_file.to_path_buf()
+        };
+
+        let file_rules: Vec<QosRule> = load_yaml(&path, env)
+            .with_context(|| format!("Failed to load QoS rules file {}", path.display()))?;
+
+        // Only inline rules override file rules; a name repeated within the
+        // file is left for `validate` to reject
+        let inline: Vec<String> = self.rules.iter().map(|r| r.name.clone()).collect();
+        self.rules.extend(file_rules.into_iter().filter(|rule| !inline.contains(&rule.name)));
+
+        Ok(())
+    }
+}
+
+impl QosRule {
+    /// Highest priority a rule may assign.
+    pub const MAX_PRIORITY: u8 = 7;
+
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            anyhow::bail!("QoS rule name must not be empty");
+        }
+        if self.priority > Self::MAX_PRIORITY {
+            anyhow::bail!("QoS rule {}: priority {} exceeds {}", self.name, self.priority, Self::MAX_PRIORITY);
+        }
+        if let Some(dscp) = self.match_criteria.dscp {
+            if dscp > 63 {
+                anyhow::bail!("QoS rule {}: DSCP {} is out of range 0-63", self.name, dscp);
+            }
+        }
+        if let Some(ref ports) = self.match_criteria.port_range {
+            if ports.start > ports.end {
+                anyhow::bail!("QoS rule {}: port range {}-{} is reversed", self.name, ports.start, ports.end);
+            }
+        }
+        let criteria = &self.match_criteria;
+        if (criteria.icmp_type.is_some() || criteria.icmp_code.is_some()) && criteria.protocol != Some(Protocol::Icmp) {
+            anyhow::bail!("QoS rule {}: icmp_type and icmp_code require protocol ICMP", self.name);
+        }
+        for vlan_id in criteria.vlan_id.iter().chain(criteria.inner_vlan_id.iter()) {
+            if !(1..=4094).contains(vlan_id) {
+                anyhow::bail!("QoS rule {}: VLAN ID {} is out of range 1-4094", self.name, vlan_id);
+            }
+        }
+        if let (Some(reserved), Some(limit)) = (self.action.min_bandwidth, self.action.bandwidth_limit) {
+            if reserved / 8 > limit.bytes_per_sec() {
+                anyhow::bail!("QoS rule {}: min_bandwidth {} bps exceeds bandwidth_limit {}", self.name, reserved, String::from(limit));
+            }
+        }
+        if let Some(ref algorithm) = self.action.scheduler_algorithm {
+            if !SCHEDULER_ALGORITHMS.contains(&algorithm.as_str()) {
+                anyhow::bail!("QoS rule {}: unknown scheduler algorithm {}", self.name, algorithm);
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::qos::{PacketInfo, QosEngine};
+    use std::fs;
 
     #[test]
     fn test_config_serialization() {
@@ -114,4 +1078,370 @@ mod tests {
         let deserialized: Config = serde_yaml::from_str(&yaml).unwrap();
         assert_eq!(config.scheduler.algorithm, deserialized.scheduler.algorithm);
     }
+
+    #[test]
+    fn test_minimal_config_takes_defaults() {
+        let config: Config = serde_yaml::from_str(r#"
+scheduler:
+  algorithm: "weighted_ecmp"
+  batch_size: 32
+links:
+  - name: "eth0"
+    interface: "eth0"
+    weight: 1.0
+    max_bandwidth: 100Mbps
+    min_latency: 10
+"#).unwrap();
+        let defaults = Config::default();
+
+        assert_eq!(config.scheduler.algorithm, "weighted_ecmp");
+        assert_eq!(config.scheduler.batch_size, 32);
+        assert_eq!(config.scheduler.max_queue_size, defaults.scheduler.max_queue_size);
+        assert_eq!(config.scheduler.grpc_timeout_ms, defaults.scheduler.grpc_timeout_ms);
+        assert_eq!(config.qos.default_priority, defaults.qos.default_priority);
+        assert_eq!(config.qos.dscp_priority_map, defaults.qos.dscp_priority_map);
+        assert_eq!(config.failover.failover_threshold, defaults.failover.failover_threshold);
+        assert!(config.failover.enabled);
+        assert!(config.links[0].time_multipliers.is_empty() && config.links[0].mtu.is_none());
+        assert!(config.shaping.is_empty() && config.ipfix.is_none());
+
+        let empty: Config = serde_yaml::from_str("{}").unwrap();
+        assert!(empty.links.is_empty());
+        assert_eq!(empty.scheduler.algorithm, defaults.scheduler.algorithm);
+    }
+
+    const RULES_FILE: &str = r#"
+- name: "voip"
+  priority: 7
+  match_criteria:
+    protocol: "UDP"
+  action:
+    link_preference: ["eth1"]
+- name: "bulk"
+  priority: 2
+  match_criteria:
+    protocol: "TCP"
+  action:
+    link_preference: ["eth1"]
+"#;
+
+    const INLINE_RULES: &str = r#"
+  rules:
+    - name: "voip"
+      priority: 6
+      match_criteria:
+        protocol: "UDP"
+      action:
+        link_preference: ["eth0"]
+"#;
+
+    fn main_config(inline_rules: &str) -> String {
+        format!(
+            r#"
+scheduler:
+  algorithm: "weighted_round_robin"
+  batch_size: 64
+  max_queue_size: 10000
+  metrics_interval: 1000
+qos:
+  default_priority: 5
+  rules_file: "qos-rules.yml"{}
+links: []
+failover:
+  enabled: true
+  health_check_interval: 5000
+  failover_threshold: 3
+  recovery_threshold: 5
+"#,
+            inline_rules.trim_end()
+        )
+    }
+
+    fn write_config_dir(main: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("scheduler-config-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("qos-rules.yml"), RULES_FILE).unwrap();
+        fs::write(dir.join("scheduler.yml"), main).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rules_file_merged_with_inline_precedence() {
+        let dir = write_config_dir(&main_config(INLINE_RULES));
+        let config = Config::from_file(dir.join("scheduler.yml")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = config.qos.rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["voip", "bulk"]);
+        // The inline definition wins over the file's "voip" rule
+        assert_eq!(config.qos.rules[0].priority, 6);
+        assert_eq!(config.qos.rules[0].action.link_preference, vec!["eth0".to_string()]);
+    }
+
+    #[test]
+    fn test_rules_file_only_classification() {
+        let dir = write_config_dir(&main_config(""));
+        let config = Config::from_file(dir.join("scheduler.yml")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let engine = QosEngine::new(config.qos.rules);
+        let packet = PacketInfo {
+            source_ip: "10.0.0.1".to_string(),
+            dest_ip: "10.0.0.2".to_string(),
+            protocol: Protocol::Tcp,
+            source_port: Some(40000),
+            dest_port: Some(443),
+            dscp: None,
+            icmp_type: None,
+            icmp_code: None,
+            vlan_id: None,
+            inner_vlan_id: None,
+            priority: 5,
+        };
+        assert_eq!(engine.classify_packet(&packet).unwrap().name, "bulk");
+        assert_eq!(engine.get_priority(&packet), 2);
+    }
+
+    #[test]
+    fn test_duplicate_rule_names_rejected() {
+        let rule = &INLINE_RULES[INLINE_RULES.find("\n    - ").unwrap()..];
+        let duplicated = format!("{}{}", INLINE_RULES.trim_end(), rule);
+        let dir = write_config_dir(&main_config(&duplicated));
+        let result = Config::from_file(dir.join("scheduler.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.unwrap_err().to_string().contains("Duplicate QoS rule name voip"));
+
+        // A name repeated within the rules file is an error too
+        let dir = write_config_dir(&main_config(""));
+        fs::write(dir.join("qos-rules.yml"), format!("{}{}", RULES_FILE, RULES_FILE)).unwrap();
+        let result = Config::from_file(dir.join("scheduler.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_duplicate_link_names_rejected() {
+        let links = "links:\n  - {name: eth0, interface: eth0, weight: 1.0, max_bandwidth: 100Mbps, min_latency: 10}\n  - {name: eth0, interface: eth1, weight: 1.0, max_bandwidth: 50Mbps, min_latency: 15}\n";
+        let dir = write_config_dir(&main_config("").replace("links: []\n", links));
+        let result = Config::from_file(dir.join("scheduler.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.unwrap_err().to_string().contains("Duplicate link name eth0"));
+    }
+
+    #[test]
+    fn test_min_bandwidth_reservations_must_fit_links() {
+        let links = "links:\n  - {name: eth0, interface: eth0, weight: 1.0, max_bandwidth: 10Mbps, min_latency: 10}\n";
+        let load = |voip: &str, bulk: &str| {
+            let rules = INLINE_RULES.replace("link_preference: [\"eth0\"]", &format!("link_preference: [\"eth0\"]\n        min_bandwidth: {}", voip));
+            let dir = write_config_dir(&main_config(&rules).replace("links: []\n", links));
+            // The file's last rule is bulk
+            fs::write(dir.join("qos-rules.yml"), format!("{}\n    min_bandwidth: {}\n", RULES_FILE.trim_end(), bulk)).unwrap();
+            let result = Config::from_file(dir.join("scheduler.yml"));
+            fs::remove_dir_all(&dir).unwrap();
+            result
+        };
+
+        let config = load("2Mbps", "6Mbps").unwrap();
+        assert_eq!(config.qos.rules[0].action.min_bandwidth, Some(2_000_000));
+        assert_eq!(config.qos.rules[1].action.min_bandwidth, Some(6_000_000));
+
+        let result = load("5Mbps", "6Mbps");
+        assert!(result.unwrap_err().to_string().contains("more than link eth0's"));
+    }
+
+    #[test]
+    fn test_env_vars_expanded_on_load() {
+        let env = |name: &str| match name {
+            "SCHEDULER_CA" => Some("/run/secrets/admin-ca.crt".to_string()),
+            "VOIP_LINK" => Some("eth2".to_string()),
+            _ => None,
+        };
+        let admin = "admin:\n  cert_file: /etc/sdwan/certs/admin.crt\n  key_file: /etc/sdwan/certs/admin.key\n  client_ca_file: \"${SCHEDULER_CA}\"\n  roles:\n    - identity: \"${UNSET:-noc}\"\n      role: read_only\n";
+        let dir = write_config_dir(&format!("{}{}", main_config(""), admin));
+        fs::write(dir.join("qos-rules.yml"), RULES_FILE.replacen("[\"eth1\"]", "[\"${VOIP_LINK}\"]", 1)).unwrap();
+        let config = Config::from_file_with_env(dir.join("scheduler.yml"), &env);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let config = config.unwrap();
+        let admin = config.admin.unwrap();
+        assert_eq!(admin.client_ca_file, "/run/secrets/admin-ca.crt");
+        assert_eq!(admin.roles[0].identity, "noc");
+        // The rules file is expanded too
+        assert_eq!(config.qos.rules[0].action.link_preference, vec!["eth2".to_string()]);
+
+        // No fallback: loading fails rather than using an empty value
+        let dir = write_config_dir(&format!("{}admin:\n  cert_file: admin.crt\n  key_file: admin.key\n  client_ca_file: \"${{UNSET}}\"\n  roles: []\n", main_config("")));
+        let result = Config::from_file_with_env(dir.join("scheduler.yml"), &env);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(format!("{:#}", result.unwrap_err()).contains("UNSET is not set"));
+    }
+
+    #[test]
+    fn test_missing_rules_file_is_an_error() {
+        let dir = write_config_dir(&main_config(INLINE_RULES));
+        fs::remove_file(dir.join("qos-rules.yml")).unwrap();
+        let result = Config::from_file(dir.join("scheduler.yml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_port_range_accepts_service_names() {
+        let parse = |port_range: &str| {
+            serde_yaml::from_str::<MatchCriteria>(&format!("port_range: {}\n", port_range))
+                .map(|criteria| criteria.port_range.map(|r| (r.start, r.end)))
+        };
+
+        assert_eq!(parse("https").unwrap(), Some((443, 443)));
+        assert_eq!(parse("sip").unwrap(), Some((5060, 5061)));
+        assert_eq!(parse("\"10000-20000\"").unwrap(), Some((10000, 20000)));
+        assert_eq!(parse("{start: 8000, end: 8100}").unwrap(), Some((8000, 8100)));
+        assert!(parse("not-a-service").is_err());
+    }
+
+    #[test]
+    fn test_bandwidth_limit_units() {
+        let parse = |limit: &str| limit.parse::<BandwidthLimit>().map(|limit| limit.bytes_per_sec());
+
+        assert_eq!(parse("1500Bps").unwrap(), 1500);
+        assert_eq!(parse("64Kbps").unwrap(), 8_000);
+        assert_eq!(parse("10Mbps").unwrap(), 1_250_000);
+        assert_eq!(parse("10 Mbps").unwrap(), 1_250_000);
+        assert_eq!(
+            "10Mbps".parse::<BandwidthLimit>().unwrap(),
+            BandwidthLimit { value: 10, unit: BandwidthUnit::Mbps }
+        );
+
+        assert!(parse("10").is_err());
+        assert!(parse("10mbps").is_err());
+        assert!(parse("10bps").is_err());
+        assert!(parse("1.5Mbps").is_err());
+        assert!(parse("Mbps").is_err());
+    }
+
+    #[test]
+    fn test_bandwidth_limit_yaml_round_trip() {
+        let action: QosAction = serde_yaml::from_str("link_preference: []\nbandwidth_limit: 5Mbps\n").unwrap();
+        let limit = action.bandwidth_limit.unwrap();
+        assert_eq!(limit.bytes_per_sec(), 625_000);
+        assert_eq!(serde_yaml::to_string(&limit).unwrap().trim(), "5Mbps");
+
+        // A bare number is rejected rather than guessed at
+        assert!(serde_yaml::from_str::<QosAction>("link_preference: []\nbandwidth_limit: 1000000\n").is_err());
+    }
+
+    #[test]
+    fn test_reservation_above_bandwidth_limit_rejected() {
+        let mut rule: QosRule = serde_yaml::from_str(
+            "name: bulk\npriority: 2\nmatch_criteria: {}\naction:\n  link_preference: []\n  bandwidth_limit: 1Mbps\n  min_bandwidth: 2Mbps\n"
+        ).unwrap();
+        assert!(rule.validate().is_err());
+        rule.action.min_bandwidth = Some(1_000_000);
+        rule.validate().unwrap();
+    }
+
+    #[test]
+    fn test_time_window_contains() {
+        let at = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        // 2026-10-16 is a Friday
+        let business: TimeWindow = serde_yaml::from_str("start: \"08:00\"\nend: \"18:00\"\ndays: [Mon, Tue, Wed, Thu, Fri]\n").unwrap();
+        assert!(business.contains(at("2026-10-16 08:00")));
+        assert!(business.contains(at("2026-10-16 17:59")));
+        assert!(!business.contains(at("2026-10-16 18:00")));
+        assert!(!business.contains(at("2026-10-17 12:00")));
+
+        // Friday night's window runs into Saturday morning, but Saturday's
+        // doesn't open
+        let night: TimeWindow = serde_yaml::from_str("start: \"22:00\"\nend: \"06:00\"\ndays: [Fri]\n").unwrap();
+        assert!(night.contains(at("2026-10-16 23:00")));
+        assert!(night.contains(at("2026-10-17 05:59")));
+        assert!(!night.contains(at("2026-10-17 23:00")));
+        assert!(!night.contains(at("2026-10-16 05:00")));
+    }
+
+    #[test]
+    fn test_admin_role_mapping_parsed_and_validated() {
+        let yaml = "cert_file: /etc/sdwan/certs/admin.crt\nkey_file: /etc/sdwan/certs/admin.key\nclient_ca_file: /etc/sdwan/certs/admin-ca.crt\nroles:\n  - identity: noc-dashboard\n    role: read_only\n  - identity: ops@example.com\n    role: operator\n";
+        let mut admin: AdminConfig = serde_yaml::from_str(yaml).unwrap();
+        admin.validate().unwrap();
+        assert_eq!(admin.roles[0].role, AdminRole::ReadOnly);
+        assert!(AdminRole::Operator > AdminRole::ReadOnly);
+
+        assert_eq!(admin.listen, "127.0.0.1:9443");
+        admin.listen = "localhost".to_string();
+        assert!(admin.validate().is_err());
+        admin.listen = "0.0.0.0:9443".to_string();
+        admin.key_file.clear();
+        assert!(admin.validate().unwrap_err().to_string().contains("key_file"));
+        admin.key_file = "/etc/sdwan/certs/admin.key".to_string();
+
+        admin.roles[1].identity = "noc-dashboard".to_string();
+        assert!(admin.validate().is_err());
+        assert!(serde_yaml::from_str::<AdminConfig>("cert_file: a.crt\nkey_file: a.key\nclient_ca_file: ca.crt\nroles:\n  - identity: x\n    role: admin\n").is_err());
+    }
+
+    #[test]
+    fn test_link_accepts_units() {
+        let link: LinkConfig = serde_yaml::from_str("name: eth0\ninterface: eth0\nweight: 1.0\nmax_bandwidth: 100Mbps\nmin_latency: 10ms\n").unwrap();
+        assert_eq!((link.max_bandwidth, link.min_latency), (100_000_000, 10));
+
+        let failover: FailoverConfig = serde_yaml::from_str("health_check_interval: 5s\nwarmup_ms: 1m\n").unwrap();
+        assert_eq!((failover.health_check_interval, failover.warmup_ms), (5_000, 60_000));
+    }
+
+    #[test]
+    fn test_failover_tier_thresholds() {
+        let yaml = "failover_threshold: 3\nrecovery_threshold: 5\ntiers:\n  - tier: 0\n    failover_threshold: 1\n  - tier: 2\n    recovery_threshold: 10\n";
+        let mut failover: FailoverConfig = serde_yaml::from_str(yaml).unwrap();
+        failover.validate().unwrap();
+        assert_eq!((failover.failover_threshold_for(0), failover.recovery_threshold_for(0)), (1, 5));
+        assert_eq!((failover.failover_threshold_for(1), failover.recovery_threshold_for(1)), (3, 5));
+        assert_eq!((failover.failover_threshold_for(2), failover.recovery_threshold_for(2)), (3, 10));
+
+        failover.tiers[1].tier = 0;
+        assert!(failover.validate().is_err());
+    }
+
+    #[test]
+    fn test_grpc_timeout_must_be_positive() {
+        SchedulerConfig::default().validate().unwrap();
+        let scheduler = SchedulerConfig { grpc_timeout_ms: 0, ..SchedulerConfig::default() };
+        assert!(scheduler.validate().unwrap_err().to_string().contains("grpc_timeout_ms"));
+    }
+
+    #[test]
+    fn test_tunnel_reassembly_timeout() {
+        let tunnel: TunnelConfig = serde_yaml::from_str("encrypt: false\nreassembly_timeout_ms: 500ms\n").unwrap();
+        assert_eq!(tunnel.reassembly_timeout_ms, Some(500));
+        let tunnel: TunnelConfig = serde_yaml::from_str("encrypt: false\n").unwrap();
+        assert_eq!(tunnel.reassembly_timeout_ms, None);
+        assert_eq!(tunnel.reassembly_window, crate::fragment::DEFAULT_REASSEMBLY_WINDOW);
+        let tunnel = TunnelConfig { reassembly_timeout_ms: Some(0), ..TunnelConfig::default() };
+        assert!(tunnel.validate().unwrap_err().to_string().contains("reassembly_timeout_ms"));
+        let tunnel: TunnelConfig = serde_yaml::from_str("reassembly_window: 0\n").unwrap();
+        assert!(tunnel.validate().unwrap_err().to_string().contains("reassembly_window"));
+        let tunnel: TunnelConfig = serde_yaml::from_str("peer: gateway:47192\n").unwrap();
+        assert!(tunnel.validate().unwrap_err().to_string().contains("tunnel.peer"));
+    }
+
+    #[test]
+    fn test_queue_depth_penalty_must_be_a_factor() {
+        for penalty in [-0.5, 1.01, f64::NAN] {
+            let scheduler = SchedulerConfig { queue_depth_penalty: penalty, ..SchedulerConfig::default() };
+            assert!(scheduler.validate().is_err(), "{}", penalty);
+        }
+        let scheduler = SchedulerConfig { queue_depth_penalty: 0.0, ..SchedulerConfig::default() };
+        scheduler.validate().unwrap();
+    }
+
+    #[test]
+    fn test_recovery_cooldown_penalty_must_be_a_factor() {
+        for penalty in [-0.1, 1.5, f64::NAN] {
+            let failover = FailoverConfig { recovery_cooldown_penalty: penalty, ..FailoverConfig::default() };
+            assert!(failover.validate().is_err(), "{}", penalty);
+        }
+        let failover = FailoverConfig { recovery_cooldown_penalty: 0.0, ..FailoverConfig::default() };
+        failover.validate().unwrap();
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,171 @@
+use crate::config::TunnelConfig;
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+
+/// Sequence number (8) + session id (4), authenticated as associated data.
+const HEADER_LEN: usize = 12;
+
+/// Seals tunnel payloads with ChaCha20-Poly1305 under a pre-shared key.
+///
+/// The 96-bit nonce is the sender's random 32-bit session id followed by the
+/// 64-bit sequence number, so nonces stay unique across sender restarts as
+/// long as the session id differs. Both travel in the clear in the frame
+/// header and are covered by the tag.
+pub struct TunnelCipher {
+    cipher: ChaCha20Poly1305,
+    session: [u8; 4],
+}
+
+impl TunnelCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        let mut session = [0u8; 4];
+        rand::thread_rng().fill_bytes(&mut session);
+
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            session,
+        }
+    }
+
+    /// Builds the cipher for `tunnel`, or `None` when encryption is off.
+    pub fn from_config(tunnel: &TunnelConfig) -> Result<Option<Self>> {
+        if !tunnel.encrypt {
+            return Ok(None);
+        }
+
+        let psk = tunnel
+            .psk
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("tunnel.encrypt is set but no tunnel.psk was given"))?;
+        Ok(Some(Self::new(parse_key(psk)?)))
+    }
+
+    /// Encrypts `payload` into a wire frame: sequence number, session id,
+    /// then ciphertext and tag.
+    pub fn seal(&self, sequence_number: u64, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len() + 16);
+        frame.extend_from_slice(&sequence_number.to_be_bytes());
+        frame.extend_from_slice(&self.session);
+
+        let nonce = nonce(&frame[..HEADER_LEN]);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: payload, aad: &frame[..HEADER_LEN] })
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt tunnel payload"))?;
+
+        frame.extend_from_slice(&ciphertext);
+        Ok(frame)
+    }
+
+    /// Verifies and decrypts a frame produced by `seal`, returning the
+    /// sequence number and payload.
+    pub fn open(&self, frame: &[u8]) -> Result<(u64, Vec<u8>)> {
+        if frame.len() < HEADER_LEN {
+            return Err(anyhow::anyhow!("Tunnel frame too short: {} bytes", frame.len()));
+        }
+
+        let (header, ciphertext) = frame.split_at(HEADER_LEN);
+        let nonce = nonce(header);
+        let payload = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad: header })
+            .map_err(|_| anyhow::anyhow!("Tunnel frame failed authentication"))?;
+
+        let sequence_number = u64::from_be_bytes(header[..8].try_into()?);
+        Ok((sequence_number, payload))
+    }
+}
+
+fn nonce(header: &[u8]) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&header[8..HEADER_LEN]);
+    nonce[4..].copy_from_slice(&header[..8]);
+    nonce
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 32]> {
+    if hex.len() != 64 {
+        return Err(anyhow::anyhow!("tunnel.psk must be 64 hex characters, got {}", hex.len()));
+    }
+    // Checked up front so slicing below stays on character boundaries
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!("tunnel.psk is not valid hex"));
+    }
+
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .context("tunnel.psk is not valid hex")?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn cipher() -> TunnelCipher {
+        TunnelCipher::new(parse_key(PSK).unwrap())
+    }
+
+    #[test]
+    fn test_seal_open_round_trip() {
+        let sender = cipher();
+        let receiver = cipher();
+        let frame = sender.seal(42, b"hello overlay").unwrap();
+        assert_ne!(&frame[HEADER_LEN..HEADER_LEN + 13], b"hello overlay");
+
+        let (sequence_number, payload) = receiver.open(&frame).unwrap();
+        assert_eq!(sequence_number, 42);
+        assert_eq!(payload, b"hello overlay");
+    }
+
+    #[test]
+    fn test_tampered_frame_fails_authentication() {
+        let cipher = cipher();
+        let mut frame = cipher.seal(7, b"payload").unwrap();
+        let last = frame.len() - 1;
+        frame[last] ^= 0x01;
+        assert!(cipher.open(&frame).is_err());
+
+        // The sequence number is authenticated too
+        let mut frame = cipher.seal(7, b"payload").unwrap();
+        frame[7] = 8;
+        assert!(cipher.open(&frame).is_err());
+    }
+
+    #[test]
+    fn test_wrong_key_fails() {
+        let frame = cipher().seal(1, b"payload").unwrap();
+        let other = TunnelCipher::new([0xff; 32]);
+        assert!(other.open(&frame).is_err());
+    }
+
+    #[test]
+    fn test_from_config() {
+        let mut tunnel = TunnelConfig::default();
+        assert!(TunnelCipher::from_config(&tunnel).unwrap().is_none());
+
+        tunnel.encrypt = true;
+        assert!(TunnelCipher::from_config(&tunnel).is_err());
+
+        tunnel.psk = Some("abcd".to_string());
+        assert!(TunnelCipher::from_config(&tunnel).is_err());
+
+        tunnel.psk = Some(PSK.to_string());
+        assert!(TunnelCipher::from_config(&tunnel).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_non_ascii_key_rejected() {
+        // 64 bytes, with a two-byte character straddling a hex pair
+        let key = format!("0é{}", "0".repeat(61));
+        assert_eq!(key.len(), 64);
+        assert!(parse_key(&key).is_err());
+        assert!(parse_key(&"g".repeat(64)).is_err());
+    }
+}
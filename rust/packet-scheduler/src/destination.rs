@@ -0,0 +1,136 @@
+use crate::cidr::Cidr;
+use crate::LinkMetrics;
+use chrono::{DateTime, Duration, Utc};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Link metrics measured towards particular destination prefixes. A link's
+/// quality to one destination can differ from another's when they are
+/// reached over different peering, which its overall metrics average away.
+/// Measurements older than the TTL are ignored, and dropped by
+/// `evict_expired`.
+pub struct DestinationMetrics {
+    metrics: RwLock<HashMap<(String, Cidr), LinkMetrics>>,
+    ttl: Duration,
+}
+
+impl DestinationMetrics {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            metrics: RwLock::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Replaces what is known about `link` towards `prefix`.
+    pub fn update(&self, link: &str, prefix: Cidr, metrics: LinkMetrics) {
+        self.metrics.write().insert((link.to_string(), prefix), metrics);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.read().is_empty()
+    }
+
+    /// Drops measurements taken more than the TTL before `now`, returning
+    /// how many.
+    pub fn evict_expired(&self, now: DateTime<Utc>) -> usize {
+        let mut metrics = self.metrics.write();
+        let before = metrics.len();
+        metrics.retain(|_, metric| now - metric.timestamp <= self.ttl);
+        before - metrics.len()
+    }
+
+    /// `links` with each link's metrics swapped for those towards the most
+    /// specific prefix containing `dest_ip`, or `None` if no link has any
+    /// measured within the TTL before `now`. Which links are usable is still
+    /// decided by `links`: a link down there stays down, and reliability
+    /// stays the link's own uptime.
+    pub fn for_destination(&self, dest_ip: &str, links: &HashMap<String, LinkMetrics>, now: DateTime<Utc>) -> Option<HashMap<String, LinkMetrics>> {
+        let dest: IpAddr = dest_ip.parse().ok()?;
+        let metrics = self.metrics.read();
+        let mut overridden = false;
+        let candidates = links.iter()
+            .map(|(link, metric)| {
+                let towards_dest = metrics.iter()
+                    .filter(|((name, prefix), dest_metric)| {
+                        name == link && prefix.contains(dest) && now - dest_metric.timestamp <= self.ttl
+                    })
+                    .max_by_key(|((_, prefix), _)| prefix.prefix_len())
+                    .map(|(_, dest_metric)| dest_metric);
+                match towards_dest {
+                    Some(dest_metric) if !metric.is_down() => {
+                        overridden = true;
+                        let mut dest_metric = dest_metric.clone();
+                        dest_metric.reliability = metric.reliability;
+                        (link.clone(), dest_metric)
+                    }
+                    _ => (link.clone(), metric.clone()),
+                }
+            })
+            .collect();
+        overridden.then_some(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(latency_ms: f64) -> LinkMetrics {
+        let mut metric = LinkMetrics::new();
+        metric.latency_ms = latency_ms;
+        metric.bandwidth_mbps = 100.0;
+        metric
+    }
+
+    #[test]
+    fn test_most_specific_prefix_overrides_link_metrics() {
+        let destinations = DestinationMetrics::new(Duration::seconds(30));
+        destinations.update("eth0", "203.0.113.0/24".parse().unwrap(), metric(80.0));
+        destinations.update("eth0", "203.0.113.128/25".parse().unwrap(), metric(60.0));
+        let links = HashMap::from([("eth0".to_string(), metric(10.0)), ("eth1".to_string(), metric(20.0))]);
+
+        let towards = destinations.for_destination("203.0.113.200", &links, Utc::now()).unwrap();
+        assert_eq!(towards["eth0"].latency_ms, 60.0);
+        assert_eq!(towards["eth1"].latency_ms, 20.0);
+        assert_eq!(destinations.for_destination("203.0.113.5", &links, Utc::now()).unwrap()["eth0"].latency_ms, 80.0);
+
+        assert!(destinations.for_destination("198.51.100.1", &links, Utc::now()).is_none());
+        assert!(destinations.for_destination("not-an-ip", &links, Utc::now()).is_none());
+    }
+
+    #[test]
+    fn test_down_link_stays_down_for_every_destination() {
+        let destinations = DestinationMetrics::new(Duration::seconds(30));
+        destinations.update("eth0", "203.0.113.0/24".parse().unwrap(), metric(5.0));
+        let mut down = metric(10.0);
+        down.packet_loss = 1.0;
+        let links = HashMap::from([("eth0".to_string(), down)]);
+
+        assert!(destinations.for_destination("203.0.113.1", &links, Utc::now()).is_none());
+    }
+
+    #[test]
+    fn test_expired_measurements_ignored_then_evicted() {
+        let destinations = DestinationMetrics::new(Duration::seconds(30));
+        let now = Utc::now();
+        let mut old = metric(80.0);
+        old.timestamp = now - Duration::seconds(20);
+        destinations.update("eth0", "203.0.113.0/24".parse().unwrap(), old);
+        destinations.update("eth0", "203.0.113.0/25".parse().unwrap(), LinkMetrics { timestamp: now, ..metric(60.0) });
+        let links = HashMap::from([("eth0".to_string(), metric(10.0))]);
+
+        // Past the TTL of both, the link's own metrics apply
+        let later = now + Duration::seconds(31);
+        assert_eq!(destinations.for_destination("203.0.113.200", &links, now).unwrap()["eth0"].latency_ms, 80.0);
+        assert!(destinations.for_destination("203.0.113.1", &links, later).is_none());
+
+        // The older, wider prefix goes first
+        assert_eq!(destinations.evict_expired(now + Duration::seconds(15)), 1);
+        assert!(destinations.for_destination("203.0.113.200", &links, now + Duration::seconds(15)).is_none());
+        assert_eq!(destinations.for_destination("203.0.113.1", &links, now + Duration::seconds(15)).unwrap()["eth0"].latency_ms, 60.0);
+        assert_eq!(destinations.evict_expired(later), 1);
+        assert!(destinations.is_empty());
+    }
+}
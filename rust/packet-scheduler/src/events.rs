@@ -0,0 +1,41 @@
+use crate::failover::LinkStatus;
+
+/// A change in a link's failover status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// A down link came back.
+    Up(String),
+    Down(String),
+    /// Latency or loss jumped sharply.
+    Degraded(String),
+    /// A degraded link is back to normal.
+    Recovered(String),
+}
+
+impl LinkEvent {
+    /// The event for a status change, if it is one.
+    pub fn transition(link_name: &str, previous: LinkStatus, current: LinkStatus) -> Option<Self> {
+        let link_name = link_name.to_string();
+        match (previous, current) {
+            (previous, current) if previous == current => None,
+            (LinkStatus::Down, LinkStatus::Up) => Some(LinkEvent::Up(link_name)),
+            (LinkStatus::Degraded, LinkStatus::Up) => Some(LinkEvent::Recovered(link_name)),
+            (_, LinkStatus::Degraded) => Some(LinkEvent::Degraded(link_name)),
+            (_, LinkStatus::Down) => Some(LinkEvent::Down(link_name)),
+            _ => None,
+        }
+    }
+
+    pub fn link_name(&self) -> &str {
+        match self {
+            LinkEvent::Up(link_name)
+            | LinkEvent::Down(link_name)
+            | LinkEvent::Degraded(link_name)
+            | LinkEvent::Recovered(link_name) => link_name,
+        }
+    }
+}
+
+/// Fans link state changes out to every interested task, so features react
+/// to the same transitions instead of each re-deriving them from metrics.
+pub type EventBus = sdwan_common::event_bus::EventBus<LinkEvent>;
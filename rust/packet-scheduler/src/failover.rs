@@ -0,0 +1,764 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::{FailoverConfig, LinkConfig};
+use crate::events::{EventBus, LinkEvent};
+use crate::LinkMetrics;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, info, warn};
+
+/// Losses below this absolute increase are never treated as anomalous, so a
+/// link going from 0.01% to 0.03% loss doesn't trip the detector.
+const MIN_LOSS_DELTA: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinkStatus {
+    Up,
+    Degraded,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkState {
+    pub status: LinkStatus,
+    /// Set when latency or loss jumped sharply within the anomaly window.
+    pub anomaly: bool,
+    pub consecutive_failures: u64,
+    pub consecutive_successes: u64,
+    /// Ring buffer of the most recent samples, oldest first.
+    pub history: VecDeque<LinkMetrics>,
+    /// When the link last came back up after being down.
+    #[serde(default)]
+    pub recovered_at: Option<DateTime<Utc>>,
+}
+
+impl LinkState {
+    fn new() -> Self {
+        Self {
+            status: LinkStatus::Up,
+            anomaly: false,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            history: VecDeque::new(),
+            recovered_at: None,
+        }
+    }
+}
+
+/// Make-before-break progress of the active link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handover {
+    /// All traffic goes over the active link.
+    Active(String),
+    /// The primary degraded: packets are duplicated onto `backup` until it
+    /// has delivered `recovery_threshold` of them.
+    Dual { primary: String, backup: String, delivered: u64 },
+}
+
+/// Tracks per-link health over time and decides which links are usable.
+pub struct FailoverManager {
+    config: FailoverConfig,
+    states: HashMap<String, LinkState>,
+    /// Member links of each failover group.
+    groups: HashMap<String, Vec<String>>,
+    /// `failover_tier` of each grouped link.
+    tiers: HashMap<String, u32>,
+    /// Last seen `active_tier` of each group, to log cascades.
+    active_tiers: HashMap<String, Option<u32>>,
+    handover: Option<Handover>,
+    /// Where status changes are published.
+    events: EventBus,
+    /// Stamps recoveries, which warm-up and cooldown count from.
+    clock: Arc<dyn Clock>,
+}
+
+impl FailoverManager {
+    pub fn new(config: FailoverConfig) -> Self {
+        Self {
+            config,
+            states: HashMap::new(),
+            groups: HashMap::new(),
+            tiers: HashMap::new(),
+            active_tiers: HashMap::new(),
+            handover: None,
+            events: EventBus::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Registers each link's `failover_group` membership and tier.
+    pub fn with_groups(mut self, links: &[LinkConfig]) -> Self {
+        for link in links {
+            if let Some(ref group) = link.failover_group {
+                self.groups.entry(group.clone()).or_default().push(link.name.clone());
+                self.tiers.insert(link.name.clone(), link.failover_tier);
+            }
+        }
+        self
+    }
+
+    fn tier(&self, link_name: &str) -> u32 {
+        self.tiers.get(link_name).copied().unwrap_or(0)
+    }
+
+    /// The tier of `group` currently carrying traffic: the best one with a
+    /// member that isn't down. None if the group is unknown or all down.
+    pub fn active_tier(&self, group: &str) -> Option<u32> {
+        self.groups.get(group)?
+            .iter()
+            .filter(|link| self.status(link) != LinkStatus::Down)
+            .map(|link| self.tier(link))
+            .min()
+    }
+
+    /// Grouped links held in reserve because a better tier of their group
+    /// is still up. Nothing is held back once every tier is down.
+    pub fn standby_links(&self) -> HashSet<String> {
+        self.groups.iter()
+            .filter_map(|(group, members)| Some((self.active_tier(group)?, members)))
+            .flat_map(|(active, members)| members.iter().filter(move |link| self.tier(link) > active))
+            .cloned()
+            .collect()
+    }
+
+    /// Logs groups whose traffic cascaded to a worse tier or climbed back.
+    fn track_active_tiers(&mut self) {
+        for group in self.group_names() {
+            let active = self.active_tier(&group);
+            let previous = self.active_tiers.insert(group.clone(), active);
+            match (previous.flatten(), active) {
+                (Some(from), Some(to)) if to > from => info!("Failover group {} fell back from tier {} to tier {}", group, from, to),
+                (Some(from), Some(to)) if to < from => info!("Failover group {} climbed back from tier {} to tier {}", group, from, to),
+                (Some(_), None) => warn!("Failover group {} has every tier down", group),
+                (None, Some(tier)) if previous.is_some() => info!("Failover group {} is back on tier {}", group, tier),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn group_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.groups.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Health of a failover group: the best latest health score among its
+    /// members that aren't down. 0 if the group is unknown, has no samples
+    /// yet, or every member is down.
+    pub fn group_health(&self, group: &str) -> f64 {
+        self.groups.get(group)
+            .into_iter()
+            .flatten()
+            .filter_map(|link| self.states.get(link))
+            .filter(|state| state.status != LinkStatus::Down)
+            .filter_map(|state| state.history.back())
+            .map(|metric| metric.health_score())
+            .fold(0.0, f64::max)
+    }
+
+    /// Records a new metrics sample for every reporting link and updates
+    /// their status.
+    pub fn update(&mut self, metrics: &HashMap<String, LinkMetrics>) {
+        for (link_name, metric) in metrics {
+            let state = self.states.entry(link_name.clone()).or_insert_with(LinkState::new);
+
+            state.history.push_back(metric.clone());
+            while state.history.len() > self.config.anomaly_window + 1 {
+                state.history.pop_front();
+            }
+            state.anomaly = detect_anomaly(&state.history, self.config.anomaly_factor);
+
+            if metric.is_healthy(self.config.health_threshold) {
+                state.consecutive_successes += 1;
+                state.consecutive_failures = 0;
+            } else {
+                state.consecutive_failures += 1;
+                state.consecutive_successes = 0;
+            }
+
+            let tier = self.tiers.get(link_name).copied().unwrap_or(0);
+            let previous = state.status;
+            state.status = match previous {
+                LinkStatus::Down if state.consecutive_successes < self.config.recovery_threshold_for(tier) => {
+                    LinkStatus::Down
+                }
+                _ if state.consecutive_failures >= self.config.failover_threshold_for(tier) => LinkStatus::Down,
+                _ if state.anomaly => LinkStatus::Degraded,
+                _ => LinkStatus::Up,
+            };
+
+            if previous == LinkStatus::Down && state.status == LinkStatus::Up {
+                state.recovered_at = Some(self.clock.now());
+            }
+            if let Some(event) = LinkEvent::transition(link_name, previous, state.status) {
+                self.events.publish(event);
+            }
+        }
+        self.track_active_tiers();
+
+        if self.config.make_before_break {
+            self.advance_handover(metrics);
+        }
+    }
+
+    pub fn handover(&self) -> Option<&Handover> {
+        self.handover.as_ref()
+    }
+
+    /// Links a packet should be sent on under make-before-break: `selected`
+    /// first, plus a duplicate on the backup while a handover is in
+    /// progress. `selected` becomes the active link if there is none yet or
+    /// it went down.
+    pub fn transmit_links(&mut self, selected: &str) -> Vec<String> {
+        match self.handover {
+            Some(Handover::Dual { ref backup, .. }) if backup != selected => vec![selected.to_string(), backup.clone()],
+            Some(Handover::Dual { .. }) => vec![selected.to_string()],
+            Some(Handover::Active(ref active)) if self.status(active) != LinkStatus::Down => vec![selected.to_string()],
+            _ => {
+                self.handover = Some(Handover::Active(selected.to_string()));
+                vec![selected.to_string()]
+            }
+        }
+    }
+
+    /// Counts a packet delivered on `link_name`; cuts over to the backup once
+    /// it has proven itself.
+    pub fn record_delivery(&mut self, link_name: &str) {
+        if let Some(Handover::Dual { ref backup, ref mut delivered, .. }) = self.handover {
+            if backup == link_name {
+                *delivered += 1;
+                if *delivered >= self.config.recovery_threshold {
+                    info!("Backup link {} is delivering, cutting over", backup);
+                    self.handover = Some(Handover::Active(backup.clone()));
+                }
+            }
+        }
+    }
+
+    /// Starts duplicating when the active link degrades, and settles on one
+    /// link when the primary recovers or either side of a handover fails.
+    fn advance_handover(&mut self, metrics: &HashMap<String, LinkMetrics>) {
+        self.handover = match self.handover.take() {
+            Some(Handover::Active(active)) if self.status(&active) == LinkStatus::Degraded => {
+                match self.best_backup(&active, metrics) {
+                    Some(backup) => {
+                        info!("Link {} degraded, duplicating onto backup {}", active, backup);
+                        Some(Handover::Dual { primary: active, backup, delivered: 0 })
+                    }
+                    None => Some(Handover::Active(active)),
+                }
+            }
+            Some(Handover::Dual { primary, backup, delivered }) => match (self.status(&primary), self.status(&backup)) {
+                (LinkStatus::Up, _) => {
+                    info!("Link {} recovered, staying on it", primary);
+                    Some(Handover::Active(primary))
+                }
+                (LinkStatus::Down, LinkStatus::Up) => Some(Handover::Active(backup)),
+                (_, LinkStatus::Up) => Some(Handover::Dual { primary, backup, delivered }),
+                // The backup failed too: try another, else ride out the primary
+                _ => match self.best_backup(&primary, metrics) {
+                    Some(other) if other != backup => Some(Handover::Dual { primary, backup: other, delivered: 0 }),
+                    _ => Some(Handover::Active(primary)),
+                },
+            },
+            handover => handover,
+        };
+    }
+
+    /// The healthiest up link other than `primary`.
+    fn best_backup(&self, primary: &str, metrics: &HashMap<String, LinkMetrics>) -> Option<String> {
+        metrics.iter()
+            .filter(|(name, _)| name.as_str() != primary && self.status(name) == LinkStatus::Up)
+            .max_by(|a, b| a.1.health_score().total_cmp(&b.1.health_score()).then_with(|| b.0.cmp(a.0)))
+            .map(|(name, _)| name.clone())
+    }
+
+    fn status(&self, link_name: &str) -> LinkStatus {
+        self.states.get(link_name).map_or(LinkStatus::Up, |state| state.status)
+    }
+
+    /// Counts a transport send failure against the link, taking it down once
+    /// `failover_threshold` consecutive failures are reached.
+    pub fn record_send_failure(&mut self, link_name: &str) {
+        let threshold = self.config.failover_threshold_for(self.tier(link_name));
+        let state = self.states.entry(link_name.to_string()).or_insert_with(LinkState::new);
+        state.consecutive_failures += 1;
+        state.consecutive_successes = 0;
+
+        if state.consecutive_failures >= threshold && state.status != LinkStatus::Down {
+            debug!("Link {} failed {} sends in a row", link_name, state.consecutive_failures);
+            state.status = LinkStatus::Down;
+            self.events.publish(LinkEvent::Down(link_name.to_string()));
+            self.track_active_tiers();
+        }
+    }
+
+    /// Score factor in (0, 1) of each link still ramping up after recovery;
+    /// links at full use are left out.
+    pub fn warmup_factors(&self, now: DateTime<Utc>) -> HashMap<String, f64> {
+        if self.config.warmup_ms == 0 {
+            return HashMap::new();
+        }
+        self.states.iter()
+            .filter(|(_, state)| state.status != LinkStatus::Down)
+            .filter_map(|(name, state)| {
+                let elapsed_ms = (now - state.recovered_at?).num_milliseconds().max(0) as f64;
+                let factor = elapsed_ms / self.config.warmup_ms as f64;
+                (factor < 1.0).then(|| (name.clone(), factor))
+            })
+            .collect()
+    }
+
+    /// Score penalty of each link still in its post-recovery cooldown.
+    pub fn cooldown_factors(&self, now: DateTime<Utc>) -> HashMap<String, f64> {
+        if self.config.recovery_cooldown_ms == 0 {
+            return HashMap::new();
+        }
+        let cooldown = chrono::Duration::milliseconds(self.config.recovery_cooldown_ms as i64);
+        self.states.iter()
+            .filter(|(_, state)| state.status != LinkStatus::Down)
+            .filter(|(_, state)| state.recovered_at.is_some_and(|recovered_at| now < recovered_at + cooldown))
+            .map(|(name, _)| (name.clone(), self.config.recovery_cooldown_penalty))
+            .collect()
+    }
+
+    pub fn states(&self) -> &HashMap<String, LinkState> {
+        &self.states
+    }
+
+    pub fn state(&self, link_name: &str) -> Option<&LinkState> {
+        self.states.get(link_name)
+    }
+
+    /// Filters `metrics` down to the links traffic should use: down links and
+    /// standby tiers are always excluded, and degraded links only while an up
+    /// link remains.
+    pub fn available_links(&self, metrics: &HashMap<String, LinkMetrics>) -> HashMap<String, LinkMetrics> {
+        let standby = self.standby_links();
+        let any_up = metrics.keys()
+            .any(|name| !standby.contains(name) && self.status(name) == LinkStatus::Up);
+        metrics
+            .iter()
+            .filter(|(name, _)| !standby.contains(*name))
+            .filter(|(name, _)| match self.status(name) {
+                LinkStatus::Up => true,
+                LinkStatus::Degraded => !any_up,
+                LinkStatus::Down => false,
+            })
+            .map(|(name, metric)| (name.clone(), metric.clone()))
+            .collect()
+    }
+}
+
+/// Drops links from `eligible` as soon as they go down, so traffic leaves
+/// them without waiting for the next metrics report to be filtered.
+pub async fn evict_down_links(
+    mut events: Receiver<LinkEvent>,
+    eligible: Arc<RwLock<Arc<HashMap<String, LinkMetrics>>>>,
+) -> Result<()> {
+    loop {
+        match events.recv().await {
+            Ok(LinkEvent::Down(link_name)) => {
+                let mut eligible = eligible.write();
+                if eligible.contains_key(&link_name) {
+                    let mut remaining = (**eligible).clone();
+                    remaining.remove(&link_name);
+                    *eligible = Arc::new(remaining);
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => warn!("Failover fell behind, {} link events missed", missed),
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Compares the newest sample against the mean of the earlier ones in the
+/// window and flags a latency or loss increase beyond `factor`.
+fn detect_anomaly(history: &VecDeque<LinkMetrics>, factor: f64) -> bool {
+    let (latest, baseline) = match history.back() {
+        Some(latest) if history.len() >= 2 => (latest, history.iter().take(history.len() - 1)),
+        _ => return false,
+    };
+
+    let count = (history.len() - 1) as f64;
+    let (latency_sum, loss_sum) = baseline.fold((0.0, 0.0), |(latency, loss), m| {
+        (latency + m.latency_ms, loss + m.packet_loss)
+    });
+    let baseline_latency = latency_sum / count;
+    let baseline_loss = loss_sum / count;
+
+    let latency_spike = baseline_latency > 0.0 && latest.latency_ms > baseline_latency * factor;
+    let loss_spike = latest.packet_loss - baseline_loss > MIN_LOSS_DELTA
+        && latest.packet_loss > baseline_loss * factor;
+
+    latency_spike || loss_spike
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FailoverTier;
+    use crate::Config;
+    use crate::test_utils::link_config;
+
+    fn sample(latency_ms: f64, packet_loss: f64) -> HashMap<String, LinkMetrics> {
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = latency_ms;
+        metrics.bandwidth_mbps = 500.0;
+        metrics.packet_loss = packet_loss;
+
+        let mut map = HashMap::new();
+        map.insert("eth0".to_string(), metrics);
+        map
+    }
+
+    #[test]
+    fn test_latency_spike_marks_link_degraded() {
+        let mut manager = FailoverManager::new(Config::default().failover);
+        for _ in 0..5 {
+            manager.update(&sample(10.0, 0.0));
+        }
+        assert!(!manager.state("eth0").unwrap().anomaly);
+
+        manager.update(&sample(40.0, 0.0));
+        let state = manager.state("eth0").unwrap();
+        assert!(state.anomaly);
+        assert_eq!(state.status, LinkStatus::Degraded);
+        // Still healthy by absolute thresholds, so no failure has been counted
+        assert_eq!(state.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_loss_jump_marks_link_degraded() {
+        let mut manager = FailoverManager::new(Config::default().failover);
+        for _ in 0..5 {
+            manager.update(&sample(10.0, 0.001));
+        }
+        manager.update(&sample(10.0, 0.05));
+        assert!(manager.state("eth0").unwrap().anomaly);
+    }
+
+    #[test]
+    fn test_gradual_change_is_not_anomalous() {
+        let mut manager = FailoverManager::new(Config::default().failover);
+        for i in 0..20 {
+            manager.update(&sample(10.0 + i as f64, 0.0));
+            assert!(!manager.state("eth0").unwrap().anomaly);
+        }
+        assert_eq!(manager.state("eth0").unwrap().history.len(), 6);
+    }
+
+    #[test]
+    fn test_send_failures_take_link_down() {
+        let mut manager = FailoverManager::new(Config::default().failover);
+        manager.update(&sample(10.0, 0.0));
+
+        manager.record_send_failure("eth0");
+        manager.record_send_failure("eth0");
+        assert_eq!(manager.state("eth0").unwrap().status, LinkStatus::Up);
+
+        manager.record_send_failure("eth0");
+        assert_eq!(manager.state("eth0").unwrap().status, LinkStatus::Down);
+        assert!(manager.available_links(&sample(10.0, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn test_degraded_link_avoided_while_another_is_up() {
+        let mut manager = FailoverManager::new(Config::default().failover);
+        let mut metrics = sample(10.0, 0.0);
+        metrics.insert("eth1".to_string(), metrics["eth0"].clone());
+        for _ in 0..5 {
+            manager.update(&metrics);
+        }
+
+        metrics.get_mut("eth0").unwrap().latency_ms = 50.0;
+        manager.update(&metrics);
+
+        let available = manager.available_links(&metrics);
+        assert!(!available.contains_key("eth0"));
+        assert!(available.contains_key("eth1"));
+    }
+
+    fn grouped_manager() -> FailoverManager {
+        let links = [
+            link_config("eth0", Some("primary")),
+            link_config("eth1", Some("primary")),
+            link_config("lte0", Some("backup")),
+        ];
+        FailoverManager::new(Config::default().failover).with_groups(&links)
+    }
+
+    #[test]
+    fn test_group_health_is_best_active_member() {
+        let mut manager = grouped_manager();
+        assert_eq!(manager.group_names(), vec!["backup", "primary"]);
+        assert_eq!(manager.group_health("primary"), 0.0);
+
+        let mut metrics = sample(10.0, 0.0);
+        let mut eth1 = metrics["eth0"].clone();
+        eth1.latency_ms = 50.0;
+        metrics.insert("eth1".to_string(), eth1.clone());
+        manager.update(&metrics);
+
+        assert_eq!(manager.group_health("primary"), metrics["eth0"].health_score());
+        assert_eq!(manager.group_health("backup"), 0.0);
+        assert_eq!(manager.group_health("nonexistent"), 0.0);
+
+        // With eth0 down, the group falls back to its remaining member
+        for _ in 0..3 {
+            manager.record_send_failure("eth0");
+        }
+        assert_eq!(manager.group_health("primary"), eth1.health_score());
+    }
+
+    fn two_links(eth0_latency_ms: f64) -> HashMap<String, LinkMetrics> {
+        let mut metrics = sample(eth0_latency_ms, 0.0);
+        let mut eth1 = LinkMetrics::new();
+        eth1.latency_ms = 20.0;
+        eth1.bandwidth_mbps = 500.0;
+        metrics.insert("eth1".to_string(), eth1);
+        metrics
+    }
+
+    #[test]
+    fn test_make_before_break_walkthrough() {
+        let mut config = Config::default().failover;
+        config.make_before_break = true;
+        let mut manager = FailoverManager::new(config);
+        for _ in 0..3 {
+            manager.update(&two_links(10.0));
+        }
+        assert_eq!(manager.transmit_links("eth0"), vec!["eth0"]);
+        // The selection is never overridden, and the active link sticks
+        assert_eq!(manager.transmit_links("eth1"), vec!["eth1"]);
+        assert_eq!(manager.handover(), Some(&Handover::Active("eth0".to_string())));
+
+        // Degrade: duplicate onto the backup
+        manager.update(&two_links(100.0));
+        assert_eq!(manager.transmit_links("eth0"), vec!["eth0", "eth1"]);
+        assert_eq!(manager.transmit_links("eth1"), vec!["eth1"]);
+
+        // Deliveries on the primary don't count towards the cutover
+        manager.record_delivery("eth0");
+        for _ in 0..4 {
+            manager.record_delivery("eth1");
+        }
+        assert!(matches!(manager.handover(), Some(Handover::Dual { delivered: 4, .. })));
+
+        manager.record_delivery("eth1");
+        assert_eq!(manager.handover(), Some(&Handover::Active("eth1".to_string())));
+        assert_eq!(manager.transmit_links("eth0"), vec!["eth0"]);
+    }
+
+    #[test]
+    fn test_make_before_break_aborts_when_primary_recovers() {
+        let mut config = Config::default().failover;
+        config.make_before_break = true;
+        config.anomaly_window = 1;
+        let mut manager = FailoverManager::new(config);
+        manager.update(&two_links(10.0));
+        manager.transmit_links("eth0");
+
+        manager.update(&two_links(100.0));
+        assert!(matches!(manager.handover(), Some(Handover::Dual { .. })));
+
+        // With a one-sample window the spike is the new baseline
+        manager.update(&two_links(100.0));
+        assert_eq!(manager.handover(), Some(&Handover::Active("eth0".to_string())));
+    }
+
+    #[test]
+    fn test_status_changes_published_to_subscribers() {
+        let mut manager = FailoverManager::new(Config::default().failover);
+        let mut events = manager.events().subscribe();
+        for _ in 0..5 {
+            manager.update(&sample(10.0, 0.0));
+        }
+        manager.update(&sample(40.0, 0.0));
+        manager.update(&sample(10.0, 0.0));
+        for _ in 0..3 {
+            manager.update(&sample(10.0, 0.9));
+        }
+        for _ in 0..5 {
+            manager.update(&sample(10.0, 0.0));
+        }
+
+        let received: Vec<LinkEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        let link = "eth0".to_string();
+        assert_eq!(received, vec![
+            LinkEvent::Degraded(link.clone()),
+            LinkEvent::Recovered(link.clone()),
+            LinkEvent::Degraded(link.clone()),
+            LinkEvent::Down(link.clone()),
+            LinkEvent::Up(link),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_down_link_evicted_before_next_report() {
+        let mut manager = FailoverManager::new(Config::default().failover);
+        let eligible = Arc::new(RwLock::new(Arc::new(sample(10.0, 0.0))));
+        let evictor = tokio::spawn(evict_down_links(manager.events().subscribe(), eligible.clone()));
+
+        for _ in 0..3 {
+            manager.record_send_failure("eth0");
+        }
+        drop(manager);
+        evictor.await.unwrap().unwrap();
+        assert!(eligible.read().is_empty());
+    }
+
+    #[test]
+    fn test_recovered_link_warms_up_linearly() {
+        let mut config = Config::default().failover;
+        config.warmup_ms = 1000;
+        let mut manager = FailoverManager::new(config);
+        for _ in 0..3 {
+            manager.update(&sample(10.0, 1.0));
+        }
+        assert!(manager.warmup_factors(Utc::now()).is_empty());
+        for _ in 0..5 {
+            manager.update(&sample(10.0, 0.0));
+        }
+
+        let recovered_at = manager.state("eth0").unwrap().recovered_at.unwrap();
+        let factor_after = |ms| manager.warmup_factors(recovered_at + chrono::Duration::milliseconds(ms)).get("eth0").copied();
+        assert_eq!(factor_after(0), Some(0.0));
+        assert_eq!(factor_after(250), Some(0.25));
+        assert_eq!(factor_after(750), Some(0.75));
+        assert_eq!(factor_after(1000), None);
+    }
+
+    #[test]
+    fn test_recovered_link_penalized_during_cooldown() {
+        let mut config = Config::default().failover;
+        config.recovery_cooldown_ms = 2000;
+        config.recovery_cooldown_penalty = 0.25;
+        let mut manager = FailoverManager::new(config);
+        for _ in 0..3 {
+            manager.update(&sample(10.0, 1.0));
+        }
+        for _ in 0..5 {
+            manager.update(&sample(10.0, 0.0));
+        }
+
+        let recovered_at = manager.state("eth0").unwrap().recovered_at.unwrap();
+        let factor_after = |ms| manager.cooldown_factors(recovered_at + chrono::Duration::milliseconds(ms)).get("eth0").copied();
+        assert_eq!(factor_after(0), Some(0.25));
+        assert_eq!(factor_after(1999), Some(0.25));
+        assert_eq!(factor_after(2000), None);
+        // No warm-up configured, so the cooldown is the only penalty
+        assert!(manager.warmup_factors(recovered_at).is_empty());
+    }
+
+    fn tiered_manager() -> FailoverManager {
+        let mut links = [
+            link_config("mpls", Some("wan")),
+            link_config("eth1", Some("wan")),
+            link_config("lte0", Some("wan")),
+        ];
+        links[1].failover_tier = 1;
+        links[2].failover_tier = 2;
+        let mut config = Config::default().failover;
+        config.tiers = vec![
+            FailoverTier { tier: 0, failover_threshold: Some(1), recovery_threshold: Some(2) },
+            FailoverTier { tier: 2, failover_threshold: Some(5), recovery_threshold: None },
+        ];
+        FailoverManager::new(config).with_groups(&links)
+    }
+
+    /// One sample per link, each healthy or badly lossy.
+    fn tier_sample(healthy: &[(&str, bool)]) -> HashMap<String, LinkMetrics> {
+        healthy.iter()
+            .map(|(name, healthy)| {
+                let metrics = if *healthy { sample(10.0, 0.0) } else { sample(10.0, 0.9) };
+                (name.to_string(), metrics["eth0"].clone())
+            })
+            .collect()
+    }
+
+    fn available(manager: &FailoverManager, metrics: &HashMap<String, LinkMetrics>) -> Vec<String> {
+        let mut names: Vec<String> = manager.available_links(metrics).into_keys().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_tiers_cascade_and_climb_back() {
+        let mut manager = tiered_manager();
+        let all_up = tier_sample(&[("mpls", true), ("eth1", true), ("lte0", true)]);
+        manager.update(&all_up);
+        assert_eq!(manager.active_tier("wan"), Some(0));
+        assert_eq!(available(&manager, &all_up), vec!["mpls"]);
+
+        // Tier 0 fails after a single bad sample; silver takes over
+        let mpls_down = tier_sample(&[("mpls", false), ("eth1", true), ("lte0", true)]);
+        manager.update(&mpls_down);
+        assert_eq!(manager.active_tier("wan"), Some(1));
+        assert_eq!(available(&manager, &mpls_down), vec!["eth1"]);
+
+        // Tier 1 uses the global threshold of 3
+        let silver_down = tier_sample(&[("mpls", false), ("eth1", false), ("lte0", false)]);
+        for _ in 0..2 {
+            manager.update(&silver_down);
+        }
+        assert_eq!(manager.active_tier("wan"), Some(1));
+        manager.update(&silver_down);
+        assert_eq!(manager.active_tier("wan"), Some(2));
+        assert_eq!(available(&manager, &silver_down), vec!["lte0"]);
+
+        // Tier 2 tolerates 5 bad samples; it has had 3
+        manager.update(&silver_down);
+        assert_eq!(manager.active_tier("wan"), Some(2));
+        manager.update(&silver_down);
+        assert_eq!(manager.active_tier("wan"), None);
+        assert!(manager.standby_links().is_empty());
+
+        // Recovery climbs back tier by tier: bronze after 5 good samples,
+        // gold after its own 2
+        let bronze_back = tier_sample(&[("mpls", false), ("eth1", false), ("lte0", true)]);
+        for _ in 0..5 {
+            manager.update(&bronze_back);
+        }
+        assert_eq!(manager.active_tier("wan"), Some(2));
+
+        let gold_back = tier_sample(&[("mpls", true), ("eth1", false), ("lte0", true)]);
+        manager.update(&gold_back);
+        assert_eq!(manager.active_tier("wan"), Some(2));
+        manager.update(&gold_back);
+        assert_eq!(manager.active_tier("wan"), Some(0));
+        assert_eq!(available(&manager, &gold_back), vec!["mpls"]);
+        assert_eq!(manager.standby_links(), HashSet::from(["eth1".to_string(), "lte0".to_string()]));
+    }
+
+    #[test]
+    fn test_send_failures_use_tier_threshold() {
+        let mut manager = tiered_manager();
+        manager.record_send_failure("mpls");
+        assert_eq!(manager.active_tier("wan"), Some(1));
+        assert!(manager.standby_links().contains("lte0"));
+        assert!(!manager.standby_links().contains("eth1"));
+    }
+
+    #[test]
+    fn test_ungrouped_links_are_never_standby() {
+        let links = [link_config("eth0", None), link_config("eth1", Some("wan"))];
+        let mut manager = FailoverManager::new(Config::default().failover).with_groups(&links);
+        manager.update(&tier_sample(&[("eth0", true), ("eth1", true)]));
+        assert!(manager.standby_links().is_empty());
+        assert_eq!(manager.active_tier("nonexistent"), None);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::protocol::Protocol;
+use crate::scheduler::Packet;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FlowKey {
+    pub source_ip: String,
+    pub dest_ip: String,
+    pub protocol: Protocol,
+}
+
+impl FlowKey {
+    pub fn from_packet(packet: &Packet) -> Self {
+        Self {
+            source_ip: packet.source_ip.clone(),
+            dest_ip: packet.dest_ip.clone(),
+            protocol: packet.protocol.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowEntry {
+    pub link_name: String,
+    pub packets: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A pinned flow, as listed in diagnostics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinnedFlow {
+    #[serde(flatten)]
+    pub key: FlowKey,
+    #[serde(flatten)]
+    pub entry: FlowEntry,
+}
+
+/// Pins flows to the link they were first scheduled on so packets of a flow
+/// aren't reordered across links. Entries expire after `idle_timeout`.
+pub struct FlowTable {
+    flows: DashMap<FlowKey, FlowEntry>,
+    /// Packets seen per flow, for rules that treat a flow's first packets
+    /// differently. Tracked apart from pins, which need flow affinity.
+    packet_counts: DashMap<FlowKey, (u64, DateTime<Utc>)>,
+    idle_timeout: Duration,
+}
+
+impl FlowTable {
+    pub fn new(idle_timeout_ms: u64) -> Self {
+        Self {
+            flows: DashMap::new(),
+            packet_counts: DashMap::new(),
+            idle_timeout: Duration::milliseconds(idle_timeout_ms as i64),
+        }
+    }
+
+    /// Returns the link the flow is pinned to, refreshing its idle timer.
+    pub fn lookup(&self, key: &FlowKey, now: DateTime<Utc>) -> Option<String> {
+        self.flows.get_mut(key).map(|mut entry| {
+            entry.packets += 1;
+            entry.last_seen = now;
+            entry.link_name.clone()
+        })
+    }
+
+    pub fn pin(&self, key: FlowKey, link_name: String, now: DateTime<Utc>) {
+        self.flows.insert(key, FlowEntry {
+            link_name,
+            packets: 1,
+            first_seen: now,
+            last_seen: now,
+        });
+    }
+
+    /// Counts a packet of the flow, returning its position in the flow:
+    /// 1 for the first packet, or the first after the flow idled out.
+    pub fn count_packet(&self, key: FlowKey, now: DateTime<Utc>) -> u64 {
+        let mut count = self.packet_counts.entry(key).or_insert((0, now));
+        if now - count.1 >= self.idle_timeout {
+            count.0 = 0;
+        }
+        count.0 += 1;
+        count.1 = now;
+        count.0
+    }
+
+    /// Removes flows idle for longer than the timeout and returns how many
+    /// pinned flows were removed.
+    pub fn expire_idle(&self, now: DateTime<Utc>) -> usize {
+        self.packet_counts.retain(|_, (_, last_seen)| now - *last_seen < self.idle_timeout);
+        let before = self.flows.len();
+        self.flows.retain(|_, entry| now - entry.last_seen < self.idle_timeout);
+        before - self.flows.len()
+    }
+
+    /// Every pinned flow, oldest first.
+    pub fn pinned_flows(&self) -> Vec<PinnedFlow> {
+        let mut flows: Vec<PinnedFlow> = self.flows.iter()
+            .map(|entry| PinnedFlow { key: entry.key().clone(), entry: entry.value().clone() })
+            .collect();
+        flows.sort_by_key(|flow| flow.entry.first_seen);
+        flows
+    }
+
+    pub fn flows_on_link(&self, link_name: &str) -> usize {
+        self.flows.iter().filter(|entry| entry.link_name == link_name).count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(source_ip: &str) -> FlowKey {
+        FlowKey {
+            source_ip: source_ip.to_string(),
+            dest_ip: "10.0.0.1".to_string(),
+            protocol: Protocol::Tcp,
+        }
+    }
+
+    #[test]
+    fn test_flow_pin_and_expiry() {
+        let table = FlowTable::new(1000);
+        let now = Utc::now();
+        table.pin(key("192.168.1.10"), "eth0".to_string(), now);
+        table.pin(key("192.168.1.11"), "eth1".to_string(), now);
+
+        let later = now + Duration::milliseconds(800);
+        assert_eq!(table.lookup(&key("192.168.1.10"), later), Some("eth0".to_string()));
+        assert_eq!(table.flows_on_link("eth0"), 1);
+
+        // Only the flow that saw no traffic since `now` has idled out
+        assert_eq!(table.expire_idle(now + Duration::milliseconds(1500)), 1);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.flows_on_link("eth1"), 0);
+    }
+
+    #[test]
+    fn test_packet_count_restarts_after_idle() {
+        let table = FlowTable::new(1000);
+        let now = Utc::now();
+        assert_eq!(table.count_packet(key("192.168.1.10"), now), 1);
+        assert_eq!(table.count_packet(key("192.168.1.10"), now), 2);
+        assert_eq!(table.count_packet(key("192.168.1.11"), now), 1);
+        assert_eq!(table.count_packet(key("192.168.1.10"), now + Duration::milliseconds(1500)), 1);
+        // Counting alone doesn't pin
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,538 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Bytes of the sequence number every tunnel frame starts with.
+const SEQUENCE_LEN: usize = 8;
+/// Bytes of fragment metadata following the sequence number.
+pub const FRAGMENT_HEADER_LEN: usize = 4;
+/// Set in the fragment header when more fragments follow.
+const MORE_FRAGMENTS: u32 = 1 << 31;
+
+/// Where a datagram's bytes sit within its frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// Offset of this fragment within the frame body (after the sequence
+    /// number).
+    pub offset: u32,
+    pub more_fragments: bool,
+}
+
+impl FragmentHeader {
+    fn encode(self) -> [u8; FRAGMENT_HEADER_LEN] {
+        let flags = if self.more_fragments { MORE_FRAGMENTS } else { 0 };
+        (self.offset | flags).to_be_bytes()
+    }
+
+    fn decode(bytes: [u8; FRAGMENT_HEADER_LEN]) -> Self {
+        let value = u32::from_be_bytes(bytes);
+        Self {
+            offset: value & !MORE_FRAGMENTS,
+            more_fragments: value & MORE_FRAGMENTS != 0,
+        }
+    }
+}
+
+/// Splits a tunnel frame into datagrams of at most `max_datagram` bytes.
+/// Each repeats the frame's sequence number, followed by a fragment header
+/// and its share of the frame body; a frame that fits is a single datagram
+/// with offset 0. Frames are split after sealing, so every fragment of an
+/// encrypted packet shares the packet's one nonce.
+pub fn split(frame: &[u8], max_datagram: usize) -> Result<Vec<Vec<u8>>> {
+    if frame.len() < SEQUENCE_LEN {
+        return Err(anyhow::anyhow!("Tunnel frame too short: {} bytes", frame.len()));
+    }
+    let chunk_len = max_datagram.saturating_sub(SEQUENCE_LEN + FRAGMENT_HEADER_LEN);
+    if chunk_len == 0 {
+        return Err(anyhow::anyhow!("Datagram size {} leaves no room for payload", max_datagram));
+    }
+    let (sequence, body) = frame.split_at(SEQUENCE_LEN);
+    if body.len() >= MORE_FRAGMENTS as usize {
+        return Err(anyhow::anyhow!("Tunnel frame too large to fragment: {} bytes", frame.len()));
+    }
+
+    let chunks: Vec<&[u8]> = if body.is_empty() { vec![body] } else { body.chunks(chunk_len).collect() };
+    let last = chunks.len() - 1;
+    Ok(chunks.into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let header = FragmentHeader {
+                offset: (index * chunk_len) as u32,
+                more_fragments: index < last,
+            };
+            let mut datagram = Vec::with_capacity(SEQUENCE_LEN + FRAGMENT_HEADER_LEN + chunk.len());
+            datagram.extend_from_slice(sequence);
+            datagram.extend_from_slice(&header.encode());
+            datagram.extend_from_slice(chunk);
+            datagram
+        })
+        .collect())
+}
+
+/// Frames a partial frame may fall behind the newest sequence number seen
+/// before it is given up on.
+pub const DEFAULT_REASSEMBLY_WINDOW: u64 = 1024;
+
+/// Sequence numbers of `bits` bits compared with RFC 1982 serial number
+/// arithmetic, so ordering survives the counter wrapping around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialSpace {
+    bits: u32,
+}
+
+impl SerialSpace {
+    pub fn new(bits: u32) -> Result<Self> {
+        if !(2..=64).contains(&bits) {
+            return Err(anyhow::anyhow!("Sequence numbers must have 2 to 64 bits, not {}", bits));
+        }
+        Ok(Self { bits })
+    }
+
+    /// Signed steps from `from` forward to `to`, modulo 2^bits: positive
+    /// when `to` is newer. Numbers exactly half the space apart have no
+    /// defined order; `to` is then taken as older.
+    pub fn distance(self, from: u64, to: u64) -> i64 {
+        let shift = 64 - self.bits;
+        ((to.wrapping_sub(from) << shift) as i64) >> shift
+    }
+
+    /// Whether `a` comes after `b`.
+    pub fn is_newer(self, a: u64, b: u64) -> bool {
+        self.distance(b, a) > 0
+    }
+
+    /// The number `steps` after `from`, wrapping around.
+    pub fn advance(self, from: u64, steps: u64) -> u64 {
+        let mask = u64::MAX >> (64 - self.bits);
+        from.wrapping_add(steps) & mask
+    }
+}
+
+impl Default for SerialSpace {
+    fn default() -> Self {
+        Self { bits: 64 }
+    }
+}
+
+/// Partial frames held at once, across all sources, before the oldest is
+/// given up on to make room for a new one.
+pub const DEFAULT_MAX_PENDING: usize = 256;
+
+/// Rebuilds tunnel frames from the datagrams made by `split`. A frame with a
+/// lost fragment is discarded once `timeout` passes without completing it,
+/// once it falls more than `window` frames behind the newest one from its
+/// source, or to make room when `max_pending` frames are incomplete.
+/// Sequence numbers are only compared within one source's stream, and only
+/// frames passed to `confirm` move that stream's window.
+pub struct Reassembler {
+    timeout: Duration,
+    window: u64,
+    max_pending: usize,
+    serial: SerialSpace,
+    sources: HashMap<SocketAddr, Source>,
+    /// Partial frames across all sources.
+    pending: usize,
+}
+
+/// Reassembly state of the datagrams from one sending socket.
+struct Source {
+    /// Newest sequence number confirmed so far.
+    newest: Option<u64>,
+    pending: HashMap<u64, PartialFrame>,
+    last_seen: Instant,
+}
+
+struct PartialFrame {
+    fragments: BTreeMap<u32, Vec<u8>>,
+    /// Body length, known once the last fragment arrives.
+    total_len: Option<usize>,
+    first_seen: Instant,
+}
+
+impl PartialFrame {
+    /// The reassembled body once every byte up to `total_len` has arrived.
+    fn complete(&self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        let mut body = Vec::with_capacity(total_len);
+        for (&offset, chunk) in &self.fragments {
+            if offset as usize != body.len() {
+                return None;
+            }
+            body.extend_from_slice(chunk);
+        }
+        (body.len() == total_len).then_some(body)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            window: DEFAULT_REASSEMBLY_WINDOW,
+            max_pending: DEFAULT_MAX_PENDING,
+            serial: SerialSpace::default(),
+            sources: HashMap::new(),
+            pending: 0,
+        }
+    }
+
+    pub fn with_window(mut self, window: u64) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// How long a partial frame may wait for its missing fragments.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Caps the partial frames held at once; also caps the sources tracked.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    /// Compares sequence numbers as `bits`-bit counters that wrap around.
+    pub fn with_sequence_bits(mut self, bits: u32) -> Result<Self> {
+        self.serial = SerialSpace::new(bits)?;
+        Ok(self)
+    }
+
+    /// Takes one datagram from `source`, returning the full frame it
+    /// completes, if any. Unfragmented datagrams complete immediately.
+    pub fn push(&mut self, source: SocketAddr, datagram: &[u8], now: Instant) -> Result<Option<Vec<u8>>> {
+        let header_len = SEQUENCE_LEN + FRAGMENT_HEADER_LEN;
+        if datagram.len() < header_len {
+            return Err(anyhow::anyhow!("Tunnel datagram too short: {} bytes", datagram.len()));
+        }
+        let sequence_number = u64::from_be_bytes(datagram[..SEQUENCE_LEN].try_into()?);
+        let header = FragmentHeader::decode(datagram[SEQUENCE_LEN..header_len].try_into()?);
+        let chunk = &datagram[header_len..];
+
+        if !self.sources.contains_key(&source) && self.sources.len() >= self.max_pending {
+            self.evict_idlest_source();
+        }
+        let (serial, window) = (self.serial, self.window as i64);
+        let state = self.sources.entry(source).or_insert_with(|| Source {
+            newest: None,
+            pending: HashMap::new(),
+            last_seen: now,
+        });
+        state.last_seen = now;
+
+        if header.offset == 0 && !header.more_fragments {
+            return Ok(Some(frame(sequence_number, chunk)));
+        }
+        // A late fragment of a frame already given up on can't complete it
+        if state.newest.is_some_and(|newest| serial.distance(sequence_number, newest) > window) {
+            return Ok(None);
+        }
+
+        if !state.pending.contains_key(&sequence_number) {
+            if self.pending >= self.max_pending {
+                self.evict_oldest_frame();
+            }
+            self.pending += 1;
+        }
+        let state = self.sources.get_mut(&source).expect("source tracked above");
+        let partial = state.pending.entry(sequence_number).or_insert_with(|| PartialFrame {
+            fragments: BTreeMap::new(),
+            total_len: None,
+            first_seen: now,
+        });
+        if !header.more_fragments {
+            partial.total_len = Some(header.offset as usize + chunk.len());
+        }
+        partial.fragments.insert(header.offset, chunk.to_vec());
+
+        match partial.complete() {
+            Some(body) => {
+                state.pending.remove(&sequence_number);
+                self.pending -= 1;
+                Ok(Some(frame(sequence_number, &body)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Records that a frame from `source`, as returned by `push`, turned out
+    /// genuine (it decrypted, or decoded when unencrypted), moving the
+    /// source's window forward to it. One frame moves the window at most
+    /// `window` frames, so a forged far-future sequence number can't make
+    /// every frame in flight stale.
+    pub fn confirm(&mut self, source: SocketAddr, sequence_number: u64) {
+        let Some(state) = self.sources.get_mut(&source) else {
+            return;
+        };
+        let newest = match state.newest {
+            None => sequence_number,
+            Some(newest) => {
+                let distance = self.serial.distance(newest, sequence_number);
+                if distance <= 0 {
+                    return;
+                }
+                self.serial.advance(newest, (distance as u64).min(self.window))
+            }
+        };
+        state.newest = Some(newest);
+
+        let (serial, window) = (self.serial, self.window as i64);
+        let before = state.pending.len();
+        state.pending.retain(|&pending, _| serial.distance(pending, newest) <= window);
+        let dropped = before - state.pending.len();
+        if dropped > 0 {
+            self.pending -= dropped;
+            debug!("Dropped {} frames from {} that fell out of the reassembly window", dropped, source);
+        }
+    }
+
+    /// Gives up on the incomplete frame that has waited longest.
+    fn evict_oldest_frame(&mut self) {
+        let oldest = self.sources.iter()
+            .flat_map(|(source, state)| state.pending.iter().map(move |(&sequence_number, partial)| (*source, sequence_number, partial.first_seen)))
+            .min_by_key(|(_, _, first_seen)| *first_seen);
+        if let Some((source, sequence_number, _)) = oldest {
+            if let Some(state) = self.sources.get_mut(&source) {
+                state.pending.remove(&sequence_number);
+                self.pending -= 1;
+                debug!("Dropped frame {} from {} to make room for reassembly", sequence_number, source);
+            }
+        }
+    }
+
+    /// Forgets the source heard from least recently, with its partial frames.
+    fn evict_idlest_source(&mut self) {
+        let idlest = self.sources.iter()
+            .min_by_key(|(_, state)| state.last_seen)
+            .map(|(source, _)| *source);
+        if let Some(state) = idlest.and_then(|source| self.sources.remove(&source)) {
+            self.pending -= state.pending.len();
+        }
+    }
+
+    /// Discards frames still incomplete after the timeout, returning how
+    /// many, and forgets sources idle that long.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.timeout;
+        let before = self.pending;
+        for state in self.sources.values_mut() {
+            state.pending.retain(|_, partial| now.duration_since(partial.first_seen) < timeout);
+        }
+        self.sources.retain(|_, state| !state.pending.is_empty() || now.duration_since(state.last_seen) < timeout);
+        self.pending = self.sources.values().map(|state| state.pending.len()).sum();
+        before - self.pending
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+fn frame(sequence_number: u64, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(SEQUENCE_LEN + body.len());
+    frame.extend_from_slice(&sequence_number.to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "192.0.2.1:4000".parse().unwrap()
+    }
+
+    fn test_frame(sequence_number: u64, body_len: usize) -> Vec<u8> {
+        let body: Vec<u8> = (0..body_len).map(|i| i as u8).collect();
+        frame(sequence_number, &body)
+    }
+
+    #[test]
+    fn test_oversized_frame_reassembles() {
+        let original = test_frame(5, 3000);
+        let datagrams = split(&original, 1000).unwrap();
+        assert_eq!(datagrams.len(), 4);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= 1000));
+
+        // Fragments may arrive in any order
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        let now = Instant::now();
+        for datagram in datagrams.iter().rev().skip(1) {
+            assert_eq!(reassembler.push(peer(), datagram, now).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(peer(), &datagrams[datagrams.len() - 1], now).unwrap(), Some(original));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_small_frame_is_one_datagram() {
+        let original = test_frame(9, 100);
+        let datagrams = split(&original, 1000).unwrap();
+        assert_eq!(datagrams.len(), 1);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        assert_eq!(reassembler.push(peer(), &datagrams[0], Instant::now()).unwrap(), Some(original));
+    }
+
+    #[test]
+    fn test_dropped_fragment_expires() {
+        let datagrams = split(&test_frame(5, 3000), 1000).unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        let now = Instant::now();
+        for (index, datagram) in datagrams.iter().enumerate() {
+            if index != 1 {
+                assert_eq!(reassembler.push(peer(), datagram, now).unwrap(), None);
+            }
+        }
+        assert_eq!(reassembler.pending(), 1);
+
+        // Later frames aren't held up by the incomplete one
+        let next = test_frame(6, 10);
+        assert_eq!(reassembler.push(peer(), &split(&next, 1000).unwrap()[0], now).unwrap(), Some(next));
+
+        assert_eq!(reassembler.expire(now + Duration::from_millis(500)), 0);
+        assert_eq!(reassembler.expire(now + Duration::from_secs(1)), 1);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    /// Pushes a datagram and confirms the frame it completes, as
+    /// `UdpTunnelReceiver` does once the frame opens.
+    fn receive(reassembler: &mut Reassembler, datagram: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let frame = reassembler.push(peer(), datagram, now).unwrap()?;
+        reassembler.confirm(peer(), u64::from_be_bytes(frame[..SEQUENCE_LEN].try_into().unwrap()));
+        Some(frame)
+    }
+
+    fn fragments(sequence_number: u64) -> Vec<Vec<u8>> {
+        split(&test_frame(sequence_number, 300), 100).unwrap()
+    }
+
+    #[test]
+    fn test_serial_comparison_across_wrap() {
+        let serial = SerialSpace::new(32).unwrap();
+        let max = u32::MAX as u64;
+        assert!(serial.is_newer(0, max));
+        assert!(serial.is_newer(5, max - 5));
+        assert!(!serial.is_newer(max, 0));
+        assert_eq!(serial.distance(max - 1, 2), 4);
+        assert_eq!(serial.distance(2, max - 1), -4);
+        // Half the space apart is ambiguous and treated as older both ways
+        assert!(!serial.is_newer(1 << 31, 0));
+        assert!(!serial.is_newer(0, 1 << 31));
+
+        let full = SerialSpace::default();
+        assert!(full.is_newer(0, u64::MAX));
+        assert!(full.is_newer(100, 99));
+        assert!(SerialSpace::new(1).is_err());
+        assert!(SerialSpace::new(65).is_err());
+    }
+
+    #[test]
+    fn test_reassembly_across_wrap() {
+        let max = u32::MAX as u64;
+        let mut reassembler = Reassembler::new(Duration::from_secs(1))
+            .with_window(4)
+            .with_sequence_bits(32)
+            .unwrap();
+        let now = Instant::now();
+
+        // A frame just before the wrap is still completed after newer
+        // frames past zero arrive, as it is within the window
+        let before_wrap = fragments(max - 1);
+        assert_eq!(receive(&mut reassembler, &before_wrap[0], now), None);
+        for sequence_number in [max, 0, 1] {
+            for datagram in fragments(sequence_number) {
+                receive(&mut reassembler, &datagram, now);
+            }
+        }
+        assert_eq!(reassembler.pending(), 1);
+        for datagram in &before_wrap[1..] {
+            receive(&mut reassembler, datagram, now);
+        }
+        assert_eq!(reassembler.pending(), 0);
+
+        // Past the window the incomplete frame is dropped, and its late
+        // fragments don't revive it
+        let stale = fragments(2);
+        assert_eq!(receive(&mut reassembler, &stale[0], now), None);
+        for sequence_number in [5, 7] {
+            let next = test_frame(sequence_number, 10);
+            assert_eq!(receive(&mut reassembler, &split(&next, 1000).unwrap()[0], now), Some(next));
+        }
+        assert_eq!(reassembler.pending(), 0);
+        for datagram in &stale[1..] {
+            assert_eq!(receive(&mut reassembler, datagram, now), None);
+        }
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_sources_reassembled_apart() {
+        let other: SocketAddr = "192.0.2.2:4000".parse().unwrap();
+        let ours = test_frame(5, 300);
+        let theirs: Vec<u8> = frame(5, &[0xaa; 300]);
+        let (ours_parts, theirs_parts) = (split(&ours, 100).unwrap(), split(&theirs, 100).unwrap());
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        let now = Instant::now();
+
+        // The same sequence number from two senders makes two frames
+        for (mine, other_part) in ours_parts[..3].iter().zip(&theirs_parts[..3]) {
+            assert_eq!(reassembler.push(peer(), mine, now).unwrap(), None);
+            assert_eq!(reassembler.push(other, other_part, now).unwrap(), None);
+        }
+        assert_eq!(reassembler.pending(), 2);
+        assert_eq!(reassembler.push(peer(), &ours_parts[3], now).unwrap(), Some(ours));
+        assert_eq!(reassembler.push(other, &theirs_parts[3], now).unwrap(), Some(theirs));
+    }
+
+    #[test]
+    fn test_pending_frames_capped() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(1)).with_max_pending(2);
+        let now = Instant::now();
+        for (offset, sequence_number) in [1, 2, 3].into_iter().enumerate() {
+            reassembler.push(peer(), &fragments(sequence_number)[0], now + Duration::from_millis(offset as u64)).unwrap();
+        }
+        assert_eq!(reassembler.pending(), 2);
+
+        // The oldest frame made room; the newer ones still complete
+        let mut completed = 0;
+        for sequence_number in [2, 3] {
+            for datagram in &fragments(sequence_number)[1..] {
+                completed += reassembler.push(peer(), datagram, now).unwrap().is_some() as usize;
+            }
+        }
+        assert_eq!(completed, 2);
+        for datagram in &fragments(1)[1..] {
+            assert_eq!(reassembler.push(peer(), datagram, now).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_far_future_frame_moves_window_one_width() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(1)).with_window(4);
+        let now = Instant::now();
+        let whole = |sequence_number| split(&test_frame(sequence_number, 10), 1000).unwrap().remove(0);
+        receive(&mut reassembler, &whole(10), now);
+        let waiting = fragments(12);
+        assert_eq!(receive(&mut reassembler, &waiting[0], now), None);
+
+        // A frame that fails to open never moves the window
+        reassembler.push(peer(), &whole(1 << 40), now).unwrap();
+        // One that opens moves it by at most its width, to 14
+        receive(&mut reassembler, &whole(1 << 41), now);
+        assert_eq!(reassembler.pending(), 1);
+        for datagram in &waiting[1..] {
+            receive(&mut reassembler, datagram, now);
+        }
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(receive(&mut reassembler, &fragments(13)[0], now), None);
+        assert_eq!(reassembler.pending(), 1);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::config::{LinkConfig, LinkShaping, QosRule};
+use crate::rate_limit::TokenBucket;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Traffic a bucket may accumulate while idle, as time at its rate.
+const BURST_TIME: Duration = Duration::from_millis(100);
+/// Every bucket holds at least one full-size Ethernet frame.
+const MIN_BURST_BYTES: u64 = 1514;
+
+/// Hierarchical token bucket shaping one link's egress. Packets wait in a
+/// queue per class until they may be sent: each class is guaranteed its
+/// `rate`, and beyond that borrows whatever the link's budget has left, up
+/// to its `ceil`. Classes borrowing at the same time take turns.
+pub struct HierarchicalTokenBucket<P> {
+    link: TokenBucket,
+    link_rate: u64,
+    /// Ordered so borrowing turns go round in a fixed order.
+    classes: BTreeMap<String, ShapedClass<P>>,
+    /// Traffic of classes the link doesn't shape, unclassified included.
+    unshaped: VecDeque<Queued<P>>,
+    /// Set once the link reserves bandwidth for a class: unshaped traffic
+    /// then borrows from the link like a class does, rather than passing.
+    reserving: bool,
+    /// Packets each queue holds before further arrivals are dropped.
+    queue_limit: usize,
+    /// Whose turn it is to borrow: a class's index, or the unshaped queue
+    /// after the last class.
+    next_turn: usize,
+}
+
+struct ShapedClass<P> {
+    /// Guaranteed rate, sent without borrowing.
+    rate: TokenBucket,
+    /// Cap on everything the class sends, borrowed or not.
+    ceil: TokenBucket,
+    queue: VecDeque<Queued<P>>,
+}
+
+struct Queued<P> {
+    bytes: usize,
+    packet: P,
+}
+
+impl<P> HierarchicalTokenBucket<P> {
+    pub fn new(shaping: &LinkShaping, queue_limit: usize, now: Instant) -> Self {
+        let mut htb = Self::unshaped(shaping.rate.bytes_per_sec(), queue_limit, now);
+        for class in &shaping.classes {
+            let ceil = class.ceil.as_ref().map_or(htb.link_rate, |ceil| ceil.bytes_per_sec());
+            htb.classes.insert(class.name.clone(), ShapedClass::new(class.rate.bytes_per_sec(), ceil, now));
+        }
+        htb
+    }
+
+    /// A link with a `link_rate` bytes/sec budget and no classes yet.
+    pub fn unshaped(link_rate: u64, queue_limit: usize, now: Instant) -> Self {
+        Self {
+            link: bucket(link_rate, now),
+            link_rate,
+            classes: BTreeMap::new(),
+            unshaped: VecDeque::new(),
+            reserving: false,
+            queue_limit,
+            next_turn: 0,
+        }
+    }
+
+    /// Guarantees `class` at least `bytes_per_sec` of the link. A class
+    /// already shaped keeps its ceiling and the larger of the two rates;
+    /// otherwise it may borrow up to the whole link.
+    pub fn reserve(&mut self, class: &str, bytes_per_sec: u64, now: Instant) {
+        match self.classes.get_mut(class) {
+            Some(shaped) => {
+                if bytes_per_sec > shaped.rate.rate_bytes_per_sec() {
+                    shaped.rate = bucket(bytes_per_sec, now);
+                }
+            }
+            None => {
+                self.classes.insert(class.to_string(), ShapedClass::new(bytes_per_sec, self.link_rate, now));
+            }
+        }
+        self.reserving = true;
+    }
+
+    /// Caps everything `class` sends on the link at `bytes_per_sec`. A
+    /// class not shaped yet gets no guaranteed rate, only borrowing.
+    pub fn limit(&mut self, class: &str, bytes_per_sec: u64, now: Instant) {
+        match self.classes.get_mut(class) {
+            Some(shaped) => {
+                if bytes_per_sec < shaped.ceil.rate_bytes_per_sec() {
+                    shaped.ceil = bucket(bytes_per_sec, now);
+                }
+            }
+            None => {
+                self.classes.insert(class.to_string(), ShapedClass::new(0, bytes_per_sec, now));
+            }
+        }
+    }
+
+    /// Queues a `bytes`-long packet of `class` (`None` for traffic no QoS
+    /// rule matched) until `dequeue` lets it go. Gives the packet back if
+    /// its queue is full.
+    pub fn enqueue(&mut self, class: Option<&str>, bytes: usize, packet: P) -> Result<(), P> {
+        let queue = match class.and_then(|class| self.classes.get_mut(class)) {
+            Some(shaped) => &mut shaped.queue,
+            None => &mut self.unshaped,
+        };
+        if queue.len() >= self.queue_limit {
+            return Err(packet);
+        }
+        queue.push_back(Queued { bytes, packet });
+        Ok(())
+    }
+
+    /// The next packet that may be sent at `now`, charging it to its class
+    /// and the link. Guaranteed traffic goes first; classes that would
+    /// have to borrow then take turns. Classes the link doesn't shape
+    /// always go, unless it reserves bandwidth for others.
+    pub fn dequeue(&mut self, now: Instant) -> Option<P> {
+        self.link.refill(now);
+        for shaped in self.classes.values_mut() {
+            shaped.rate.refill(now);
+            shaped.ceil.refill(now);
+        }
+
+        for shaped in self.classes.values_mut() {
+            let Some(bytes) = shaped.queue.front().map(|queued| queued.bytes) else {
+                continue;
+            };
+            if shaped.rate.has(bytes) && shaped.ceil.has(bytes) {
+                // Charged to the link too, so others can only borrow what's
+                // left, but never refused by it
+                shaped.rate.take(bytes);
+                shaped.ceil.take(bytes);
+                self.link.take(bytes);
+                return shaped.queue.pop_front().map(|queued| queued.packet);
+            }
+        }
+
+        let turns = self.classes.len() + 1;
+        for offset in 0..turns {
+            let turn = (self.next_turn + offset) % turns;
+            if let Some(packet) = self.borrow(turn) {
+                self.next_turn = (turn + 1) % turns;
+                return Some(packet);
+            }
+        }
+        None
+    }
+
+    /// Sends the head of the `turn`th queue on borrowed budget, if it has
+    /// one that fits.
+    fn borrow(&mut self, turn: usize) -> Option<P> {
+        match self.classes.values_mut().nth(turn) {
+            Some(shaped) => {
+                let bytes = shaped.queue.front()?.bytes;
+                if !shaped.ceil.has(bytes) || !self.link.has(bytes) {
+                    return None;
+                }
+                shaped.ceil.take(bytes);
+                self.link.take(bytes);
+                shaped.queue.pop_front().map(|queued| queued.packet)
+            }
+            None => {
+                let bytes = self.unshaped.front()?.bytes;
+                if self.reserving {
+                    if !self.link.has(bytes) {
+                        return None;
+                    }
+                    self.link.take(bytes);
+                }
+                self.unshaped.pop_front().map(|queued| queued.packet)
+            }
+        }
+    }
+
+    /// How long until `dequeue` may let another packet go, or `None` if
+    /// nothing is queued. As of the last `dequeue`, which should have been
+    /// called at `now` until it returned `None`.
+    pub fn next_release_in(&self) -> Option<Duration> {
+        let classes = self.classes.values().filter_map(|shaped| {
+            let bytes = shaped.queue.front()?.bytes;
+            let guaranteed = shaped.rate.wait_for(bytes);
+            let borrowed = self.link.wait_for(bytes);
+            Some(guaranteed.min(borrowed).max(shaped.ceil.wait_for(bytes)))
+        });
+        let unshaped = self.unshaped.front().map(|queued| {
+            if self.reserving {
+                self.link.wait_for(queued.bytes)
+            } else {
+                Duration::ZERO
+            }
+        });
+        classes.chain(unshaped).min()
+    }
+
+    /// Every packet still waiting, with its class (`None` for unshaped
+    /// traffic) and length, for moving them to a rebuilt shaper.
+    pub fn into_queued(self) -> Vec<(Option<String>, usize, P)> {
+        let classes = self.classes.into_iter()
+            .flat_map(|(name, shaped)| shaped.queue.into_iter().map(move |queued| (Some(name.clone()), queued.bytes, queued.packet)));
+        let unshaped = self.unshaped.into_iter().map(|queued| (None, queued.bytes, queued.packet));
+        classes.chain(unshaped).collect()
+    }
+
+    /// Packets waiting across every queue.
+    pub fn queued(&self) -> usize {
+        self.classes.values().map(|shaped| shaped.queue.len()).sum::<usize>() + self.unshaped.len()
+    }
+}
+
+impl<P> ShapedClass<P> {
+    fn new(rate: u64, ceil: u64, now: Instant) -> Self {
+        Self {
+            rate: bucket(rate, now),
+            ceil: bucket(ceil, now),
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+fn bucket(bytes_per_sec: u64, now: Instant) -> TokenBucket {
+    TokenBucket::with_burst_time(bytes_per_sec, BURST_TIME, MIN_BURST_BYTES, now)
+}
+
+/// One shaper per shaped link, plus one for every other link when a QoS
+/// rule reserves `min_bandwidth` or sets a `bandwidth_limit`. Each queue holds up to `queue_limit`
+/// packets.
+pub fn build_shapers<P>(
+    shaping: &[LinkShaping],
+    links: &[LinkConfig],
+    rules: &[QosRule],
+    queue_limit: usize,
+    now: Instant,
+) -> Result<HashMap<String, HierarchicalTokenBucket<P>>> {
+    let mut shapers = HashMap::new();
+    for link_shaping in shaping {
+        link_shaping.validate(links)?;
+        if shapers.insert(link_shaping.link.clone(), HierarchicalTokenBucket::new(link_shaping, queue_limit, now)).is_some() {
+            anyhow::bail!("Link {} is shaped more than once", link_shaping.link);
+        }
+    }
+
+    let reservations: Vec<(&str, u64)> = rules.iter()
+        .filter_map(|rule| rule.action.min_bandwidth.map(|bps| (rule.name.as_str(), bps / 8)))
+        .collect();
+    if !reservations.is_empty() {
+        for link in links {
+            let htb = shapers.entry(link.name.clone())
+                .or_insert_with(|| HierarchicalTokenBucket::unshaped(link.max_bandwidth / 8, queue_limit, now));
+            for (class, bytes_per_sec) in &reservations {
+                htb.reserve(class, *bytes_per_sec, now);
+            }
+        }
+    }
+
+    let limits: Vec<(&str, u64)> = rules.iter()
+        .filter_map(|rule| rule.action.bandwidth_limit.map(|limit| (rule.name.as_str(), limit.bytes_per_sec())))
+        .collect();
+    if !limits.is_empty() {
+        for link in links {
+            let htb = shapers.entry(link.name.clone())
+                .or_insert_with(|| HierarchicalTokenBucket::unshaped(link.max_bandwidth / 8, queue_limit, now));
+            for (class, bytes_per_sec) in &limits {
+                htb.limit(class, *bytes_per_sec, now);
+            }
+        }
+    }
+    Ok(shapers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ShapingClass;
+
+    const PACKET: usize = 1000;
+    const QUEUE_LIMIT: usize = 8;
+
+    fn shaping() -> LinkShaping {
+        // Rates in bytes/sec: the link has 100k, bulk 20k guaranteed up to
+        // 80k, interactive 20k guaranteed up to 40k
+        let class = |name: &str, rate: &str, ceil: &str| ShapingClass {
+            name: name.to_string(),
+            rate: rate.parse().unwrap(),
+            ceil: Some(ceil.parse().unwrap()),
+        };
+        LinkShaping {
+            link: "eth0".to_string(),
+            rate: "800Kbps".parse().unwrap(),
+            classes: vec![class("bulk", "160Kbps", "640Kbps"), class("interactive", "160Kbps", "320Kbps")],
+        }
+    }
+
+    /// Keeps `classes` backlogged for `seconds`, returning the bytes/sec
+    /// each got. Packets are tagged with their class's index.
+    fn saturate(htb: &mut HierarchicalTokenBucket<usize>, classes: &[&str], start: Instant, seconds: u64) -> Vec<f64> {
+        let mut sent = vec![0usize; classes.len()];
+        for ms in 0..seconds * 1000 {
+            let now = start + Duration::from_millis(ms);
+            for (index, class) in classes.iter().enumerate() {
+                while htb.enqueue(Some(class), PACKET, index).is_ok() {}
+            }
+            while let Some(index) = htb.dequeue(now) {
+                sent[index] += PACKET;
+            }
+        }
+        sent.iter().map(|bytes| *bytes as f64 / seconds as f64).collect()
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() <= expected * 0.05, "got {} bytes/sec, expected {}", actual, expected);
+    }
+
+    #[test]
+    fn test_idle_class_bandwidth_borrowed_up_to_ceiling() {
+        let start = Instant::now();
+        let mut htb = HierarchicalTokenBucket::new(&shaping(), QUEUE_LIMIT, start);
+
+        // Alone, bulk borrows well past its 20k guarantee but stops at its
+        // 80k ceiling even though the link has 100k
+        let rates = saturate(&mut htb, &["bulk"], start, 10);
+        assert_near(rates[0], 80_000.0);
+    }
+
+    #[test]
+    fn test_guarantees_hold_when_classes_compete() {
+        let start = Instant::now();
+        let mut htb = HierarchicalTokenBucket::new(&shaping(), QUEUE_LIMIT, start);
+
+        // Together they fill the link; interactive still gets at least its
+        // guarantee however hard bulk pushes
+        let rates = saturate(&mut htb, &["bulk", "interactive"], start, 10);
+        assert_near(rates[0] + rates[1], 100_000.0);
+        assert!(rates[1] >= 20_000.0 * 0.95, "interactive got {}", rates[1]);
+        assert!(rates[0] <= 80_000.0 * 1.05 && rates[1] <= 40_000.0 * 1.05);
+    }
+
+    #[test]
+    fn test_excess_waits_in_queue_until_budget_refills() {
+        let start = Instant::now();
+        let mut htb = HierarchicalTokenBucket::new(&shaping(), QUEUE_LIMIT, start);
+
+        // Interactive's 40k ceiling holds 4k of burst: four packets go, the
+        // rest wait rather than being dropped
+        for id in 0..QUEUE_LIMIT {
+            htb.enqueue(Some("interactive"), PACKET, id).unwrap();
+        }
+        let sent: Vec<usize> = std::iter::from_fn(|| htb.dequeue(start)).collect();
+        assert_eq!(sent, vec![0, 1, 2, 3]);
+        assert_eq!(htb.queued(), 4);
+
+        // One packet's worth refills in 25ms at 40k
+        let wait = htb.next_release_in().unwrap();
+        assert!(wait > Duration::from_millis(20) && wait <= Duration::from_millis(25), "wait was {:?}", wait);
+        let later = start + wait + Duration::from_micros(1);
+        assert_eq!(htb.dequeue(later), Some(4));
+        assert_eq!(htb.dequeue(later), None);
+
+        // Only a full queue drops
+        for id in 0..5 {
+            htb.enqueue(Some("interactive"), PACKET, 10 + id).unwrap();
+        }
+        assert!(htb.enqueue(Some("interactive"), PACKET, 99).is_err());
+    }
+
+    #[test]
+    fn test_unshaped_class_passes() {
+        let start = Instant::now();
+        let mut htb = HierarchicalTokenBucket::new(&shaping(), QUEUE_LIMIT, start);
+        for id in 0..1000 {
+            htb.enqueue(Some("voip"), PACKET, id).unwrap();
+            assert_eq!(htb.dequeue(start), Some(id));
+        }
+        assert_eq!(htb.next_release_in(), None);
+    }
+
+    #[test]
+    fn test_invalid_shaping_rejected() {
+        let links = [crate::test_utils::link_config("eth0", None)];
+        let now = Instant::now();
+        let build = |shaping: &[LinkShaping]| build_shapers::<()>(shaping, &links, &[], QUEUE_LIMIT, now);
+        assert!(build(&[shaping()]).is_ok());
+        assert!(build(&[shaping(), shaping()]).is_err());
+
+        let mut unknown_link = shaping();
+        unknown_link.link = "eth9".to_string();
+        assert!(build(&[unknown_link]).is_err());
+
+        let mut oversubscribed = shaping();
+        oversubscribed.classes[0].rate = "700Kbps".parse().unwrap();
+        assert!(build(&[oversubscribed]).is_err());
+
+        let mut ceil_below_rate = shaping();
+        ceil_below_rate.classes[1].ceil = Some("100Kbps".parse().unwrap());
+        assert!(build(&[ceil_below_rate]).is_err());
+    }
+
+    fn reserving_rule(name: &str, min_bandwidth: &str) -> QosRule {
+        serde_yaml::from_str(&format!(
+            "name: {}\npriority: 7\nmatch_criteria: {{}}\naction:\n  link_preference: []\n  min_bandwidth: {}\n",
+            name, min_bandwidth
+        )).unwrap()
+    }
+
+    #[test]
+    fn test_reserved_class_gets_its_floor_under_saturation() {
+        // An 800Kbps (100k bytes/sec) link with no explicit shaping; voip
+        // reserves 240Kbps (30k bytes/sec)
+        let mut link = crate::test_utils::link_config("eth0", None);
+        link.max_bandwidth = 800_000;
+        let start = Instant::now();
+        let mut shapers = build_shapers(&[], &[link], &[reserving_rule("voip", "240Kbps")], QUEUE_LIMIT, start).unwrap();
+        let htb = shapers.get_mut("eth0").unwrap();
+
+        // Bulk, which no rule reserves for, is offered traffic first every
+        // millisecond and would take the whole link on its own
+        let rates = saturate(htb, &["bulk", "voip"], start, 10);
+        assert!(rates[1] >= 30_000.0 * 0.95, "voip got {}", rates[1]);
+        assert_near(rates[0] + rates[1], 100_000.0);
+    }
+
+    #[test]
+    fn test_unclassified_traffic_waits_its_turn_beside_reservations() {
+        let mut link = crate::test_utils::link_config("eth0", None);
+        link.max_bandwidth = 800_000;
+        let start = Instant::now();
+        let mut shapers = build_shapers(&[], &[link], &[reserving_rule("voip", "240Kbps")], QUEUE_LIMIT, start).unwrap();
+        let htb = shapers.get_mut("eth0").unwrap();
+
+        // Past the link's 10-packet burst unclassified traffic is held, not
+        // dropped, and goes once the link's budget refills
+        let mut sent = 0;
+        for batch in [0..8, 8..16] {
+            for id in batch {
+                htb.enqueue(None, PACKET, id).unwrap();
+            }
+            sent += std::iter::from_fn(|| htb.dequeue(start)).count();
+        }
+        assert_eq!(sent, 10);
+        assert_eq!(htb.queued(), 6);
+        let later = start + Duration::from_millis(60);
+        assert_eq!(std::iter::from_fn(|| htb.dequeue(later)).count(), 6);
+    }
+
+    #[test]
+    fn test_bandwidth_limit_caps_class_on_every_link() {
+        let mut rule = reserving_rule("bulk", "8Kbps");
+        rule.action.min_bandwidth = None;
+        rule.action.bandwidth_limit = Some("80Kbps".parse().unwrap());
+        let links = [crate::test_utils::link_config("eth0", None), crate::test_utils::link_config("eth1", None)];
+        let start = Instant::now();
+        let mut shapers = build_shapers(&[], &links, &[rule], QUEUE_LIMIT, start).unwrap();
+        assert_eq!(shapers.len(), 2);
+        let htb = shapers.get_mut("eth1").unwrap();
+
+        // 10k bytes/sec: a 1514-byte burst, then 1000 bytes every 100ms,
+        // while unclassified traffic isn't held back
+        for id in 0..4 {
+            htb.enqueue(Some("bulk"), PACKET, id).unwrap();
+        }
+        htb.enqueue(None, PACKET, 10).unwrap();
+        assert_eq!(std::iter::from_fn(|| htb.dequeue(start)).collect::<Vec<_>>(), vec![0, 10]);
+        let later = start + Duration::from_millis(100);
+        assert_eq!(std::iter::from_fn(|| htb.dequeue(later)).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(htb.queued(), 2);
+    }
+
+    #[test]
+    fn test_bandwidth_limit_lowers_shaped_class_ceil() {
+        let mut rule = reserving_rule("bulk", "8Kbps");
+        rule.action.min_bandwidth = None;
+        rule.action.bandwidth_limit = Some("10000Bps".parse().unwrap());
+        let link = crate::test_utils::link_config("eth0", None);
+        let start = Instant::now();
+        let mut shapers = build_shapers(&[shaping()], &[link], &[rule], QUEUE_LIMIT, start).unwrap();
+        let htb = shapers.get_mut("eth0").unwrap();
+
+        // bulk's own ceil is 80k bytes/sec; the limit holds it to 10k
+        for id in 0..QUEUE_LIMIT {
+            htb.enqueue(Some("bulk"), PACKET, id).unwrap();
+        }
+        let later = start + Duration::from_millis(200);
+        let sent = std::iter::from_fn(|| htb.dequeue(start)).count() + std::iter::from_fn(|| htb.dequeue(later)).count();
+        assert!(sent <= 4, "sent {}", sent);
+    }
+
+    #[test]
+    fn test_queued_packets_keep_their_class_when_moved() {
+        let start = Instant::now();
+        let mut htb = HierarchicalTokenBucket::new(&shaping(), QUEUE_LIMIT, start);
+        htb.enqueue(Some("bulk"), PACKET, 1).unwrap();
+        htb.enqueue(None, 64, 2).unwrap();
+        assert_eq!(htb.into_queued(), vec![(Some("bulk".to_string()), PACKET, 1), (None, 64, 2)]);
+    }
+
+    #[test]
+    fn test_reservation_raises_shaped_class_rate() {
+        let start = Instant::now();
+        let mut htb = HierarchicalTokenBucket::new(&shaping(), QUEUE_LIMIT, start);
+        // Interactive is shaped at 20k..40k; reserving 32k lifts its
+        // guarantee and keeps the ceiling
+        htb.reserve("interactive", 32_000, start);
+        let rates = saturate(&mut htb, &["bulk", "interactive"], start, 10);
+        assert!(rates[1] >= 32_000.0 * 0.95, "interactive got {}", rates[1]);
+        assert!(rates[1] <= 40_000.0 * 1.05);
+        assert_near(rates[0] + rates[1], 100_000.0);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::config::Config;
+use anyhow::Result;
+use sdwan_common::init_config::{render_annotated, FieldDocs};
+
+const FIELD_DOCS: &FieldDocs = &[
+    ("scheduler", "packet scheduling"),
+    ("scheduler.algorithm", "weighted_round_robin, weighted_ecmp or mos"),
+    ("scheduler.batch_size", "packets a worker takes from the queue at once"),
+    ("scheduler.max_queue_size", "packets buffered before intake blocks"),
+    ("scheduler.metrics_interval", "ms between link metrics updates"),
+    ("scheduler.flow_affinity", "pin each flow to the link of its first packet"),
+    ("scheduler.flow_idle_timeout", "ms without traffic before a pinned flow is forgotten"),
+    ("scheduler.selection_hysteresis", "score margin needed to switch away from the current link"),
+    ("scheduler.shadow_algorithm", "optional, evaluated without routing; divergence is counted"),
+    ("scheduler.send_retries", "alternate links to try when a send fails"),
+    ("scheduler.workers", "parallel scheduling workers; idle ones steal queued packets"),
+    ("scheduler.metrics_channel_capacity", "metrics reports buffered from the underlay manager"),
+    ("scheduler.packet_channel_capacity", "optional, each link's scheduled-packet output queue; defaults to max_queue_size"),
+    ("scheduler.overflow_policy", "block, drop_oldest or drop_newest when a channel is full"),
+    ("scheduler.queue_depth_penalty", "0-1, how much a filling output queue lowers its link's score"),
+    ("scheduler.rng_seed", "optional, makes randomized picks (weighted_ecmp, tiebreaks) reproducible"),
+    ("scheduler.reorder_window_min_ms", "lower bound on the reorder window, which follows the latency spread of active links"),
+    ("scheduler.reorder_window_max_ms", "upper bound on the reorder window"),
+    ("scheduler.load_shedding", "optional, shed low-priority traffic under load: high_watermark, low_watermark, shed_priority"),
+    ("scheduler.grpc_timeout_ms", "deadline for each metrics request; last-known metrics are kept on timeout"),
+    ("scheduler.idle_link_after", "ms a link goes unselected before the underlay manager probes it less often"),
+    ("scheduler.destination_metrics_ttl", "ms per-destination metrics stay in use after they were measured"),
+    ("qos", "traffic classification"),
+    ("qos.rules", "matched in order; see docs/configuration.md for the rule format"),
+    ("qos.default_priority", "priority of packets nothing else classifies"),
+    ("qos.rules_file", "optional, YAML list of rules merged after inline rules"),
+    ("qos.protocol_defaults", "priority for unmatched packets by protocol, e.g. ICMP: 6"),
+    ("qos.dscp_priority_map", "priority for unmatched packets by DSCP class or codepoint (RFC 4594)"),
+    ("qos.trust_dscp_from", "optional, CIDRs whose DSCP markings are honored; others are treated as unmarked"),
+    ("links", "overlay links; each needs name, interface, weight, max_bandwidth and min_latency; failover_tier ranks it within its failover_group, 0 first; time_multipliers scale a link's score on a schedule; mtu fragments larger tunnel frames; max_in_flight caps its unacknowledged packets, which stop counting after in_flight_timeout_ms"),
+    ("failover", "link health tracking"),
+    ("failover.enabled", "exclude unhealthy links from selection"),
+    ("failover.health_check_interval", "ms between health checks"),
+    ("failover.failover_threshold", "consecutive unhealthy samples before a link is excluded"),
+    ("failover.recovery_threshold", "consecutive healthy samples before it is used again"),
+    ("failover.health_threshold", "minimum health score for a healthy sample"),
+    ("failover.anomaly_factor", "latency/loss growth that marks a link degraded"),
+    ("failover.anomaly_window", "samples the latest one is compared against"),
+    ("failover.make_before_break", "active/backup: duplicate onto the backup before cutting over"),
+    ("failover.warmup_ms", "ms over which a recovered link ramps up to full traffic; 0 disables"),
+    ("failover.recovery_cooldown_ms", "ms after recovery during which a link's score is penalized; 0 disables"),
+    ("failover.recovery_cooldown_penalty", "score factor (0.0-1.0) applied during the recovery cooldown"),
+    ("failover.tiers", "per-tier failover_threshold and recovery_threshold overrides"),
+    ("tunnel", "optional, tunnel encryption (encrypt and psk); reassembly_timeout_ms (unset follows the reorder window) and reassembly_window bound how long and how many frames back the receiver waits for missing fragments"),
+    ("ipfix", "optional, flow record export: collector, idle_timeout_ms, active_timeout_ms"),
+    ("policy_routes", "source subnets pinned to a link: source (CIDR), link, on_link_down (fallback or drop)"),
+    ("shaping", "per-link egress budgets: link, rate, and classes (QoS rule name, guaranteed rate, optional ceil to borrow up to)"),
+    ("admin", "optional, mTLS admin API: listen, cert_file, key_file, client_ca_file, roles (identity CN/SAN, role read_only or operator)"),
+];
+
+/// Renders `Config::default()` as YAML with a comment on every field, as a
+/// starting point for a new configuration file.
+pub fn starter_config() -> Result<String> {
+    render_annotated(&Config::default(), "Packet scheduler configuration", FIELD_DOCS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdwan_common::init_config::undocumented_fields;
+
+    #[test]
+    fn test_starter_config_parses_back() {
+        let yaml = starter_config().unwrap();
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        config.qos.validate().unwrap();
+        assert_eq!(serde_yaml::to_value(&config).unwrap(), serde_yaml::to_value(Config::default()).unwrap());
+
+        assert!(yaml.contains("  workers: 1  # parallel scheduling workers"));
+        assert!(yaml.contains("    EF: 6\n"));
+    }
+
+    #[test]
+    fn test_every_field_is_documented() {
+        let mut missing = undocumented_fields(&Config::default(), FIELD_DOCS).unwrap();
+        // Only the entries of free-form tables go undocumented
+        missing.retain(|field| !field.starts_with("qos.dscp_priority_map."));
+        assert!(missing.is_empty(), "undocumented fields: {:?}", missing);
+    }
+}
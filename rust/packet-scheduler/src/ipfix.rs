@@ -0,0 +1,440 @@
+use crate::config::IpfixConfig;
+use crate::protocol::Protocol;
+use crate::scheduler::ScheduledPacket;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+const VARIABLE_LENGTH: u16 = 65535;
+const MESSAGE_HEADER_LEN: usize = 16;
+const SET_HEADER_LEN: usize = 4;
+/// Keeps messages inside a single unfragmented datagram on common paths.
+const MAX_MESSAGE_BYTES: usize = 1400;
+
+/// (information element id, length) per field, in record order. The address
+/// fields are the only difference between the IPv4 and IPv6 templates.
+const IPV4_FIELDS: [(u16, u16); 10] = [
+    (8, 4),                 // sourceIPv4Address
+    (12, 4),                // destinationIPv4Address
+    (7, 2),                 // sourceTransportPort
+    (11, 2),                // destinationTransportPort
+    (4, 1),                 // protocolIdentifier
+    (1, 8),                 // octetDeltaCount
+    (2, 8),                 // packetDeltaCount
+    (152, 8),               // flowStartMilliseconds
+    (153, 8),               // flowEndMilliseconds
+    (82, VARIABLE_LENGTH),  // interfaceName, carrying the link name
+];
+const IPV6_FIELDS: [(u16, u16); 10] = [
+    (27, 16),               // sourceIPv6Address
+    (28, 16),               // destinationIPv6Address
+    (7, 2),
+    (11, 2),
+    (4, 1),
+    (1, 8),
+    (2, 8),
+    (152, 8),
+    (153, 8),
+    (82, VARIABLE_LENGTH),
+];
+
+/// Aggregated traffic of one flow on one link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowRecord {
+    pub source_ip: IpAddr,
+    pub dest_ip: IpAddr,
+    pub source_port: u16,
+    pub dest_port: u16,
+    pub protocol: Protocol,
+    pub link_name: String,
+    pub octets: u64,
+    pub packets: u64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RecordKey {
+    source_ip: IpAddr,
+    dest_ip: IpAddr,
+    source_port: u16,
+    dest_port: u16,
+    protocol: Protocol,
+    link_name: String,
+}
+
+impl FlowRecord {
+    fn key(&self) -> RecordKey {
+        RecordKey {
+            source_ip: self.source_ip,
+            dest_ip: self.dest_ip,
+            source_port: self.source_port,
+            dest_port: self.dest_port,
+            protocol: self.protocol.clone(),
+            link_name: self.link_name.clone(),
+        }
+    }
+
+    /// Records with an IPv6 endpoint use the IPv6 template, with any IPv4
+    /// endpoint in IPv4-mapped form.
+    fn is_ipv6(&self) -> bool {
+        self.source_ip.is_ipv6() || self.dest_ip.is_ipv6()
+    }
+
+    fn link_name_bytes(&self) -> &[u8] {
+        let name = self.link_name.as_bytes();
+        &name[..name.len().min(254)]
+    }
+
+    fn encoded_len(&self) -> usize {
+        let addresses = if self.is_ipv6() { 32 } else { 8 };
+        addresses + 2 + 2 + 1 + 8 + 8 + 8 + 8 + 1 + self.link_name_bytes().len()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        if self.is_ipv6() {
+            buf.extend_from_slice(&ipv6_octets(self.source_ip));
+            buf.extend_from_slice(&ipv6_octets(self.dest_ip));
+        } else {
+            buf.extend_from_slice(&ipv4_octets(self.source_ip));
+            buf.extend_from_slice(&ipv4_octets(self.dest_ip));
+        }
+        buf.extend_from_slice(&self.source_port.to_be_bytes());
+        buf.extend_from_slice(&self.dest_port.to_be_bytes());
+        buf.push(self.protocol.number());
+        buf.extend_from_slice(&self.octets.to_be_bytes());
+        buf.extend_from_slice(&self.packets.to_be_bytes());
+        buf.extend_from_slice(&(self.start.timestamp_millis() as u64).to_be_bytes());
+        buf.extend_from_slice(&(self.end.timestamp_millis() as u64).to_be_bytes());
+        let name = self.link_name_bytes();
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(name);
+    }
+}
+
+fn ipv4_octets(addr: IpAddr) -> [u8; 4] {
+    match addr {
+        IpAddr::V4(addr) => addr.octets(),
+        IpAddr::V6(_) => unreachable!("IPv6 records use the IPv6 template"),
+    }
+}
+
+fn ipv6_octets(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
+/// Aggregates scheduled packets into per-flow records (5-tuple plus the
+/// selected link) and exports them as IPFIX (RFC 7011) over UDP. A record is
+/// exported once its flow has been idle for `idle_timeout`, or has been
+/// active for `active_timeout`, after which later packets start a new one.
+/// Every message carries the templates, so collectors can decode it alone.
+pub struct IpfixExporter {
+    socket: UdpSocket,
+    records: HashMap<RecordKey, FlowRecord>,
+    idle_timeout: Duration,
+    active_timeout: Duration,
+    observation_domain_id: u32,
+    /// Data records exported so far, as carried in the message header.
+    sequence_number: u32,
+}
+
+impl IpfixExporter {
+    pub fn new(config: &IpfixConfig) -> Result<Self> {
+        let collector = config.collector.to_socket_addrs()
+            .with_context(|| format!("Invalid IPFIX collector address {}", config.collector))?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("IPFIX collector {} did not resolve", config.collector))?;
+        let local: SocketAddr = if collector.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse()?;
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(collector)
+            .with_context(|| format!("Failed to connect to IPFIX collector {}", collector))?;
+
+        Ok(Self {
+            socket,
+            records: HashMap::new(),
+            idle_timeout: Duration::milliseconds(config.idle_timeout_ms as i64),
+            active_timeout: Duration::milliseconds(config.active_timeout_ms as i64),
+            observation_domain_id: config.observation_domain_id,
+            sequence_number: 0,
+        })
+    }
+
+    /// Adds a scheduled packet to its flow's record.
+    pub fn record(&mut self, scheduled: &ScheduledPacket) -> Result<()> {
+        let packet = &scheduled.packet;
+        let record = FlowRecord {
+            source_ip: packet.source_ip.parse()
+                .with_context(|| format!("Invalid source address {}", packet.source_ip))?,
+            dest_ip: packet.dest_ip.parse()
+                .with_context(|| format!("Invalid destination address {}", packet.dest_ip))?,
+            source_port: packet.source_port.unwrap_or(0),
+            dest_port: packet.dest_port.unwrap_or(0),
+            protocol: packet.protocol.clone(),
+            link_name: scheduled.link_name.clone(),
+            octets: packet.data.len() as u64,
+            packets: 1,
+            start: packet.timestamp,
+            end: packet.timestamp,
+        };
+
+        self.records.entry(record.key())
+            .and_modify(|existing| {
+                existing.octets += record.octets;
+                existing.packets += 1;
+                existing.start = existing.start.min(record.start);
+                existing.end = existing.end.max(record.end);
+            })
+            .or_insert(record);
+
+        Ok(())
+    }
+
+    /// Exports records whose flow hit the idle or active timeout as of
+    /// `now`, returning how many were exported.
+    pub fn flush_expired(&mut self, now: DateTime<Utc>) -> Result<usize> {
+        let expired: Vec<RecordKey> = self.records.iter()
+            .filter(|(_, record)| now - record.end >= self.idle_timeout || now - record.start >= self.active_timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let records = expired.iter().filter_map(|key| self.records.remove(key)).collect();
+        self.export(records)
+    }
+
+    /// Exports every pending record, e.g. on shutdown.
+    pub fn flush(&mut self) -> Result<usize> {
+        let records = self.records.drain().map(|(_, record)| record).collect();
+        self.export(records)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.records.len()
+    }
+
+    fn export(&mut self, mut records: Vec<FlowRecord>) -> Result<usize> {
+        let count = records.len();
+        records.sort_by_key(|record| record.start);
+
+        let overhead = MESSAGE_HEADER_LEN + template_set_len() + 2 * SET_HEADER_LEN;
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        for record in records {
+            let size = record.encoded_len();
+            if !batch.is_empty() && overhead + batch_bytes + size > MAX_MESSAGE_BYTES {
+                self.send(&batch)?;
+                batch.clear();
+                batch_bytes = 0;
+            }
+            batch_bytes += size;
+            batch.push(record);
+        }
+        if !batch.is_empty() {
+            self.send(&batch)?;
+        }
+
+        Ok(count)
+    }
+
+    fn send(&mut self, records: &[FlowRecord]) -> Result<()> {
+        let message = encode_message(records, Utc::now(), self.sequence_number, self.observation_domain_id);
+        self.socket.send(&message).context("Failed to send IPFIX message")?;
+        self.sequence_number = self.sequence_number.wrapping_add(records.len() as u32);
+        Ok(())
+    }
+}
+
+fn template_set_len() -> usize {
+    SET_HEADER_LEN + 2 * (4 + IPV4_FIELDS.len() * 4)
+}
+
+fn encode_message(records: &[FlowRecord], export_time: DateTime<Utc>, sequence_number: u32, observation_domain_id: u32) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MAX_MESSAGE_BYTES);
+    buf.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]); // length, filled in below
+    buf.extend_from_slice(&(export_time.timestamp() as u32).to_be_bytes());
+    buf.extend_from_slice(&sequence_number.to_be_bytes());
+    buf.extend_from_slice(&observation_domain_id.to_be_bytes());
+
+    let start = begin_set(&mut buf, TEMPLATE_SET_ID);
+    for (template_id, fields) in [(IPV4_TEMPLATE_ID, &IPV4_FIELDS), (IPV6_TEMPLATE_ID, &IPV6_FIELDS)] {
+        buf.extend_from_slice(&template_id.to_be_bytes());
+        buf.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (element, length) in fields {
+            buf.extend_from_slice(&element.to_be_bytes());
+            buf.extend_from_slice(&length.to_be_bytes());
+        }
+    }
+    end_set(&mut buf, start);
+
+    for (template_id, ipv6) in [(IPV4_TEMPLATE_ID, false), (IPV6_TEMPLATE_ID, true)] {
+        let mut family = records.iter().filter(|record| record.is_ipv6() == ipv6).peekable();
+        if family.peek().is_none() {
+            continue;
+        }
+        let start = begin_set(&mut buf, template_id);
+        for record in family {
+            record.encode(&mut buf);
+        }
+        end_set(&mut buf, start);
+    }
+
+    let length = buf.len() as u16;
+    buf[2..4].copy_from_slice(&length.to_be_bytes());
+    buf
+}
+
+fn begin_set(buf: &mut Vec<u8>, set_id: u16) -> usize {
+    let start = buf.len();
+    buf.extend_from_slice(&set_id.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    start
+}
+
+fn end_set(buf: &mut [u8], start: usize) {
+    let length = (buf.len() - start) as u16;
+    buf[start + 2..start + 4].copy_from_slice(&length.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Packet;
+    use std::net::Ipv4Addr;
+
+    fn scheduled(source_port: u16, bytes: usize, timestamp: DateTime<Utc>) -> ScheduledPacket {
+        ScheduledPacket {
+            packet: Packet {
+                id: 1,
+                data: vec![0u8; bytes],
+                priority: 5,
+                source_ip: "192.168.1.10".to_string(),
+                dest_ip: "10.0.0.1".to_string(),
+                protocol: Protocol::Udp,
+                source_port: Some(source_port),
+                dest_port: Some(5060),
+                dscp: None,
+                icmp_type: None,
+                icmp_code: None,
+                vlan_id: None,
+                inner_vlan_id: None,
+                timestamp,
+            },
+            link_name: "eth0".to_string(),
+            sequence_number: 1,
+        }
+    }
+
+    fn collector() -> (UdpSocket, IpfixConfig) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+        let config = IpfixConfig {
+            collector: socket.local_addr().unwrap().to_string(),
+            idle_timeout_ms: 1000,
+            active_timeout_ms: 10000,
+            observation_domain_id: 7,
+        };
+        (socket, config)
+    }
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_be_bytes(buf[at..at + 2].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], at: usize) -> u64 {
+        u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+    }
+
+    /// Returns the message's sequence number and the raw IPv4 data records.
+    fn ipv4_records(message: &[u8]) -> (u32, Vec<Vec<u8>>) {
+        assert_eq!(u16_at(message, 0), IPFIX_VERSION);
+        assert_eq!(u16_at(message, 2) as usize, message.len());
+        assert_eq!(u32::from_be_bytes(message[12..16].try_into().unwrap()), 7);
+        let sequence_number = u32::from_be_bytes(message[8..12].try_into().unwrap());
+
+        let mut records = Vec::new();
+        let mut at = MESSAGE_HEADER_LEN;
+        while at < message.len() {
+            let (set_id, length) = (u16_at(message, at), u16_at(message, at + 2) as usize);
+            if set_id == IPV4_TEMPLATE_ID {
+                let mut offset = at + SET_HEADER_LEN;
+                while offset < at + length {
+                    let name_len = message[offset + 45] as usize;
+                    let end = offset + 46 + name_len;
+                    records.push(message[offset..end].to_vec());
+                    offset = end;
+                }
+            }
+            at += length;
+        }
+        (sequence_number, records)
+    }
+
+    #[test]
+    fn test_record_exported_on_idle_timeout() {
+        let (collector, config) = collector();
+        let mut exporter = IpfixExporter::new(&config).unwrap();
+        let start = Utc::now();
+        for (offset, bytes) in [(0, 100), (200, 300), (400, 50)] {
+            exporter.record(&scheduled(40000, bytes, start + Duration::milliseconds(offset))).unwrap();
+        }
+        let end = start + Duration::milliseconds(400);
+
+        // Still active within the idle timeout
+        assert_eq!(exporter.flush_expired(end + Duration::milliseconds(999)).unwrap(), 0);
+        assert_eq!(exporter.flush_expired(end + Duration::milliseconds(1000)).unwrap(), 1);
+        assert_eq!(exporter.pending(), 0);
+
+        let mut message = [0u8; 1500];
+        let len = collector.recv(&mut message).unwrap();
+        let (sequence_number, records) = ipv4_records(&message[..len]);
+        assert_eq!(sequence_number, 0);
+        assert_eq!(records.len(), 1);
+
+        let record = &records[0];
+        assert_eq!(record[0..4], Ipv4Addr::new(192, 168, 1, 10).octets());
+        assert_eq!(record[4..8], Ipv4Addr::new(10, 0, 0, 1).octets());
+        assert_eq!(u16_at(record, 8), 40000);
+        assert_eq!(u16_at(record, 10), 5060);
+        assert_eq!(record[12], 17);
+        assert_eq!(u64_at(record, 13), 450);
+        assert_eq!(u64_at(record, 21), 3);
+        assert_eq!(u64_at(record, 29), start.timestamp_millis() as u64);
+        assert_eq!(u64_at(record, 37), end.timestamp_millis() as u64);
+        assert_eq!(&record[46..], b"eth0");
+    }
+
+    #[test]
+    fn test_active_timeout_splits_long_flows() {
+        let (collector, config) = collector();
+        let mut exporter = IpfixExporter::new(&config).unwrap();
+        let start = Utc::now();
+        // A busy flow never idles, and a second flow on another port
+        for offset in (0..10000).step_by(500) {
+            exporter.record(&scheduled(40000, 100, start + Duration::milliseconds(offset))).unwrap();
+        }
+        exporter.record(&scheduled(40001, 100, start + Duration::milliseconds(9500))).unwrap();
+
+        assert_eq!(exporter.flush_expired(start + Duration::milliseconds(10000)).unwrap(), 1);
+        exporter.record(&scheduled(40000, 100, start + Duration::milliseconds(10000))).unwrap();
+        assert_eq!(exporter.pending(), 2);
+        assert_eq!(exporter.flush().unwrap(), 2);
+
+        let mut message = [0u8; 1500];
+        let len = collector.recv(&mut message).unwrap();
+        let (_, records) = ipv4_records(&message[..len]);
+        assert_eq!(u64_at(&records[0], 21), 20);
+
+        // The sequence number counts the records exported before
+        let len = collector.recv(&mut message).unwrap();
+        let (sequence_number, records) = ipv4_records(&message[..len]);
+        assert_eq!(sequence_number, 1);
+        assert_eq!(records.len(), 2);
+    }
+}
@@ -1,10 +1,42 @@
+pub mod admin;
+pub mod alerting;
+pub mod channel;
+pub mod cidr;
 pub mod config;
+#[cfg(feature = "encryption")]
+pub mod crypto;
+pub mod destination;
+pub mod events;
+pub mod failover;
+pub mod flow;
+pub mod htb;
+pub mod fragment;
+pub mod init_config;
+pub mod ipfix;
+pub mod load_shed;
 pub mod scheduler;
+pub mod services;
 pub mod qos;
 pub mod metrics;
+pub mod metrics_client;
+pub mod metrics_provider;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+pub mod packet_id;
+pub mod policy;
 pub mod proto;
+pub mod protocol;
+pub mod rate_limit;
+pub mod stats;
+pub mod transport;
+pub mod units;
+pub mod work_queue;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
+pub use sdwan_common::{clock, log_limit, runtime, supervisor};
 pub use config::Config;
 pub use scheduler::PacketScheduler;
 pub use config::QosRule;
-pub use metrics::LinkMetrics; 
\ No newline at end of file
+pub use metrics::LinkMetrics;
+pub use protocol::Protocol; 
\ No newline at end of file
@@ -0,0 +1,98 @@
+use crate::config::LoadSheddingConfig;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoadMode {
+    Normal,
+    /// Links are picked by static weight instead of live scoring, and
+    /// low-priority packets are dropped.
+    Shedding,
+}
+
+/// Switches between `Normal` and `Shedding` on packet queue depth. Shedding
+/// starts above the high watermark and stops only at or below the low one,
+/// so depth hovering around a single threshold doesn't flap the mode.
+pub struct LoadShedder {
+    high_watermark: usize,
+    low_watermark: usize,
+    shed_priority: u8,
+    shedding: AtomicBool,
+}
+
+impl LoadShedder {
+    pub fn new(config: &LoadSheddingConfig) -> Result<Self> {
+        if config.low_watermark >= config.high_watermark {
+            return Err(anyhow::anyhow!(
+                "load_shedding.low_watermark {} must be below high_watermark {}",
+                config.low_watermark, config.high_watermark
+            ));
+        }
+
+        Ok(Self {
+            high_watermark: config.high_watermark,
+            low_watermark: config.low_watermark,
+            shed_priority: config.shed_priority,
+            shedding: AtomicBool::new(false),
+        })
+    }
+
+    /// Updates the mode for the current queue depth, returning the new mode
+    /// if it changed.
+    pub fn update(&self, depth: usize) -> Option<LoadMode> {
+        let shedding = self.shedding.load(Ordering::Relaxed);
+        let target = if shedding { depth > self.low_watermark } else { depth > self.high_watermark };
+        if target == shedding {
+            return None;
+        }
+
+        // Another worker may have made the same transition first
+        self.shedding.compare_exchange(shedding, target, Ordering::Relaxed, Ordering::Relaxed)
+            .ok()
+            .map(|_| self.mode())
+    }
+
+    pub fn mode(&self) -> LoadMode {
+        if self.shedding.load(Ordering::Relaxed) { LoadMode::Shedding } else { LoadMode::Normal }
+    }
+
+    /// Whether a packet of `priority` is dropped in the current mode.
+    pub fn should_drop(&self, priority: u8) -> bool {
+        self.mode() == LoadMode::Shedding && priority <= self.shed_priority
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shedder() -> LoadShedder {
+        LoadShedder::new(&LoadSheddingConfig { high_watermark: 100, low_watermark: 20, shed_priority: 1 }).unwrap()
+    }
+
+    #[test]
+    fn test_mode_follows_watermarks() {
+        let shedder = shedder();
+        assert_eq!(shedder.update(100), None);
+        assert_eq!(shedder.mode(), LoadMode::Normal);
+        assert!(!shedder.should_drop(0));
+
+        assert_eq!(shedder.update(101), Some(LoadMode::Shedding));
+        assert!(shedder.should_drop(1));
+        assert!(!shedder.should_drop(2));
+
+        // Between the watermarks the mode holds either way
+        assert_eq!(shedder.update(50), None);
+        assert_eq!(shedder.mode(), LoadMode::Shedding);
+        assert_eq!(shedder.update(20), Some(LoadMode::Normal));
+        assert_eq!(shedder.update(50), None);
+        assert_eq!(shedder.mode(), LoadMode::Normal);
+    }
+
+    #[test]
+    fn test_watermarks_must_be_ordered() {
+        let config = LoadSheddingConfig { high_watermark: 20, low_watermark: 20, shed_priority: 1 };
+        assert!(LoadShedder::new(&config).is_err());
+    }
+}
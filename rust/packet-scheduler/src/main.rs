@@ -1,6 +1,15 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use packet_scheduler::admin::AdminServer;
 use packet_scheduler::scheduler::PacketScheduler;
 use packet_scheduler::config::Config;
+use packet_scheduler::init_config::starter_config;
+use packet_scheduler::metrics_client::UnderlayClient;
+use packet_scheduler::metrics_provider::ReplayMetricsProvider;
+use packet_scheduler::runtime::{build_runtime, RuntimeFlavor};
+use packet_scheduler::transport::{tunnel_peer, UdpTunnelTransport};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tracing::{info, error};
 
 #[derive(Parser)]
@@ -14,14 +23,50 @@ struct Args {
     #[arg(short, long, default_value = "info")]
     log_level: String,
 
+    /// Tokio runtime flavor; current-thread has the smallest footprint
+    #[arg(long, value_enum, default_value = "multi-thread")]
+    runtime: RuntimeFlavor,
+
+    /// Worker threads of the multi-threaded runtime [default: one per core];
+    /// rejected with --runtime current-thread
+    #[arg(long)]
+    worker_threads: Option<usize>,
+
     /// Underlay manager endpoint
     #[arg(long, default_value = "http://localhost:9093")]
     underlay_endpoint: String,
+
+    /// Take link metrics from this recording (one JSON report per line)
+    /// instead of the underlay manager
+    #[arg(long)]
+    metrics_replay: Option<String>,
+
+    /// Also write every scheduled packet to this rotating pcapng file
+    #[cfg(feature = "pcap")]
+    #[arg(long)]
+    pcap: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Subcommand)]
+enum Command {
+    /// Print a commented starter configuration with the default settings
+    InitConfig,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    build_runtime(args.runtime, args.worker_threads)?.block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+
+    if let Some(Command::InitConfig) = args.command {
+        print!("{}", starter_config()?);
+        return Ok(());
+    }
 
     // Initialize logging
     tracing_subscriber::fmt()
@@ -34,15 +79,94 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::from_file(&args.config)?;
     info!("Loaded configuration from {}", args.config);
 
+    // Find the tunnel's far end before the scheduler takes the config
+    let tunnel = match config.tunnel {
+        Some(ref tunnel) => {
+            let underlay = UnderlayClient::new(&args.underlay_endpoint, Duration::from_millis(config.scheduler.grpc_timeout_ms));
+            let peer = tunnel_peer(tunnel, &underlay, Duration::from_secs(5)).await?;
+            Some(Arc::new(UdpTunnelTransport::from_config(&config, peer).await?))
+        }
+        None => None,
+    };
+
+    let admin = config.admin.clone();
+
     // Create packet scheduler
-    let mut scheduler = PacketScheduler::new(config, args.underlay_endpoint).await?;
+    let scheduler = match args.metrics_replay {
+        Some(ref path) => {
+            let replay = ReplayMetricsProvider::from_file(path)?;
+            info!("Replaying link metrics from {}", path);
+            PacketScheduler::with_metrics_provider(config, Arc::new(replay)).await?
+        }
+        None => PacketScheduler::new(config, args.underlay_endpoint).await?,
+    };
+    let scheduler = match tunnel {
+        Some(tunnel) => {
+            let mut scheduler = scheduler;
+            scheduler.set_tunnel_transport(tunnel);
+            scheduler
+        }
+        None => scheduler,
+    };
+    #[cfg(feature = "pcap")]
+    let scheduler = {
+        let mut scheduler = scheduler;
+        if let Some(ref path) = args.pcap {
+            scheduler.set_pcap_exporter(packet_scheduler::pcap::PcapExporter::create(path)?);
+            info!("Writing scheduled packets to {}", path);
+        }
+        scheduler
+    };
+    let scheduler = Arc::new(scheduler);
     info!("Packet scheduler initialized");
 
-    // Start the scheduler
-    if let Err(e) = scheduler.run().await {
+    #[cfg(unix)]
+    tokio::spawn(dump_state_on_sigusr1(scheduler.clone()));
+
+    if let Some(ref admin) = admin {
+        let server = AdminServer::from_config(admin, scheduler.clone())?;
+        let listener = TcpListener::bind(&admin.listen).await?;
+        tokio::spawn(async move {
+            if let Err(e) = server.serve(listener).await {
+                error!("Admin API error: {}", e);
+            }
+        });
+    }
+
+    // Start the scheduler, stopping it cleanly on Ctrl-C
+    let (mut handle, shutdown) = scheduler.spawn();
+    let result = tokio::select! {
+        result = &mut handle => result?,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down packet scheduler");
+            shutdown.cancel();
+            handle.await?
+        }
+    };
+    if let Err(e) = result {
         error!("Scheduler error: {}", e);
         return Err(e.into());
     }
 
     Ok(())
-} 
\ No newline at end of file
+}
+
+/// Logs the scheduler's state as JSON on each SIGUSR1, for support bundles.
+#[cfg(unix)]
+async fn dump_state_on_sigusr1(scheduler: Arc<PacketScheduler>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Can't listen for SIGUSR1, state dumps disabled: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        match serde_json::to_string(&scheduler.debug_state()) {
+            Ok(state) => info!("Scheduler state: {}", state),
+            Err(e) => error!("Failed to serialize scheduler state: {}", e),
+        }
+    }
+}
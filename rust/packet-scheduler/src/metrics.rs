@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LinkMetrics {
@@ -8,6 +10,29 @@ pub struct LinkMetrics {
     pub packet_loss: f64,
     pub bandwidth_mbps: f64,
     pub timestamp: DateTime<Utc>,
+    /// Fraction of recent probe cycles in which the link was healthy.
+    #[serde(default = "default_reliability")]
+    pub reliability: f64,
+    /// How much latency rises while the link is saturated by the bandwidth
+    /// test. Large values make a link a poor fit for interactive traffic.
+    #[serde(default)]
+    pub bufferbloat_ms: f64,
+    /// Upload throughput, when the underlay manager measured directions
+    /// separately. Outgoing traffic is limited by this, not the download.
+    #[serde(default)]
+    pub bandwidth_up_mbps: Option<f64>,
+    /// Cleared when the underlay manager ran no bandwidth test for the link,
+    /// so a zero `bandwidth_mbps` means "unknown" rather than "no capacity".
+    #[serde(default = "default_bandwidth_measured")]
+    pub bandwidth_measured: bool,
+}
+
+fn default_reliability() -> f64 {
+    1.0
+}
+
+fn default_bandwidth_measured() -> bool {
+    true
 }
 
 impl LinkMetrics {
@@ -18,20 +43,88 @@ impl LinkMetrics {
             packet_loss: 0.0,
             bandwidth_mbps: 0.0,
             timestamp: Utc::now(),
+            reliability: default_reliability(),
+            bufferbloat_ms: 0.0,
+            bandwidth_up_mbps: None,
+            bandwidth_measured: default_bandwidth_measured(),
         }
     }
     
+    /// A link that can't carry traffic: its bandwidth probe came back empty or
+    /// every probe was lost. Low latency alone doesn't make such a link usable.
+    /// Bandwidth that was never measured doesn't count against the link.
+    pub fn is_down(&self) -> bool {
+        (self.bandwidth_measured && self.bandwidth_mbps <= 0.0) || self.packet_loss >= 1.0
+    }
+    
     pub fn health_score(&self) -> f64 {
+        if self.is_down() {
+            return 0.0;
+        }
+        
         let latency_score = 1.0 / (1.0 + self.latency_ms);
-        let bandwidth_score = (self.bandwidth_mbps / 1000.0).min(1.0);
         let loss_score = 1.0 - self.packet_loss;
         
-        (latency_score + bandwidth_score + loss_score) / 3.0
+        // A link that keeps flapping scores lower than a steady one with the
+        // same instantaneous measurements
+        (latency_score + self.bandwidth_score() + loss_score) / 3.0 * self.reliability
+    }
+    
+    /// Egress bandwidth relative to 1Gbps, capped at 1 so faster links
+    /// can't outweigh the latency and loss terms. Unmeasured bandwidth
+    /// scores halfway.
+    pub fn bandwidth_score(&self) -> f64 {
+        if !self.bandwidth_measured {
+            return 0.5;
+        }
+        (self.bandwidth_up_mbps.unwrap_or(self.bandwidth_mbps) / 1000.0).clamp(0.0, 1.0)
     }
     
     pub fn is_healthy(&self, threshold: f64) -> bool {
         self.health_score() >= threshold
     }
+    
+    /// Estimated voice quality on the 1 (bad) to 4.5 (best) MOS scale, from
+    /// a simplified ITU-T G.107 E-model: jitter counts double towards the
+    /// delay (it has to be absorbed by a jitter buffer) and each percent of
+    /// loss costs 2.5 R-factor points.
+    pub fn mos_score(&self) -> f64 {
+        if self.is_down() {
+            return 1.0;
+        }
+        
+        let effective_latency = self.latency_ms + 2.0 * self.jitter_ms + 10.0;
+        let delay_impairment = if effective_latency < 160.0 {
+            effective_latency / 40.0
+        } else {
+            (effective_latency - 120.0) / 10.0
+        };
+        let r_factor = (93.2 - delay_impairment - 2.5 * self.packet_loss * 100.0).clamp(0.0, 100.0);
+        // The polynomial dips slightly below 1 for R-factors under 6.5
+        let mos = 1.0 + 0.035 * r_factor + 7e-6 * r_factor * (r_factor - 60.0) * (100.0 - r_factor);
+        mos.clamp(1.0, 4.5)
+    }
+}
+
+impl Default for LinkMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How long a receiver should hold out-of-order packets: the latency spread
+/// between the fastest and slowest usable link, plus the worst jitter, kept
+/// within `[min, max]`. Down links are ignored.
+pub fn reorder_window(metrics: &HashMap<String, LinkMetrics>, min: Duration, max: Duration) -> Duration {
+    let active = || metrics.values().filter(|metric| !metric.is_down());
+    let fastest = active().map(|metric| metric.latency_ms).fold(f64::INFINITY, f64::min);
+    let slowest = active().map(|metric| metric.latency_ms).fold(0.0, f64::max);
+    let jitter = active().map(|metric| metric.jitter_ms).fold(0.0, f64::max);
+    
+    if !fastest.is_finite() {
+        return min;
+    }
+    Duration::from_secs_f64((slowest - fastest + jitter) / 1000.0).clamp(min, max.max(min))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,6 +142,12 @@ impl MetricsSnapshot {
     }
 }
 
+impl Default for MetricsSnapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -73,4 +172,116 @@ mod tests {
         
         assert!(metrics.is_healthy(0.5));
     }
+    
+    #[test]
+    fn test_zero_bandwidth_link_is_down() {
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 1.0;
+        metrics.bandwidth_mbps = 0.0;
+        
+        assert!(metrics.is_down());
+        assert_eq!(metrics.health_score(), 0.0);
+    }
+    
+    #[test]
+    fn test_unmeasured_bandwidth_link_is_up() {
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 1.0;
+        metrics.bandwidth_measured = false;
+        
+        assert!(!metrics.is_down());
+        assert_eq!(metrics.bandwidth_score(), 0.5);
+        assert!(metrics.health_score() > 0.0);
+    }
+    
+    #[test]
+    fn test_total_loss_link_is_down() {
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 1.0;
+        metrics.bandwidth_mbps = 500.0;
+        metrics.packet_loss = 1.0;
+        
+        assert!(metrics.is_down());
+        assert!(!metrics.is_healthy(0.01));
+    }
+    
+    #[test]
+    fn test_asymmetric_link_scored_by_upload() {
+        // 500 down / 20 up: egress is limited to the upload
+        let mut metrics = LinkMetrics::new();
+        metrics.bandwidth_mbps = 500.0;
+        assert_eq!(metrics.bandwidth_score(), 0.5);
+        
+        metrics.bandwidth_up_mbps = Some(20.0);
+        assert_eq!(metrics.bandwidth_score(), 0.02);
+    }
+    
+    fn links(latencies: &[f64]) -> HashMap<String, LinkMetrics> {
+        latencies.iter().enumerate().map(|(i, latency)| {
+            let mut metrics = LinkMetrics::new();
+            metrics.latency_ms = *latency;
+            metrics.jitter_ms = 2.0;
+            metrics.bandwidth_mbps = 100.0;
+            (format!("eth{}", i), metrics)
+        }).collect()
+    }
+    
+    #[test]
+    fn test_reorder_window_follows_latency_spread() {
+        let (min, max) = (Duration::from_millis(5), Duration::from_millis(100));
+        
+        let narrow = reorder_window(&links(&[10.0, 14.0]), min, max);
+        let wide = reorder_window(&links(&[10.0, 70.0]), min, max);
+        assert_eq!(narrow, Duration::from_millis(6));
+        assert_eq!(wide, Duration::from_millis(62));
+        
+        // Bounded on both ends
+        assert_eq!(reorder_window(&links(&[10.0, 10.0]), Duration::from_millis(5), max), min);
+        assert_eq!(reorder_window(&links(&[10.0, 600.0]), min, max), max);
+        assert_eq!(reorder_window(&HashMap::new(), min, max), min);
+    }
+    
+    #[test]
+    fn test_reorder_window_ignores_down_links() {
+        let mut metrics = links(&[10.0, 14.0, 300.0]);
+        metrics.get_mut("eth2").unwrap().packet_loss = 1.0;
+        
+        assert_eq!(reorder_window(&metrics, Duration::from_millis(5), Duration::from_millis(100)), Duration::from_millis(6));
+    }
+
+    fn voice_link(latency_ms: f64, jitter_ms: f64, packet_loss: f64) -> LinkMetrics {
+        LinkMetrics { latency_ms, jitter_ms, packet_loss, bandwidth_mbps: 100.0, ..LinkMetrics::new() }
+    }
+    
+    #[test]
+    fn test_mos_ranks_impairment_profiles() {
+        let fiber = voice_link(10.0, 1.0, 0.0);
+        let lte = voice_link(60.0, 15.0, 0.01);
+        let satellite = voice_link(600.0, 30.0, 0.005);
+        let lossy = voice_link(20.0, 5.0, 0.08);
+        
+        assert!(fiber.mos_score() > 4.3);
+        assert!(fiber.mos_score() > lte.mos_score());
+        assert!(lte.mos_score() > lossy.mos_score());
+        assert!(satellite.mos_score() < 3.0);
+        assert_eq!(voice_link(10.0, 1.0, 1.0).mos_score(), 1.0);
+    }
+    
+    #[test]
+    fn test_mos_falls_monotonically_with_each_impairment() {
+        let mut previous = [f64::INFINITY; 3];
+        for step in 0..100 {
+            let step = step as f64;
+            let scores = [
+                voice_link(step * 10.0, 2.0, 0.0).mos_score(),
+                voice_link(20.0, step * 2.0, 0.0).mos_score(),
+                voice_link(20.0, 2.0, step / 200.0).mos_score(),
+            ];
+            for (score, previous) in scores.iter().zip(&mut previous) {
+                assert!((1.0..=4.5).contains(score));
+                assert!(*score <= *previous);
+                *previous = *score;
+            }
+        }
+    }
 } 
\ No newline at end of file
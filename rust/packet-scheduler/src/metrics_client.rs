@@ -0,0 +1,298 @@
+use crate::log_limit::RateLimitedLogger;
+use crate::metrics_provider::MetricsProvider;
+use crate::proto::{
+    DestinationMetric, DestinationMetricsRequest, DestinationMetricsResponse, MetricsDiffRequest,
+    MetricsDiffResponse, PeerInfo, PeerListRequest, PeerListResponse, RpcReply, RpcRequest, SetIdleRequest,
+    SetIdleResponse,
+};
+use crate::LinkMetrics;
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+/// Where link metrics come from: the underlay manager's metrics service.
+#[async_trait]
+pub trait MetricsSource: Send + Sync {
+    async fn fetch(&self) -> Result<HashMap<String, LinkMetrics>>;
+}
+
+/// Client of the underlay manager's RPC listener. Each call opens a
+/// connection, sends one request line and reads the reply line.
+pub struct UnderlayClient {
+    /// `host:port`; an `http://` prefix on the configured endpoint is
+    /// ignored.
+    addr: String,
+    timeout: Duration,
+}
+
+impl UnderlayClient {
+    pub fn new(endpoint: &str, timeout: Duration) -> Self {
+        let addr = endpoint.trim_start_matches("http://").trim_end_matches('/');
+        Self {
+            addr: addr.to_string(),
+            timeout,
+        }
+    }
+
+    /// Interfaces whose metrics changed after `since_version`; 0 asks for
+    /// all of them.
+    pub async fn metrics_diff(&self, since_version: u64) -> Result<MetricsDiffResponse> {
+        self.call(RpcRequest::MetricsDiff(MetricsDiffRequest { since_version })).await
+    }
+
+    /// Peer managers the underlay manager's discovery has heard, sorted by
+    /// node ID.
+    pub async fn list_peers(&self) -> Result<Vec<PeerInfo>> {
+        let response: PeerListResponse = self.call(RpcRequest::ListPeers(PeerListRequest {})).await?;
+        Ok(response.peers)
+    }
+
+    /// Marks a link the scheduler isn't using as idle, or in use again.
+    pub async fn set_idle(&self, interface_name: &str, idle: bool) -> Result<()> {
+        let request = SetIdleRequest { interface_name: interface_name.to_string(), idle };
+        let _: SetIdleResponse = self.call(RpcRequest::SetIdle(request)).await?;
+        Ok(())
+    }
+
+    /// Every link's latest metrics towards each destination prefix the
+    /// underlay manager probes.
+    pub async fn destination_metrics(&self) -> Result<Vec<DestinationMetric>> {
+        let response: DestinationMetricsResponse = self.call(RpcRequest::DestinationMetrics(DestinationMetricsRequest {})).await?;
+        Ok(response.metrics)
+    }
+
+    async fn call<T: DeserializeOwned>(&self, request: RpcRequest) -> Result<T> {
+        let exchange = async {
+            let stream = TcpStream::connect(&self.addr).await?;
+            let (reader, mut writer) = stream.into_split();
+            let mut line = serde_json::to_vec(&request)?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+            let reply = BufReader::new(reader).lines().next_line().await?
+                .ok_or_else(|| anyhow::anyhow!("Underlay manager closed the connection"))?;
+            anyhow::Ok(serde_json::from_str::<RpcReply>(&reply)?)
+        };
+        let reply = tokio::time::timeout(self.timeout, exchange).await
+            .map_err(|_| anyhow::anyhow!("Underlay manager at {} didn't answer within {:?}", self.addr, self.timeout))??;
+        match reply {
+            RpcReply::Result(result) => Ok(serde_json::from_value(result)?),
+            RpcReply::Error(e) => Err(anyhow::anyhow!("Underlay manager error: {}", e)),
+        }
+    }
+}
+
+/// Link metrics from the underlay manager, fetched as diffs against the
+/// version last seen and merged into a locally held map.
+pub struct RemoteMetricsSource {
+    client: Arc<UnderlayClient>,
+    /// Version the local map is current as of, and the map.
+    state: Mutex<(u64, HashMap<String, LinkMetrics>)>,
+}
+
+impl RemoteMetricsSource {
+    pub fn new(client: Arc<UnderlayClient>) -> Self {
+        Self {
+            client,
+            state: Mutex::new((0, HashMap::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricsSource for RemoteMetricsSource {
+    async fn fetch(&self) -> Result<HashMap<String, LinkMetrics>> {
+        let since_version = self.state.lock().0;
+        let mut diff = self.client.metrics_diff(since_version).await?;
+        // A restarted manager counts versions from scratch again
+        let restarted = diff.version < since_version;
+        if restarted {
+            diff = self.client.metrics_diff(0).await?;
+        }
+        let mut state = self.state.lock();
+        if restarted {
+            state.1.clear();
+        }
+        state.0 = diff.apply(&mut state.1);
+        Ok(state.1.clone())
+    }
+}
+
+/// Fetches metrics with a per-call deadline, so a stalled underlay manager
+/// can't wedge the metrics loop. A fetch that fails or times out falls back
+/// to the last metrics received.
+pub struct MetricsClient {
+    source: Arc<dyn MetricsSource>,
+    timeout: Duration,
+    last_known: Mutex<Option<HashMap<String, LinkMetrics>>>,
+    log_limit: RateLimitedLogger,
+}
+
+impl MetricsClient {
+    pub fn new(source: Arc<dyn MetricsSource>, timeout: Duration) -> Self {
+        Self {
+            source,
+            timeout,
+            last_known: Mutex::new(None),
+            log_limit: RateLimitedLogger::new(Duration::from_secs(30)),
+        }
+    }
+
+    /// The latest metrics, or the last known ones if this fetch failed.
+    /// `None` until a fetch has succeeded.
+    pub async fn poll(&self) -> Option<HashMap<String, LinkMetrics>> {
+        match tokio::time::timeout(self.timeout, self.source.fetch()).await {
+            Ok(Ok(metrics)) => {
+                *self.last_known.lock() = Some(metrics.clone());
+                return Some(metrics);
+            }
+            Ok(Err(e)) => {
+                if let Some(suppressed) = self.log_limit.check("fetch failed") {
+                    warn!("Metrics fetch failed, keeping last-known metrics: {}{}", e, suppressed);
+                }
+            }
+            Err(_) => {
+                if let Some(suppressed) = self.log_limit.check("fetch timed out") {
+                    warn!("Metrics fetch timed out after {:?}, keeping last-known metrics{}", self.timeout, suppressed);
+                }
+            }
+        }
+        self.last_known.lock().clone()
+    }
+}
+
+#[async_trait]
+impl MetricsProvider for MetricsClient {
+    async fn latest(&self) -> HashMap<String, LinkMetrics> {
+        self.poll().await.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::MetricsResponse;
+    use chrono::Utc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    /// Answers immediately until told to stall.
+    struct SlowSource {
+        stalled: AtomicBool,
+    }
+
+    #[async_trait]
+    impl MetricsSource for SlowSource {
+        async fn fetch(&self) -> Result<HashMap<String, LinkMetrics>> {
+            if self.stalled.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+            let mut metrics = HashMap::new();
+            metrics.insert("eth0".to_string(), LinkMetrics::new());
+            Ok(metrics)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_fetch_times_out_to_last_known_metrics() {
+        let source = Arc::new(SlowSource { stalled: AtomicBool::new(true) });
+        let client = MetricsClient::new(source.clone(), Duration::from_millis(50));
+
+        // Nothing to fall back to yet
+        assert!(client.poll().await.is_none());
+
+        source.stalled.store(false, Ordering::Relaxed);
+        let fresh = client.poll().await.unwrap();
+
+        source.stalled.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        let fallback = client.poll().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(fallback["eth0"].timestamp, fresh["eth0"].timestamp);
+
+        // And the next fetch goes back to the source
+        source.stalled.store(false, Ordering::Relaxed);
+        assert!(client.poll().await.unwrap()["eth0"].timestamp > fresh["eth0"].timestamp);
+    }
+
+    fn diff(version: u64, interfaces: &[(&str, f64)]) -> MetricsDiffResponse {
+        let metrics = interfaces.iter()
+            .map(|(interface_name, latency_ms)| MetricsResponse {
+                interface_name: interface_name.to_string(),
+                latency_ms: *latency_ms,
+                jitter_ms: 1.0,
+                packet_loss: 0.0,
+                bandwidth_mbps: 100.0,
+                reliability: 1.0,
+                bufferbloat_ms: 0.0,
+                bandwidth_up_mbps: None,
+                bandwidth_measured: true,
+                timestamp: Utc::now().to_rfc3339(),
+            })
+            .collect();
+        MetricsDiffResponse { version, metrics, timestamp: Utc::now().to_rfc3339() }
+    }
+
+    /// Answers each connection's request with the next of `replies` and
+    /// returns the `since_version` of every request it got.
+    async fn fake_underlay(replies: Vec<MetricsDiffResponse>) -> (String, tokio::task::JoinHandle<Vec<u64>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for reply in replies {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let line = BufReader::new(reader).lines().next_line().await.unwrap().unwrap();
+                let RpcRequest::MetricsDiff(request) = serde_json::from_str(&line).unwrap() else {
+                    panic!("expected a metrics diff request: {}", line);
+                };
+                requests.push(request.since_version);
+                let reply = RpcReply::Result(serde_json::to_value(reply).unwrap());
+                writer.write_all(format!("{}\n", serde_json::to_string(&reply).unwrap()).as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        (endpoint, requests)
+    }
+
+    #[tokio::test]
+    async fn test_remote_metrics_applied_as_diffs() {
+        let (endpoint, requests) = fake_underlay(vec![
+            diff(2, &[("eth0", 10.0), ("eth1", 15.0)]),
+            diff(3, &[("eth1", 40.0)]),
+            // The manager restarted, so everything is fetched again
+            diff(1, &[]),
+            diff(1, &[("eth0", 12.0)]),
+        ]).await;
+        let source = RemoteMetricsSource::new(Arc::new(UnderlayClient::new(&endpoint, Duration::from_secs(1))));
+
+        source.fetch().await.unwrap();
+        let metrics = source.fetch().await.unwrap();
+        assert_eq!((metrics["eth0"].latency_ms, metrics["eth1"].latency_ms), (10.0, 40.0));
+
+        let metrics = source.fetch().await.unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics["eth0"].latency_ms, 12.0);
+        assert_eq!(requests.await.unwrap(), vec![0, 2, 3, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_underlay_error_reply_fails_fetch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = UnderlayClient::new(&listener.local_addr().unwrap().to_string(), Duration::from_secs(1));
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"{\"error\": \"busy\"}\n").await.unwrap();
+        });
+        let e = client.metrics_diff(0).await.unwrap_err();
+        assert!(e.to_string().contains("busy"), "{}", e);
+    }
+}
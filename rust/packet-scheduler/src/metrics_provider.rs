@@ -0,0 +1,105 @@
+use crate::LinkMetrics;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Where the scheduler gets link metrics from. Polled once per metrics
+/// interval; an empty map means no link is currently usable.
+#[async_trait]
+pub trait MetricsProvider: Send + Sync {
+    async fn latest(&self) -> HashMap<String, LinkMetrics>;
+}
+
+/// Metrics held in memory and replaced with `set`, for tests and for
+/// embedding the scheduler where metrics arrive some other way.
+#[derive(Default)]
+pub struct StaticMetricsProvider {
+    metrics: Mutex<HashMap<String, LinkMetrics>>,
+}
+
+impl StaticMetricsProvider {
+    pub fn new(metrics: HashMap<String, LinkMetrics>) -> Self {
+        Self {
+            metrics: Mutex::new(metrics),
+        }
+    }
+
+    pub fn set(&self, metrics: HashMap<String, LinkMetrics>) {
+        *self.metrics.lock() = metrics;
+    }
+}
+
+#[async_trait]
+impl MetricsProvider for StaticMetricsProvider {
+    async fn latest(&self) -> HashMap<String, LinkMetrics> {
+        self.metrics.lock().clone()
+    }
+}
+
+/// Plays back recorded metrics reports, one per poll, then keeps reporting
+/// the last. The file holds one JSON report (interface name to metrics) per
+/// line; blank lines are skipped.
+pub struct ReplayMetricsProvider {
+    reports: Vec<HashMap<String, LinkMetrics>>,
+    next: Mutex<usize>,
+}
+
+impl ReplayMetricsProvider {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read metrics replay {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid metrics replay {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let reports = content.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| serde_json::from_str(line).with_context(|| format!("Line {}", index + 1)))
+            .collect::<Result<Vec<_>>>()?;
+        if reports.is_empty() {
+            return Err(anyhow::anyhow!("No metrics reports"));
+        }
+        Ok(Self {
+            reports,
+            next: Mutex::new(0),
+        })
+    }
+}
+
+#[async_trait]
+impl MetricsProvider for ReplayMetricsProvider {
+    async fn latest(&self) -> HashMap<String, LinkMetrics> {
+        let mut next = self.next.lock();
+        let report = self.reports[*next].clone();
+        *next = (*next + 1).min(self.reports.len() - 1);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replay_steps_through_reports_then_holds_last() {
+        let report = |bandwidth_mbps: f64| {
+            let mut metric = LinkMetrics::new();
+            metric.bandwidth_mbps = bandwidth_mbps;
+            serde_json::to_string(&HashMap::from([("eth0", metric)])).unwrap()
+        };
+        let content = format!("{}\n\n{}\n", report(100.0), report(50.0));
+        let provider = ReplayMetricsProvider::parse(&content).unwrap();
+
+        assert_eq!(provider.latest().await["eth0"].bandwidth_mbps, 100.0);
+        assert_eq!(provider.latest().await["eth0"].bandwidth_mbps, 50.0);
+        assert_eq!(provider.latest().await["eth0"].bandwidth_mbps, 50.0);
+
+        assert!(ReplayMetricsProvider::parse("").is_err());
+        assert!(ReplayMetricsProvider::parse("{\"eth0\": 1}").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Hands out packet ids from a shared counter. After `u64::MAX` ids wrap
+/// back to 1 rather than overflowing; 0 is never issued, so it can stand for
+/// "no id yet".
+#[derive(Debug, Default)]
+pub struct PacketIdAllocator {
+    last: AtomicU64,
+}
+
+impl PacketIdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An allocator whose first id follows `last`.
+    pub fn starting_after(last: u64) -> Self {
+        Self { last: AtomicU64::new(last) }
+    }
+
+    pub fn next(&self) -> u64 {
+        let previous = self.last
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(following(last)))
+            .unwrap_or_else(|last| last);
+        following(previous)
+    }
+}
+
+fn following(id: u64) -> u64 {
+    id.checked_add(1).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_ids_unique_across_threads() {
+        let allocator = Arc::new(PacketIdAllocator::new());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let allocator = allocator.clone();
+                std::thread::spawn(move || (0..10_000).map(|_| allocator.next()).collect::<Vec<u64>>())
+            })
+            .collect();
+
+        let ids: HashSet<u64> = handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(ids.len(), 80_000);
+        assert_eq!(ids.iter().min(), Some(&1));
+        assert_eq!(ids.iter().max(), Some(&80_000));
+    }
+
+    #[test]
+    fn test_wraps_past_max_without_issuing_zero() {
+        let allocator = PacketIdAllocator::starting_after(u64::MAX - 1);
+        assert_eq!(allocator.next(), u64::MAX);
+        assert_eq!(allocator.next(), 1);
+        assert_eq!(allocator.next(), 2);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::scheduler::ScheduledPacket;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use pcap_file::pcapng::blocks::enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption};
+use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
+use pcap_file::pcapng::PcapNgWriter;
+use pcap_file::DataLink;
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const DEFAULT_MAX_FILE_BYTES: u64 = 100 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 5;
+
+/// Writes scheduled packets to a pcapng file for offline debugging of
+/// routing decisions. Each packet carries a comment recording its link and
+/// sequence number. Once the file passes `max_file_bytes` it is rotated to
+/// `<path>.1`, `<path>.2`, ... keeping at most `max_files` files in total.
+pub struct PcapExporter {
+    path: PathBuf,
+    writer: PcapNgWriter<BufWriter<File>>,
+    written: u64,
+    max_file_bytes: u64,
+    max_files: usize,
+}
+
+impl PcapExporter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::with_rotation(path, DEFAULT_MAX_FILE_BYTES, DEFAULT_MAX_FILES)
+    }
+
+    pub fn with_rotation<P: AsRef<Path>>(path: P, max_file_bytes: u64, max_files: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let writer = open_writer(&path)?;
+
+        Ok(Self {
+            path,
+            writer,
+            written: 0,
+            max_file_bytes,
+            max_files: max_files.max(1),
+        })
+    }
+
+    pub fn write(&mut self, packet: &ScheduledPacket) -> Result<()> {
+        if self.written >= self.max_file_bytes {
+            self.rotate()?;
+        }
+
+        let timestamp = packet.packet.timestamp
+            .signed_duration_since(DateTime::<Utc>::UNIX_EPOCH)
+            .to_std()
+            .unwrap_or_default();
+        let block = EnhancedPacketBlock {
+            interface_id: 0,
+            timestamp,
+            original_len: packet.packet.data.len() as u32,
+            data: Cow::Borrowed(&packet.packet.data),
+            options: vec![EnhancedPacketOption::Comment(Cow::Owned(annotation(packet)))],
+        };
+        self.written += self.writer.write_pcapng_block(block)? as u64;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.get_mut().flush()?;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+
+        // Shift <path>.N-1 to <path>.N, ..., <path> to <path>.1; the oldest
+        // file falls off the end
+        for index in (1..self.max_files).rev() {
+            let from = if index == 1 { self.path.clone() } else { rotated_path(&self.path, index - 1) };
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index))
+                    .with_context(|| format!("Failed to rotate {}", from.display()))?;
+            }
+        }
+
+        self.writer = open_writer(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// The comment attached to each captured packet.
+pub fn annotation(packet: &ScheduledPacket) -> String {
+    format!("link={} seq={}", packet.link_name, packet.sequence_number)
+}
+
+/// Parses an [`annotation`] back into its link name and sequence number.
+pub fn parse_annotation(comment: &str) -> Option<(String, u64)> {
+    let (link, seq) = comment.strip_prefix("link=")?.rsplit_once(" seq=")?;
+    Some((link.to_string(), seq.parse().ok()?))
+}
+
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+fn open_writer(path: &Path) -> Result<PcapNgWriter<BufWriter<File>>> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create pcap file {}", path.display()))?;
+    let mut writer = PcapNgWriter::new(BufWriter::new(file))?;
+
+    // Scheduled packets are raw IP, captured on a single pseudo-interface
+    writer.write_pcapng_block(InterfaceDescriptionBlock {
+        linktype: DataLink::RAW,
+        snaplen: 0,
+        options: vec![],
+    })?;
+
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Protocol;
+    use crate::scheduler::Packet;
+    use pcap_file::pcapng::{Block, PcapNgReader};
+
+    fn scheduled(link: &str, sequence_number: u64) -> ScheduledPacket {
+        ScheduledPacket {
+            packet: Packet {
+                id: sequence_number,
+                data: vec![0x45; 60],
+                priority: 5,
+                source_ip: "192.168.1.10".to_string(),
+                dest_ip: "10.0.0.1".to_string(),
+                protocol: Protocol::Udp,
+                source_port: None,
+                dest_port: None,
+                dscp: None,
+                icmp_type: None,
+                icmp_code: None,
+                vlan_id: None,
+                inner_vlan_id: None,
+                timestamp: Utc::now(),
+            },
+            link_name: link.to_string(),
+            sequence_number,
+        }
+    }
+
+    fn read_annotations(path: &Path) -> Vec<(String, u64)> {
+        let mut reader = PcapNgReader::new(File::open(path).unwrap()).unwrap();
+        let mut annotations = Vec::new();
+        while let Some(block) = reader.next_block() {
+            if let Block::EnhancedPacket(packet) = block.unwrap() {
+                assert_eq!(packet.data.len(), 60);
+                for option in packet.options {
+                    if let EnhancedPacketOption::Comment(comment) = option {
+                        annotations.push(parse_annotation(&comment).unwrap());
+                    }
+                }
+            }
+        }
+        annotations
+    }
+
+    #[test]
+    fn test_packets_written_with_link_annotation() {
+        let path = std::env::temp_dir().join(format!("sched-{}.pcapng", uuid::Uuid::new_v4()));
+        let mut exporter = PcapExporter::create(&path).unwrap();
+        for (link, seq) in [("eth0", 1), ("eth1", 2), ("eth0", 3)] {
+            exporter.write(&scheduled(link, seq)).unwrap();
+        }
+        exporter.flush().unwrap();
+
+        let annotations = read_annotations(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(annotations, vec![
+            ("eth0".to_string(), 1),
+            ("eth1".to_string(), 2),
+            ("eth0".to_string(), 3),
+        ]);
+    }
+
+    #[test]
+    fn test_file_rotates_past_size_limit() {
+        let path = std::env::temp_dir().join(format!("sched-{}.pcapng", uuid::Uuid::new_v4()));
+        // Every packet pushes the file over the limit, so each lands in its own file
+        let mut exporter = PcapExporter::with_rotation(&path, 1, 2).unwrap();
+        for seq in 1..=3 {
+            exporter.write(&scheduled("eth0", seq)).unwrap();
+        }
+        exporter.flush().unwrap();
+
+        let current = read_annotations(&path);
+        let previous = read_annotations(&rotated_path(&path, 1));
+        assert!(!rotated_path(&path, 2).exists());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(rotated_path(&path, 1)).unwrap();
+
+        assert_eq!(current, vec![("eth0".to_string(), 3)]);
+        assert_eq!(previous, vec![("eth0".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_annotation_round_trip() {
+        let packet = scheduled("wan.100", 42);
+        assert_eq!(parse_annotation(&annotation(&packet)), Some(("wan.100".to_string(), 42)));
+        assert_eq!(parse_annotation("garbage"), None);
+    }
+} 
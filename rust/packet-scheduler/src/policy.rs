@@ -0,0 +1,125 @@
+use crate::cidr::Cidr;
+use crate::config::{LinkConfig, PolicyFallback, PolicyRoute};
+use crate::LinkMetrics;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// What a policy route decided for a packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// Send over this link.
+    Link(String),
+    /// The mandated link is down and the route forbids any other.
+    Drop,
+}
+
+/// Source-based policy routes, consulted before health-based selection.
+/// The most specific matching subnet wins; among equally specific ones, the
+/// first configured.
+pub struct PolicyRoutes {
+    routes: Vec<CompiledRoute>,
+}
+
+struct CompiledRoute {
+    source: Cidr,
+    link: String,
+    on_link_down: PolicyFallback,
+}
+
+impl PolicyRoutes {
+    pub fn new(routes: &[PolicyRoute], links: &[LinkConfig]) -> Result<Self> {
+        let mut compiled = Vec::with_capacity(routes.len());
+        for route in routes {
+            if !links.iter().any(|link| link.name == route.link) {
+                anyhow::bail!("Policy route for {} names unknown link {}", route.source, route.link);
+            }
+            let source = route.source.parse()
+                .with_context(|| format!("Invalid policy route source {}", route.source))?;
+            compiled.push(CompiledRoute {
+                source,
+                link: route.link.clone(),
+                on_link_down: route.on_link_down,
+            });
+        }
+        // Stable, so equally specific routes keep their configured order
+        compiled.sort_by_key(|route| std::cmp::Reverse(route.source.prefix_len()));
+        Ok(Self { routes: compiled })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// The decision for a packet from `source_ip`, given the links currently
+    /// available in `metrics`. `None` leaves the packet to the link selector.
+    pub fn route(&self, source_ip: &str, metrics: &HashMap<String, LinkMetrics>) -> Option<PolicyDecision> {
+        let source: IpAddr = source_ip.parse().ok()?;
+        let route = self.routes.iter().find(|route| route.source.contains(source))?;
+
+        let link_up = metrics.get(&route.link).is_some_and(|metric| !metric.is_down());
+        match (link_up, route.on_link_down) {
+            (true, _) => Some(PolicyDecision::Link(route.link.clone())),
+            (false, PolicyFallback::Drop) => Some(PolicyDecision::Drop),
+            (false, PolicyFallback::Fallback) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::link_config;
+
+    fn route(source: &str, link: &str, on_link_down: PolicyFallback) -> PolicyRoute {
+        PolicyRoute { source: source.to_string(), link: link.to_string(), on_link_down }
+    }
+
+    fn metrics(links: &[&str]) -> HashMap<String, LinkMetrics> {
+        links.iter()
+            .map(|name| {
+                let mut metric = LinkMetrics::new();
+                metric.bandwidth_mbps = 100.0;
+                (name.to_string(), metric)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_most_specific_route_wins() {
+        let links = [link_config("eth0", None), link_config("eth1", None)];
+        let routes = PolicyRoutes::new(&[
+            route("10.0.0.0/8", "eth0", PolicyFallback::Fallback),
+            route("10.1.0.0/16", "eth1", PolicyFallback::Fallback),
+            route("2001:db8::/32", "eth1", PolicyFallback::Fallback),
+        ], &links).unwrap();
+        let up = metrics(&["eth0", "eth1"]);
+
+        assert_eq!(routes.route("10.1.2.3", &up), Some(PolicyDecision::Link("eth1".to_string())));
+        assert_eq!(routes.route("10.2.0.1", &up), Some(PolicyDecision::Link("eth0".to_string())));
+        assert_eq!(routes.route("2001:db8::1", &up), Some(PolicyDecision::Link("eth1".to_string())));
+        assert_eq!(routes.route("192.168.1.10", &up), None);
+    }
+
+    #[test]
+    fn test_down_link_drops_or_falls_back() {
+        let links = [link_config("eth0", None), link_config("eth1", None)];
+        let routes = PolicyRoutes::new(&[
+            route("10.1.0.0/16", "eth1", PolicyFallback::Drop),
+            route("10.2.0.0/16", "eth1", PolicyFallback::Fallback),
+        ], &links).unwrap();
+        let eth1_down = metrics(&["eth0"]);
+
+        assert_eq!(routes.route("10.1.0.1", &eth1_down), Some(PolicyDecision::Drop));
+        assert_eq!(routes.route("10.2.0.1", &eth1_down), None);
+    }
+
+    #[test]
+    fn test_invalid_routes_rejected() {
+        let links = [link_config("eth0", None)];
+        assert!(PolicyRoutes::new(&[route("10.0.0.0/33", "eth0", PolicyFallback::Drop)], &links).is_err());
+        assert!(PolicyRoutes::new(&[route("10.0.0/8", "eth0", PolicyFallback::Drop)], &links).is_err());
+        assert!(PolicyRoutes::new(&[route("10.0.0.0/8", "eth9", PolicyFallback::Drop)], &links).is_err());
+        assert!(PolicyRoutes::new(&[route("10.0.0.1", "eth0", PolicyFallback::Drop)], &links).is_ok());
+    }
+}
@@ -1,7 +1,12 @@
 // Protocol buffer definitions for packet scheduler
 // This will be used for gRPC communication with other components
 
+use crate::config::QosRule;
+use crate::scheduler::{DrainMode, LinkScoreBreakdown, SchedulerDebugState};
+use crate::LinkMetrics;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetricsRequest {
@@ -15,9 +20,127 @@ pub struct MetricsResponse {
     pub jitter_ms: f64,
     pub packet_loss: f64,
     pub bandwidth_mbps: f64,
+    pub reliability: f64,
+    pub bufferbloat_ms: f64,
+    #[serde(default)]
+    pub bandwidth_up_mbps: Option<f64>,
+    /// False when the underlay ran no bandwidth test for the link.
+    #[serde(default = "default_bandwidth_measured")]
+    pub bandwidth_measured: bool,
     pub timestamp: String,
 }
 
+fn default_bandwidth_measured() -> bool {
+    true
+}
+
+impl From<&MetricsResponse> for LinkMetrics {
+    fn from(response: &MetricsResponse) -> Self {
+        Self {
+            latency_ms: response.latency_ms,
+            jitter_ms: response.jitter_ms,
+            packet_loss: response.packet_loss,
+            bandwidth_mbps: response.bandwidth_mbps,
+            timestamp: DateTime::parse_from_rfc3339(&response.timestamp)
+                .map(|timestamp| timestamp.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+            reliability: response.reliability,
+            bufferbloat_ms: response.bufferbloat_ms,
+            bandwidth_up_mbps: response.bandwidth_up_mbps,
+            bandwidth_measured: response.bandwidth_measured,
+        }
+    }
+}
+
+/// Asks the underlay manager for interfaces whose metrics changed after
+/// `since_version`; 0 asks for everything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsDiffRequest {
+    pub since_version: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsDiffResponse {
+    pub version: u64,
+    pub metrics: Vec<MetricsResponse>,
+    pub timestamp: String,
+}
+
+impl MetricsDiffResponse {
+    /// Merges the changed interfaces into a locally held metrics map and
+    /// returns the version to send as `since_version` next time.
+    pub fn apply(&self, metrics: &mut HashMap<String, LinkMetrics>) -> u64 {
+        for response in &self.metrics {
+            metrics.insert(response.interface_name.clone(), LinkMetrics::from(response));
+        }
+        self.version
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationMetricsRequest {}
+
+/// A link's metrics towards the destinations in `prefix`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationMetric {
+    pub prefix: String,
+    pub metrics: MetricsResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationMetricsResponse {
+    pub metrics: Vec<DestinationMetric>,
+    pub timestamp: String,
+}
+
+/// A call to the underlay manager's RPC listener, sent as one JSON line
+/// such as `{"method": "metrics_diff", "params": {"since_version": 0}}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum RpcRequest {
+    MetricsDiff(MetricsDiffRequest),
+    ListPeers(PeerListRequest),
+    SetIdle(SetIdleRequest),
+    DestinationMetrics(DestinationMetricsRequest),
+}
+
+/// The underlay manager's answer line: `{"result": ...}` or
+/// `{"error": "..."}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcReply {
+    Result(serde_json::Value),
+    Error(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerListRequest {}
+
+/// A peer underlay manager found by discovery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub node_id: String,
+    /// The peer manager's RPC endpoint, as `ip:port`.
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerListResponse {
+    /// Sorted by node ID.
+    pub peers: Vec<PeerInfo>,
+}
+
+/// Tells the underlay manager whether the scheduler is using a link, so it
+/// probes links it isn't using less often.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetIdleRequest {
+    pub interface_name: String,
+    pub idle: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetIdleResponse {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PacketRequest {
     pub packet_id: u64,
@@ -36,6 +159,97 @@ pub struct PacketResponse {
     pub status: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QosRuleRequest {
+    pub rule: QosRule,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveQosRuleRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QosRuleResponse {
+    pub name: String,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupHealthRequest {
+    pub group: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupHealthResponse {
+    pub group: String,
+    pub health: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkWeightRequest {
+    pub link_name: String,
+    /// Factor in 0.0-1.0 applied to the link's score; 1.0 clears it.
+    pub multiplier: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkWeightResponse {
+    pub link_name: String,
+    pub multiplier: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExplainSelectionRequest {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExplainSelectionResponse {
+    /// Best score first; the chosen link is marked `selected`.
+    pub links: Vec<LinkScoreBreakdown>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebugStateRequest {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebugStateResponse {
+    pub state: SchedulerDebugState,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrainLinkRequest {
+    pub link_name: String,
+    pub mode: DrainMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UndrainLinkRequest {
+    pub link_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrainLinkResponse {
+    pub link_name: String,
+    pub status: String,
+}
+
+/// A call to the admin API, sent over TLS as one JSON line such as
+/// `{"method": "set_link_weight", "params": {"link_name": "eth0", "multiplier": 0.5}}`
+/// and answered by an `RpcReply` line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum AdminRequest {
+    DrainLink(DrainLinkRequest),
+    UndrainLink(UndrainLinkRequest),
+    AddRule(QosRuleRequest),
+    UpdateRule(QosRuleRequest),
+    RemoveRule(RemoveQosRuleRequest),
+    SetLinkWeight(LinkWeightRequest),
+    GroupHealth(GroupHealthRequest),
+    ExplainSelection(ExplainSelectionRequest),
+    DebugState(DebugStateRequest),
+}
+
 // Service trait for gRPC communication
 #[async_trait::async_trait]
 pub trait MetricsService {
@@ -45,4 +259,98 @@ pub trait MetricsService {
 #[async_trait::async_trait]
 pub trait PacketService {
     async fn schedule_packet(&self, request: PacketRequest) -> Result<PacketResponse, Box<dyn std::error::Error>>;
-} 
\ No newline at end of file
+}
+
+/// Lets a controller push individual QoS rule changes instead of reloading
+/// the whole configuration.
+#[async_trait::async_trait]
+pub trait QosRuleService {
+    async fn add_rule(&self, request: QosRuleRequest) -> Result<QosRuleResponse, Box<dyn std::error::Error>>;
+    async fn update_rule(&self, request: QosRuleRequest) -> Result<QosRuleResponse, Box<dyn std::error::Error>>;
+    async fn remove_rule(&self, request: RemoveQosRuleRequest) -> Result<QosRuleResponse, Box<dyn std::error::Error>>;
+}
+
+#[async_trait::async_trait]
+pub trait GroupHealthService {
+    async fn get_group_health(&self, request: GroupHealthRequest) -> Result<GroupHealthResponse, Box<dyn std::error::Error>>;
+}
+
+/// Lets operators shift traffic off a link gradually without draining it.
+#[async_trait::async_trait]
+pub trait LinkWeightService {
+    async fn set_link_weight(&self, request: LinkWeightRequest) -> Result<LinkWeightResponse, Box<dyn std::error::Error>>;
+}
+
+/// Answers "why did traffic go to this link?" for operators.
+#[async_trait::async_trait]
+pub trait SelectionExplanationService {
+    async fn explain_last_selection(&self, request: ExplainSelectionRequest) -> Result<ExplainSelectionResponse, Box<dyn std::error::Error>>;
+}
+
+/// Dumps the scheduler's state for support bundles.
+#[async_trait::async_trait]
+pub trait DebugStateService {
+    async fn get_debug_state(&self, request: DebugStateRequest) -> Result<DebugStateResponse, Box<dyn std::error::Error>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(interface_name: &str, latency_ms: f64) -> MetricsResponse {
+        MetricsResponse {
+            interface_name: interface_name.to_string(),
+            latency_ms,
+            jitter_ms: 1.0,
+            packet_loss: 0.0,
+            bandwidth_mbps: 100.0,
+            reliability: 1.0,
+            bufferbloat_ms: 0.0,
+            bandwidth_up_mbps: None,
+            bandwidth_measured: true,
+            timestamp: Utc::now().to_rfc3339(),
+        }
+    }
+
+    #[test]
+    fn test_metrics_diff_applied_to_local_map() {
+        let mut metrics = HashMap::new();
+        let full = MetricsDiffResponse {
+            version: 2,
+            metrics: vec![response("eth0", 10.0), response("eth1", 15.0)],
+            timestamp: Utc::now().to_rfc3339(),
+        };
+        assert_eq!(full.apply(&mut metrics), 2);
+
+        let diff = MetricsDiffResponse {
+            version: 3,
+            metrics: vec![response("eth1", 40.0)],
+            timestamp: Utc::now().to_rfc3339(),
+        };
+        assert_eq!(diff.apply(&mut metrics), 3);
+        assert_eq!(metrics["eth0"].latency_ms, 10.0);
+        assert_eq!(metrics["eth1"].latency_ms, 40.0);
+    }
+
+    /// Shared with the underlay manager's proto tests, so a field renamed or
+    /// dropped on either side fails one of them.
+    const WIRE_METRICS_DIFF: &str = include_str!("../../testdata/metrics_diff.json");
+
+    #[test]
+    fn test_underlay_metrics_diff_wire_format() {
+        let diff: MetricsDiffResponse = serde_json::from_str(WIRE_METRICS_DIFF).unwrap();
+        let mut metrics = HashMap::new();
+        assert_eq!(diff.apply(&mut metrics), 7);
+
+        let eth0 = &metrics["eth0"];
+        assert_eq!((eth0.latency_ms, eth0.jitter_ms, eth0.packet_loss), (12.5, 1.5, 0.01));
+        assert_eq!((eth0.reliability, eth0.bufferbloat_ms, eth0.bandwidth_up_mbps), (0.95, 30.0, Some(40.0)));
+        assert_eq!(eth0.timestamp.to_rfc3339(), "2026-10-16T09:30:00+00:00");
+        assert!(eth0.bandwidth_measured);
+        // No carrier arrives as total loss
+        assert!(metrics["wwan0"].is_down());
+        // Bandwidth never tested is unknown, not zero capacity
+        assert!(!metrics["lte0"].bandwidth_measured);
+        assert!(!metrics["lte0"].is_down());
+    }
+}
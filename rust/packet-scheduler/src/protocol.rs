@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// UDP ports QUIC is commonly carried on (HTTPS/HTTP3 and DNS over QUIC).
+pub const QUIC_PORTS: [u16; 2] = [443, 853];
+
+/// Normalized transport protocol of a packet. Parsed case-insensitively;
+/// unrecognized names are kept upper-cased in `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Sctp,
+    Quic,
+    Other(String),
+}
+
+impl Protocol {
+    /// Refines a transport protocol using the packet's ports and payload:
+    /// UDP to or from a QUIC port is QUIC, unless the payload is present and
+    /// lacks the QUIC fixed bit (RFC 9000, section 17).
+    pub fn classify(self, source_port: Option<u16>, dest_port: Option<u16>, payload: &[u8]) -> Self {
+        if self != Protocol::Udp {
+            return self;
+        }
+
+        let on_quic_port = [source_port, dest_port].iter()
+            .flatten()
+            .any(|port| QUIC_PORTS.contains(port));
+        let fixed_bit = payload.first().map_or(true, |first| first & 0x40 != 0);
+
+        if on_quic_port && fixed_bit {
+            Protocol::Quic
+        } else {
+            Protocol::Udp
+        }
+    }
+
+    /// The protocol carrying this one on the wire: UDP for QUIC, otherwise
+    /// the protocol itself.
+    pub fn transport(&self) -> Self {
+        match self {
+            Protocol::Quic => Protocol::Udp,
+            other => other.clone(),
+        }
+    }
+
+    /// Whether a rule naming `self` applies to a packet of `protocol`. A UDP
+    /// rule also covers QUIC, since QUIC traffic is UDP on the wire.
+    pub fn matches(&self, protocol: &Protocol) -> bool {
+        self == protocol || *self == protocol.transport()
+    }
+
+    /// IANA protocol number of the transport. `Other` names that are not a
+    /// number map to 255 (reserved).
+    pub fn number(&self) -> u8 {
+        match self {
+            Protocol::Icmp => 1,
+            Protocol::Tcp => 6,
+            Protocol::Udp | Protocol::Quic => 17,
+            Protocol::Sctp => 132,
+            Protocol::Other(name) => name.parse().unwrap_or(255),
+        }
+    }
+}
+
+impl From<&str> for Protocol {
+    fn from(name: &str) -> Self {
+        match name.to_uppercase().as_str() {
+            "TCP" => Protocol::Tcp,
+            "UDP" => Protocol::Udp,
+            "ICMP" => Protocol::Icmp,
+            "SCTP" => Protocol::Sctp,
+            "QUIC" => Protocol::Quic,
+            other => Protocol::Other(other.to_string()),
+        }
+    }
+}
+
+impl From<String> for Protocol {
+    fn from(name: String) -> Self {
+        Protocol::from(name.as_str())
+    }
+}
+
+impl From<Protocol> for String {
+    fn from(protocol: Protocol) -> Self {
+        protocol.to_string()
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Udp => write!(f, "UDP"),
+            Protocol::Icmp => write!(f, "ICMP"),
+            Protocol::Sctp => write!(f, "SCTP"),
+            Protocol::Quic => write!(f, "QUIC"),
+            Protocol::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_is_case_insensitive() {
+        assert_eq!(Protocol::from("udp"), Protocol::Udp);
+        assert_eq!(Protocol::from("Sctp"), Protocol::Sctp);
+        assert_eq!(Protocol::from("gre"), Protocol::Other("GRE".to_string()));
+        assert_eq!(Protocol::from("gre").to_string(), "GRE");
+    }
+
+    #[test]
+    fn test_quic_on_443_classified_distinctly_from_udp() {
+        // Long header: header form and fixed bits set
+        let initial = [0xc3, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(Protocol::Udp.classify(Some(50000), Some(443), &initial), Protocol::Quic);
+        assert_eq!(Protocol::Udp.classify(Some(443), Some(50000), &[]), Protocol::Quic);
+
+        assert_eq!(Protocol::Udp.classify(Some(50000), Some(5060), &initial), Protocol::Udp);
+        // Fixed bit clear: not QUIC even on 443
+        assert_eq!(Protocol::Udp.classify(Some(50000), Some(443), &[0x00]), Protocol::Udp);
+        // Only UDP is refined
+        assert_eq!(Protocol::Tcp.classify(Some(50000), Some(443), &initial), Protocol::Tcp);
+    }
+
+    #[test]
+    fn test_udp_rule_covers_quic() {
+        assert!(Protocol::Udp.matches(&Protocol::Quic));
+        assert!(Protocol::Quic.matches(&Protocol::Quic));
+        assert!(!Protocol::Quic.matches(&Protocol::Udp));
+        assert!(!Protocol::Tcp.matches(&Protocol::Sctp));
+    }
+}
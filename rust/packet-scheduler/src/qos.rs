@@ -1,33 +1,102 @@
-use crate::config::QosRule;
+use crate::cidr::Cidr;
+use crate::config::{QosConfig, QosRule};
+use crate::protocol::Protocol;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PacketInfo {
     pub source_ip: String,
     pub dest_ip: String,
-    pub protocol: String,
+    pub protocol: Protocol,
     pub source_port: Option<u16>,
     pub dest_port: Option<u16>,
     pub dscp: Option<u8>,
+    /// ICMP message type and code; set only for ICMP packets.
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    /// Outer and, for QinQ, inner 802.1Q VLAN IDs; unset when untagged.
+    pub vlan_id: Option<u16>,
+    pub inner_vlan_id: Option<u16>,
     pub priority: u8,
 }
 
+/// Priorities of packets no QoS rule matches: by DSCP, then by protocol,
+/// then `default_priority`.
+pub struct DefaultPriorities {
+    protocol_defaults: HashMap<Protocol, u8>,
+    /// Keyed by DSCP codepoint
+    dscp_priorities: HashMap<u8, u8>,
+    default_priority: u8,
+}
+
+impl DefaultPriorities {
+    pub fn from_config(config: &QosConfig) -> Self {
+        Self {
+            protocol_defaults: config.protocol_defaults.iter()
+                .map(|(protocol, priority)| (Protocol::from(protocol.as_str()), *priority))
+                .collect(),
+            dscp_priorities: config.dscp_priority_map.iter()
+                .filter_map(|(class, priority)| parse_dscp(class).map(|dscp| (dscp, *priority)))
+                .collect(),
+            default_priority: config.default_priority,
+        }
+    }
+    
+    /// `dscp` must already have passed the trust boundary.
+    pub fn priority(&self, protocol: &Protocol, dscp: Option<u8>) -> u8 {
+        if let Some(priority) = dscp.and_then(|dscp| self.dscp_priorities.get(&dscp)) {
+            *priority
+        } else if let Some(priority) = self.protocol_defaults.get(protocol)
+            .or_else(|| self.protocol_defaults.get(&protocol.transport()))
+        {
+            *priority
+        } else {
+            self.default_priority
+        }
+    }
+}
+
+impl Default for DefaultPriorities {
+    fn default() -> Self {
+        Self {
+            protocol_defaults: HashMap::new(),
+            dscp_priorities: HashMap::new(),
+            default_priority: 5,
+        }
+    }
+}
+
 pub struct QosEngine {
     rules: Vec<QosRule>,
+    defaults: DefaultPriorities,
+    /// `None` trusts every source's DSCP.
+    trust_dscp_from: Option<Vec<Cidr>>,
 }
 
 impl QosEngine {
     pub fn new(rules: Vec<QosRule>) -> Self {
-        Self { rules }
+        Self {
+            rules,
+            defaults: DefaultPriorities::default(),
+            trust_dscp_from: None,
+        }
     }
     
-    pub fn classify_packet(&self, packet: &PacketInfo) -> Option<&QosRule> {
-        for rule in &self.rules {
-            if self.matches_rule(packet, rule) {
-                return Some(rule);
-            }
+    pub fn from_config(config: &QosConfig) -> Self {
+        Self {
+            rules: config.rules.clone(),
+            defaults: DefaultPriorities::from_config(config),
+            trust_dscp_from: config.trust_dscp_from.clone(),
         }
-        None
+    }
+    
+    fn trusted_dscp(&self, packet: &PacketInfo) -> Option<u8> {
+        trusted_dscp(packet.dscp, &packet.source_ip, self.trust_dscp_from.as_deref())
+    }
+    
+    pub fn classify_packet(&self, packet: &PacketInfo) -> Option<&QosRule> {
+        self.rules.iter().find(|rule| self.matches_rule(packet, rule))
     }
     
     fn matches_rule(&self, packet: &PacketInfo, rule: &QosRule) -> bool {
@@ -49,7 +118,7 @@ impl QosEngine {
         
         // Check protocol
         if let Some(ref protocol) = criteria.protocol {
-            if packet.protocol != *protocol {
+            if !protocol.matches(&packet.protocol) {
                 return false;
             }
         }
@@ -63,23 +132,36 @@ impl QosEngine {
             }
         }
         
-        // Check DSCP
-        if let Some(dscp) = criteria.dscp {
-            if let Some(packet_dscp) = packet.dscp {
-                if packet_dscp != dscp {
-                    return false;
-                }
+        // Check DSCP; a rule naming one never matches a packet without it
+        if criteria.dscp.is_some_and(|dscp| self.trusted_dscp(packet) != Some(dscp)) {
+            return false;
+        }
+        
+        // Check ICMP type and code, which only ICMP packets carry
+        if packet.protocol == Protocol::Icmp {
+            if criteria.icmp_type.is_some_and(|icmp_type| packet.icmp_type != Some(icmp_type)) {
+                return false;
             }
+            if criteria.icmp_code.is_some_and(|icmp_code| packet.icmp_code != Some(icmp_code)) {
+                return false;
+            }
+        }
+        
+        // Check VLAN tags; a rule naming a tag never matches without it
+        if criteria.vlan_id.is_some_and(|vlan_id| packet.vlan_id != Some(vlan_id)) {
+            return false;
+        }
+        if criteria.inner_vlan_id.is_some_and(|inner_vlan_id| packet.inner_vlan_id != Some(inner_vlan_id)) {
+            return false;
         }
         
         true
     }
     
     pub fn get_priority(&self, packet: &PacketInfo) -> u8 {
-        if let Some(rule) = self.classify_packet(packet) {
-            rule.priority
-        } else {
-            5 // Default priority
+        match self.classify_packet(packet) {
+            Some(rule) => rule.priority,
+            None => self.defaults.priority(&packet.protocol, self.trusted_dscp(packet)),
         }
     }
     
@@ -91,9 +173,10 @@ impl QosEngine {
         }
     }
     
+    /// Bandwidth limit of the packet's class, in bytes per second.
     pub fn get_bandwidth_limit(&self, packet: &PacketInfo) -> Option<u64> {
         if let Some(rule) = self.classify_packet(packet) {
-            rule.action.bandwidth_limit
+            rule.action.bandwidth_limit.map(|limit| limit.bytes_per_sec())
         } else {
             None
         }
@@ -108,10 +191,53 @@ impl QosEngine {
     }
 }
 
+/// A packet's DSCP as classification sees it: cleared to 0 unless the
+/// source is inside the trust boundary. `None` trusts every source.
+pub fn trusted_dscp(dscp: Option<u8>, source_ip: &str, trust_dscp_from: Option<&[Cidr]>) -> Option<u8> {
+    let Some(trusted) = trust_dscp_from else {
+        return dscp;
+    };
+    let source = source_ip.parse().ok();
+    let is_trusted = source.is_some_and(|source| trusted.iter().any(|cidr| cidr.contains(source)));
+    match dscp {
+        Some(_) if !is_trusted => Some(0),
+        dscp => dscp,
+    }
+}
+
+/// Parses a DSCP class name (`EF`, `AF41`, `CS6`, `DF`; case-insensitive)
+/// or a decimal codepoint into its 6-bit value.
+pub fn parse_dscp(class: &str) -> Option<u8> {
+    let class = class.trim().to_uppercase();
+    let dscp = match class.as_str() {
+        "EF" => 46,
+        "DF" | "BE" => 0,
+        "VA" => 44,
+        _ => {
+            if let Some(selector) = class.strip_prefix("CS") {
+                match selector.parse::<u8>().ok()? {
+                    selector @ 0..=7 => selector << 3,
+                    _ => return None,
+                }
+            } else if let Some(af) = class.strip_prefix("AF") {
+                let mut digits = af.chars().map(|c| c.to_digit(10));
+                match (digits.next(), digits.next(), digits.next()) {
+                    (Some(Some(class @ 1..=4)), Some(Some(drop @ 1..=3)), None) => (class * 8 + drop * 2) as u8,
+                    _ => return None,
+                }
+            } else {
+                class.parse().ok()?
+            }
+        }
+    };
+    (dscp <= 63).then_some(dscp)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::{MatchCriteria, QosAction, PortRange};
+    use crate::Config;
     
     #[test]
     fn test_qos_classification() {
@@ -122,14 +248,22 @@ mod tests {
                 match_criteria: MatchCriteria {
                     source_ip: Some("192.168.1.100".to_string()),
                     dest_ip: None,
-                    protocol: Some("UDP".to_string()),
+                    protocol: Some(Protocol::Udp),
                     port_range: Some(PortRange { start: 10000, end: 20000 }),
                     dscp: Some(46),
+                    icmp_type: None,
+                    icmp_code: None,
+                    vlan_id: None,
+                    inner_vlan_id: None,
                 },
                 action: QosAction {
                     link_preference: vec!["eth0".to_string()],
-                    bandwidth_limit: Some(1000000),
+                    bandwidth_limit: Some("1Mbps".parse().unwrap()),
                     latency_threshold: Some(20),
+                    max_age_ms: None,
+                    pin_first_packets: None,
+                    scheduler_algorithm: None,
+                    min_bandwidth: None,
                 },
             },
         ];
@@ -139,10 +273,14 @@ mod tests {
         let packet = PacketInfo {
             source_ip: "192.168.1.100".to_string(),
             dest_ip: "192.168.1.200".to_string(),
-            protocol: "UDP".to_string(),
+            protocol: Protocol::Udp,
             source_port: Some(12345),
             dest_port: Some(15000),
             dscp: Some(46),
+            icmp_type: None,
+            icmp_code: None,
+            vlan_id: None,
+            inner_vlan_id: None,
             priority: 5,
         };
         
@@ -159,14 +297,22 @@ mod tests {
                 match_criteria: MatchCriteria {
                     source_ip: Some("192.168.1.100".to_string()),
                     dest_ip: None,
-                    protocol: Some("UDP".to_string()),
+                    protocol: Some(Protocol::Udp),
                     port_range: None,
                     dscp: None,
+                    icmp_type: None,
+                    icmp_code: None,
+                    vlan_id: None,
+                    inner_vlan_id: None,
                 },
                 action: QosAction {
                     link_preference: vec![],
                     bandwidth_limit: None,
                     latency_threshold: None,
+                    max_age_ms: None,
+                    pin_first_packets: None,
+                    scheduler_algorithm: None,
+                    min_bandwidth: None,
                 },
             },
         ];
@@ -176,14 +322,294 @@ mod tests {
         let packet = PacketInfo {
             source_ip: "192.168.1.101".to_string(), // Different IP
             dest_ip: "192.168.1.200".to_string(),
-            protocol: "UDP".to_string(),
+            protocol: Protocol::Udp,
             source_port: Some(12345),
             dest_port: Some(15000),
             dscp: None,
+            icmp_type: None,
+            icmp_code: None,
+            vlan_id: None,
+            inner_vlan_id: None,
             priority: 5,
         };
         
         assert!(qos_engine.classify_packet(&packet).is_none());
         assert_eq!(qos_engine.get_priority(&packet), 5); // Default priority
     }
+    
+    fn protocol_defaults_config(rules: Vec<QosRule>) -> QosConfig {
+        let mut protocol_defaults = HashMap::new();
+        protocol_defaults.insert("ICMP".to_string(), 6);
+        protocol_defaults.insert("udp".to_string(), 4);
+        QosConfig {
+            rules,
+            default_priority: 3,
+            rules_file: None,
+            protocol_defaults,
+            dscp_priority_map: HashMap::new(),
+            trust_dscp_from: None,
+        }
+    }
+    
+    fn packet(protocol: &str, dest_port: Option<u16>) -> PacketInfo {
+        PacketInfo {
+            source_ip: "192.168.1.100".to_string(),
+            dest_ip: "192.168.1.200".to_string(),
+            protocol: Protocol::from(protocol),
+            source_port: None,
+            dest_port,
+            dscp: None,
+            icmp_type: None,
+            icmp_code: None,
+            vlan_id: None,
+            inner_vlan_id: None,
+            priority: 5,
+        }
+    }
+    
+    #[test]
+    fn test_protocol_default_priority() {
+        let qos_engine = QosEngine::from_config(&protocol_defaults_config(vec![]));
+        
+        assert_eq!(qos_engine.get_priority(&packet("ICMP", None)), 6);
+        assert_eq!(qos_engine.get_priority(&packet("UDP", Some(53))), 4);
+        // No protocol default for TCP, so the configured default applies
+        assert_eq!(qos_engine.get_priority(&packet("TCP", Some(443))), 3);
+    }
+    
+    #[test]
+    fn test_rule_overrides_protocol_default() {
+        let rules = vec![
+            QosRule {
+                name: "voip".to_string(),
+                priority: 7,
+                match_criteria: MatchCriteria {
+                    source_ip: None,
+                    dest_ip: None,
+                    protocol: Some(Protocol::Udp),
+                    port_range: Some(PortRange { start: 10000, end: 20000 }),
+                    dscp: None,
+                    icmp_type: None,
+                    icmp_code: None,
+                    vlan_id: None,
+                    inner_vlan_id: None,
+                },
+                action: QosAction {
+                    link_preference: vec![],
+                    bandwidth_limit: None,
+                    latency_threshold: None,
+                    max_age_ms: None,
+                    pin_first_packets: None,
+                    scheduler_algorithm: None,
+                    min_bandwidth: None,
+                },
+            },
+        ];
+        let qos_engine = QosEngine::from_config(&protocol_defaults_config(rules));
+        
+        assert_eq!(qos_engine.get_priority(&packet("UDP", Some(15000))), 7);
+        assert_eq!(qos_engine.get_priority(&packet("UDP", Some(53))), 4);
+    }
+    
+    fn icmp_rule(name: &str, priority: u8, icmp_type: Option<u8>, icmp_code: Option<u8>) -> QosRule {
+        QosRule {
+            name: name.to_string(),
+            priority,
+            match_criteria: MatchCriteria {
+                source_ip: None,
+                dest_ip: None,
+                protocol: Some(Protocol::Icmp),
+                port_range: None,
+                dscp: None,
+                icmp_type,
+                icmp_code,
+                vlan_id: None,
+                inner_vlan_id: None,
+            },
+            action: QosAction {
+                link_preference: vec![],
+                bandwidth_limit: None,
+                latency_threshold: None,
+                max_age_ms: None,
+                pin_first_packets: None,
+                scheduler_algorithm: None,
+                min_bandwidth: None,
+            },
+        }
+    }
+    
+    fn icmp_packet(icmp_type: u8, icmp_code: u8) -> PacketInfo {
+        PacketInfo {
+            icmp_type: Some(icmp_type),
+            icmp_code: Some(icmp_code),
+            ..packet("ICMP", None)
+        }
+    }
+    
+    #[test]
+    fn test_icmp_echo_request_classified_distinctly() {
+        let rules = vec![
+            icmp_rule("echo-request", 2, Some(8), None),
+            // Fragmentation needed carries path MTU discovery
+            icmp_rule("frag-needed", 7, Some(3), Some(4)),
+        ];
+        let qos_engine = QosEngine::from_config(&protocol_defaults_config(rules));
+        
+        assert_eq!(qos_engine.classify_packet(&icmp_packet(8, 0)).unwrap().name, "echo-request");
+        assert_eq!(qos_engine.get_priority(&icmp_packet(8, 0)), 2);
+        assert_eq!(qos_engine.get_priority(&icmp_packet(3, 4)), 7);
+        // Other unreachables and echo replies fall through to the ICMP default
+        assert_eq!(qos_engine.get_priority(&icmp_packet(3, 1)), 6);
+        assert_eq!(qos_engine.get_priority(&icmp_packet(0, 0)), 6);
+        // ICMP packets without a known type only match type-agnostic rules
+        assert_eq!(qos_engine.get_priority(&packet("ICMP", None)), 6);
+    }
+    
+    #[test]
+    fn test_icmp_criteria_require_icmp_protocol() {
+        assert!(icmp_rule("echo", 2, Some(8), None).validate().is_ok());
+        
+        let mut rule = icmp_rule("echo", 2, Some(8), None);
+        rule.match_criteria.protocol = Some(Protocol::Udp);
+        assert!(rule.validate().is_err());
+        rule.match_criteria.protocol = None;
+        assert!(rule.validate().is_err());
+    }
+    
+    #[test]
+    fn test_quic_rule_skips_plain_udp() {
+        let rules = vec![
+            QosRule {
+                name: "http3".to_string(),
+                priority: 6,
+                match_criteria: MatchCriteria {
+                    source_ip: None,
+                    dest_ip: None,
+                    protocol: Some(Protocol::Quic),
+                    port_range: None,
+                    dscp: None,
+                    icmp_type: None,
+                    icmp_code: None,
+                    vlan_id: None,
+                    inner_vlan_id: None,
+                },
+                action: QosAction {
+                    link_preference: vec![],
+                    bandwidth_limit: None,
+                    latency_threshold: None,
+                    max_age_ms: None,
+                    pin_first_packets: None,
+                    scheduler_algorithm: None,
+                    min_bandwidth: None,
+                },
+            },
+        ];
+        let qos_engine = QosEngine::from_config(&protocol_defaults_config(rules));
+        
+        let mut quic = packet("UDP", Some(443));
+        quic.protocol = quic.protocol.classify(quic.source_port, quic.dest_port, &[0xc3]);
+        assert_eq!(qos_engine.get_priority(&quic), 6);
+        // Plain UDP falls through to the UDP default
+        assert_eq!(qos_engine.get_priority(&packet("UDP", Some(53))), 4);
+    }
+    
+    #[test]
+    fn test_parse_dscp_classes() {
+        assert_eq!(parse_dscp("EF"), Some(46));
+        assert_eq!(parse_dscp("af41"), Some(34));
+        assert_eq!(parse_dscp("AF13"), Some(14));
+        assert_eq!(parse_dscp("CS6"), Some(48));
+        assert_eq!(parse_dscp("26"), Some(26));
+        assert_eq!(parse_dscp("AF51"), None);
+        assert_eq!(parse_dscp("CS8"), None);
+        assert_eq!(parse_dscp("64"), None);
+    }
+    
+    #[test]
+    fn test_default_dscp_map_prioritizes_ef() {
+        let qos_engine = QosEngine::from_config(&Config::default().qos);
+        let mut voice = packet("UDP", Some(5004));
+        voice.dscp = Some(46);
+        let mut bulk = packet("TCP", Some(873));
+        bulk.dscp = Some(10);
+        
+        assert_eq!(qos_engine.get_priority(&voice), 6);
+        assert!(qos_engine.get_priority(&voice) > qos_engine.get_priority(&bulk));
+        // Unmarked traffic still gets the configured default
+        assert_eq!(qos_engine.get_priority(&packet("TCP", Some(443))), 5);
+    }
+
+    fn vlan_rule(name: &str, priority: u8, vlan_id: Option<u16>, inner_vlan_id: Option<u16>) -> QosRule {
+        let mut rule = icmp_rule(name, priority, None, None);
+        rule.match_criteria.protocol = None;
+        rule.match_criteria.vlan_id = vlan_id;
+        rule.match_criteria.inner_vlan_id = inner_vlan_id;
+        rule
+    }
+    
+    fn tagged_packet(vlan_id: Option<u16>, inner_vlan_id: Option<u16>) -> PacketInfo {
+        PacketInfo { vlan_id, inner_vlan_id, ..packet("TCP", Some(443)) }
+    }
+    
+    #[test]
+    fn test_classified_by_outer_and_inner_vlan() {
+        let rules = vec![
+            // Customer 20 inside service VLAN 100
+            vlan_rule("s100-c20", 7, Some(100), Some(20)),
+            vlan_rule("s100", 4, Some(100), None),
+            vlan_rule("c30", 2, None, Some(30)),
+        ];
+        let qos_engine = QosEngine::from_config(&protocol_defaults_config(rules));
+        let class = |packet: &PacketInfo| qos_engine.classify_packet(packet).map(|rule| rule.name.clone());
+        
+        assert_eq!(class(&tagged_packet(Some(100), Some(20))).as_deref(), Some("s100-c20"));
+        assert_eq!(class(&tagged_packet(Some(100), Some(21))).as_deref(), Some("s100"));
+        assert_eq!(class(&tagged_packet(Some(100), None)).as_deref(), Some("s100"));
+        assert_eq!(class(&tagged_packet(Some(200), Some(30))).as_deref(), Some("c30"));
+        // A rule naming a tag doesn't match packets without it
+        assert_eq!(class(&tagged_packet(Some(200), None)), None);
+        assert_eq!(class(&tagged_packet(None, None)), None);
+    }
+    
+    #[test]
+    fn test_vlan_ids_validated() {
+        assert!(vlan_rule("s100", 4, Some(100), Some(4094)).validate().is_ok());
+        assert!(vlan_rule("bad", 4, Some(0), None).validate().is_err());
+        assert!(vlan_rule("bad", 4, Some(100), Some(4095)).validate().is_err());
+    }
+
+    #[test]
+    fn test_dscp_only_trusted_from_inside_boundary() {
+        let mut config = Config::default().qos;
+        config.trust_dscp_from = Some(vec!["10.0.0.0/8".parse().unwrap()]);
+        let mut ef_rule = icmp_rule("voice", 7, None, None);
+        ef_rule.match_criteria.protocol = None;
+        ef_rule.match_criteria.dscp = Some(46);
+        config.rules = vec![ef_rule];
+        let qos_engine = QosEngine::from_config(&config);
+        
+        let marked_from = |source_ip: &str, dscp: u8| PacketInfo {
+            source_ip: source_ip.to_string(),
+            dscp: Some(dscp),
+            ..packet("UDP", Some(5004))
+        };
+        assert_eq!(qos_engine.classify_packet(&marked_from("10.1.2.3", 46)).unwrap().name, "voice");
+        assert!(qos_engine.classify_packet(&marked_from("192.168.1.100", 46)).is_none());
+        assert!(qos_engine.classify_packet(&marked_from("not-an-ip", 46)).is_none());
+        let unmarked = PacketInfo { dscp: None, ..marked_from("10.1.2.3", 46) };
+        assert!(qos_engine.classify_packet(&unmarked).is_none());
+        
+        // Untrusted markings don't reach the DSCP priority table either
+        assert_eq!(qos_engine.get_priority(&marked_from("10.1.2.3", 48)), 7);
+        assert_eq!(qos_engine.get_priority(&marked_from("192.168.1.100", 48)), config.default_priority);
+    }
+    
+    #[test]
+    fn test_trust_boundary_parsed_from_config() {
+        let config: QosConfig = serde_yaml::from_str("trust_dscp_from: [\"10.0.0.0/8\", \"fd00::/8\"]\n").unwrap();
+        let trusted = config.trust_dscp_from.unwrap();
+        assert_eq!(trusted[1].to_string(), "fd00::/8");
+        assert!(serde_yaml::from_str::<QosConfig>("trust_dscp_from: [\"10.0.0.0/33\"]\n").is_err());
+        assert!(Config::default().qos.trust_dscp_from.is_none());
+    }
 } 
\ No newline at end of file
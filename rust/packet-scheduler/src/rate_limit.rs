@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+/// Tokens are bytes, refilled continuously at `rate` bytes/sec up to
+/// `burst` bytes.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full.
+    pub fn new(rate_bytes_per_sec: u64, burst_bytes: u64, now: Instant) -> Self {
+        Self {
+            rate: rate_bytes_per_sec as f64,
+            burst: burst_bytes as f64,
+            tokens: burst_bytes as f64,
+            last_refill: now,
+        }
+    }
+
+    /// A bucket holding `burst` worth of traffic at its rate, but never less
+    /// than `min_burst_bytes` so a full-size packet always fits.
+    pub fn with_burst_time(rate_bytes_per_sec: u64, burst: Duration, min_burst_bytes: u64, now: Instant) -> Self {
+        let burst_bytes = ((rate_bytes_per_sec as f64 * burst.as_secs_f64()) as u64).max(min_burst_bytes);
+        Self::new(rate_bytes_per_sec, burst_bytes, now)
+    }
+
+    pub fn rate_bytes_per_sec(&self) -> u64 {
+        self.rate as u64
+    }
+
+    pub fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = self.last_refill.max(now);
+    }
+
+    pub fn has(&self, bytes: usize) -> bool {
+        self.tokens >= bytes as f64
+    }
+
+    /// How long until `bytes` tokens will have built up, as of the last
+    /// refill. Zero if they already have.
+    pub fn wait_for(&self, bytes: usize) -> Duration {
+        let missing = bytes as f64 - self.tokens;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        if self.rate <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64(missing / self.rate)
+    }
+
+    /// Removes `bytes` tokens, which may leave the bucket in debt.
+    pub fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    /// Refills, then takes `bytes` if that many tokens are available.
+    pub fn try_take(&mut self, bytes: usize, now: Instant) -> bool {
+        self.refill(now);
+        if !self.has(bytes) {
+            return false;
+        }
+        self.take(bytes);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_at_rate_up_to_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, 500, start);
+        assert!(bucket.try_take(500, start));
+        assert!(!bucket.try_take(1, start));
+
+        assert!(!bucket.try_take(200, start + Duration::from_millis(100)));
+        assert!(bucket.try_take(200, start + Duration::from_millis(200)));
+
+        // Idle time only refills up to the burst
+        assert!(!bucket.try_take(501, start + Duration::from_secs(10)));
+        assert!(bucket.try_take(500, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_wait_for_counts_missing_tokens_at_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, 500, start);
+        assert_eq!(bucket.wait_for(500), Duration::ZERO);
+        bucket.take(500);
+        assert_eq!(bucket.wait_for(250), Duration::from_millis(250));
+    }
+}
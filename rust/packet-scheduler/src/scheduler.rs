@@ -1,87 +1,646 @@
+use crate::alerting::alert_on_link_events;
+use crate::channel::{bounded_with_policy, PolicySender, SendOutcome};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{LinkConfig, SCHEDULER_ALGORITHMS};
+use crate::destination::DestinationMetrics;
+use crate::events::LinkEvent;
+use crate::failover::{evict_down_links, FailoverManager, LinkState};
+use crate::flow::{FlowKey, FlowTable, PinnedFlow};
+use crate::htb::{build_shapers, HierarchicalTokenBucket};
+use crate::ipfix::IpfixExporter;
+use crate::load_shed::{LoadMode, LoadShedder};
+use crate::log_limit::RateLimitedLogger;
+use crate::metrics_client::{MetricsClient, RemoteMetricsSource, UnderlayClient};
+use crate::metrics_provider::MetricsProvider;
+use crate::packet_id::PacketIdAllocator;
+#[cfg(feature = "pcap")]
+use crate::pcap::PcapExporter;
+use crate::policy::{PolicyDecision, PolicyRoutes};
+use crate::proto::{
+    DebugStateRequest, DebugStateResponse, DebugStateService, ExplainSelectionRequest, ExplainSelectionResponse,
+    GroupHealthRequest, GroupHealthResponse, GroupHealthService, SelectionExplanationService,
+};
+use crate::protocol::Protocol;
+use crate::qos::{trusted_dscp, DefaultPriorities};
+use crate::stats::{SchedulerStats, StatsSnapshot};
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::transport::{PacketTransport, UdpTunnelReceiver, UdpTunnelTransport};
+use crate::work_queue::WorkQueues;
 use crate::{Config, LinkMetrics, QosRule};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
 use dashmap::DashMap;
-use parking_lot::RwLock;
-use std::collections::HashMap;
+use parking_lot::{Mutex, RwLock};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
 use chrono::{DateTime, Utc};
-use tracing::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
 
+#[derive(Clone)]
 pub struct Packet {
     pub id: u64,
     pub data: Vec<u8>,
     pub priority: u8,
     pub source_ip: String,
     pub dest_ip: String,
-    pub protocol: String,
+    pub protocol: Protocol,
+    /// Transport ports, for protocols that have them.
+    pub source_port: Option<u16>,
+    pub dest_port: Option<u16>,
+    /// DSCP codepoint as marked by the sender, if known.
+    pub dscp: Option<u8>,
+    /// ICMP message type and code, for ICMP packets.
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    /// Outer and, for QinQ, inner 802.1Q VLAN IDs, for L2 overlays.
+    pub vlan_id: Option<u16>,
+    pub inner_vlan_id: Option<u16>,
     pub timestamp: DateTime<Utc>,
 }
 
+/// How a drained link stops carrying traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DrainMode {
+    /// Exclude the link immediately, moving its pinned flows elsewhere.
+    Hard,
+    /// Stop assigning new flows but let pinned flows finish; becomes `Hard`
+    /// once the link's last flow idles out.
+    Soft,
+}
+
 pub struct ScheduledPacket {
     pub packet: Packet,
     pub link_name: String,
     pub sequence_number: u64,
 }
 
+/// Feeds packets into a scheduler from other tasks, numbering each from the
+/// scheduler's packet id allocator. Clones feed the same scheduler.
+#[derive(Clone)]
+pub struct PacketIntake {
+    sender: Sender<Packet>,
+    packet_ids: Arc<PacketIdAllocator>,
+}
+
+impl PacketIntake {
+    /// Queues a packet for scheduling under a fresh packet id; fails if the
+    /// intake queue is full.
+    pub fn enqueue(&self, mut packet: Packet) -> Result<()> {
+        packet.id = self.packet_ids.next();
+        self.sender.try_send(packet)
+            .map_err(|_| anyhow::anyhow!("Packet intake queue is full"))
+    }
+}
+
+/// A scheduled packet waiting in its link's output queue, with how to
+/// handle a failed send.
+struct QueuedPacket {
+    scheduled: ScheduledPacket,
+    /// Retry on other links if the send fails; duplicates aren't.
+    retry: bool,
+    /// The class's algorithm override, for picking a retry link.
+    algorithm: Option<String>,
+}
+
+/// Output queue of one link, drained by its own task into the transport so
+/// a backlog on one link doesn't hold up packets for the others.
+struct LinkQueue {
+    sender: PolicySender<QueuedPacket>,
+    receiver: Receiver<QueuedPacket>,
+    /// Signalled when a packet is queued, waking the drain task.
+    queued: Notify,
+    /// Signalled when a packet is taken off, waking a sender waiting for room
+    /// under `OverflowPolicy::Block`.
+    room: Notify,
+    /// Whether a drain task has been started for the queue.
+    draining: AtomicBool,
+}
+
+impl LinkQueue {
+    fn new(capacity: usize, policy: crate::channel::OverflowPolicy) -> Self {
+        let (sender, receiver) = bounded_with_policy(capacity, policy);
+        Self {
+            sender,
+            receiver,
+            queued: Notify::new(),
+            room: Notify::new(),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Next queued packet, if any, making room for a waiting sender.
+    fn take(&self) -> Option<QueuedPacket> {
+        let queued = self.receiver.try_recv().ok()?;
+        self.room.notify_one();
+        Some(queued)
+    }
+}
+
+/// How a selector arrived at one link's score. The components add up to
+/// `score`; metrics a selector doesn't weigh contribute 0.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkScoreBreakdown {
+    pub link_name: String,
+    pub score: f64,
+    pub latency: f64,
+    pub bandwidth: f64,
+    pub loss: f64,
+    pub jitter: f64,
+    pub selected: bool,
+}
+
+impl LinkScoreBreakdown {
+    /// Breakdown of the health score shared by the built-in selectors: the
+    /// mean of the latency, bandwidth and loss scores, scaled by reliability.
+    fn health(link_name: &str, metric: &LinkMetrics) -> Self {
+        let share = metric.reliability / 3.0;
+        Self {
+            link_name: link_name.to_string(),
+            score: metric.health_score(),
+            latency: 1.0 / (1.0 + metric.latency_ms) * share,
+            bandwidth: metric.bandwidth_score() * share,
+            loss: (1.0 - metric.packet_loss) * share,
+            jitter: 0.0,
+            selected: false,
+        }
+    }
+}
+
+/// Everything the scheduler decides with, for diagnosing it after the fact.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchedulerDebugState {
+    pub timestamp: DateTime<Utc>,
+    /// Links eligible for selection, as of the latest metrics report.
+    pub metrics: HashMap<String, LinkMetrics>,
+    /// Sorted by name.
+    pub qos_rules: Vec<QosRule>,
+    pub flows: Vec<PinnedFlow>,
+    pub drained_links: HashMap<String, DrainMode>,
+    pub link_multipliers: HashMap<String, f64>,
+    /// Failover status and recent history of each link.
+    pub link_states: HashMap<String, LinkState>,
+    pub load_mode: LoadMode,
+    /// `LinkSelector::name` of the scheduler's selector.
+    pub selector: String,
+    pub stats: StatsSnapshot,
+}
+
 #[async_trait]
 pub trait LinkSelector {
+    /// Identifies the selector in logs and debug output; for the built-in
+    /// selectors, the `scheduler.algorithm` that chooses it.
+    fn name(&self) -> &str;
+    
     async fn select_link(&self, packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String>;
+    
+    /// Selects from metrics specific to this packet, such as those towards
+    /// its destination, which must neither come from nor go into anything
+    /// cached between metrics reports.
+    async fn select_link_uncached(&self, packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        self.select_link(packet, metrics).await
+    }
+    
+    /// Called whenever the scheduler installs a new metrics report, so
+    /// selectors caching work derived from the previous one can drop it.
+    fn metrics_updated(&self) {}
+    
+    /// Score breakdown of each usable link in `metrics`, for debugging
+    /// selections. Empty for selectors that can't explain themselves.
+    fn explain(&self, _metrics: &HashMap<String, LinkMetrics>) -> Vec<LinkScoreBreakdown> {
+        Vec::new()
+    }
+}
+
+#[async_trait]
+impl<T: LinkSelector + Send + Sync> LinkSelector for Arc<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+    
+    async fn select_link(&self, packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        (**self).select_link(packet, metrics).await
+    }
+    
+    async fn select_link_uncached(&self, packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        (**self).select_link_uncached(packet, metrics).await
+    }
+    
+    fn metrics_updated(&self) {
+        (**self).metrics_updated()
+    }
+    
+    fn explain(&self, metrics: &HashMap<String, LinkMetrics>) -> Vec<LinkScoreBreakdown> {
+        (**self).explain(metrics)
+    }
 }
 
 pub struct WeightedRoundRobinSelector {
     current_weights: Arc<RwLock<HashMap<String, f64>>>,
+    /// Score margin a challenger must beat the last choice by to replace it.
+    hysteresis: f64,
+    last_choice: RwLock<Option<String>>,
+    /// Configured `LinkConfig::weight` per link, used to break score ties.
+    link_weights: HashMap<String, f64>,
+    rng: Mutex<StdRng>,
+    /// Link scores per priority class, kept until the next metrics update.
+    /// `None` when caching is disabled and every packet rescores the links.
+    rankings: Option<RwLock<HashMap<u8, CachedRanking>>>,
+    scoring_runs: AtomicU64,
+}
+
+/// Health score per link.
+type Ranking = Arc<HashMap<String, f64>>;
+
+/// A ranking and the candidate links it was scored from. Callers that
+/// filter candidates (retries, drains, in-flight caps) mustn't be served a
+/// ranking that still holds the links they left out.
+struct CachedRanking {
+    candidates: HashSet<String>,
+    ranking: Ranking,
+}
+
+impl CachedRanking {
+    fn scored_for(&self, metrics: &HashMap<String, LinkMetrics>) -> bool {
+        self.candidates.len() == metrics.len() && metrics.keys().all(|name| self.candidates.contains(name))
+    }
 }
 
+/// Scores closer than this are treated as equal.
+const SCORE_EPSILON: f64 = 1e-9;
+
 impl WeightedRoundRobinSelector {
     pub fn new() -> Self {
+        Self::with_hysteresis(0.0)
+    }
+    
+    pub fn with_hysteresis(hysteresis: f64) -> Self {
         Self {
             current_weights: Arc::new(RwLock::new(HashMap::new())),
+            hysteresis,
+            last_choice: RwLock::new(None),
+            link_weights: HashMap::new(),
+            rng: Mutex::new(StdRng::from_entropy()),
+            rankings: None,
+            scoring_runs: AtomicU64::new(0),
+        }
+    }
+    
+    /// Scores links once per metrics update and priority class instead of
+    /// once per packet. Metrics changes only take effect after
+    /// `metrics_updated`, which the scheduler calls on every new report.
+    pub fn with_ranking_cache(mut self) -> Self {
+        self.rankings = Some(RwLock::new(HashMap::new()));
+        self
+    }
+    
+    /// How many times the links have been scored.
+    pub fn scoring_runs(&self) -> u64 {
+        self.scoring_runs.load(Ordering::Relaxed)
+    }
+    
+    /// Breaks ties between equally-scored links by a weighted-random pick
+    /// over their configured weights. Unlisted links weigh 1.0.
+    pub fn with_link_weights(mut self, links: &[LinkConfig]) -> Self {
+        self.link_weights = links.iter().map(|link| (link.name.clone(), link.weight)).collect();
+        self
+    }
+    
+    /// Seeds the tiebreak RNG for reproducible selection sequences.
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng = Mutex::new(StdRng::seed_from_u64(seed));
+        self
+    }
+    
+    /// Picks among `tied` in proportion to their configured weights.
+    fn weighted_tiebreak(&self, mut tied: Vec<String>) -> String {
+        // Sort so a given seed yields the same picks regardless of map order
+        tied.sort();
+        let weights = tied.iter().map(|name| self.link_weights.get(name).copied().unwrap_or(1.0).max(0.0));
+        match WeightedIndex::new(weights) {
+            Ok(distribution) => tied.swap_remove(distribution.sample(&mut *self.rng.lock())),
+            // All configured weights are zero; fall back to the first
+            Err(_) => tied.swap_remove(0),
         }
     }
 }
 
+impl Default for WeightedRoundRobinSelector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait]
 impl LinkSelector for WeightedRoundRobinSelector {
-    async fn select_link(&self, _packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
-        let mut weights = self.current_weights.write();
-        
-        // Update weights based on current metrics
-        for (link_name, metric) in metrics {
-            let health_score = self.calculate_health_score(metric);
-            weights.insert(link_name.clone(), health_score);
+    fn name(&self) -> &str {
+        "weighted_round_robin"
+    }
+    
+    async fn select_link(&self, packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        self.choose(&self.ranking(packet.priority, metrics))
+    }
+    
+    async fn select_link_uncached(&self, _packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        self.choose(&self.score_links(metrics))
+    }
+    
+    fn metrics_updated(&self) {
+        if let Some(ref rankings) = self.rankings {
+            rankings.write().clear();
         }
-        
+    }
+    
+    fn explain(&self, metrics: &HashMap<String, LinkMetrics>) -> Vec<LinkScoreBreakdown> {
+        metrics.iter()
+            .filter(|(_, metric)| !metric.is_down())
+            .map(|(name, metric)| LinkScoreBreakdown::health(name, metric))
+            .collect()
+    }
+}
+
+impl WeightedRoundRobinSelector {
+    /// The highest-scored link in `weights`, subject to tiebreaks and
+    /// hysteresis.
+    fn choose(&self, weights: &HashMap<String, f64>) -> Result<String> {
         // Select link with highest weight
-        let selected = weights.iter()
+        let (best, best_score) = weights.iter()
             .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
-            .map(|(name, _)| name.clone())
+            .map(|(name, score)| (name.clone(), *score))
             .ok_or_else(|| anyhow::anyhow!("No available links"))?;
+        
+        // Equal scores carry no preference, so honour the configured weights
+        let tied: Vec<String> = weights.iter()
+            .filter(|(_, score)| best_score - **score <= SCORE_EPSILON)
+            .map(|(name, _)| name.clone())
+            .collect();
+        if tied.len() > 1 {
+            let selected = self.weighted_tiebreak(tied);
+            *self.last_choice.write() = Some(selected.clone());
+            return Ok(selected);
+        }
+        
+        // Stick with the previous link unless the best one clearly beats it
+        let mut last_choice = self.last_choice.write();
+        let selected = match last_choice.as_ref().and_then(|last| weights.get(last).map(|score| (last, *score))) {
+            Some((last, last_score)) if best_score <= last_score + self.hysteresis => last.clone(),
+            _ => best,
+        };
+        *last_choice = Some(selected.clone());
             
         Ok(selected)
     }
+    
+    /// Link scores for `class`, served from the cache when enabled and it
+    /// was scored from the same candidate links.
+    fn ranking(&self, class: u8, metrics: &HashMap<String, LinkMetrics>) -> Ranking {
+        let cached = self.rankings.as_ref().and_then(|rankings| {
+            rankings.read().get(&class)
+                .filter(|cached| cached.scored_for(metrics))
+                .map(|cached| cached.ranking.clone())
+        });
+        if let Some(ranking) = cached {
+            return ranking;
+        }
+        
+        let ranking = Arc::new(self.score_links(metrics));
+        if let Some(ref rankings) = self.rankings {
+            rankings.write().insert(class, CachedRanking {
+                candidates: metrics.keys().cloned().collect(),
+                ranking: ranking.clone(),
+            });
+        }
+        ranking
+    }
+    
+    fn score_links(&self, metrics: &HashMap<String, LinkMetrics>) -> HashMap<String, f64> {
+        self.scoring_runs.fetch_add(1, Ordering::Relaxed);
+        let mut weights = self.current_weights.write();
+        
+        // Links that stopped reporting are no longer candidates
+        weights.retain(|link_name, _| metrics.contains_key(link_name));
+        
+        // Update weights based on current metrics
+        for (link_name, metric) in metrics {
+            // Down links are never selected, however good their latency
+            if metric.is_down() {
+                weights.remove(link_name);
+                continue;
+            }
+            weights.insert(link_name.clone(), metric.health_score());
+        }
+        
+        weights.clone()
+    }
 }
 
-impl WeightedRoundRobinSelector {
-    fn calculate_health_score(&self, metric: &LinkMetrics) -> f64 {
-        let latency_score = 1.0 / (1.0 + metric.latency_ms as f64);
-        let bandwidth_score = metric.bandwidth_mbps / 1000.0; // Normalize to 1Gbps
-        let loss_score = 1.0 - metric.packet_loss;
+/// Spreads packets across all usable links at random, in proportion to their
+/// health scores. Seed it for reproducible selection sequences.
+pub struct WeightedEcmpSelector {
+    rng: Mutex<StdRng>,
+}
+
+impl WeightedEcmpSelector {
+    /// Seeds from `seed` if given, otherwise from OS entropy.
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self { rng: Mutex::new(rng) }
+    }
+}
+
+#[async_trait]
+impl LinkSelector for WeightedEcmpSelector {
+    fn name(&self) -> &str {
+        "weighted_ecmp"
+    }
+    
+    async fn select_link(&self, _packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        // Sort so a given seed yields the same picks regardless of map order
+        let mut candidates: Vec<(&String, f64)> = metrics.iter()
+            .filter(|(_, metric)| !metric.is_down())
+            .map(|(name, metric)| (name, metric.health_score()))
+            .filter(|(_, score)| *score > 0.0)
+            .collect();
+        candidates.sort_by(|a, b| a.0.cmp(b.0));
+        
+        let distribution = WeightedIndex::new(candidates.iter().map(|(_, score)| *score))
+            .map_err(|_| anyhow::anyhow!("No available links"))?;
+        let index = distribution.sample(&mut *self.rng.lock());
         
-        (latency_score + bandwidth_score + loss_score) / 3.0
+        Ok(candidates[index].0.clone())
+    }
+    
+    fn explain(&self, metrics: &HashMap<String, LinkMetrics>) -> Vec<LinkScoreBreakdown> {
+        metrics.iter()
+            .filter(|(_, metric)| !metric.is_down())
+            .map(|(name, metric)| LinkScoreBreakdown::health(name, metric))
+            .collect()
+    }
+}
+
+/// Picks the link with the best estimated voice quality (`mos_score`),
+/// scaled by reliability, for classes carrying VoIP. Ties go to the first
+/// name.
+pub struct MosSelector;
+
+#[async_trait]
+impl LinkSelector for MosSelector {
+    fn name(&self) -> &str {
+        "mos"
+    }
+    
+    async fn select_link(&self, _packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        metrics.iter()
+            .filter(|(_, metric)| !metric.is_down())
+            .map(|(name, metric)| (name, metric.mos_score() * metric.reliability))
+            .filter(|(_, score)| *score > 0.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| anyhow::anyhow!("No available links"))
+    }
+}
+
+/// Builds the selector for a `scheduler.algorithm` (one of
+/// `SCHEDULER_ALGORITHMS`), configured from `config.scheduler`.
+pub fn build_selector(algorithm: &str, config: &Config) -> Result<Box<dyn LinkSelector + Send + Sync>> {
+    match algorithm {
+        "weighted_round_robin" => {
+            let selector = WeightedRoundRobinSelector::with_hysteresis(config.scheduler.selection_hysteresis)
+                .with_link_weights(&config.links)
+                .with_ranking_cache();
+            Ok(Box::new(match config.scheduler.rng_seed {
+                Some(seed) => selector.with_rng_seed(seed),
+                None => selector,
+            }))
+        }
+        "weighted_ecmp" => Ok(Box::new(WeightedEcmpSelector::new(config.scheduler.rng_seed))),
+        "mos" => Ok(Box::new(MosSelector)),
+        _ => Err(anyhow::anyhow!("Unknown scheduler algorithm: {}", algorithm)),
+    }
+}
+
+/// Picks the available link with the highest configured weight, without
+/// scoring metrics. Used while shedding load. Unlisted links weigh 1.0 and
+/// ties go to the first name.
+pub struct StaticWeightSelector {
+    link_weights: HashMap<String, f64>,
+}
+
+impl StaticWeightSelector {
+    pub fn new(links: &[LinkConfig]) -> Self {
+        Self {
+            link_weights: links.iter().map(|link| (link.name.clone(), link.weight)).collect(),
+        }
     }
 }
 
+#[async_trait]
+impl LinkSelector for StaticWeightSelector {
+    fn name(&self) -> &str {
+        "static_weight"
+    }
+    
+    async fn select_link(&self, _packet: &Packet, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        metrics.iter()
+            .filter(|(_, metric)| !metric.is_down())
+            .map(|(name, _)| (name, self.link_weights.get(name).copied().unwrap_or(1.0)))
+            .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| anyhow::anyhow!("No available links"))
+    }
+}
+
+/// Minimum time between repeats of the same hot-path error log.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often metrics collection retries a report the full channel refused.
+const METRICS_BACKPRESSURE_POLL: Duration = Duration::from_millis(10);
+
+/// Link chosen by the latest selector run, the candidates it chose from and
+/// the class's algorithm override.
+struct LastSelection {
+    link_name: String,
+    candidates: HashMap<String, LinkMetrics>,
+    algorithm: Option<String>,
+}
+
 pub struct PacketScheduler {
     config: Config,
     link_selector: Box<dyn LinkSelector + Send + Sync>,
+    shadow_selector: Option<Box<dyn LinkSelector + Send + Sync>>,
+    /// One selector per algorithm, for QoS classes that override
+    /// `link_selector`.
+    class_selectors: HashMap<String, Box<dyn LinkSelector + Send + Sync>>,
+    /// Replaces `link_selector` while shedding load.
+    static_selector: StaticWeightSelector,
+    load_shedder: Option<LoadShedder>,
+    stats: Arc<SchedulerStats>,
     metrics_receiver: Receiver<HashMap<String, LinkMetrics>>,
-    packet_sender: Sender<ScheduledPacket>,
+    /// Scheduled packets per link, waiting for the link's drain task to hand
+    /// them to the transport.
+    link_queues: DashMap<String, Arc<LinkQueue>>,
+    intake: PacketIntake,
+    work_queues: WorkQueues,
+    /// Links eligible for selection, as of the latest metrics report.
+    current_metrics: Arc<RwLock<Arc<HashMap<String, LinkMetrics>>>>,
+    /// Per-destination metrics layered over `current_metrics` when
+    /// selecting for a packet.
+    destination_metrics: DestinationMetrics,
+    transport: Option<Arc<dyn PacketTransport + Send + Sync>>,
+    /// Receiving end whose reassembly timeout follows the reorder window.
+    tunnel_receiver: Option<Arc<UdpTunnelReceiver>>,
+    #[cfg(feature = "pcap")]
+    pcap: Option<Mutex<PcapExporter>>,
+    ipfix: Option<Mutex<IpfixExporter>>,
     qos_rules: Arc<DashMap<String, QosRule>>,
-    sequence_counter: Arc<RwLock<u64>>,
-    running: Arc<RwLock<bool>>,
+    /// Priorities of packets no QoS rule matches.
+    default_priorities: DefaultPriorities,
+    failover: Arc<RwLock<FailoverManager>>,
+    flow_table: Arc<FlowTable>,
+    drained_links: Arc<DashMap<String, DrainMode>>,
+    policy_routes: PolicyRoutes,
+    /// Egress shaper per shaped link, holding packets until their class may
+    /// send. Rebuilt when QoS rules change.
+    shapers: RwLock<HashMap<String, Mutex<HierarchicalTokenBucket<QueuedPacket>>>>,
+    /// Signalled when a shaper holds packets back, waking the shaping task.
+    shaper_queued: Notify,
+    /// Operator-set factors in 0.0-1.0 on link scores; absent means 1.0.
+    link_multipliers: Arc<DashMap<String, f64>>,
+    /// `max_in_flight` and `in_flight_timeout_ms` of each capped link.
+    in_flight_caps: HashMap<String, (usize, chrono::Duration)>,
+    /// Packets sent on capped links and not yet acknowledged, by sequence
+    /// number, with when they were sent.
+    in_flight: Arc<DashMap<String, HashMap<u64, DateTime<Utc>>>>,
+    /// Multipliers of the links' time windows active at the last selection.
+    time_multipliers: Mutex<HashMap<String, f64>>,
+    /// Packet deadlines, flow idling and time windows are judged against it.
+    clock: Arc<dyn Clock>,
+    last_selected: Arc<DashMap<String, DateTime<Utc>>>,
+    /// Kept for `explain_last_selection`.
+    last_selection: Mutex<Option<LastSelection>>,
+    sequence_counter: AtomicU64,
+    packet_ids: Arc<PacketIdAllocator>,
+    /// Throttles per-packet error logs while a link or exporter is failing.
+    log_limit: RateLimitedLogger,
+    /// The underlay manager metrics come from, told which links are idle.
+    /// Unset when metrics come from elsewhere.
+    underlay: Option<Arc<UnderlayClient>>,
+    /// Supervisor of the metrics collection task; finishes early only if the
+    /// task keeps failing.
+    metrics_task: Mutex<Option<JoinHandle<Result<()>>>>,
+    shutdown: CancellationToken,
 }
 
 impl PacketScheduler {
@@ -89,177 +648,3447 @@ impl PacketScheduler {
         config: Config,
         underlay_endpoint: String,
     ) -> Result<Self> {
-        let (metrics_sender, metrics_receiver) = bounded(100);
-        let (packet_sender, _packet_receiver) = bounded(config.scheduler.max_queue_size);
+        let link_selector = build_selector(&config.scheduler.algorithm, &config)?;
+        Self::with_selector(config, underlay_endpoint, link_selector).await
+    }
+    
+    /// The selector for a class overriding the algorithm, else the
+    /// scheduler's own.
+    fn selector(&self, algorithm: Option<&str>) -> &(dyn LinkSelector + Send + Sync) {
+        match algorithm.and_then(|algorithm| self.class_selectors.get(algorithm)) {
+            Some(selector) => selector.as_ref(),
+            None => self.link_selector.as_ref(),
+        }
+    }
+    
+    /// Creates a scheduler with a caller-provided link selector instead of
+    /// the one named by `scheduler.algorithm`.
+    pub async fn with_selector(
+        config: Config,
+        underlay_endpoint: String,
+        link_selector: Box<dyn LinkSelector + Send + Sync>,
+    ) -> Result<Self> {
+        let timeout = Duration::from_millis(config.scheduler.grpc_timeout_ms);
+        let underlay = Arc::new(UnderlayClient::new(&underlay_endpoint, timeout));
+        let metrics_client = MetricsClient::new(Arc::new(RemoteMetricsSource::new(underlay.clone())), timeout);
+        let mut scheduler = Self::with_selector_and_provider(config, link_selector, Arc::new(metrics_client)).await?;
+        scheduler.underlay = Some(underlay);
+        Ok(scheduler)
+    }
+    
+    /// Creates a scheduler that takes link metrics from `metrics_provider`
+    /// instead of the underlay manager.
+    pub async fn with_metrics_provider(config: Config, metrics_provider: Arc<dyn MetricsProvider>) -> Result<Self> {
+        let link_selector = build_selector(&config.scheduler.algorithm, &config)?;
+        Self::with_selector_and_provider(config, link_selector, metrics_provider).await
+    }
+    
+    pub async fn with_selector_and_provider(
+        config: Config,
+        link_selector: Box<dyn LinkSelector + Send + Sync>,
+        metrics_provider: Arc<dyn MetricsProvider>,
+    ) -> Result<Self> {
+        let shadow_selector = match config.scheduler.shadow_algorithm {
+            Some(ref algorithm) => Some(build_selector(algorithm, &config)?),
+            None => None,
+        };
+        let class_selectors = SCHEDULER_ALGORITHMS.iter()
+            .map(|algorithm| Ok((algorithm.to_string(), build_selector(algorithm, &config)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        
+        let overflow_policy = config.scheduler.overflow_policy;
+        let (metrics_sender, metrics_receiver) = bounded_with_policy(config.scheduler.metrics_channel_capacity, overflow_policy);
+        let packet_capacity = config.scheduler.packet_channel_capacity.unwrap_or(config.scheduler.max_queue_size);
+        let link_queues = config.links.iter()
+            .map(|link| (link.name.clone(), Arc::new(LinkQueue::new(packet_capacity, overflow_policy))))
+            .collect();
+        let (intake_sender, intake_receiver) = bounded(config.scheduler.max_queue_size);
+        let packet_ids = Arc::new(PacketIdAllocator::new());
+        let work_queues = WorkQueues::new(intake_receiver, config.scheduler.workers, config.scheduler.batch_size);
         
         // Initialize QoS rules
         let qos_rules = Arc::new(DashMap::new());
         for rule in &config.qos.rules {
             qos_rules.insert(rule.name.clone(), rule.clone());
         }
+        let default_priorities = DefaultPriorities::from_config(&config.qos);
         
-        // Start metrics collection
-        Self::start_metrics_collection(underlay_endpoint, metrics_sender).await?;
+        let load_shedder = match config.scheduler.load_shedding {
+            Some(ref load_shedding) => Some(LoadShedder::new(load_shedding)?),
+            None => None,
+        };
         
-        let link_selector: Box<dyn LinkSelector + Send + Sync> = match config.scheduler.algorithm.as_str() {
-            "weighted_round_robin" => Box::new(WeightedRoundRobinSelector::new()),
-            _ => return Err(anyhow::anyhow!("Unknown scheduler algorithm: {}", config.scheduler.algorithm)),
+        let policy_routes = PolicyRoutes::new(&config.policy_routes, &config.links)?;
+        let shapers = build_shapers(&config.shaping, &config.links, &config.qos.rules, packet_capacity, std::time::Instant::now())?
+            .into_iter()
+            .map(|(link, shaper)| (link, Mutex::new(shaper)))
+            .collect::<HashMap<_, _>>();
+        
+        let ipfix = match config.ipfix {
+            Some(ref ipfix) => Some(Mutex::new(IpfixExporter::new(ipfix)?)),
+            None => None,
         };
         
+        // Start metrics collection
+        let shutdown = CancellationToken::new();
+        let metrics_task = Self::start_metrics_collection(metrics_provider, metrics_sender, shutdown.clone()).await?;
+        
+        let failover = Arc::new(RwLock::new(FailoverManager::new(config.failover.clone()).with_groups(&config.links)));
+        let current_metrics = Arc::new(RwLock::new(Arc::new(HashMap::new())));
+        let events = failover.read().events().clone();
+        supervise("link alerts", RestartPolicy::default(), shutdown.clone(), {
+            let events = events.clone();
+            move || alert_on_link_events(events.subscribe())
+        });
+        supervise("failover events", RestartPolicy::default(), shutdown.clone(), {
+            let current_metrics = current_metrics.clone();
+            move || evict_down_links(events.subscribe(), current_metrics.clone())
+        });
+        let flow_table = Arc::new(FlowTable::new(config.scheduler.flow_idle_timeout));
+        let in_flight_caps = config.links.iter()
+            .filter_map(|link| {
+                let timeout = chrono::Duration::milliseconds(link.in_flight_timeout_ms as i64);
+                Some((link.name.clone(), (link.max_in_flight?, timeout)))
+            })
+            .collect();
+        
+        let destination_metrics = DestinationMetrics::new(chrono::Duration::milliseconds(config.scheduler.destination_metrics_ttl as i64));
         Ok(Self {
+            static_selector: StaticWeightSelector::new(&config.links),
             config,
             link_selector,
+            shadow_selector,
+            class_selectors,
+            load_shedder,
+            stats: Arc::new(SchedulerStats::new()),
             metrics_receiver,
-            packet_sender,
+            link_queues,
+            intake: PacketIntake { sender: intake_sender, packet_ids: packet_ids.clone() },
+            work_queues,
+            current_metrics,
+            destination_metrics,
+            transport: None,
+            tunnel_receiver: None,
+            #[cfg(feature = "pcap")]
+            pcap: None,
+            ipfix,
             qos_rules,
-            sequence_counter: Arc::new(RwLock::new(0)),
-            running: Arc::new(RwLock::new(true)),
+            default_priorities,
+            failover,
+            flow_table,
+            drained_links: Arc::new(DashMap::new()),
+            policy_routes,
+            shapers: RwLock::new(shapers),
+            shaper_queued: Notify::new(),
+            link_multipliers: Arc::new(DashMap::new()),
+            in_flight_caps,
+            in_flight: Arc::new(DashMap::new()),
+            time_multipliers: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            last_selected: Arc::new(DashMap::new()),
+            last_selection: Mutex::new(None),
+            sequence_counter: AtomicU64::new(0),
+            packet_ids,
+            log_limit: RateLimitedLogger::new(LOG_INTERVAL),
+            underlay: None,
+            metrics_task: Mutex::new(Some(metrics_task)),
+            shutdown,
         })
     }
     
+    /// Polls the provider once per second, handing each report to `run`.
     async fn start_metrics_collection(
-        _endpoint: String,
-        sender: Sender<HashMap<String, LinkMetrics>>,
-    ) -> Result<()> {
-        // TODO: Implement gRPC client to underlay manager
-        tokio::spawn(async move {
-            loop {
-                // Simulate metrics collection
-                let mut metrics = HashMap::new();
-                metrics.insert("eth0".to_string(), LinkMetrics {
-                    latency_ms: 10.0,
-                    jitter_ms: 2.0,
-                    packet_loss: 0.001,
-                    bandwidth_mbps: 100.0,
-                    timestamp: Utc::now(),
-                });
-                metrics.insert("eth1".to_string(), LinkMetrics {
-                    latency_ms: 15.0,
-                    jitter_ms: 3.0,
-                    packet_loss: 0.002,
-                    bandwidth_mbps: 50.0,
-                    timestamp: Utc::now(),
-                });
-                
-                if let Err(e) = sender.send(metrics) {
-                    error!("Failed to send metrics: {}", e);
+        provider: Arc<dyn MetricsProvider>,
+        sender: PolicySender<HashMap<String, LinkMetrics>>,
+        shutdown: CancellationToken,
+    ) -> Result<JoinHandle<Result<()>>> {
+        let sender = Arc::new(sender);
+        
+        Ok(supervise("metrics collection", RestartPolicy::default(), shutdown, move || {
+            let provider = provider.clone();
+            let sender = sender.clone();
+            async move {
+                loop {
+                    let mut report = provider.latest().await;
+                    // Under the block policy a full channel holds the next
+                    // poll back until the scheduler has taken a report, without
+                    // blocking the runtime thread
+                    loop {
+                        match sender.try_send(report) {
+                            Ok(SendOutcome::Sent) => break,
+                            Ok(outcome) => {
+                                debug!("Metrics channel full: {:?}", outcome);
+                                break;
+                            }
+                            Err(TrySendError::Full(rejected)) => {
+                                report = rejected;
+                                tokio::time::sleep(METRICS_BACKPRESSURE_POLL).await;
+                            }
+                            Err(TrySendError::Disconnected(_)) => return Err(anyhow::anyhow!("Metrics receiver dropped")),
+                        }
+                    }
+                    
+                    tokio::time::sleep(Duration::from_millis(1000)).await;
                 }
-                
-                tokio::time::sleep(Duration::from_millis(1000)).await;
             }
-        });
-        
-        Ok(())
+        }))
+    }
+    
+    /// Starts the scheduler on a background task. Cancelling the returned
+    /// token stops it; the handle resolves once every worker has exited.
+    pub fn spawn(self: Arc<Self>) -> (JoinHandle<Result<()>>, CancellationToken) {
+        let shutdown = self.shutdown_token();
+        (tokio::spawn(self.run()), shutdown)
     }
     
-    pub async fn run(&mut self) -> Result<()> {
-        info!("Starting packet scheduler with algorithm: {}", self.config.scheduler.algorithm);
+    /// Runs the scheduler until stopped: one worker task per configured
+    /// worker schedules packets while this task tracks link metrics.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        info!(
+            "Starting packet scheduler with algorithm: {} and {} workers",
+            self.config.scheduler.algorithm,
+            self.work_queues.workers()
+        );
         
-        let mut current_metrics = HashMap::new();
+        let workers: Vec<_> = (0..self.work_queues.workers())
+            .map(|worker| tokio::spawn(self.clone().run_worker(worker)))
+            .collect();
+        let mut drains = vec![tokio::spawn(self.clone().run_shapers())];
+        if let Some(ref underlay) = self.underlay {
+            drains.push(tokio::spawn(self.clone().report_idle_links(underlay.clone())));
+            drains.push(tokio::spawn(self.clone().poll_destination_metrics(underlay.clone())));
+        }
         
-        loop {
-            if !*self.running.read() {
+        while !self.shutdown.is_cancelled() {
+            // Links that only appear in metrics get their queue on first use
+            drains.extend(self.start_link_drains());
+            
+            // A worker only exits early on error, and metrics collection only
+            // once its supervisor gives up; bring everything down with them
+            if workers.iter().any(|worker| worker.is_finished())
+                || self.metrics_task.lock().as_ref().is_some_and(|task| task.is_finished())
+            {
                 break;
             }
             
-            // Update metrics
             if let Ok(metrics) = self.metrics_receiver.try_recv() {
-                current_metrics = metrics;
-                debug!("Updated link metrics: {:?}", current_metrics);
+                debug!("Updated link metrics: {:?}", metrics);
+                *self.current_metrics.write() = Arc::new(self.refresh_metrics(&metrics));
+                self.adapt_reassembly_timeout();
+                self.reap_idle_flows();
+                self.export_flow_records(self.clock.now());
             }
             
-            // Process packets (simulated)
-            self.process_packet_batch(&current_metrics).await?;
-            
-            tokio::time::sleep(Duration::from_millis(10)).await;
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+            }
+        }
+        
+        self.stop();
+        for worker in workers {
+            worker.await??;
+        }
+        for drain in drains {
+            drain.await?;
+        }
+        if let Some(ref ipfix) = self.ipfix {
+            if let Err(e) = ipfix.lock().flush() {
+                warn!("Failed to export final IPFIX records: {}", e);
+            }
+        }
+        let metrics_task = self.metrics_task.lock().take();
+        if let Some(metrics_task) = metrics_task {
+            metrics_task.await??;
         }
         
         Ok(())
     }
     
-    async fn process_packet_batch(&self, metrics: &HashMap<String, LinkMetrics>) -> Result<()> {
-        // Simulate packet processing
-        let packet = Packet {
-            id: 1,
-            data: vec![0u8; 1500],
-            priority: 5,
-            source_ip: "192.168.1.100".to_string(),
-            dest_ip: "192.168.1.200".to_string(),
-            protocol: "TCP".to_string(),
-            timestamp: Utc::now(),
-        };
-        
-        // Apply QoS rules
-        let _qos_rule = self.apply_qos_rules(&packet);
-        
-        // Select link
-        let link_name = self.link_selector.select_link(&packet, metrics).await?;
-        
-        // Create scheduled packet
-        let sequence_number = {
-            let mut counter = self.sequence_counter.write();
-            *counter += 1;
-            *counter
-        };
-        
-        let scheduled_packet = ScheduledPacket {
-            packet,
-            link_name,
-            sequence_number,
-        };
-        
-        // Send to next stage
-        if let Err(e) = self.packet_sender.send(scheduled_packet) {
-            error!("Failed to send scheduled packet: {}", e);
+    /// Starts a drain task for each link queue that doesn't have one yet.
+    fn start_link_drains(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        self.link_queues.iter()
+            .filter(|entry| !entry.value().draining.swap(true, Ordering::Relaxed))
+            .map(|entry| tokio::spawn(self.clone().drain_link_queue(entry.value().clone())))
+            .collect()
+    }
+    
+    /// Hands a link's queued packets to the transport, one at a time, until
+    /// the scheduler stops.
+    async fn drain_link_queue(self: Arc<Self>, queue: Arc<LinkQueue>) {
+        loop {
+            while let Some(queued) = queue.take() {
+                self.deliver(queued).await;
+            }
+            tokio::select! {
+                _ = queue.queued.notified() => {}
+                _ = self.shutdown.cancelled() => return,
+            }
+        }
+    }
+    
+    /// Sends whatever is queued on every link right away, for tests that
+    /// schedule without running the scheduler.
+    #[cfg(test)]
+    async fn flush_link_queues(&self) {
+        let queues: Vec<Arc<LinkQueue>> = self.link_queues.iter().map(|entry| entry.value().clone()).collect();
+        for queue in queues {
+            while let Some(queued) = queue.take() {
+                self.deliver(queued).await;
+            }
+        }
+    }
+    
+    async fn run_worker(self: Arc<Self>, worker: usize) -> Result<()> {
+        while !self.shutdown.is_cancelled() {
+            let metrics = self.current_metrics.read().clone();
+            
+            // Leave packets queued while no link is available, and only back
+            // off when there's no work; a batch that was all shed isn't a
+            // reason to
+            if metrics.is_empty() || (self.process_packet_batch(worker, &metrics).await? == 0 && self.work_queues.depth() == 0) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
         }
         
         Ok(())
     }
     
-    fn apply_qos_rules(&self, packet: &Packet) -> Option<QosRule> {
-        for rule in self.qos_rules.iter() {
-            if self.matches_rule(packet, rule.value()) {
-                return Some(rule.value().clone());
-            }
+    /// Re-evaluates every link, including ones currently excluded, against a
+    /// fresh metrics report and returns the links eligible for selection.
+    fn refresh_metrics(&self, metrics: &HashMap<String, LinkMetrics>) -> HashMap<String, LinkMetrics> {
+        self.link_selector.metrics_updated();
+        if let Some(ref shadow) = self.shadow_selector {
+            shadow.metrics_updated();
         }
-        None
+        for selector in self.class_selectors.values() {
+            selector.metrics_updated();
+        }
+        
+        if !self.config.failover.enabled {
+            return metrics.clone();
+        }
+        
+        let mut failover = self.failover.write();
+        failover.update(metrics);
+        failover.available_links(metrics)
     }
     
-    fn matches_rule(&self, packet: &Packet, rule: &QosRule) -> bool {
-        if let Some(ref source_ip) = rule.match_criteria.source_ip {
-            if packet.source_ip != *source_ip {
-                return false;
+    /// Takes up to `batch_size` packets for `worker` and schedules them,
+    /// returning how many were dispatched to a link. Shed, expired and
+    /// dropped packets don't count.
+    async fn process_packet_batch(&self, worker: usize, metrics: &HashMap<String, LinkMetrics>) -> Result<usize> {
+        let mut batch = self.work_queues.next_batch(worker);
+        let count = batch.len();
+        
+        if let Some(ref shedder) = self.load_shedder {
+            // Judge the backlog as it was before this batch was taken
+            match shedder.update(self.work_queues.depth() + count) {
+                Some(LoadMode::Shedding) => warn!("Packet queue above high watermark, shedding load"),
+                Some(LoadMode::Normal) => info!("Packet queue drained, load shedding stopped"),
+                None => {}
             }
+            batch.retain(|packet| !shedder.should_drop(packet.priority));
+            self.stats.record_shed((count - batch.len()) as u64);
         }
         
-        if let Some(ref dest_ip) = rule.match_criteria.dest_ip {
-            if packet.dest_ip != *dest_ip {
-                return false;
+        // Classify once here; packets already past their class's deadline
+        // are dropped before they take a sequence number
+        let now = self.clock.now();
+        let mut classified = Vec::with_capacity(batch.len());
+        for mut packet in batch {
+            let qos_rule = self.apply_qos_rules(&mut packet);
+            let max_age_ms = qos_rule.as_ref().and_then(|rule| rule.action.max_age_ms);
+            if max_age_ms.is_some_and(|max_age_ms| (now - packet.timestamp).num_milliseconds() > max_age_ms as i64) {
+                debug!("Dropping packet {} past its deadline", packet.id);
+                self.stats.record_expired();
+                continue;
             }
+            classified.push((packet, qos_rule));
+        }
+        if classified.is_empty() {
+            return Ok(0);
         }
         
-        if let Some(ref protocol) = rule.match_criteria.protocol {
-            if packet.protocol != *protocol {
-                return false;
+        // Reserve sequence numbers for the whole batch in one step
+        let first_sequence = self.sequence_counter.fetch_add(classified.len() as u64, Ordering::Relaxed) + 1;
+        
+        let mut dispatched = 0;
+        for (offset, (packet, qos_rule)) in classified.into_iter().enumerate() {
+            if self.schedule_packet(packet, qos_rule, first_sequence + offset as u64, metrics).await? {
+                dispatched += 1;
             }
         }
         
-        true
+        Ok(dispatched)
     }
     
-    pub fn stop(&self) {
-        *self.running.write() = false;
+    /// Records `link`'s metrics towards destinations in `prefix` (CIDR),
+    /// which then take precedence over its overall metrics when selecting
+    /// for packets to those destinations.
+    pub fn update_destination_metrics(&self, link: &str, prefix: &str, metrics: LinkMetrics) -> Result<()> {
+        let prefix = prefix.parse()
+            .with_context(|| format!("Invalid destination prefix {}", prefix))?;
+        self.destination_metrics.update(link, prefix, metrics);
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
     
-    #[tokio::test]
-    async fn test_packet_scheduler_creation() {
-        let config = Config::default();
-        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await;
-        assert!(scheduler.is_ok());
+    /// Receives link status changes from now on.
+    pub fn subscribe_link_events(&self) -> tokio::sync::broadcast::Receiver<LinkEvent> {
+        self.failover.read().events().subscribe()
+    }
+    
+    pub fn load_mode(&self) -> LoadMode {
+        self.load_shedder.as_ref().map_or(LoadMode::Normal, |shedder| shedder.mode())
+    }
+    
+    /// Routes one classified packet and queues it on its link. Returns
+    /// whether it was dispatched rather than dropped.
+    async fn schedule_packet(
+        &self,
+        packet: Packet,
+        qos_rule: Option<QosRule>,
+        sequence_number: u64,
+        metrics: &HashMap<String, LinkMetrics>,
+    ) -> Result<bool> {
+        let algorithm = qos_rule.as_ref().and_then(|rule| rule.action.scheduler_algorithm.as_deref());
+        
+        // Policy routes override health-based selection. A pinned link at its
+        // in-flight cap counts as down, like every other path passes it over.
+        let full_links = self.full_links();
+        let policy = if self.policy_routes.is_empty() {
+            None
+        } else if full_links.is_empty() {
+            self.policy_routes.route(&packet.source_ip, metrics)
+        } else {
+            let with_room: HashMap<String, LinkMetrics> = metrics.iter()
+                .filter(|(name, _)| !full_links.contains(*name))
+                .map(|(name, metric)| (name.clone(), metric.clone()))
+                .collect();
+            self.policy_routes.route(&packet.source_ip, &with_room)
+        };
+        let selected = match policy {
+            // Recorded like a selection, so the link isn't probed as idle and
+            // a soft drain waits for the flow
+            Some(PolicyDecision::Link(link_name)) => {
+                let now = self.clock.now();
+                if self.config.scheduler.flow_affinity {
+                    self.flow_table.pin(FlowKey::from_packet(&packet), link_name.clone(), now);
+                }
+                self.last_selected.insert(link_name.clone(), now);
+                link_name
+            }
+            Some(PolicyDecision::Drop) => {
+                debug!("Dropping packet {}: its policy route's link is down", packet.id);
+                self.stats.record_policy_drop();
+                return Ok(false);
+            }
+            None => match self.first_packet_link(&packet, qos_rule.as_ref(), metrics, &full_links)
+                .or_else(|| self.preferred_link(qos_rule.as_ref(), metrics, &full_links))
+            {
+                Some(link_name) => link_name,
+                None => {
+                    match self.select_link_for(&packet, algorithm, metrics).await {
+                        Ok(link_name) => link_name,
+                        // Every link is down, drained or full: drop the packet
+                        // rather than stop the worker
+                        Err(e) => {
+                            if let Some(suppressed) = self.log_limit.check("no link") {
+                                warn!("Dropping packet {}: {}{}", packet.id, e, suppressed);
+                            }
+                            self.stats.record_unroutable();
+                            return Ok(false);
+                        }
+                    }
+                }
+            },
+        };
+        
+        // End-to-end delay: time spent queued here plus the link's latency
+        if let Some(ref rule) = qos_rule {
+            let queued_ms = (self.clock.now() - packet.timestamp).num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0;
+            let link_ms = metrics.get(&selected).map_or(0.0, |metric| metric.latency_ms);
+            self.stats.record_class_delay(&rule.name, queued_ms + link_ms, rule.action.latency_threshold);
+        }
+        
+        // During a make-before-break handover a copy also goes to the backup,
+        // with the same sequence number so the far end can dedupe, unless the
+        // backup isn't a selection candidate, is weighted down to nothing by
+        // its multipliers or is full
+        let mut links = if self.make_before_break() {
+            self.failover.write().transmit_links(&selected)
+        } else {
+            vec![selected]
+        };
+        let link_name = links.remove(0);
+        if !links.is_empty() {
+            let candidates = self.selection_candidates(metrics, &self.queue_depth_factors(), &full_links, self.clock.now());
+            links.retain(|link| candidates.get(link).is_some_and(|metric| metric.reliability > 0.0) && !full_links.contains(link));
+        }
+        let duplicates: Vec<ScheduledPacket> = links.into_iter()
+            .map(|duplicate_link| ScheduledPacket {
+                packet: packet.clone(),
+                link_name: duplicate_link,
+                sequence_number,
+            })
+            .collect();
+        
+        let scheduled_packet = ScheduledPacket {
+            packet,
+            link_name,
+            sequence_number,
+        };
+        
+        // A shaped link holds the packet until its class may send; only a
+        // full class queue drops it, along with its duplicates
+        let unshaped = match self.shapers.read().get(&scheduled_packet.link_name) {
+            Some(shaper) => {
+                let class = qos_rule.as_ref().map(|rule| rule.name.as_str());
+                let bytes = scheduled_packet.packet.data.len();
+                let queued = QueuedPacket {
+                    scheduled: scheduled_packet,
+                    retry: true,
+                    algorithm: algorithm.map(str::to_string),
+                };
+                if let Err(rejected) = shaper.lock().enqueue(class, bytes, queued) {
+                    let rejected = rejected.scheduled;
+                    debug!("Dropping packet {}: class {} has a full queue on {}", rejected.packet.id, class.unwrap_or("<unclassified>"), rejected.link_name);
+                    self.stats.record_shaped();
+                    return Ok(false);
+                }
+                None
+            }
+            None => Some(scheduled_packet),
+        };
+        
+        for duplicate in duplicates {
+            self.dispatch(duplicate, false, None).await;
+        }
+        self.stats.record_scheduled();
+        match unshaped {
+            Some(scheduled_packet) => self.dispatch(scheduled_packet, true, algorithm).await,
+            None => {
+                self.release_shaped().await;
+                self.shaper_queued.notify_one();
+            }
+        }
+        
+        Ok(true)
+    }
+    
+    /// Sends every packet the link shapers let go now.
+    async fn release_shaped(&self) {
+        let now = std::time::Instant::now();
+        let released: Vec<QueuedPacket> = self.shapers.read().values()
+            .flat_map(|shaper| {
+                let mut shaper = shaper.lock();
+                std::iter::from_fn(|| shaper.dequeue(now)).collect::<Vec<_>>()
+            })
+            .collect();
+        for queued in released {
+            self.dispatch(queued.scheduled, queued.retry, queued.algorithm.as_deref()).await;
+        }
+    }
+    
+    /// Releases packets held by the link shapers as their budgets refill,
+    /// until the scheduler stops.
+    async fn run_shapers(self: Arc<Self>) {
+        loop {
+            self.release_shaped().await;
+            let wait = self.shapers.read().values()
+                .filter_map(|shaper| shaper.lock().next_release_in())
+                .min();
+            let refilled = async {
+                match wait {
+                    Some(wait) => tokio::time::sleep(wait).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = refilled => {}
+                _ = self.shaper_queued.notified() => {}
+                _ = self.shutdown.cancelled() => return,
+            }
+        }
+    }
+    
+    /// Records the packet to the capture exporters and queues it on its
+    /// link. With `retry`, a failed send is retried on other links, picked
+    /// with the class's `algorithm` if it overrides the scheduler's. A full
+    /// queue under `OverflowPolicy::Block` is waited out without holding the
+    /// worker's thread.
+    async fn dispatch(&self, scheduled_packet: ScheduledPacket, retry: bool, algorithm: Option<&str>) {
+        #[cfg(feature = "pcap")]
+        if let Some(ref pcap) = self.pcap {
+            if let Err(e) = pcap.lock().write(&scheduled_packet) {
+                if let Some(suppressed) = self.log_limit.check("pcap") {
+                    warn!("Failed to write packet to pcap: {}{}", e, suppressed);
+                }
+            }
+        }
+        if let Some(ref ipfix) = self.ipfix {
+            if let Err(e) = ipfix.lock().record(&scheduled_packet) {
+                if let Some(suppressed) = self.log_limit.check("ipfix") {
+                    warn!("Failed to record packet for IPFIX: {}{}", e, suppressed);
+                }
+            }
+        }
+        
+        let link_name = scheduled_packet.link_name.clone();
+        let queue = self.link_queue_for(&link_name);
+        let mut queued = QueuedPacket {
+            scheduled: scheduled_packet,
+            retry,
+            algorithm: algorithm.map(str::to_string),
+        };
+        let outcome = loop {
+            match queue.sender.try_send(queued) {
+                Ok(outcome) => break outcome,
+                Err(TrySendError::Full(rejected)) => {
+                    queued = rejected;
+                    tokio::select! {
+                        _ = queue.room.notified() => {}
+                        _ = self.shutdown.cancelled() => return,
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    if let Some(suppressed) = self.log_limit.check("packet channel") {
+                        error!("Failed to queue scheduled packet for {}{}", link_name, suppressed);
+                    }
+                    return;
+                }
+            }
+        };
+        if outcome != SendOutcome::DroppedNewest {
+            queue.queued.notify_one();
+        }
+        if outcome != SendOutcome::Sent {
+            if let Some(suppressed) = self.log_limit.check(&format!("packet queue full {}", link_name)) {
+                warn!("Packet queue for {} full: {:?}{}", link_name, outcome, suppressed);
+            }
+        }
+    }
+    
+    /// Sends a packet taken off its link's queue over the transport, and
+    /// counts it in flight on the link that carried it.
+    async fn deliver(&self, queued: QueuedPacket) {
+        let Some(ref transport) = self.transport else {
+            if let Some(suppressed) = self.log_limit.check("no transport") {
+                warn!("Dropping scheduled packet for {}: no transport to send it on{}", queued.scheduled.link_name, suppressed);
+            }
+            return;
+        };
+        let sequence_number = queued.scheduled.sequence_number;
+        let sent = if queued.retry {
+            let metrics = self.current_metrics.read().clone();
+            self.send_with_retry(transport.as_ref(), queued.scheduled, queued.algorithm.as_deref(), &metrics).await
+        } else {
+            let link_name = queued.scheduled.link_name.clone();
+            transport.send(&queued.scheduled).await.map(|()| link_name)
+        };
+        match sent {
+            Ok(link_name) => {
+                self.record_in_flight(&link_name, sequence_number);
+                if self.make_before_break() {
+                    self.failover.write().record_delivery(&link_name);
+                }
+            }
+            Err(e) => {
+                if let Some(suppressed) = self.log_limit.check("dispatch") {
+                    error!("Dropping scheduled packet: {}{}", e, suppressed);
+                }
+            }
+        }
+    }
+    
+    fn make_before_break(&self) -> bool {
+        self.config.failover.enabled && self.config.failover.make_before_break
+    }
+    
+    /// Sends the packet over its selected link. If the transport fails, the
+    /// failure is reported to failover tracking and the packet is retried on
+    /// the next-best link, up to `send_retries` times, chosen among the
+    /// remaining links as `select_link_for` would with `algorithm`. Returns
+    /// the link the packet was finally sent on.
+    async fn send_with_retry(
+        &self,
+        transport: &(dyn PacketTransport + Send + Sync),
+        mut scheduled: ScheduledPacket,
+        algorithm: Option<&str>,
+        metrics: &HashMap<String, LinkMetrics>,
+    ) -> Result<String> {
+        let mut failed_links: Vec<String> = Vec::new();
+        
+        loop {
+            match transport.send(&scheduled).await {
+                Ok(()) => break,
+                Err(e) => {
+                    if let Some(suppressed) = self.log_limit.check(&format!("send {}", scheduled.link_name)) {
+                        warn!("Send on link {} failed: {}{}", scheduled.link_name, e, suppressed);
+                    }
+                    self.failover.write().record_send_failure(&scheduled.link_name);
+                    failed_links.push(scheduled.link_name.clone());
+                }
+            }
+            
+            if failed_links.len() > self.config.scheduler.send_retries {
+                return Err(anyhow::anyhow!(
+                    "Packet {} failed on {} links", scheduled.sequence_number, failed_links.len()
+                ));
+            }
+            
+            let remaining: HashMap<String, LinkMetrics> = metrics.iter()
+                .filter(|(name, _)| !failed_links.contains(name))
+                .map(|(name, metric)| (name.clone(), metric.clone()))
+                .collect();
+            // Links the operator has weighted down to nothing aren't retried on
+            let mut candidates = self.selection_candidates(&remaining, &self.queue_depth_factors(), &self.full_links(), self.clock.now());
+            candidates.retain(|_, metric| metric.reliability > 0.0);
+            scheduled.link_name = self.selector(algorithm).select_link_uncached(&scheduled.packet, &candidates).await?;
+        }
+        
+        // Keep the flow on the link that actually worked
+        if !failed_links.is_empty() && self.config.scheduler.flow_affinity {
+            self.flow_table.pin(FlowKey::from_packet(&scheduled.packet), scheduled.link_name.clone(), self.clock.now());
+        }
+        
+        Ok(scheduled.link_name)
+    }
+    
+    /// Queues a packet for scheduling under a fresh packet id; fails if the
+    /// intake queue is full.
+    pub fn enqueue(&self, packet: Packet) -> Result<()> {
+        self.intake.enqueue(packet)
+    }
+    
+    /// A handle producers on other tasks can use to feed packets in, numbered
+    /// as `enqueue` numbers them.
+    pub fn intake(&self) -> PacketIntake {
+        self.intake.clone()
+    }
+    
+    pub fn packet_ids(&self) -> Arc<PacketIdAllocator> {
+        self.packet_ids.clone()
+    }
+    
+    pub fn set_transport(&mut self, transport: Arc<dyn PacketTransport + Send + Sync>) {
+        self.transport = Some(transport);
+    }
+    
+    /// Sends over a UDP tunnel and feeds the far end's acks into the
+    /// in-flight counts of links with a `max_in_flight` cap.
+    pub fn set_tunnel_transport(&mut self, transport: Arc<UdpTunnelTransport>) {
+        for link_name in self.in_flight_caps.keys() {
+            let transport = transport.clone();
+            let in_flight = self.in_flight.clone();
+            let link_name = link_name.clone();
+            supervise("in-flight acks", RestartPolicy::default(), self.shutdown.clone(), move || {
+                let transport = transport.clone();
+                let in_flight = in_flight.clone();
+                let link_name = link_name.clone();
+                async move {
+                    loop {
+                        let sequence_number = transport.recv_ack(&link_name).await?;
+                        if let Some(mut unacked) = in_flight.get_mut(&link_name) {
+                            unacked.remove(&sequence_number);
+                        }
+                    }
+                }
+            });
+        }
+        self.transport = Some(transport);
+    }
+    
+    /// Has the tunnel's receiving end give up on incomplete fragmented frames
+    /// after the reorder window, updated with each metrics report, unless
+    /// `tunnel.reassembly_timeout_ms` fixes its timeout.
+    pub fn set_tunnel_receiver(&mut self, receiver: Arc<UdpTunnelReceiver>) {
+        let fixed = self.config.tunnel.as_ref().is_some_and(|tunnel| tunnel.reassembly_timeout_ms.is_some());
+        if !fixed {
+            self.tunnel_receiver = Some(receiver);
+        }
+    }
+    
+    fn adapt_reassembly_timeout(&self) {
+        if let Some(ref receiver) = self.tunnel_receiver {
+            receiver.set_reassembly_timeout(self.reorder_window());
+        }
+    }
+    
+    /// Replaces the system clock, here and in failover tracking.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.failover.write().set_clock(clock.clone());
+        self.clock = clock;
+    }
+    
+    /// Also records every scheduled packet to a pcap capture.
+    #[cfg(feature = "pcap")]
+    pub fn set_pcap_exporter(&mut self, exporter: PcapExporter) {
+        self.pcap = Some(Mutex::new(exporter));
+    }
+    
+    /// Picks a link for the packet, honouring flow affinity and drains, with
+    /// the class's `algorithm` if it overrides the scheduler's.
+    async fn select_link_for(&self, packet: &Packet, algorithm: Option<&str>, metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        let now = self.clock.now();
+        let flow_key = self.config.scheduler.flow_affinity.then(|| FlowKey::from_packet(packet));
+        let full_links = self.full_links();
+        
+        // Links measured towards this destination are judged by that
+        let towards_dest = self.destination_metrics.for_destination(&packet.dest_ip, metrics, now);
+        let metrics = towards_dest.as_ref().unwrap_or(metrics);
+        
+        // Cached rankings only pick up the end of a cooldown with each
+        // metrics report; queue depth and warm-up change too quickly, so deep
+        // queues and warming links bypass the cache
+        let queue_factors = self.queue_depth_factors();
+        let warming = !self.failover.read().warmup_factors(now).is_empty();
+        let candidates = self.selection_candidates(metrics, &queue_factors, &full_links, now);
+        
+        // A pinned flow stays on its link while that is still a candidate the
+        // operator hasn't weighted down to nothing; a soft drain lets pinned
+        // flows finish
+        if let Some(ref key) = flow_key {
+            if let Some(link_name) = self.flow_table.lookup(key, now) {
+                let usable = match candidates.get(&link_name) {
+                    Some(metric) => metric.reliability > 0.0,
+                    None => metrics.contains_key(&link_name) && self.drain_mode(&link_name) == Some(DrainMode::Soft),
+                };
+                if usable && !full_links.contains(&link_name) {
+                    self.last_selected.insert(link_name.clone(), now);
+                    return Ok(link_name);
+                }
+            }
+        }
+        // Rankings cached for the full link set don't apply to a filtered one
+        let uncached = towards_dest.is_some() || !queue_factors.is_empty() || warming || candidates.len() < metrics.len();
+        // Live scoring (and its shadow) is skipped while shedding load
+        let link_name = if self.load_mode() == LoadMode::Shedding {
+            self.static_selector.select_link(packet, &candidates).await?
+        } else {
+            let selector = self.selector(algorithm);
+            let link_name = if uncached {
+                selector.select_link_uncached(packet, &candidates).await?
+            } else {
+                selector.select_link(packet, &candidates).await?
+            };
+            
+            if let Some(ref shadow) = self.shadow_selector {
+                let shadow_link = if uncached {
+                    shadow.select_link_uncached(packet, &candidates).await
+                } else {
+                    shadow.select_link(packet, &candidates).await
+                };
+                match shadow_link {
+                    Ok(shadow_link) => {
+                        if shadow_link != link_name {
+                            debug!("Shadow selector {} chose {} instead of {}", shadow.name(), shadow_link, link_name);
+                        }
+                        self.stats.record_shadow(shadow_link != link_name);
+                    }
+                    Err(e) => debug!("Shadow selector failed: {}", e),
+                }
+            }
+            link_name
+        };
+        
+        if let Some(key) = flow_key {
+            self.flow_table.pin(key, link_name.clone(), now);
+        }
+        self.last_selected.insert(link_name.clone(), now);
+        *self.last_selection.lock() = Some(LastSelection {
+            link_name: link_name.clone(),
+            candidates,
+            algorithm: algorithm.map(str::to_string),
+        });
+        
+        Ok(link_name)
+    }
+    
+    /// The links `select_link_for` chooses among: undrained links, with the
+    /// operator's, the time windows', warm-up, cooldown and queue depth
+    /// multipliers applied to their reliability, which every selector's score
+    /// is proportional to. Links at their in-flight cap wait while another
+    /// link has room.
+    fn selection_candidates(
+        &self,
+        metrics: &HashMap<String, LinkMetrics>,
+        queue_factors: &HashMap<String, f64>,
+        full_links: &HashSet<String>,
+        now: DateTime<Utc>,
+    ) -> HashMap<String, LinkMetrics> {
+        let time_multipliers = self.active_time_multipliers();
+        let (warmup_factors, cooldown_factors) = {
+            let failover = self.failover.read();
+            (failover.warmup_factors(now), failover.cooldown_factors(now))
+        };
+        let mut candidates: HashMap<String, LinkMetrics> = metrics.iter()
+            .filter(|(name, _)| !self.drained_links.contains_key(*name))
+            .map(|(name, metric)| {
+                let mut metric = metric.clone();
+                if let Some(multiplier) = self.link_multipliers.get(name) {
+                    metric.reliability *= *multiplier;
+                }
+                if let Some(multiplier) = time_multipliers.get(name) {
+                    metric.reliability *= multiplier;
+                }
+                if let Some(factor) = warmup_factors.get(name) {
+                    metric.reliability *= factor;
+                }
+                if let Some(penalty) = cooldown_factors.get(name) {
+                    metric.reliability *= penalty;
+                }
+                if let Some(factor) = queue_factors.get(name) {
+                    metric.reliability *= factor;
+                }
+                (name.clone(), metric)
+            })
+            .collect();
+        let capped = candidates.keys().any(|name| full_links.contains(name))
+            && candidates.keys().any(|name| !full_links.contains(name));
+        if capped {
+            candidates.retain(|name, _| !full_links.contains(name));
+        }
+        candidates
+    }
+    
+    /// The selection candidate with room in flight that is most reliable
+    /// once its multipliers are applied, for a packet among the first
+    /// `pin_first_packets` of its flow, or `None` to select normally.
+    /// Flow affinity pins the flow on its first normal selection instead.
+    fn first_packet_link(
+        &self,
+        packet: &Packet,
+        qos_rule: Option<&QosRule>,
+        metrics: &HashMap<String, LinkMetrics>,
+        full_links: &HashSet<String>,
+    ) -> Option<String> {
+        let pin_first_packets = qos_rule?.action.pin_first_packets?;
+        let now = self.clock.now();
+        if self.flow_table.count_packet(FlowKey::from_packet(packet), now) > u64::from(pin_first_packets) {
+            return None;
+        }
+        
+        let candidates = self.selection_candidates(metrics, &self.queue_depth_factors(), full_links, now);
+        let link_name = candidates.iter()
+            .filter(|(name, metric)| !metric.is_down() && metric.reliability > 0.0 && !full_links.contains(*name))
+            .max_by(|(a_name, a), (b_name, b)| a.reliability.partial_cmp(&b.reliability).unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| b.packet_loss.partial_cmp(&a.packet_loss).unwrap_or(std::cmp::Ordering::Equal))
+                .then_with(|| b_name.cmp(a_name)))
+            .map(|(name, _)| name.clone())?;
+        self.last_selected.insert(link_name.clone(), now);
+        Some(link_name)
+    }
+    
+    /// The first link in the class's `link_preference` chain that is a
+    /// selection candidate with room in flight and healthy once its
+    /// multipliers are applied, or `None` to fall back to the best link
+    /// overall.
+    fn preferred_link(&self, qos_rule: Option<&QosRule>, metrics: &HashMap<String, LinkMetrics>, full_links: &HashSet<String>) -> Option<String> {
+        let chain = &qos_rule?.action.link_preference;
+        if chain.is_empty() {
+            return None;
+        }
+        let now = self.clock.now();
+        let candidates = self.selection_candidates(metrics, &self.queue_depth_factors(), full_links, now);
+        let link_name = chain.iter()
+            .find(|name| {
+                candidates.get(*name).is_some_and(|metric| metric.is_healthy(self.config.failover.health_threshold))
+                    && !full_links.contains(*name)
+            })?
+            .clone();
+        self.last_selected.insert(link_name.clone(), now);
+        Some(link_name)
+    }
+    
+    /// Per-link scores behind the latest selection, best first, with the
+    /// chosen link marked. Packets following a pinned flow don't count as
+    /// selections. Scores include operator and time-of-day multipliers.
+    pub fn explain_last_selection(&self) -> Vec<LinkScoreBreakdown> {
+        let last_selection = self.last_selection.lock();
+        let Some(ref last) = *last_selection else {
+            return Vec::new();
+        };
+        
+        let mut breakdown = self.selector(last.algorithm.as_deref()).explain(&last.candidates);
+        for link in breakdown.iter_mut() {
+            link.selected = link.link_name == last.link_name;
+        }
+        breakdown.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.link_name.cmp(&b.link_name)));
+        breakdown
+    }
+    
+    /// The link's output queue, created on first use for links that only
+    /// appear in metrics. Cloned out so waiting for room doesn't hold the map.
+    fn link_queue_for(&self, link_name: &str) -> Arc<LinkQueue> {
+        if let Some(queue) = self.link_queues.get(link_name) {
+            return queue.clone();
+        }
+        let capacity = self.config.scheduler.packet_channel_capacity.unwrap_or(self.config.scheduler.max_queue_size);
+        self.link_queues.entry(link_name.to_string())
+            .or_insert_with(|| Arc::new(LinkQueue::new(capacity, self.config.scheduler.overflow_policy)))
+            .clone()
+    }
+    
+    /// Score factors of links with packets waiting in their output queue for
+    /// the transport, falling linearly with how full the queue is.
+    fn queue_depth_factors(&self) -> HashMap<String, f64> {
+        let penalty = self.config.scheduler.queue_depth_penalty;
+        if penalty == 0.0 {
+            return HashMap::new();
+        }
+        self.link_queues.iter()
+            .filter(|entry| !entry.value().receiver.is_empty())
+            .map(|entry| {
+                let queue = &entry.value().receiver;
+                let fill = queue.len() as f64 / queue.capacity().unwrap_or(usize::MAX).max(1) as f64;
+                (entry.key().clone(), 1.0 - penalty * fill)
+            })
+            .collect()
+    }
+    
+    /// Counts a packet sent on a link with a `max_in_flight` cap until the
+    /// far end acknowledges it.
+    fn record_in_flight(&self, link_name: &str, sequence_number: u64) {
+        if self.in_flight_caps.contains_key(link_name) {
+            self.in_flight.entry(link_name.to_string()).or_default().insert(sequence_number, self.clock.now());
+        }
+    }
+    
+    /// Ack feedback from the receive side: the packet with `sequence_number`
+    /// arrived over `link_name` and no longer counts against its cap.
+    pub fn acknowledge(&self, link_name: &str, sequence_number: u64) {
+        if let Some(mut in_flight) = self.in_flight.get_mut(link_name) {
+            in_flight.remove(&sequence_number);
+        }
+    }
+    
+    /// Links with `max_in_flight` packets unacknowledged, after forgetting
+    /// those sent more than the link's `in_flight_timeout_ms` ago.
+    fn full_links(&self) -> HashSet<String> {
+        if self.in_flight_caps.is_empty() {
+            return HashSet::new();
+        }
+        let now = self.clock.now();
+        self.in_flight_caps.iter()
+            .filter(|(name, (cap, timeout))| self.in_flight.get_mut(*name).is_some_and(|mut in_flight| {
+                in_flight.retain(|_, sent_at| *sent_at > now - *timeout);
+                in_flight.len() >= *cap
+            }))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+    
+    /// Unacknowledged packets on each link with an in-flight cap.
+    pub fn in_flight(&self) -> HashMap<String, usize> {
+        self.in_flight.iter()
+            .map(|entry| (entry.key().clone(), entry.value().len()))
+            .collect()
+    }
+    
+    /// Packets waiting in each link's output queue.
+    pub fn link_queue_depths(&self) -> HashMap<String, usize> {
+        self.link_queues.iter()
+            .map(|entry| (entry.key().clone(), entry.value().receiver.len()))
+            .collect()
+    }
+    
+    /// A consistent-enough copy of the scheduler's state; each section is
+    /// read under its own lock.
+    pub fn debug_state(&self) -> SchedulerDebugState {
+        let mut qos_rules: Vec<QosRule> = self.qos_rules.iter().map(|rule| rule.value().clone()).collect();
+        qos_rules.sort_by(|a, b| a.name.cmp(&b.name));
+        SchedulerDebugState {
+            timestamp: self.clock.now(),
+            metrics: (**self.current_metrics.read()).clone(),
+            qos_rules,
+            flows: self.flow_table.pinned_flows(),
+            drained_links: self.drained_links.iter().map(|entry| (entry.key().clone(), *entry.value())).collect(),
+            link_multipliers: self.link_multipliers.iter().map(|entry| (entry.key().clone(), *entry.value())).collect(),
+            link_states: self.failover.read().states().clone(),
+            load_mode: self.load_mode(),
+            selector: self.link_selector.name().to_string(),
+            stats: self.stats.snapshot(),
+        }
+    }
+    
+    /// Links from `metrics` that haven't been selected within `idle_for`.
+    /// The underlay manager probes these at a reduced cadence instead of at
+    /// full rate, so they are still re-evaluated and can recover.
+    pub fn idle_links(&self, metrics: &HashMap<String, LinkMetrics>, idle_for: chrono::Duration) -> Vec<String> {
+        let cutoff = self.clock.now() - idle_for;
+        let mut idle: Vec<String> = metrics.keys()
+            .filter(|name| self.last_selected.get(*name).map_or(true, |last| *last < cutoff))
+            .cloned()
+            .collect();
+        idle.sort();
+        idle
+    }
+    
+    /// Keeps the underlay manager told which links haven't been selected
+    /// within `scheduler.idle_link_after`, until the scheduler stops.
+    async fn report_idle_links(self: Arc<Self>, underlay: Arc<UnderlayClient>) {
+        let mut reported = HashSet::new();
+        loop {
+            self.report_idle_changes(&underlay, &mut reported).await;
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = tokio::time::sleep(Duration::from_millis(self.config.scheduler.metrics_interval)) => {}
+            }
+        }
+    }
+    
+    /// Reports links that became idle or were selected again since the
+    /// links in `reported` were reported idle. A failed report is retried
+    /// next time.
+    async fn report_idle_changes(&self, underlay: &UnderlayClient, reported: &mut HashSet<String>) {
+        let metrics = self.current_metrics.read().clone();
+        let idle_for = chrono::Duration::milliseconds(self.config.scheduler.idle_link_after as i64);
+        let idle: HashSet<String> = self.idle_links(&metrics, idle_for).into_iter().collect();
+        let changes: Vec<(String, bool)> = idle.difference(reported).map(|link| (link.clone(), true))
+            .chain(reported.difference(&idle).map(|link| (link.clone(), false)))
+            .collect();
+        for (link_name, is_idle) in changes {
+            match underlay.set_idle(&link_name, is_idle).await {
+                Ok(()) if is_idle => {
+                    reported.insert(link_name);
+                }
+                Ok(()) => {
+                    reported.remove(&link_name);
+                }
+                Err(e) => {
+                    if let Some(suppressed) = self.log_limit.check("idle report") {
+                        warn!("Failed to report link {} as {}: {}{}", link_name, if is_idle { "idle" } else { "in use" }, e, suppressed);
+                    }
+                }
+            }
+        }
+    }
+    
+    /// Keeps per-destination metrics current from the underlay manager's
+    /// destination probes, until the scheduler stops.
+    async fn poll_destination_metrics(self: Arc<Self>, underlay: Arc<UnderlayClient>) {
+        loop {
+            self.refresh_destination_metrics(&underlay).await;
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = tokio::time::sleep(Duration::from_millis(self.config.scheduler.metrics_interval)) => {}
+            }
+        }
+    }
+    
+    /// Records the underlay manager's latest per-destination metrics and
+    /// drops those past `scheduler.destination_metrics_ttl`, so a prefix
+    /// that stops being measured falls back to the links' overall metrics.
+    async fn refresh_destination_metrics(&self, underlay: &UnderlayClient) {
+        match underlay.destination_metrics().await {
+            Ok(measured) => {
+                for destination in measured {
+                    let link_name = &destination.metrics.interface_name;
+                    if let Err(e) = self.update_destination_metrics(link_name, &destination.prefix, LinkMetrics::from(&destination.metrics)) {
+                        if let Some(suppressed) = self.log_limit.check("destination metrics") {
+                            warn!("Ignoring metrics of {} towards {}: {}{}", link_name, destination.prefix, e, suppressed);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                if let Some(suppressed) = self.log_limit.check("destination metrics") {
+                    warn!("Failed to fetch destination metrics: {}{}", e, suppressed);
+                }
+            }
+        }
+        let expired = self.destination_metrics.evict_expired(self.clock.now());
+        if expired > 0 {
+            debug!("Dropped {} expired destination metrics", expired);
+        }
+    }
+    
+    /// Exports IPFIX records of flows that timed out as of `now`.
+    fn export_flow_records(&self, now: DateTime<Utc>) {
+        if let Some(ref ipfix) = self.ipfix {
+            match ipfix.lock().flush_expired(now) {
+                Ok(0) => {}
+                Ok(exported) => debug!("Exported {} IPFIX flow records", exported),
+                Err(e) => warn!("Failed to export IPFIX records: {}", e),
+            }
+        }
+    }
+    
+    /// Expires idle flows and completes soft drains whose link has no
+    /// remaining flows.
+    fn reap_idle_flows(&self) {
+        let expired = self.flow_table.expire_idle(self.clock.now());
+        if expired > 0 {
+            debug!("Expired {} idle flows", expired);
+        }
+        
+        for mut drain in self.drained_links.iter_mut() {
+            if *drain.value() == DrainMode::Soft && self.flow_table.flows_on_link(drain.key()) == 0 {
+                info!("Soft drain of link {} complete", drain.key());
+                *drain.value_mut() = DrainMode::Hard;
+            }
+        }
+    }
+    
+    pub(crate) fn drain_link(&self, link_name: &str, mode: DrainMode) {
+        info!("Draining link {} ({:?})", link_name, mode);
+        self.drained_links.insert(link_name.to_string(), mode);
+        self.invalidate_rankings();
+    }
+    
+    pub(crate) fn undrain_link(&self, link_name: &str) {
+        if self.drained_links.remove(link_name).is_some() {
+            info!("Link {} returned to service", link_name);
+            self.invalidate_rankings();
+        }
+    }
+    
+    /// Scales the link's selection score by `multiplier` (0.0-1.0) until
+    /// changed again: 0.5 halves its attractiveness and 0.0 effectively
+    /// drains it. 1.0 restores the computed score.
+    pub(crate) fn set_link_multiplier(&self, link_name: &str, multiplier: f64) -> Result<()> {
+        if !(0.0..=1.0).contains(&multiplier) {
+            return Err(anyhow::anyhow!("Link multiplier {} for {} is outside 0.0-1.0", multiplier, link_name));
+        }
+        
+        info!("Setting score multiplier of link {} to {}", link_name, multiplier);
+        if multiplier == 1.0 {
+            self.link_multipliers.remove(link_name);
+        } else {
+            self.link_multipliers.insert(link_name.to_string(), multiplier);
+        }
+        self.invalidate_rankings();
+        Ok(())
+    }
+    
+    /// Multipliers of the links' time windows open at the clock's current
+    /// time. A window opening or closing invalidates cached rankings.
+    fn active_time_multipliers(&self) -> HashMap<String, f64> {
+        if self.config.links.iter().all(|link| link.time_multipliers.is_empty()) {
+            return HashMap::new();
+        }
+        
+        let now = self.clock.local_now();
+        let active: HashMap<String, f64> = self.config.links.iter()
+            .filter_map(|link| link.time_multiplier(now).map(|multiplier| (link.name.clone(), multiplier)))
+            .collect();
+        let mut last = self.time_multipliers.lock();
+        if *last != active {
+            info!("Time-of-day link multipliers now {:?}", active);
+            *last = active.clone();
+            self.invalidate_rankings();
+        }
+        active
+    }
+    
+    /// Drops rankings selectors cached from the current metrics, after a
+    /// change to what their scores are scaled by.
+    fn invalidate_rankings(&self) {
+        self.link_selector.metrics_updated();
+        if let Some(ref shadow) = self.shadow_selector {
+            shadow.metrics_updated();
+        }
+        for selector in self.class_selectors.values() {
+            selector.metrics_updated();
+        }
+    }
+    
+    pub fn link_multiplier(&self, link_name: &str) -> f64 {
+        self.link_multipliers.get(link_name).map(|multiplier| *multiplier).unwrap_or(1.0)
+    }
+    
+    pub fn drain_mode(&self, link_name: &str) -> Option<DrainMode> {
+        self.drained_links.get(link_name).map(|mode| *mode)
+    }
+    
+    /// The first QoS rule matching the packet. Markings from sources outside
+    /// `trust_dscp_from` are classified as DSCP 0.
+    /// Classifies the packet, setting its priority to its class's, or for
+    /// packets no rule matches, to its DSCP's or protocol's default.
+    fn apply_qos_rules(&self, packet: &mut Packet) -> Option<QosRule> {
+        let dscp = trusted_dscp(packet.dscp, &packet.source_ip, self.config.qos.trust_dscp_from.as_deref());
+        let rule = self.qos_rules.iter()
+            .find(|rule| self.matches_rule(packet, dscp, rule.value()))
+            .map(|rule| rule.value().clone());
+        packet.priority = match rule {
+            Some(ref rule) => rule.priority,
+            None => self.default_priorities.priority(&packet.protocol, dscp),
+        };
+        rule
+    }
+    
+    fn matches_rule(&self, packet: &Packet, dscp: Option<u8>, rule: &QosRule) -> bool {
+        if let Some(ref source_ip) = rule.match_criteria.source_ip {
+            if packet.source_ip != *source_ip {
+                return false;
+            }
+        }
+        
+        if let Some(ref dest_ip) = rule.match_criteria.dest_ip {
+            if packet.dest_ip != *dest_ip {
+                return false;
+            }
+        }
+        
+        if let Some(ref protocol) = rule.match_criteria.protocol {
+            if !protocol.matches(&packet.protocol) {
+                return false;
+            }
+        }
+        
+        // A rule naming a DSCP never matches a packet without one
+        if rule.match_criteria.dscp.is_some_and(|rule_dscp| dscp != Some(rule_dscp)) {
+            return false;
+        }
+        
+        // ICMP type and code only apply to ICMP packets, which carry them
+        if packet.protocol == Protocol::Icmp {
+            if rule.match_criteria.icmp_type.is_some_and(|icmp_type| packet.icmp_type != Some(icmp_type)) {
+                return false;
+            }
+            if rule.match_criteria.icmp_code.is_some_and(|icmp_code| packet.icmp_code != Some(icmp_code)) {
+                return false;
+            }
+        }
+        
+        if rule.match_criteria.vlan_id.is_some_and(|vlan_id| packet.vlan_id != Some(vlan_id)) {
+            return false;
+        }
+        if rule.match_criteria.inner_vlan_id.is_some_and(|inner_vlan_id| packet.inner_vlan_id != Some(inner_vlan_id)) {
+            return false;
+        }
+        
+        true
+    }
+    
+    /// Adds a QoS rule at runtime. Fails if the rule is invalid, a rule with
+    /// the same name already exists or its `min_bandwidth` doesn't fit.
+    pub(crate) fn add_qos_rule(&self, rule: QosRule) -> Result<()> {
+        rule.validate()?;
+        if self.qos_rules.contains_key(&rule.name) {
+            return Err(anyhow::anyhow!("QoS rule {} already exists", rule.name));
+        }
+        let shapers = self.shapers_for(self.rules_with(Some(&rule), None))?;
+        info!("Added QoS rule {}", rule.name);
+        self.qos_rules.insert(rule.name.clone(), rule);
+        self.replace_shapers(shapers);
+        Ok(())
+    }
+    
+    /// Replaces an existing QoS rule, taking effect for the next packet.
+    pub(crate) fn update_qos_rule(&self, rule: QosRule) -> Result<()> {
+        rule.validate()?;
+        if !self.qos_rules.contains_key(&rule.name) {
+            return Err(anyhow::anyhow!("QoS rule {} not found", rule.name));
+        }
+        let shapers = self.shapers_for(self.rules_with(Some(&rule), None))?;
+        info!("Updated QoS rule {}", rule.name);
+        self.qos_rules.insert(rule.name.clone(), rule);
+        self.replace_shapers(shapers);
+        Ok(())
+    }
+    
+    pub(crate) fn remove_qos_rule(&self, name: &str) -> Result<QosRule> {
+        let (_, rule) = self.qos_rules.remove(name)
+            .ok_or_else(|| anyhow::anyhow!("QoS rule {} not found", name))?;
+        info!("Removed QoS rule {}", name);
+        // Dropping a reservation never makes the rest fit any worse
+        if let Ok(shapers) = self.shapers_for(self.rules_with(None, Some(name))) {
+            self.replace_shapers(shapers);
+        }
+        Ok(rule)
+    }
+    
+    /// The current QoS rules with `added` in place of any rule of the same
+    /// name and without `removed`.
+    fn rules_with(&self, added: Option<&QosRule>, removed: Option<&str>) -> Vec<QosRule> {
+        let replaced = added.map(|rule| rule.name.as_str());
+        self.qos_rules.iter()
+            .filter(|rule| Some(rule.key().as_str()) != replaced && Some(rule.key().as_str()) != removed)
+            .map(|rule| rule.value().clone())
+            .chain(added.cloned())
+            .collect()
+    }
+    
+    /// Link shapers for `rules`, failing if their reservations don't fit
+    /// the links the way the configured rules' must.
+    fn shapers_for(&self, rules: Vec<QosRule>) -> Result<HashMap<String, HierarchicalTokenBucket<QueuedPacket>>> {
+        let qos = crate::config::QosConfig { rules, ..self.config.qos.clone() };
+        qos.validate_reservations(&self.config.links, &self.config.shaping)?;
+        let queue_limit = self.config.scheduler.packet_channel_capacity.unwrap_or(self.config.scheduler.max_queue_size);
+        build_shapers(&self.config.shaping, &self.config.links, &qos.rules, queue_limit, std::time::Instant::now())
+    }
+    
+    /// Swaps in rebuilt shapers, moving packets the old ones still hold
+    /// into them. A link that is no longer shaped gets a shaper that only
+    /// lets its held packets go.
+    fn replace_shapers(&self, mut shapers: HashMap<String, HierarchicalTokenBucket<QueuedPacket>>) {
+        let queue_limit = self.config.scheduler.packet_channel_capacity.unwrap_or(self.config.scheduler.max_queue_size);
+        let mut current = self.shapers.write();
+        for (link, old) in current.drain() {
+            let old = old.into_inner();
+            if old.queued() == 0 {
+                continue;
+            }
+            let shaper = shapers.entry(link)
+                .or_insert_with(|| HierarchicalTokenBucket::unshaped(0, queue_limit, std::time::Instant::now()));
+            for (class, bytes, queued) in old.into_queued() {
+                if shaper.enqueue(class.as_deref(), bytes, queued).is_err() {
+                    self.stats.record_shaped();
+                }
+            }
+        }
+        *current = shapers.into_iter().map(|(link, shaper)| (link, Mutex::new(shaper))).collect();
+        drop(current);
+        self.shaper_queued.notify_one();
+    }
+    
+    /// Reorder window for the receiving end, adapted to the latency spread of
+    /// the links currently in use. An attached tunnel receiver uses it as its
+    /// reassembly timeout.
+    pub fn reorder_window(&self) -> Duration {
+        crate::metrics::reorder_window(
+            &self.current_metrics.read(),
+            Duration::from_millis(self.config.scheduler.reorder_window_min_ms),
+            Duration::from_millis(self.config.scheduler.reorder_window_max_ms),
+        )
+    }
+    
+    pub fn stats(&self) -> Arc<SchedulerStats> {
+        self.stats.clone()
+    }
+    
+    pub fn failover(&self) -> Arc<RwLock<FailoverManager>> {
+        self.failover.clone()
+    }
+    
+    pub fn stop(&self) {
+        self.shutdown.cancel();
+    }
+    
+    /// Token that stops the scheduler when cancelled, for embedders that
+    /// shut down from outside the task running it.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+}
+
+impl Drop for PacketScheduler {
+    fn drop(&mut self) {
+        // Stops the metrics collection task
+        self.shutdown.cancel();
+    }
+}
+
+#[async_trait]
+impl GroupHealthService for PacketScheduler {
+    async fn get_group_health(&self, request: GroupHealthRequest) -> Result<GroupHealthResponse, Box<dyn std::error::Error>> {
+        let failover = self.failover.read();
+        if !failover.group_names().contains(&request.group) {
+            return Err(format!("Unknown failover group {}", request.group).into());
+        }
+        let health = failover.group_health(&request.group);
+        Ok(GroupHealthResponse { group: request.group, health })
+    }
+}
+
+#[async_trait]
+impl SelectionExplanationService for PacketScheduler {
+    async fn explain_last_selection(&self, _request: ExplainSelectionRequest) -> Result<ExplainSelectionResponse, Box<dyn std::error::Error>> {
+        Ok(ExplainSelectionResponse { links: PacketScheduler::explain_last_selection(self) })
+    }
+}
+
+#[async_trait]
+impl DebugStateService for PacketScheduler {
+    async fn get_debug_state(&self, _request: DebugStateRequest) -> Result<DebugStateResponse, Box<dyn std::error::Error>> {
+        Ok(DebugStateResponse { state: self.debug_state() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{link_config, MockClock, MockLinkSelector, MockTransport};
+    
+    #[tokio::test]
+    async fn test_packet_scheduler_creation() {
+        let config = Config::default();
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await;
+        assert!(scheduler.is_ok());
+    }
+    
+    fn test_packet(source_ip: &str) -> Packet {
+        Packet {
+            id: 1,
+            data: vec![0u8; 100],
+            priority: 5,
+            source_ip: source_ip.to_string(),
+            dest_ip: "10.0.0.1".to_string(),
+            protocol: Protocol::Tcp,
+            source_port: None,
+            dest_port: None,
+            dscp: None,
+            icmp_type: None,
+            icmp_code: None,
+            vlan_id: None,
+            inner_vlan_id: None,
+            timestamp: Utc::now(),
+        }
+    }
+    
+    fn test_metrics() -> HashMap<String, LinkMetrics> {
+        let mut metrics = HashMap::new();
+        for (name, latency) in [("eth0", 5.0), ("eth1", 20.0)] {
+            let mut metric = LinkMetrics::new();
+            metric.latency_ms = latency;
+            metric.bandwidth_mbps = 100.0;
+            metrics.insert(name.to_string(), metric);
+        }
+        metrics
+    }
+    
+    async fn flow_affinity_scheduler() -> PacketScheduler {
+        let mut config = Config::default();
+        config.scheduler.flow_affinity = true;
+        PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_soft_drain_keeps_existing_flows() {
+        let scheduler = flow_affinity_scheduler().await;
+        let metrics = test_metrics();
+        let existing = test_packet("192.168.1.10");
+        scheduler.flow_table.pin(FlowKey::from_packet(&existing), "eth0".to_string(), Utc::now());
+        
+        scheduler.drain_link("eth0", DrainMode::Soft);
+        
+        assert_eq!(scheduler.select_link_for(&existing, None, &metrics).await.unwrap(), "eth0");
+        let new_flow = test_packet("192.168.1.11");
+        assert_eq!(scheduler.select_link_for(&new_flow, None, &metrics).await.unwrap(), "eth1");
+    }
+    
+    #[tokio::test]
+    async fn test_hard_drain_moves_existing_flows() {
+        let scheduler = flow_affinity_scheduler().await;
+        let metrics = test_metrics();
+        let existing = test_packet("192.168.1.10");
+        scheduler.flow_table.pin(FlowKey::from_packet(&existing), "eth0".to_string(), Utc::now());
+        
+        scheduler.drain_link("eth0", DrainMode::Hard);
+        
+        assert_eq!(scheduler.select_link_for(&existing, None, &metrics).await.unwrap(), "eth1");
+    }
+    
+    #[tokio::test]
+    async fn test_soft_drain_completes_when_flows_idle_out() {
+        let scheduler = flow_affinity_scheduler().await;
+        let idle_since = Utc::now() - chrono::Duration::milliseconds(60000);
+        scheduler.flow_table.pin(FlowKey::from_packet(&test_packet("192.168.1.10")), "eth0".to_string(), idle_since);
+        
+        scheduler.drain_link("eth0", DrainMode::Soft);
+        scheduler.reap_idle_flows();
+        
+        assert_eq!(scheduler.drain_mode("eth0"), Some(DrainMode::Hard));
+    }
+    
+    #[tokio::test]
+    async fn test_with_selector_routes_by_mock() {
+        let mock = Arc::new(MockLinkSelector::new(&["eth1", "eth0"]));
+        let scheduler = PacketScheduler::with_selector(
+            Config::default(),
+            "http://localhost:9093".to_string(),
+            Box::new(mock.clone()),
+        ).await.unwrap();
+        let metrics = test_metrics();
+        
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth1");
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth0");
+        assert!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.is_err());
+        assert_eq!(mock.calls(), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_flow_affinity_consults_selector_once_per_flow() {
+        let mut config = Config::default();
+        config.scheduler.flow_affinity = true;
+        let mock = Arc::new(MockLinkSelector::new(&["eth1", "eth0"]));
+        let scheduler = PacketScheduler::with_selector(
+            config,
+            "http://localhost:9093".to_string(),
+            Box::new(mock.clone()),
+        ).await.unwrap();
+        let metrics = test_metrics();
+        
+        for _ in 0..5 {
+            assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth1");
+        }
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.11"), None, &metrics).await.unwrap(), "eth0");
+        assert_eq!(mock.calls(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_hysteresis_prevents_flapping() {
+        let selector = WeightedRoundRobinSelector::with_hysteresis(0.05);
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().latency_ms = 10.0;
+        metrics.get_mut("eth1").unwrap().latency_ms = 10.0;
+        
+        metrics.get_mut("eth0").unwrap().bandwidth_mbps = 110.0;
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        
+        // eth1 edges ahead, but by less than the margin
+        metrics.get_mut("eth0").unwrap().bandwidth_mbps = 100.0;
+        metrics.get_mut("eth1").unwrap().bandwidth_mbps = 110.0;
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        
+        // eth1 now wins by more than the margin
+        metrics.get_mut("eth1").unwrap().bandwidth_mbps = 400.0;
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+    }
+    
+    #[tokio::test]
+    async fn test_no_hysteresis_switches_immediately() {
+        let selector = WeightedRoundRobinSelector::new();
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().latency_ms = 10.0;
+        metrics.get_mut("eth1").unwrap().latency_ms = 10.0;
+        
+        metrics.get_mut("eth0").unwrap().bandwidth_mbps = 110.0;
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        
+        metrics.get_mut("eth0").unwrap().bandwidth_mbps = 100.0;
+        metrics.get_mut("eth1").unwrap().bandwidth_mbps = 110.0;
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+    }
+    
+    #[tokio::test]
+    async fn test_fast_lossy_link_loses_to_clean_link() {
+        let selector = WeightedRoundRobinSelector::new();
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        let eth1 = metrics.get_mut("eth1").unwrap();
+        eth1.bandwidth_mbps = 10_000.0;
+        eth1.packet_loss = 0.8;
+        
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        let breakdown = selector.explain(&metrics);
+        assert!(breakdown.iter().all(|link| link.bandwidth <= 1.0 / 3.0));
+    }
+    
+    #[tokio::test]
+    async fn test_stable_link_preferred_over_flapping_link() {
+        let selector = WeightedRoundRobinSelector::new();
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().latency_ms = 10.0;
+        metrics.get_mut("eth1").unwrap().latency_ms = 10.0;
+        metrics.get_mut("eth0").unwrap().reliability = 0.5;
+        
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+    }
+    
+    #[tokio::test]
+    async fn test_zero_bandwidth_link_not_selected() {
+        let selector = WeightedRoundRobinSelector::new();
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().bandwidth_mbps = 0.0;
+        
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+    }
+    
+    #[tokio::test]
+    async fn test_total_loss_link_not_selected() {
+        let selector = WeightedRoundRobinSelector::new();
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        
+        metrics.get_mut("eth0").unwrap().packet_loss = 1.0;
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+        
+        metrics.get_mut("eth1").unwrap().bandwidth_mbps = 0.0;
+        assert!(selector.select_link(&packet, &metrics).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_shadow_divergence_counted() {
+        let mut config = Config::default();
+        config.scheduler.shadow_algorithm = Some("weighted_round_robin".to_string());
+        let scheduler = PacketScheduler::with_selector(
+            config,
+            "http://localhost:9093".to_string(),
+            Box::new(MockLinkSelector::new(&["eth1", "eth0"])),
+        ).await.unwrap();
+        let metrics = test_metrics();
+        
+        // The shadow prefers eth0; routing still follows the primary
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth1");
+        assert_eq!(scheduler.stats().shadow_divergences(), 1);
+        
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth0");
+        let stats = scheduler.stats().snapshot();
+        assert_eq!(stats.shadow_decisions, 2);
+        assert_eq!(stats.shadow_divergences, 1);
+    }
+    
+    async fn scheduler_with_transport(
+        config: Config,
+        selections: &[&str],
+        transport: Arc<MockTransport>,
+    ) -> PacketScheduler {
+        let mut scheduler = PacketScheduler::with_selector(
+            config,
+            "http://localhost:9093".to_string(),
+            Box::new(MockLinkSelector::new(selections)),
+        ).await.unwrap();
+        scheduler.set_transport(transport);
+        scheduler
+    }
+    
+    fn scheduled(link_name: &str, sequence_number: u64) -> ScheduledPacket {
+        ScheduledPacket {
+            packet: test_packet("192.168.1.10"),
+            link_name: link_name.to_string(),
+            sequence_number,
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_send_retries_on_alternate_link() {
+        let transport = Arc::new(MockTransport::failing(&["eth0"]));
+        let scheduler = scheduler_with_transport(Config::default(), &["eth1"], transport.clone()).await;
+        
+        let link = scheduler.send_with_retry(transport.as_ref(), scheduled("eth0", 1), None, &test_metrics()).await.unwrap();
+        
+        assert_eq!(link, "eth1");
+        assert_eq!(transport.sent(), vec![("eth1".to_string(), 1)]);
+        assert_eq!(scheduler.failover.read().state("eth0").unwrap().consecutive_failures, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_send_gives_up_after_retries() {
+        let transport = Arc::new(MockTransport::failing(&["eth0", "eth1"]));
+        let scheduler = scheduler_with_transport(Config::default(), &["eth1"], transport.clone()).await;
+        
+        let result = scheduler.send_with_retry(transport.as_ref(), scheduled("eth0", 1), None, &test_metrics()).await;
+        
+        assert!(result.is_err());
+        assert!(transport.sent().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_send_retry_repins_flow() {
+        let mut config = Config::default();
+        config.scheduler.flow_affinity = true;
+        let transport = Arc::new(MockTransport::failing(&["eth0"]));
+        let scheduler = scheduler_with_transport(config, &["eth1"], transport.clone()).await;
+        
+        scheduler.send_with_retry(transport.as_ref(), scheduled("eth0", 1), None, &test_metrics()).await.unwrap();
+        
+        let key = FlowKey::from_packet(&test_packet("192.168.1.10"));
+        assert_eq!(scheduler.flow_table.lookup(&key, Utc::now()), Some("eth1".to_string()));
+    }
+    
+    #[tokio::test]
+    async fn test_packets_processed_in_batches() {
+        let mut config = Config::default();
+        config.scheduler.batch_size = 64;
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        let metrics = test_metrics();
+        
+        for _ in 0..150 {
+            scheduler.enqueue(test_packet("192.168.1.10")).unwrap();
+        }
+        
+        let mut batches = Vec::new();
+        loop {
+            let processed = scheduler.process_packet_batch(0, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+            if processed == 0 {
+                break;
+            }
+            batches.push(processed);
+        }
+        
+        assert_eq!(batches, vec![64, 64, 22]);
+        let sequence_numbers: Vec<u64> = transport.sent().iter().map(|(_, seq)| *seq).collect();
+        assert_eq!(sequence_numbers, (1..=150).collect::<Vec<u64>>());
+        assert_eq!(scheduler.stats().snapshot().packets_scheduled, 150);
+    }
+    
+    #[tokio::test]
+    async fn test_run_schedules_with_in_memory_metrics() {
+        let mut metrics = test_metrics();
+        metrics.remove("eth0");
+        let provider = Arc::new(crate::metrics_provider::StaticMetricsProvider::new(metrics));
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::with_metrics_provider(Config::default(), provider).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        let scheduler = Arc::new(scheduler);
+        for _ in 0..3 {
+            scheduler.enqueue(test_packet("192.168.1.10")).unwrap();
+        }
+        
+        let (handle, shutdown) = scheduler.clone().spawn();
+        let sent = tokio::time::timeout(Duration::from_secs(5), async {
+            while transport.sent().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+        sent.expect("packets were not scheduled");
+        
+        // Only the link the provider reported is used
+        assert!(transport.sent().iter().all(|(link, _)| link == "eth1"));
+    }
+    
+    #[tokio::test]
+    async fn test_scheduler_survives_every_link_drained() {
+        let provider = Arc::new(crate::metrics_provider::StaticMetricsProvider::new(test_metrics()));
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::with_metrics_provider(Config::default(), provider).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        let scheduler = Arc::new(scheduler);
+        scheduler.drain_link("eth0", DrainMode::Hard);
+        scheduler.drain_link("eth1", DrainMode::Hard);
+        for _ in 0..3 {
+            scheduler.enqueue(test_packet("192.168.1.10")).unwrap();
+        }
+        
+        let (handle, shutdown) = scheduler.clone().spawn();
+        let dropped = tokio::time::timeout(Duration::from_secs(5), async {
+            while scheduler.stats().snapshot().packets_unroutable < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let still_running = !handle.is_finished();
+        
+        // Once a link comes back, packets flow again
+        scheduler.undrain_link("eth1");
+        scheduler.enqueue(test_packet("192.168.1.10")).unwrap();
+        let sent = tokio::time::timeout(Duration::from_secs(5), async {
+            while transport.sent().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+        
+        dropped.expect("packets were not counted as unroutable");
+        assert!(still_running);
+        sent.expect("packets were not scheduled after undraining");
+        assert_eq!(transport.sent()[0].0, "eth1");
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_sequence_numbers_unique_across_workers() {
+        let mut config = Config::default();
+        config.scheduler.workers = 4;
+        config.scheduler.batch_size = 16;
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::with_metrics_provider(config, Arc::new(crate::metrics_provider::StaticMetricsProvider::new(test_metrics()))).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        
+        for i in 0..2000 {
+            scheduler.enqueue(test_packet(&format!("192.168.{}.{}", i / 250, i % 250))).unwrap();
+        }
+        
+        let scheduler = Arc::new(scheduler);
+        let handle = tokio::spawn(scheduler.clone().run());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while transport.sent().len() < 2000 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        scheduler.stop();
+        handle.await.unwrap().unwrap();
+        
+        let mut sequence_numbers: Vec<u64> = transport.sent().iter().map(|(_, seq)| *seq).collect();
+        sequence_numbers.sort_unstable();
+        assert_eq!(sequence_numbers, (1..=2000).collect::<Vec<u64>>());
+    }
+    
+    #[tokio::test]
+    async fn test_load_shedding_follows_queue_depth() {
+        let mut config = Config::default();
+        config.scheduler.batch_size = 4;
+        config.scheduler.load_shedding = Some(crate::config::LoadSheddingConfig {
+            high_watermark: 8,
+            low_watermark: 2,
+            shed_priority: 1,
+        });
+        let mut heavy = link_config("eth1", None);
+        heavy.weight = 2.0;
+        config.links = vec![link_config("eth0", None), heavy];
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        let metrics = test_metrics();
+        
+        let packet = |priority| Packet { priority, ..test_packet("192.168.1.10") };
+        for id in 0..12 {
+            scheduler.enqueue(packet(if id % 2 == 0 { 1 } else { 5 })).unwrap();
+        }
+        
+        // A backlog of 12 is above the high watermark; it stays above the low
+        // one until the queue is empty. Only the unshed half of each batch
+        // counts as dispatched
+        for _ in 0..3 {
+            assert_eq!(scheduler.process_packet_batch(0, &metrics).await.unwrap(), 2);
+            scheduler.flush_link_queues().await;
+            assert_eq!(scheduler.load_mode(), LoadMode::Shedding);
+        }
+        let stats = scheduler.stats().snapshot();
+        assert_eq!(stats.packets_shed, 6);
+        assert_eq!(stats.packets_scheduled, 6);
+        // Static weights prefer eth1, though eth0 scores better
+        assert!(transport.sent().iter().all(|(link, _)| link == "eth1"));
+        
+        scheduler.enqueue(packet(1)).unwrap();
+        assert_eq!(scheduler.process_packet_batch(0, &metrics).await.unwrap(), 1);
+        scheduler.flush_link_queues().await;
+        assert_eq!(scheduler.load_mode(), LoadMode::Normal);
+        assert_eq!(scheduler.stats().snapshot().packets_scheduled, 7);
+        assert_eq!(transport.sent().last().unwrap().0, "eth0");
+    }
+    
+    #[tokio::test]
+    async fn test_time_window_shifts_selection() {
+        // eth0 is metered during business hours
+        let mut config = Config::default();
+        let mut metered = link_config("eth0", None);
+        metered.time_multipliers = vec![crate::config::TimedMultiplier {
+            window: serde_yaml::from_str("start: \"08:00\"\nend: \"18:00\"\ndays: [Mon, Tue, Wed, Thu, Fri]\n").unwrap(),
+            multiplier: 0.5,
+        }];
+        config.links = vec![metered, link_config("eth1", None)];
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        scheduler.set_clock(clock.clone());
+        let metrics = test_metrics();
+        let set_time = |s: &str| clock.set(chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap().and_utc());
+        
+        // Friday noon: eth0's better latency doesn't outweigh its multiplier
+        set_time("2026-10-16 12:00");
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth1");
+        
+        // Friday evening and Saturday noon are outside the window
+        set_time("2026-10-16 19:00");
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth0");
+        set_time("2026-10-17 12:00");
+        assert_eq!(scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap(), "eth0");
+    }
+    
+    #[tokio::test]
+    async fn test_last_selection_explained() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        assert!(scheduler.explain_last_selection().is_empty());
+        
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth1").unwrap().packet_loss = 0.1;
+        metrics.get_mut("eth1").unwrap().reliability = 0.8;
+        let selected = scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap();
+        
+        let breakdown = scheduler.explain_last_selection();
+        assert_eq!(breakdown.len(), 2);
+        assert_eq!(breakdown[0].link_name, selected);
+        assert!(breakdown[0].selected && !breakdown[1].selected);
+        for link in &breakdown {
+            let components = link.latency + link.bandwidth + link.loss + link.jitter;
+            assert!((components - link.score).abs() < 1e-9, "{:?}", link);
+        }
+        
+        // Through the service, as an operator would ask
+        let response = SelectionExplanationService::explain_last_selection(&scheduler, ExplainSelectionRequest {}).await.unwrap();
+        assert_eq!(response.links, breakdown);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_full_metrics_channel_waits_without_blocking_the_runtime() {
+        let mut config = Config::default();
+        config.scheduler.metrics_channel_capacity = 1;
+        config.scheduler.overflow_policy = crate::channel::OverflowPolicy::Block;
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        
+        // Nothing takes reports, so the second waits for room; a blocking send
+        // would stall this single-threaded runtime here for good
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(scheduler.metrics_receiver.try_recv().is_ok());
+        assert!(scheduler.metrics_receiver.try_recv().is_err());
+        
+        // Once there's room the waiting report goes through
+        tokio::time::sleep(METRICS_BACKPRESSURE_POLL * 2).await;
+        assert!(scheduler.metrics_receiver.try_recv().is_ok());
+        scheduler.stop();
+    }
+    
+    #[tokio::test]
+    async fn test_full_link_queue_does_not_block_others() {
+        let mut config = Config {
+            links: vec![link_config("eth0", None), link_config("eth1", None)],
+            ..Config::default()
+        };
+        config.scheduler.packet_channel_capacity = Some(2);
+        config.scheduler.overflow_policy = crate::channel::OverflowPolicy::DropNewest;
+        let selections = ["eth0", "eth0", "eth0", "eth1", "eth1", "eth1"];
+        let mut scheduler = PacketScheduler::with_selector(
+            config,
+            "http://localhost:9093".to_string(),
+            Box::new(MockLinkSelector::new(&selections)),
+        ).await.unwrap();
+        let transport = Arc::new(MockTransport::new());
+        scheduler.set_transport(transport.clone());
+        let metrics = test_metrics();
+        
+        // Nothing drains eth0, so its queue fills and the third packet is dropped
+        for sequence_number in 1..=3 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, sequence_number, &metrics).await.unwrap();
+        }
+        assert_eq!(scheduler.link_queue_depths()["eth0"], 2);
+        
+        // eth1 keeps flowing as its own queue drains
+        let eth1 = scheduler.link_queue_for("eth1");
+        for sequence_number in 4..=6 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, sequence_number, &metrics).await.unwrap();
+            scheduler.deliver(eth1.take().unwrap()).await;
+        }
+        let sent_on = |link: &str| -> Vec<u64> {
+            transport.sent().into_iter().filter(|(name, _)| name == link).map(|(_, sequence_number)| sequence_number).collect()
+        };
+        assert_eq!(sent_on("eth1"), vec![4, 5, 6]);
+        assert!(sent_on("eth0").is_empty());
+        
+        scheduler.flush_link_queues().await;
+        assert_eq!(sent_on("eth0"), vec![1, 2]);
+    }
+    
+    #[tokio::test]
+    async fn test_running_scheduler_drains_link_queues_into_transport() {
+        let mut config = Config::default();
+        // A one-packet queue under Block makes workers wait for the drain
+        // task on every packet
+        config.scheduler.packet_channel_capacity = Some(1);
+        config.scheduler.overflow_policy = crate::channel::OverflowPolicy::Block;
+        let mut scheduler = PacketScheduler::with_metrics_provider(config, Arc::new(crate::metrics_provider::StaticMetricsProvider::new(test_metrics()))).await.unwrap();
+        let transport = Arc::new(MockTransport::new());
+        scheduler.set_transport(transport.clone());
+        let scheduler = Arc::new(scheduler);
+        let (handle, shutdown) = scheduler.clone().spawn();
+        
+        for id in 0..50 {
+            scheduler.enqueue(test_packet(&format!("192.168.1.{}", id))).unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while transport.sent().len() < 50 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.expect("queued packets were not all sent");
+        assert!(scheduler.link_queue_depths().values().all(|depth| *depth == 0));
+        
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_deep_queue_shifts_selection() {
+        let mut config = Config::default();
+        config.scheduler.packet_channel_capacity = Some(10);
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let transport = Arc::new(MockTransport::new());
+        scheduler.set_transport(transport.clone());
+        let metrics = test_metrics();
+        let packet = test_packet("192.168.1.10");
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth0");
+        
+        // Back up eth0's queue as if the transport had stalled on it
+        let eth0 = scheduler.link_queue_for("eth0");
+        for sequence_number in 0..8 {
+            eth0.sender.send(QueuedPacket { scheduled: scheduled("eth0", sequence_number), retry: false, algorithm: None }).unwrap();
+        }
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth1");
+        
+        // Once the transport catches up eth0 is preferred again
+        scheduler.flush_link_queues().await;
+        assert_eq!(transport.sent().len(), 8);
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth0");
+    }
+    
+    #[tokio::test]
+    async fn test_policy_route_pins_source_subnet() {
+        let mut config = Config::default();
+        config.links = vec![link_config("eth0", None), link_config("eth1", None)];
+        config.policy_routes = vec![crate::config::PolicyRoute {
+            source: "192.168.1.0/24".to_string(),
+            link: "eth1".to_string(),
+            on_link_down: crate::config::PolicyFallback::Drop,
+        }];
+        config.scheduler.flow_affinity = true;
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        let mut metrics = test_metrics();
+        
+        // eth0 scores better, but the subnet is pinned to eth1
+        scheduler.schedule_packet(test_packet("192.168.1.10"), None, 1, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        scheduler.schedule_packet(test_packet("10.0.0.1"), None, 2, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        assert_eq!(transport.sent(), vec![("eth1".to_string(), 1), ("eth0".to_string(), 2)]);
+        // The policy link counts as selected, not idle, and carries the flow
+        assert!(scheduler.idle_links(&metrics, chrono::Duration::seconds(60)).is_empty());
+        assert_eq!(scheduler.flow_table.flows_on_link("eth1"), 1);
+        
+        // With eth1 gone the pinned subnet is dropped, not rerouted
+        metrics.remove("eth1");
+        scheduler.schedule_packet(test_packet("192.168.1.10"), None, 3, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        assert_eq!(transport.sent().len(), 2);
+        assert_eq!(scheduler.stats().snapshot().packets_policy_dropped, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_shaped_class_queued_over_ceiling() {
+        let mut config = Config::default();
+        config.links = vec![link_config("eth0", None), link_config("eth1", None)];
+        config.shaping = vec![crate::config::LinkShaping {
+            link: "eth0".to_string(),
+            rate: "80Kbps".parse().unwrap(),
+            classes: vec![crate::config::ShapingClass {
+                name: "voip".to_string(),
+                rate: "8Kbps".parse().unwrap(),
+                ceil: Some("16Kbps".parse().unwrap()),
+            }],
+        }];
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        scheduler.add_qos_rule(voip_rule(7)).unwrap();
+        let metrics = test_metrics();
+        
+        // A burst of 100-byte voip packets: one full-size frame's worth fits
+        // the class's bucket, the rest waits for its ceiling to refill
+        for seq in 1..=30 {
+            let mut packet = test_packet("192.168.1.100");
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        // Unclassified traffic isn't shaped
+        for seq in 31..=35 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, seq, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        
+        assert_eq!(transport.sent().len(), 20);
+        assert_eq!(scheduler.shapers.read()["eth0"].lock().queued(), 15);
+        assert_eq!(scheduler.stats().snapshot().packets_shaped, 0);
+    }
+
+    #[tokio::test]
+    async fn test_shaped_class_dropped_when_queue_full() {
+        let mut config = Config::default();
+        config.scheduler.packet_channel_capacity = Some(4);
+        config.links = vec![link_config("eth0", None), link_config("eth1", None)];
+        config.shaping = vec![crate::config::LinkShaping {
+            link: "eth0".to_string(),
+            rate: "80Kbps".parse().unwrap(),
+            classes: vec![crate::config::ShapingClass {
+                name: "voip".to_string(),
+                rate: "8Kbps".parse().unwrap(),
+                ceil: Some("16Kbps".parse().unwrap()),
+            }],
+        }];
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        scheduler.add_qos_rule(voip_rule(7)).unwrap();
+        let metrics = test_metrics();
+        
+        // 15 go at once and 4 wait; the rest find the voip queue full
+        for seq in 1..=30 {
+            let mut packet = test_packet("192.168.1.100");
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        assert_eq!(transport.sent().len(), 15);
+        assert_eq!(scheduler.stats().snapshot().packets_shaped, 11);
+    }
+    
+    #[tokio::test]
+    async fn test_enqueue_assigns_packet_ids() {
+        let mut config = Config::default();
+        config.scheduler.batch_size = 8;
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        
+        for _ in 0..3 {
+            scheduler.enqueue(test_packet("192.168.1.10")).unwrap();
+        }
+        let batch = scheduler.work_queues.next_batch(0);
+        assert_eq!(batch.iter().map(|packet| packet.id).collect::<Vec<u64>>(), vec![1, 2, 3]);
+        assert_eq!(scheduler.packet_ids().next(), 4);
+        
+        // Packets fed in from another task are numbered from the same counter
+        let intake = scheduler.intake();
+        tokio::spawn(async move { intake.enqueue(test_packet("192.168.1.10")) }).await.unwrap().unwrap();
+        assert_eq!(scheduler.work_queues.next_batch(0)[0].id, 5);
+    }
+    
+    #[tokio::test]
+    async fn test_enqueue_fails_when_full() {
+        let mut config = Config::default();
+        config.scheduler.max_queue_size = 2;
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        
+        scheduler.enqueue(test_packet("192.168.1.10")).unwrap();
+        scheduler.enqueue(test_packet("192.168.1.10")).unwrap();
+        assert!(scheduler.enqueue(test_packet("192.168.1.10")).is_err());
+    }
+    
+    fn voip_rule(priority: u8) -> QosRule {
+        QosRule {
+            name: "voip".to_string(),
+            priority,
+            match_criteria: crate::config::MatchCriteria {
+                source_ip: Some("192.168.1.100".to_string()),
+                dest_ip: None,
+                protocol: None,
+                port_range: None,
+                dscp: None,
+                icmp_type: None,
+                icmp_code: None,
+                vlan_id: None,
+                inner_vlan_id: None,
+            },
+            action: crate::config::QosAction {
+                link_preference: vec![],
+                bandwidth_limit: None,
+                latency_threshold: None,
+                max_age_ms: None,
+                pin_first_packets: None,
+                scheduler_algorithm: None,
+                min_bandwidth: None,
+            },
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_runtime_qos_rule_changes_apply_immediately() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let mut packet = test_packet("192.168.1.100");
+        assert!(scheduler.apply_qos_rules(&mut packet).is_none());
+        
+        scheduler.add_qos_rule(voip_rule(6)).unwrap();
+        assert_eq!(scheduler.apply_qos_rules(&mut packet).unwrap().priority, 6);
+        assert!(scheduler.add_qos_rule(voip_rule(6)).is_err());
+        
+        scheduler.update_qos_rule(voip_rule(7)).unwrap();
+        assert_eq!(scheduler.apply_qos_rules(&mut packet).unwrap().priority, 7);
+        
+        scheduler.remove_qos_rule("voip").unwrap();
+        assert!(scheduler.apply_qos_rules(&mut packet).is_none());
+        assert!(scheduler.remove_qos_rule("voip").is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_icmp_rule_matches_only_its_type() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let mut rule = voip_rule(2);
+        rule.name = "echo-request".to_string();
+        rule.match_criteria.source_ip = None;
+        rule.match_criteria.protocol = Some(Protocol::Icmp);
+        rule.match_criteria.icmp_type = Some(8);
+        scheduler.add_qos_rule(rule).unwrap();
+        
+        let icmp = |icmp_type: Option<u8>, icmp_code: Option<u8>| Packet {
+            protocol: Protocol::Icmp,
+            icmp_type,
+            icmp_code,
+            ..test_packet("192.168.1.100")
+        };
+        assert_eq!(scheduler.apply_qos_rules(&mut icmp(Some(8), Some(0))).unwrap().name, "echo-request");
+        assert!(scheduler.apply_qos_rules(&mut icmp(Some(0), Some(0))).is_none());
+        assert!(scheduler.apply_qos_rules(&mut icmp(Some(3), Some(4))).is_none());
+        assert!(scheduler.apply_qos_rules(&mut icmp(None, None)).is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_untrusted_dscp_not_classified() {
+        let mut config = Config::default();
+        config.qos.trust_dscp_from = Some(vec!["10.0.0.0/8".parse().unwrap()]);
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let mut rule = voip_rule(7);
+        rule.match_criteria.source_ip = None;
+        rule.match_criteria.dscp = Some(46);
+        scheduler.add_qos_rule(rule).unwrap();
+        
+        let marked_from = |source_ip: &str| Packet { dscp: Some(46), ..test_packet(source_ip) };
+        assert_eq!(scheduler.apply_qos_rules(&mut marked_from("10.1.2.3")).unwrap().name, "voip");
+        assert!(scheduler.apply_qos_rules(&mut marked_from("192.168.1.100")).is_none());
+        assert!(scheduler.apply_qos_rules(&mut Packet { dscp: Some(10), ..test_packet("10.1.2.3") }).is_none());
+        // Nor does a packet that carries no marking at all
+        assert!(scheduler.apply_qos_rules(&mut Packet { dscp: None, ..test_packet("10.1.2.3") }).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_packets_get_trusted_dscp_priority() {
+        let mut config = Config::default();
+        config.qos.trust_dscp_from = Some(vec!["10.0.0.0/8".parse().unwrap()]);
+        config.qos.default_priority = 3;
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        
+        let classified = |mut packet: Packet| {
+            scheduler.apply_qos_rules(&mut packet);
+            packet.priority
+        };
+        // EF is telephony in the default RFC 4594 table
+        assert_eq!(classified(Packet { dscp: Some(46), ..test_packet("10.1.2.3") }), 6);
+        assert_eq!(classified(Packet { dscp: Some(8), ..test_packet("10.1.2.3") }), 1);
+        // Marks from outside the trust boundary count as DF
+        assert_eq!(classified(Packet { dscp: Some(46), ..test_packet("192.168.1.10") }), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_unmatched_packets_get_protocol_defaults() {
+        let mut config = Config::default();
+        config.qos.protocol_defaults.insert("ICMP".to_string(), 6);
+        config.qos.default_priority = 3;
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.add_qos_rule(voip_rule(4)).unwrap();
+        
+        let classified = |mut packet: Packet| {
+            scheduler.apply_qos_rules(&mut packet);
+            packet.priority
+        };
+        assert_eq!(classified(test_packet("192.168.1.100")), 4);
+        assert_eq!(classified(Packet { protocol: Protocol::Icmp, ..test_packet("192.168.1.10") }), 6);
+        assert_eq!(classified(Packet { dscp: None, ..test_packet("192.168.1.10") }), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_scheduled_packets_count_towards_class_sla() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let mut rule = voip_rule(7);
+        rule.action.latency_threshold = Some(20);
+        scheduler.add_qos_rule(rule).unwrap();
+        let metrics = test_metrics();
+        
+        // eth0 adds 5ms; the stale packets waited 100ms before scheduling
+        for seq in 1..=4 {
+            let mut packet = test_packet("192.168.1.100");
+            if seq > 3 {
+                packet.timestamp = Utc::now() - chrono::Duration::milliseconds(100);
+            }
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+        }
+        scheduler.schedule_packet(test_packet("192.168.1.10"), None, 5, &metrics).await.unwrap();
+        
+        assert_eq!(scheduler.stats().sla_compliance("voip"), 0.75);
+        assert_eq!(scheduler.stats().snapshot().class_sla.len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_packet_past_deadline_dropped() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let mut rule = voip_rule(7);
+        rule.action.max_age_ms = Some(50);
+        scheduler.add_qos_rule(rule).unwrap();
+        
+        let mut stale = test_packet("192.168.1.100");
+        stale.timestamp = Utc::now() - chrono::Duration::milliseconds(200);
+        // Classes without a deadline are sent however long they waited
+        let mut unclassified = test_packet("192.168.1.10");
+        unclassified.timestamp = stale.timestamp;
+        scheduler.enqueue(stale).unwrap();
+        scheduler.enqueue(test_packet("192.168.1.100")).unwrap();
+        scheduler.enqueue(unclassified).unwrap();
+        
+        assert_eq!(scheduler.process_packet_batch(0, &test_metrics()).await.unwrap(), 2);
+        let stats = scheduler.stats().snapshot();
+        assert_eq!(stats.packets_expired, 1);
+        assert_eq!(stats.packets_scheduled, 2);
+        assert_eq!(stats.packets_shed, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_stale_packets_and_idle_flows_evicted_on_mock_clock() {
+        let mut config = Config::default();
+        config.scheduler.flow_affinity = true;
+        config.scheduler.flow_idle_timeout = 1000;
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        scheduler.set_clock(clock.clone());
+        let mut rule = voip_rule(7);
+        rule.action.max_age_ms = Some(50);
+        scheduler.add_qos_rule(rule).unwrap();
+        let metrics = test_metrics();
+        let voice = || Packet { timestamp: clock.now(), ..test_packet("192.168.1.100") };
+        
+        // Exactly at the deadline a packet is still sent; a millisecond
+        // later it's dropped
+        scheduler.enqueue(voice()).unwrap();
+        clock.advance(chrono::Duration::milliseconds(50));
+        assert_eq!(scheduler.process_packet_batch(0, &metrics).await.unwrap(), 1);
+        scheduler.enqueue(voice()).unwrap();
+        clock.advance(chrono::Duration::milliseconds(51));
+        assert_eq!(scheduler.process_packet_batch(0, &metrics).await.unwrap(), 0);
+        let stats = scheduler.stats().snapshot();
+        assert_eq!((stats.packets_scheduled, stats.packets_expired), (1, 1));
+        
+        // The flow pinned by the sent packet 51ms ago idles out with the clock
+        assert_eq!(scheduler.flow_table.pinned_flows().len(), 1);
+        clock.advance(chrono::Duration::milliseconds(948));
+        scheduler.reap_idle_flows();
+        assert_eq!(scheduler.flow_table.pinned_flows().len(), 1);
+        clock.advance(chrono::Duration::milliseconds(2));
+        scheduler.reap_idle_flows();
+        assert!(scheduler.flow_table.pinned_flows().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_first_packets_of_flow_pinned_to_most_reliable_link() {
+        let mut config = Config::default();
+        config.scheduler.flow_affinity = true;
+        let mock = Arc::new(MockLinkSelector::new(&["eth0", "eth0"]));
+        let mut scheduler = PacketScheduler::with_selector(config, "http://localhost:9093".to_string(), Box::new(mock.clone()))
+            .await
+            .unwrap();
+        let transport = Arc::new(MockTransport::new());
+        scheduler.set_transport(transport.clone());
+        let mut rule = voip_rule(7);
+        rule.action.pin_first_packets = Some(2);
+        scheduler.add_qos_rule(rule).unwrap();
+        
+        // eth0 is what the selector picks; eth1 is the more reliable link
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().reliability = 0.9;
+        
+        for seq in 1..=4 {
+            let mut packet = test_packet("192.168.1.100");
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        // A flow no rule pins goes straight to the selector
+        scheduler.schedule_packet(test_packet("192.168.1.10"), None, 5, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        
+        let links: Vec<String> = transport.sent().into_iter().map(|(link, _)| link).collect();
+        assert_eq!(links, ["eth1", "eth1", "eth0", "eth0", "eth0"]);
+        // After the pinned packets the flow sticks to its first selection
+        assert_eq!(mock.calls(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_pins_follow_link_multipliers() {
+        let mut config = Config::default();
+        config.scheduler.flow_affinity = true;
+        let mock = Arc::new(MockLinkSelector::new(&["eth0", "eth1"]));
+        let mut scheduler = PacketScheduler::with_selector(config, "http://localhost:9093".to_string(), Box::new(mock.clone()))
+            .await
+            .unwrap();
+        let transport = Arc::new(MockTransport::new());
+        scheduler.set_transport(transport.clone());
+        let mut rule = voip_rule(7);
+        rule.action.pin_first_packets = Some(1);
+        scheduler.add_qos_rule(rule).unwrap();
+        
+        // eth1 is the more reliable link until the operator halves it
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().reliability = 0.9;
+        scheduler.set_link_multiplier("eth1", 0.5).unwrap();
+        
+        for seq in 1..=3 {
+            if seq == 3 {
+                // Weighting the flow's link down to nothing moves the flow
+                scheduler.set_link_multiplier("eth0", 0.0).unwrap();
+            }
+            let mut packet = test_packet("192.168.1.100");
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        
+        let links: Vec<String> = transport.sent().into_iter().map(|(link, _)| link).collect();
+        assert_eq!(links, ["eth0", "eth0", "eth1"]);
+        assert_eq!(mock.calls(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_invalid_runtime_qos_rule_rejected() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        
+        assert!(scheduler.add_qos_rule(voip_rule(9)).is_err());
+        assert!(scheduler.update_qos_rule(voip_rule(6)).is_err());
+        
+        scheduler.add_qos_rule(voip_rule(6)).unwrap();
+        let mut reversed_ports = voip_rule(6);
+        reversed_ports.match_criteria.port_range = Some(crate::config::PortRange { start: 2000, end: 1000 });
+        assert!(scheduler.update_qos_rule(reversed_ports).is_err());
+        assert_eq!(scheduler.qos_rules.get("voip").unwrap().priority, 6);
+    }
+    
+    #[tokio::test]
+    async fn test_runtime_reservations_checked_and_shaped() {
+        let mut links = vec![link_config("eth0", None), link_config("eth1", None)];
+        links[0].max_bandwidth = 800_000;
+        let config = Config { links, ..Default::default() };
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        assert!(scheduler.shapers.read().is_empty());
+        
+        // More than eth0 can carry
+        let mut voip = voip_rule(6);
+        voip.action.min_bandwidth = Some(1_000_000);
+        assert!(scheduler.add_qos_rule(voip.clone()).is_err());
+        assert!(scheduler.qos_rules.is_empty());
+        
+        voip.action.min_bandwidth = Some(240_000);
+        scheduler.add_qos_rule(voip.clone()).unwrap();
+        assert_eq!(scheduler.shapers.read().len(), 2);
+        
+        voip.action.min_bandwidth = Some(900_000);
+        assert!(scheduler.update_qos_rule(voip).is_err());
+        assert_eq!(scheduler.qos_rules.get("voip").unwrap().action.min_bandwidth, Some(240_000));
+        
+        scheduler.remove_qos_rule("voip").unwrap();
+        assert!(scheduler.shapers.read().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_queued_packets_survive_shaper_rebuild() {
+        let mut config = Config::default();
+        config.links = vec![link_config("eth0", None), link_config("eth1", None)];
+        config.shaping = vec![crate::config::LinkShaping {
+            link: "eth0".to_string(),
+            rate: "80Kbps".parse().unwrap(),
+            classes: vec![crate::config::ShapingClass {
+                name: "voip".to_string(),
+                rate: "8Kbps".parse().unwrap(),
+                ceil: Some("16Kbps".parse().unwrap()),
+            }],
+        }];
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        scheduler.add_qos_rule(voip_rule(7)).unwrap();
+        let metrics = test_metrics();
+        for seq in 1..=30 {
+            let mut packet = test_packet("192.168.1.100");
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+        }
+        let queued = scheduler.shapers.read()["eth0"].lock().queued();
+        assert!(queued > 0);
+        
+        scheduler.update_qos_rule(voip_rule(6)).unwrap();
+        assert_eq!(scheduler.shapers.read()["eth0"].lock().queued(), queued);
+    }
+    
+    #[tokio::test]
+    async fn test_unknown_algorithm_rejected() {
+        let mut config = Config::default();
+        config.scheduler.algorithm = "coin_flip".to_string();
+        assert!(PacketScheduler::new(config, "http://localhost:9093".to_string()).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_excluded_link_recovers_and_is_reselected() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let packet = test_packet("192.168.1.10");
+        
+        let mut metrics = test_metrics();
+        let eth1 = metrics.get_mut("eth1").unwrap();
+        eth1.packet_loss = 1.0;
+        eth1.bandwidth_mbps = 0.0;
+        for _ in 0..3 {
+            let available = scheduler.refresh_metrics(&metrics);
+            assert_eq!(scheduler.select_link_for(&packet, None, &available).await.unwrap(), "eth0");
+        }
+        assert!(!scheduler.refresh_metrics(&metrics).contains_key("eth1"));
+        assert_eq!(scheduler.idle_links(&metrics, chrono::Duration::zero()), vec!["eth0", "eth1"]);
+        assert_eq!(scheduler.idle_links(&metrics, chrono::Duration::seconds(60)), vec!["eth1"]);
+        
+        // eth1 keeps being re-evaluated while excluded and comes back better
+        let eth1 = metrics.get_mut("eth1").unwrap();
+        eth1.packet_loss = 0.0;
+        eth1.latency_ms = 1.0;
+        eth1.bandwidth_mbps = 500.0;
+        let mut available = HashMap::new();
+        for _ in 0..5 {
+            available = scheduler.refresh_metrics(&metrics);
+        }
+        assert!(available.contains_key("eth1"));
+        assert_eq!(scheduler.select_link_for(&packet, None, &available).await.unwrap(), "eth1");
+        assert!(scheduler.idle_links(&metrics, chrono::Duration::seconds(60)).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_idle_links_reported_to_underlay() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let underlay = UnderlayClient::new(&listener.local_addr().unwrap().to_string(), Duration::from_secs(1));
+        let requests = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let line = BufReader::new(reader).lines().next_line().await.unwrap().unwrap();
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                requests.push((request["params"]["interface_name"].as_str().unwrap().to_string(), request["params"]["idle"].as_bool().unwrap()));
+                writer.write_all(b"{\"result\": {}}\n").await.unwrap();
+            }
+            requests
+        });
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        *scheduler.current_metrics.write() = Arc::new(test_metrics());
+        scheduler.last_selected.insert("eth0".to_string(), Utc::now());
+        
+        let mut reported = HashSet::new();
+        scheduler.report_idle_changes(&underlay, &mut reported).await;
+        // Nothing changed, so nothing is sent
+        scheduler.report_idle_changes(&underlay, &mut reported).await;
+        scheduler.last_selected.insert("eth1".to_string(), Utc::now());
+        scheduler.report_idle_changes(&underlay, &mut reported).await;
+        
+        assert!(reported.is_empty());
+        assert_eq!(requests.await.unwrap(), vec![("eth1".to_string(), true), ("eth1".to_string(), false)]);
+    }
+    
+    #[tokio::test]
+    async fn test_destination_metrics_polled_from_underlay() {
+        use crate::proto::{DestinationMetric, DestinationMetricsResponse, MetricsResponse, RpcReply};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let underlay = UnderlayClient::new(&listener.local_addr().unwrap().to_string(), Duration::from_secs(1));
+        let measured = |link: &str, latency_ms: f64, timestamp: DateTime<Utc>| DestinationMetric {
+            prefix: "203.0.113.0/24".to_string(),
+            metrics: MetricsResponse {
+                interface_name: link.to_string(),
+                latency_ms,
+                jitter_ms: 1.0,
+                packet_loss: 0.0,
+                bandwidth_mbps: 100.0,
+                reliability: 1.0,
+                bufferbloat_ms: 0.0,
+                bandwidth_up_mbps: None,
+                bandwidth_measured: true,
+                timestamp: timestamp.to_rfc3339(),
+            },
+        };
+        let now = Utc::now();
+        // eth0 is faster overall but peers badly with 203.0.113.0/24
+        let replies = vec![
+            vec![measured("eth0", 150.0, now), measured("eth1", 30.0, now)],
+            // Then only eth1 is measured, long ago
+            vec![measured("eth1", 30.0, now - chrono::Duration::minutes(5))],
+        ];
+        tokio::spawn(async move {
+            for metrics in replies {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                BufReader::new(reader).lines().next_line().await.unwrap().unwrap();
+                let response = DestinationMetricsResponse { metrics, timestamp: Utc::now().to_rfc3339() };
+                let reply = RpcReply::Result(serde_json::to_value(response).unwrap());
+                writer.write_all(format!("{}\n", serde_json::to_string(&reply).unwrap()).as_bytes()).await.unwrap();
+            }
+        });
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let metrics = test_metrics();
+        let packet = Packet { dest_ip: "203.0.113.9".to_string(), ..test_packet("192.168.1.10") };
+        
+        scheduler.refresh_destination_metrics(&underlay).await;
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth1");
+        
+        // Measurements past the TTL are dropped, leaving the overall metrics
+        let clock = Arc::new(MockClock::new(now + chrono::Duration::minutes(1)));
+        let mut scheduler = scheduler;
+        scheduler.set_clock(clock);
+        scheduler.refresh_destination_metrics(&underlay).await;
+        assert!(scheduler.destination_metrics.is_empty());
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth0");
+    }
+    
+    #[tokio::test]
+    async fn test_link_multiplier_shifts_selection() {
+        let mut config = Config::default();
+        config.scheduler.algorithm = "weighted_ecmp".to_string();
+        config.scheduler.rng_seed = Some(3);
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let packet = test_packet("192.168.1.10");
+        let metrics = test_metrics();
+        
+        let eth0_share = || async {
+            let mut picks = 0;
+            for _ in 0..4000 {
+                if scheduler.select_link_for(&packet, None, &metrics).await.unwrap() == "eth0" {
+                    picks += 1;
+                }
+            }
+            picks as f64 / 4000.0
+        };
+        
+        // Scores 0.422 and 0.383 split traffic about 52:48
+        let share = eth0_share().await;
+        assert!((share - 0.525).abs() < 0.03, "eth0 share was {}", share);
+        
+        scheduler.set_link_multiplier("eth0", 0.5).unwrap();
+        let share = eth0_share().await;
+        assert!((share - 0.356).abs() < 0.03, "eth0 share was {}", share);
+        
+        scheduler.set_link_multiplier("eth0", 0.0).unwrap();
+        assert_eq!(eth0_share().await, 0.0);
+        
+        assert!(scheduler.set_link_multiplier("eth0", 1.5).is_err());
+        scheduler.set_link_multiplier("eth0", 1.0).unwrap();
+        assert_eq!(scheduler.link_multiplier("eth0"), 1.0);
+    }
+    
+    #[tokio::test]
+    async fn test_link_multiplier_invalidates_cached_ranking() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let packet = test_packet("192.168.1.10");
+        let metrics = test_metrics();
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth0");
+        
+        scheduler.set_link_multiplier("eth0", 0.5).unwrap();
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth1");
+    }
+    
+    #[tokio::test]
+    async fn test_group_health_service() {
+        let config = Config {
+            links: vec![link_config("eth0", Some("primary")), link_config("eth1", Some("backup"))],
+            ..Config::default()
+        };
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.refresh_metrics(&test_metrics());
+        
+        let response = scheduler.get_group_health(GroupHealthRequest { group: "primary".to_string() }).await.unwrap();
+        assert_eq!(response.health, test_metrics()["eth0"].health_score());
+        assert!(scheduler.get_group_health(GroupHealthRequest { group: "tertiary".to_string() }).await.is_err());
+    }
+    
+    #[test]
+    fn test_build_selector_for_each_algorithm() {
+        let config = Config::default();
+        for algorithm in SCHEDULER_ALGORITHMS {
+            assert_eq!(build_selector(algorithm, &config).unwrap().name(), algorithm);
+        }
+        
+        let error = build_selector("round_robin", &config).err().unwrap();
+        assert_eq!(error.to_string(), "Unknown scheduler algorithm: round_robin");
+    }
+    
+    #[tokio::test]
+    async fn test_debug_state_names_selector() {
+        let mut config = Config::default();
+        config.scheduler.algorithm = "mos".to_string();
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        assert_eq!(scheduler.debug_state().selector, "mos");
+        
+        config = Config::default();
+        config.scheduler.algorithm = "fastest".to_string();
+        assert!(PacketScheduler::new(config, "http://localhost:9093".to_string()).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_seeded_ecmp_selection_is_reproducible() {
+        let packet = test_packet("192.168.1.10");
+        let metrics = test_metrics();
+        let mut config = Config::default();
+        config.scheduler.algorithm = "weighted_ecmp".to_string();
+        config.scheduler.rng_seed = Some(42);
+        
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let selector = build_selector(&config.scheduler.algorithm, &config).unwrap();
+            let mut picks = Vec::new();
+            for _ in 0..100 {
+                picks.push(selector.select_link(&packet, &metrics).await.unwrap());
+            }
+            runs.push(picks);
+        }
+        
+        assert_eq!(runs[0], runs[1]);
+        // Both links are healthy, so both get traffic
+        assert!(runs[0].iter().any(|link| link == "eth0"));
+        assert!(runs[0].iter().any(|link| link == "eth1"));
+    }
+    
+    #[tokio::test]
+    async fn test_ecmp_skips_down_links() {
+        let selector = WeightedEcmpSelector::new(Some(7));
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().packet_loss = 1.0;
+        
+        for _ in 0..20 {
+            assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+        }
+        metrics.get_mut("eth1").unwrap().bandwidth_mbps = 0.0;
+        assert!(selector.select_link(&packet, &metrics).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_equal_scores_follow_configured_weights() {
+        let mut heavy = link_config("eth0", None);
+        heavy.weight = 3.0;
+        let selector = WeightedRoundRobinSelector::new()
+            .with_link_weights(&[heavy, link_config("eth1", None)])
+            .with_rng_seed(11);
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        let eth0 = metrics["eth0"].clone();
+        metrics.insert("eth1".to_string(), eth0);
+        
+        let mut eth0_picks = 0;
+        for _ in 0..4000 {
+            if selector.select_link(&packet, &metrics).await.unwrap() == "eth0" {
+                eth0_picks += 1;
+            }
+        }
+        
+        // Weights 3:1 should give eth0 about 75% of selections
+        let share = eth0_picks as f64 / 4000.0;
+        assert!((share - 0.75).abs() < 0.03, "eth0 share was {}", share);
+    }
+    
+    #[tokio::test]
+    async fn test_vanished_link_is_not_selected() {
+        let selector = WeightedRoundRobinSelector::new();
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        
+        metrics.remove("eth0");
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+        
+        metrics.clear();
+        assert!(selector.select_link(&packet, &metrics).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_ranking_cache_scores_once_per_metrics_update() {
+        let selector = WeightedRoundRobinSelector::new().with_ranking_cache();
+        let mut packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        
+        for _ in 0..100 {
+            assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        }
+        assert_eq!(selector.scoring_runs(), 1);
+        
+        // Each priority class keeps its own ranking
+        packet.priority = 7;
+        selector.select_link(&packet, &metrics).await.unwrap();
+        assert_eq!(selector.scoring_runs(), 2);
+        
+        // eth1 is preferred only once the new report is announced
+        metrics.get_mut("eth0").unwrap().packet_loss = 0.5;
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        selector.metrics_updated();
+        for _ in 0..100 {
+            assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+        }
+        assert_eq!(selector.scoring_runs(), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_spawned_scheduler_stops_on_cancel() {
+        let scheduler = Arc::new(PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap());
+        let (handle, shutdown) = scheduler.clone().spawn();
+        
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.is_finished());
+        
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_make_before_break_delivers_without_gaps() {
+        let mut config = Config::default();
+        config.failover.make_before_break = true;
+        let transport = Arc::new(MockTransport::new());
+        let scheduler = scheduler_with_transport(config, &["eth0"; 18], transport.clone()).await;
+        
+        let healthy = test_metrics();
+        let mut degraded = test_metrics();
+        degraded.get_mut("eth0").unwrap().latency_ms = 50.0;
+        
+        for _ in 0..3 {
+            scheduler.refresh_metrics(&healthy);
+        }
+        let available = scheduler.refresh_metrics(&healthy);
+        for seq in 1..=10 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, seq, &available).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        
+        // eth0 degrades: packets are duplicated until eth1 has delivered 5
+        let available = scheduler.refresh_metrics(&degraded);
+        for seq in 11..=18 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, seq, &available).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        
+        let sent = transport.sent();
+        let on = |link: &str| sent.iter().filter(|(l, _)| l == link).map(|(_, seq)| *seq).collect::<Vec<u64>>();
+        assert_eq!(on("eth0"), (1..=18).collect::<Vec<u64>>());
+        assert_eq!(on("eth1"), (11..=15).collect::<Vec<u64>>());
+        
+        let mut delivered: Vec<u64> = sent.iter().map(|(_, seq)| *seq).collect();
+        delivered.sort_unstable();
+        delivered.dedup();
+        assert_eq!(delivered, (1..=18).collect::<Vec<u64>>());
+    }
+    
+    #[tokio::test]
+    async fn test_drains_honored_during_handover() {
+        let mut config = Config::default();
+        config.failover.make_before_break = true;
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        
+        let healthy = test_metrics();
+        let mut degraded = test_metrics();
+        degraded.get_mut("eth0").unwrap().latency_ms = 50.0;
+        for _ in 0..3 {
+            scheduler.refresh_metrics(&healthy);
+        }
+        let available = scheduler.refresh_metrics(&healthy);
+        scheduler.schedule_packet(test_packet("192.168.1.10"), None, 1, &available).await.unwrap();
+        scheduler.flush_link_queues().await;
+        // Failover would leave the degraded primary out; offer both
+        scheduler.refresh_metrics(&degraded);
+        assert!(matches!(scheduler.failover.read().handover(), Some(crate::failover::Handover::Dual { .. })));
+        
+        // A drained backup gets no duplicates
+        scheduler.drain_link("eth1", DrainMode::Hard);
+        for seq in 2..=3 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, seq, &degraded).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        // Nor does a backup the operator has weighted down to nothing
+        scheduler.undrain_link("eth1");
+        scheduler.set_link_multiplier("eth1", 0.0).unwrap();
+        scheduler.schedule_packet(test_packet("192.168.1.10"), None, 4, &degraded).await.unwrap();
+        scheduler.flush_link_queues().await;
+        // Nor does a drained primary keep the traffic
+        scheduler.set_link_multiplier("eth1", 1.0).unwrap();
+        scheduler.drain_link("eth0", DrainMode::Hard);
+        for seq in 5..=6 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, seq, &degraded).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        
+        let sent = transport.sent();
+        let on = |link: &str| sent.iter().filter(|(l, _)| l == link).map(|(_, seq)| *seq).collect::<Vec<u64>>();
+        assert_eq!(on("eth0"), vec![1, 2, 3, 4]);
+        assert_eq!(on("eth1"), vec![5, 6]);
+    }
+    
+    #[tokio::test]
+    async fn test_scheduled_flow_exported_as_ipfix() {
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+        let mut config = Config::default();
+        config.ipfix = Some(crate::config::IpfixConfig {
+            collector: collector.local_addr().unwrap().to_string(),
+            idle_timeout_ms: 1000,
+            active_timeout_ms: 60000,
+            observation_domain_id: 0,
+        });
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        
+        let metrics = test_metrics();
+        for seq in 1..=4 {
+            scheduler.schedule_packet(test_packet("192.168.1.10"), None, seq, &metrics).await.unwrap();
+        }
+        scheduler.export_flow_records(Utc::now());
+        assert_eq!(scheduler.ipfix.as_ref().unwrap().lock().pending(), 1);
+        
+        scheduler.export_flow_records(Utc::now() + chrono::Duration::seconds(1));
+        let mut message = [0u8; 1500];
+        let len = collector.recv(&mut message).unwrap();
+        // Header, template set, then a single IPv4 data record for eth0
+        let record = &message[len - 50..len];
+        assert_eq!(&record[0..4], &[192, 168, 1, 10]);
+        assert_eq!(record[12], 6);
+        assert_eq!(u64::from_be_bytes(record[13..21].try_into().unwrap()), 400);
+        assert_eq!(u64::from_be_bytes(record[21..29].try_into().unwrap()), 4);
+        assert_eq!(&record[45..], b"\x04eth0");
+    }
+    
+    #[tokio::test]
+    async fn test_classes_route_with_their_own_algorithms() {
+        let mut config = Config::default();
+        config.scheduler.rng_seed = Some(7);
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.add_qos_rule(voip_rule(7)).unwrap();
+        let mut bulk = voip_rule(1);
+        bulk.name = "bulk".to_string();
+        bulk.match_criteria.source_ip = Some("192.168.1.200".to_string());
+        bulk.action.scheduler_algorithm = Some("weighted_ecmp".to_string());
+        scheduler.add_qos_rule(bulk).unwrap();
+        let metrics = test_metrics();
+        
+        // Round robin always picks the faster eth0; ECMP spreads bulk over both
+        let mut links = HashMap::new();
+        for seq in 0..200 {
+            let source_ip = if seq % 2 == 0 { "192.168.1.100" } else { "192.168.1.200" };
+            let mut packet = test_packet(source_ip);
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+            let selected = scheduler.last_selection.lock().as_ref().unwrap().link_name.clone();
+            links.entry(source_ip).or_insert_with(std::collections::HashSet::new).insert(selected);
+        }
+        
+        assert_eq!(links["192.168.1.100"], std::collections::HashSet::from(["eth0".to_string()]));
+        assert_eq!(links["192.168.1.200"].len(), 2);
+    }
+    
+    #[test]
+    fn test_unknown_class_algorithm_rejected() {
+        let mut rule = voip_rule(7);
+        rule.action.scheduler_algorithm = Some("fastest".to_string());
+        assert!(rule.validate().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_best_link_depends_on_destination() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let metrics = test_metrics();
+        // eth0 is faster overall but peers badly with 203.0.113.0/24
+        for (link, latency_ms) in [("eth0", 150.0), ("eth1", 30.0)] {
+            let mut metric = LinkMetrics::new();
+            metric.latency_ms = latency_ms;
+            metric.bandwidth_mbps = 100.0;
+            scheduler.update_destination_metrics(link, "203.0.113.0/24", metric).unwrap();
+        }
+        assert!(scheduler.update_destination_metrics("eth0", "203.0.113.0/33", LinkMetrics::new()).is_err());
+        
+        let packet_to = |dest_ip: &str| Packet { dest_ip: dest_ip.to_string(), ..test_packet("192.168.1.10") };
+        assert_eq!(scheduler.select_link_for(&packet_to("10.0.0.1"), None, &metrics).await.unwrap(), "eth0");
+        assert_eq!(scheduler.select_link_for(&packet_to("203.0.113.9"), None, &metrics).await.unwrap(), "eth1");
+        // Cached rankings for other destinations are unaffected
+        assert_eq!(scheduler.select_link_for(&packet_to("10.0.0.1"), None, &metrics).await.unwrap(), "eth0");
+    }
+
+    #[tokio::test]
+    async fn test_recovered_link_share_ramps_up() {
+        let mut config = Config::default();
+        config.scheduler.algorithm = "weighted_ecmp".to_string();
+        config.scheduler.rng_seed = Some(5);
+        config.failover.warmup_ms = 1000;
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        scheduler.set_clock(clock.clone());
+        let packet = test_packet("192.168.1.10");
+        
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth1").unwrap().packet_loss = 1.0;
+        for _ in 0..3 {
+            scheduler.refresh_metrics(&metrics);
+        }
+        metrics.get_mut("eth1").unwrap().packet_loss = 0.0;
+        let mut available = HashMap::new();
+        for _ in 0..5 {
+            available = scheduler.refresh_metrics(&metrics);
+        }
+        
+        let eth1_share = || async {
+            let mut picks = 0;
+            for _ in 0..2000 {
+                if scheduler.select_link_for(&packet, None, &available).await.unwrap() == "eth1" {
+                    picks += 1;
+                }
+            }
+            picks as f64 / 2000.0
+        };
+        
+        let just_recovered = eth1_share().await;
+        clock.advance(chrono::Duration::milliseconds(500));
+        let warming = eth1_share().await;
+        clock.advance(chrono::Duration::milliseconds(600));
+        let warmed = eth1_share().await;
+        assert!(just_recovered < 0.1, "eth1 share was {}", just_recovered);
+        assert!(warming > just_recovered && warmed > warming, "eth1 shares were {} {} {}", just_recovered, warming, warmed);
+        // Full use again: scores 0.422 and 0.383
+        assert!((warmed - 0.475).abs() < 0.04, "eth1 share was {}", warmed);
+    }
+
+    #[tokio::test]
+    async fn test_recovered_link_wins_round_robin_once_warmed_up() {
+        let mut config = Config::default();
+        config.failover.warmup_ms = 1000;
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        scheduler.set_clock(clock.clone());
+        let packet = test_packet("192.168.1.10");
+        
+        // eth0 is the better link, but it went down and came back
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().packet_loss = 1.0;
+        for _ in 0..3 {
+            scheduler.refresh_metrics(&metrics);
+        }
+        metrics.get_mut("eth0").unwrap().packet_loss = 0.0;
+        let mut available = HashMap::new();
+        for _ in 0..5 {
+            available = scheduler.refresh_metrics(&metrics);
+        }
+        assert_eq!(scheduler.select_link_for(&packet, None, &available).await.unwrap(), "eth1");
+        
+        // No new metrics report: the cached ranking mustn't freeze the ramp
+        clock.advance(chrono::Duration::milliseconds(500));
+        assert_eq!(scheduler.select_link_for(&packet, None, &available).await.unwrap(), "eth1");
+        clock.advance(chrono::Duration::milliseconds(450));
+        assert_eq!(scheduler.select_link_for(&packet, None, &available).await.unwrap(), "eth0");
+    }
+
+    #[tokio::test]
+    async fn test_debug_state_dump_includes_every_section() {
+        let scheduler = flow_affinity_scheduler().await;
+        let metrics = test_metrics();
+        *scheduler.current_metrics.write() = Arc::new(scheduler.refresh_metrics(&metrics));
+        scheduler.add_qos_rule(voip_rule(6)).unwrap();
+        scheduler.select_link_for(&test_packet("192.168.1.10"), None, &metrics).await.unwrap();
+        scheduler.drain_link("eth1", DrainMode::Soft);
+        scheduler.set_link_multiplier("eth0", 0.5).unwrap();
+        scheduler.stats.record_shed(3);
+        
+        let response = scheduler.get_debug_state(DebugStateRequest {}).await.unwrap();
+        let dump = serde_json::to_value(&response.state).unwrap();
+        
+        assert_eq!(dump["metrics"].as_object().unwrap().len(), 2);
+        assert_eq!(dump["qos_rules"][0]["name"], "voip");
+        assert_eq!(dump["flows"][0]["source_ip"], "192.168.1.10");
+        assert_eq!(dump["flows"][0]["link_name"], "eth0");
+        assert_eq!(dump["drained_links"]["eth1"], "Soft");
+        assert_eq!(dump["link_multipliers"]["eth0"], 0.5);
+        assert_eq!(dump["link_states"]["eth0"]["status"], "Up");
+        assert_eq!(dump["load_mode"], "Normal");
+        assert_eq!(dump["stats"]["packets_shed"], 3);
+        assert!(dump["timestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_link_preference_chain_falls_through_to_next_healthy_link() {
+        let mut scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let transport = Arc::new(MockTransport::new());
+        scheduler.set_transport(transport.clone());
+        let mut rule = voip_rule(7);
+        rule.action.link_preference = vec!["eth0".to_string(), "eth1".to_string()];
+        scheduler.add_qos_rule(rule).unwrap();
+        
+        // eth2 is the best link overall
+        let mut metrics = test_metrics();
+        let mut best = metrics["eth0"].clone();
+        best.latency_ms = 1.0;
+        best.bandwidth_mbps = 1000.0;
+        metrics.insert("eth2".to_string(), best);
+        
+        // The chain is followed in order while any of it is up, and a link
+        // the operator has weighted down to nothing counts as down
+        for (seq, goes_down) in [(1, None), (2, Some("eth0")), (3, Some("eth1"))] {
+            match goes_down {
+                Some("eth0") => scheduler.set_link_multiplier("eth0", 0.0).unwrap(),
+                Some(link_name) => metrics.get_mut(link_name).unwrap().packet_loss = 1.0,
+                None => {}
+            }
+            let mut packet = test_packet("192.168.1.100");
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        
+        let links: Vec<String> = transport.sent().into_iter().map(|(link, _)| link).collect();
+        assert_eq!(links, ["eth0", "eth1", "eth2"]);
+    }
+
+    #[tokio::test]
+    async fn test_voip_class_selects_on_mos() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let mut voip = voip_rule(7);
+        voip.action.scheduler_algorithm = Some("mos".to_string());
+        scheduler.add_qos_rule(voip).unwrap();
+        
+        // eth0 has the better health score thanks to its bandwidth, but its
+        // jitter and loss make it the worse path for voice
+        let mut metrics = HashMap::new();
+        for (name, jitter_ms, packet_loss, bandwidth_mbps) in [("eth0", 40.0, 0.03, 1000.0), ("eth1", 2.0, 0.0, 20.0)] {
+            let mut metric = LinkMetrics::new();
+            metric.latency_ms = 20.0;
+            metric.jitter_ms = jitter_ms;
+            metric.packet_loss = packet_loss;
+            metric.bandwidth_mbps = bandwidth_mbps;
+            metrics.insert(name.to_string(), metric);
+        }
+        assert!(metrics["eth0"].health_score() > metrics["eth1"].health_score());
+        
+        let mut selected = Vec::new();
+        for (seq, source_ip) in ["192.168.1.100", "192.168.1.50"].into_iter().enumerate() {
+            let mut packet = test_packet(source_ip);
+            let qos_rule = scheduler.apply_qos_rules(&mut packet);
+            scheduler.schedule_packet(packet, qos_rule, seq as u64, &metrics).await.unwrap();
+            selected.push(scheduler.last_selection.lock().as_ref().unwrap().link_name.clone());
+        }
+        assert_eq!(selected, vec!["eth1", "eth0"]);
+    }
+
+    #[tokio::test]
+    async fn test_recovered_link_penalized_until_cooldown_ends() {
+        let mut config = Config::default();
+        config.scheduler.algorithm = "mos".to_string();
+        config.failover.recovery_cooldown_ms = 300;
+        let scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        let packet = test_packet("192.168.1.10");
+        
+        let mut metrics = test_metrics();
+        metrics.get_mut("eth0").unwrap().packet_loss = 1.0;
+        for _ in 0..3 {
+            scheduler.refresh_metrics(&metrics);
+        }
+        metrics.get_mut("eth0").unwrap().packet_loss = 0.0;
+        let mut available = HashMap::new();
+        for _ in 0..5 {
+            available = scheduler.refresh_metrics(&metrics);
+        }
+        
+        // eth0 is usable but carries half weight, losing to the slower eth1
+        assert!(available.contains_key("eth0"));
+        assert_eq!(scheduler.select_link_for(&packet, None, &available).await.unwrap(), "eth1");
+        tokio::time::sleep(std::time::Duration::from_millis(350)).await;
+        assert_eq!(scheduler.select_link_for(&packet, None, &available).await.unwrap(), "eth0");
+    }
+
+    #[tokio::test]
+    async fn test_link_at_in_flight_cap_defers_to_another() {
+        let mut eth0 = link_config("eth0", None);
+        eth0.max_in_flight = Some(2);
+        eth0.in_flight_timeout_ms = 500;
+        let config = Config {
+            links: vec![eth0, link_config("eth1", None)],
+            ..Config::default()
+        };
+        let transport = Arc::new(MockTransport::new());
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        scheduler.set_clock(clock.clone());
+        let metrics = test_metrics();
+        
+        // eth0 scores better until two of its packets are unacknowledged
+        for sequence_number in 1..=3 {
+            scheduler.schedule_packet(test_packet("10.0.0.1"), None, sequence_number, &metrics).await.unwrap();
+            scheduler.flush_link_queues().await;
+        }
+        assert_eq!(transport.sent(), vec![("eth0".to_string(), 1), ("eth0".to_string(), 2), ("eth1".to_string(), 3)]);
+        assert_eq!(scheduler.in_flight(), HashMap::from([("eth0".to_string(), 2)]));
+        
+        // An ack makes room again
+        scheduler.acknowledge("eth0", 1);
+        scheduler.schedule_packet(test_packet("10.0.0.1"), None, 4, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        scheduler.schedule_packet(test_packet("10.0.0.1"), None, 5, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        assert_eq!(transport.sent()[3..], [("eth0".to_string(), 4), ("eth1".to_string(), 5)]);
+        
+        // Packets whose acks never came stop counting after the timeout
+        clock.advance(chrono::Duration::milliseconds(501));
+        scheduler.schedule_packet(test_packet("10.0.0.1"), None, 6, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        assert_eq!(transport.sent()[5], ("eth0".to_string(), 6));
+    }
+
+    #[tokio::test]
+    async fn test_pinned_paths_respect_in_flight_cap() {
+        let mut eth0 = link_config("eth0", None);
+        eth0.max_in_flight = Some(1);
+        let config = Config {
+            links: vec![eth0, link_config("eth1", None)],
+            policy_routes: vec![crate::config::PolicyRoute {
+                source: "10.1.0.0/16".to_string(),
+                link: "eth0".to_string(),
+                on_link_down: crate::config::PolicyFallback::Fallback,
+            }],
+            ..Config::default()
+        };
+        let transport = Arc::new(MockTransport::new());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        let mut rule = voip_rule(7);
+        rule.action.link_preference = vec!["eth0".to_string(), "eth1".to_string()];
+        scheduler.add_qos_rule(rule).unwrap();
+        let metrics = test_metrics();
+        
+        // The policy route falls back while its link is full
+        scheduler.schedule_packet(test_packet("10.1.0.1"), None, 1, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        scheduler.schedule_packet(test_packet("10.1.0.1"), None, 2, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        
+        // So does the preference chain
+        let mut packet = test_packet("192.168.1.100");
+        let qos_rule = scheduler.apply_qos_rules(&mut packet);
+        scheduler.schedule_packet(packet, qos_rule, 3, &metrics).await.unwrap();
+        scheduler.flush_link_queues().await;
+        
+        let links: Vec<String> = transport.sent().into_iter().map(|(link, _)| link).collect();
+        assert_eq!(links, ["eth0", "eth1", "eth1"]);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_reassembly_timeout_follows_reorder_window() {
+        let mut scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let receiver = Arc::new(UdpTunnelReceiver::bind("127.0.0.1:0".parse().unwrap()).await.unwrap());
+        scheduler.set_tunnel_receiver(receiver.clone());
+        
+        // 5ms and 20ms links: the window is their spread plus jitter
+        let metrics = test_metrics();
+        *scheduler.current_metrics.write() = Arc::new(scheduler.refresh_metrics(&metrics));
+        scheduler.adapt_reassembly_timeout();
+        assert_eq!(receiver.reassembly_timeout(), scheduler.reorder_window());
+        assert!(receiver.reassembly_timeout() < Duration::from_millis(100));
+        
+        // A configured timeout stays put
+        let mut config = Config::default();
+        config.tunnel = Some(crate::config::TunnelConfig { reassembly_timeout_ms: Some(750), ..Default::default() });
+        let mut scheduler = PacketScheduler::new(config.clone(), "http://localhost:9093".to_string()).await.unwrap();
+        let receiver = Arc::new(UdpTunnelReceiver::from_config(&config, "127.0.0.1:0".parse().unwrap()).await.unwrap());
+        scheduler.set_tunnel_receiver(receiver.clone());
+        *scheduler.current_metrics.write() = Arc::new(scheduler.refresh_metrics(&metrics));
+        scheduler.adapt_reassembly_timeout();
+        assert_eq!(receiver.reassembly_timeout(), Duration::from_millis(750));
+    }
+    
+    #[tokio::test]
+    async fn test_tunnel_acks_release_in_flight() {
+        let receiver = crate::transport::UdpTunnelReceiver::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut eth0 = link_config("eth0", None);
+        eth0.interface = "lo".to_string();
+        eth0.source_address = Some("127.0.0.1".to_string());
+        eth0.max_in_flight = Some(4);
+        let config = Config {
+            links: vec![eth0],
+            ..Config::default()
+        };
+        let transport = Arc::new(UdpTunnelTransport::bind(&config.links, receiver.local_addr().unwrap()).await.unwrap());
+        let mut scheduler = PacketScheduler::new(config, "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_tunnel_transport(transport);
+        
+        scheduler.schedule_packet(test_packet("10.0.0.1"), None, 1, &test_metrics()).await.unwrap();
+        scheduler.flush_link_queues().await;
+        assert_eq!(scheduler.in_flight(), HashMap::from([("eth0".to_string(), 1)]));
+        
+        assert_eq!(receiver.recv().await.unwrap().0, 1);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while scheduler.in_flight()["eth0"] > 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_retry_with_cached_ranking() {
+        let transport = Arc::new(MockTransport::failing(&["eth0"]));
+        let mut scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        scheduler.set_transport(transport.clone());
+        let metrics = test_metrics();
+        
+        // The weighted round robin selector has ranked eth0 first and cached it
+        let packet = test_packet("192.168.1.10");
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth0");
+        
+        let link = scheduler.send_with_retry(transport.as_ref(), scheduled("eth0", 1), None, &metrics).await.unwrap();
+        assert_eq!(link, "eth1");
+        assert_eq!(transport.sent(), vec![("eth1".to_string(), 1)]);
+    }
+    
+    #[tokio::test]
+    async fn test_send_retry_skips_full_links_and_follows_class_algorithm() {
+        let mut eth1 = link_config("eth1", None);
+        eth1.max_in_flight = Some(1);
+        let config = Config {
+            links: vec![link_config("eth0", None), eth1, link_config("eth2", None)],
+            ..Config::default()
+        };
+        let transport = Arc::new(MockTransport::failing(&["eth0"]));
+        // The scheduler's own selector would retry on eth1
+        let scheduler = scheduler_with_transport(config, &["eth1"], transport.clone()).await;
+        let mut metrics = test_metrics();
+        let mut eth2 = LinkMetrics::new();
+        eth2.latency_ms = 40.0;
+        eth2.bandwidth_mbps = 100.0;
+        metrics.insert("eth2".to_string(), eth2);
+        
+        // Round robin prefers eth1 over eth2, but eth1 is at its cap
+        scheduler.record_in_flight("eth1", 100);
+        let link = scheduler.send_with_retry(transport.as_ref(), scheduled("eth0", 1), Some("weighted_round_robin"), &metrics).await.unwrap();
+        
+        assert_eq!(link, "eth2");
+        assert_eq!(transport.sent(), vec![("eth2".to_string(), 1)]);
+    }
+    
+    #[tokio::test]
+    async fn test_send_retry_skips_links_weighted_to_nothing() {
+        let config = Config {
+            links: vec![link_config("eth0", None), link_config("eth1", None), link_config("eth2", None)],
+            ..Config::default()
+        };
+        let transport = Arc::new(MockTransport::failing(&["eth0"]));
+        let scheduler = scheduler_with_transport(config, &["eth1"], transport.clone()).await;
+        let mut metrics = test_metrics();
+        let mut eth2 = LinkMetrics::new();
+        eth2.latency_ms = 40.0;
+        eth2.bandwidth_mbps = 100.0;
+        metrics.insert("eth2".to_string(), eth2);
+        
+        // Round robin would retry on eth1, but the operator has zeroed it
+        scheduler.set_link_multiplier("eth1", 0.0).unwrap();
+        let link = scheduler.send_with_retry(transport.as_ref(), scheduled("eth0", 1), Some("weighted_round_robin"), &metrics).await.unwrap();
+        
+        assert_eq!(link, "eth2");
+    }
+    
+    #[tokio::test]
+    async fn test_drain_invalidates_cached_ranking() {
+        let scheduler = PacketScheduler::new(Config::default(), "http://localhost:9093".to_string()).await.unwrap();
+        let metrics = test_metrics();
+        let packet = test_packet("192.168.1.10");
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth0");
+        
+        scheduler.drain_link("eth0", DrainMode::Hard);
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth1");
+        
+        scheduler.undrain_link("eth0");
+        assert_eq!(scheduler.select_link_for(&packet, None, &metrics).await.unwrap(), "eth0");
+    }
+    
+    #[tokio::test]
+    async fn test_cached_ranking_only_serves_its_candidates() {
+        let selector = WeightedRoundRobinSelector::new().with_ranking_cache();
+        let packet = test_packet("192.168.1.10");
+        let mut metrics = test_metrics();
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth0");
+        
+        metrics.remove("eth0");
+        assert_eq!(selector.select_link(&packet, &metrics).await.unwrap(), "eth1");
+        assert_eq!(selector.scoring_runs(), 2);
     }
 } 
\ No newline at end of file
@@ -0,0 +1,129 @@
+use crate::config::PortRange;
+use crate::protocol::Protocol;
+use std::fs;
+
+/// Well-known services accepted in place of a numeric port range.
+const BUILTIN_SERVICES: &[(&str, u16, u16)] = &[
+    ("ftp", 21, 21),
+    ("ssh", 22, 22),
+    ("telnet", 23, 23),
+    ("smtp", 25, 25),
+    ("dns", 53, 53),
+    ("http", 80, 80),
+    ("ntp", 123, 123),
+    ("snmp", 161, 162),
+    ("bgp", 179, 179),
+    ("https", 443, 443),
+    ("ike", 500, 500),
+    ("syslog", 514, 514),
+    ("openvpn", 1194, 1194),
+    ("mysql", 3306, 3306),
+    ("rdp", 3389, 3389),
+    ("ipsec-nat-t", 4500, 4500),
+    ("sip", 5060, 5061),
+    ("postgresql", 5432, 5432),
+];
+
+const SERVICES_FILE: &str = "/etc/services";
+
+/// Parses a port range written as a string: a single port (`"443"`), a
+/// range (`"10000-20000"`) or a service name (`"https"`). Names are looked up
+/// in the built-in table first, then among the `/etc/services` entries for
+/// `protocol`, or for any protocol when it isn't given.
+pub fn parse_port_spec(spec: &str, protocol: Option<&Protocol>) -> Result<PortRange, String> {
+    let spec = spec.trim();
+
+    if let Some((start, end)) = spec.split_once('-') {
+        if let (Ok(start), Ok(end)) = (start.trim().parse(), end.trim().parse()) {
+            return Ok(PortRange { start, end });
+        }
+    }
+    if let Ok(port) = spec.parse() {
+        return Ok(PortRange { start: port, end: port });
+    }
+
+    resolve_service(spec, protocol).ok_or_else(|| format!("unknown service or port range: {}", spec))
+}
+
+pub fn resolve_service(name: &str, protocol: Option<&Protocol>) -> Option<PortRange> {
+    let name = name.to_lowercase();
+    BUILTIN_SERVICES.iter()
+        .find(|(service, _, _)| *service == name)
+        .map(|(_, start, end)| PortRange { start: *start, end: *end })
+        .or_else(|| {
+            let services = fs::read_to_string(SERVICES_FILE).ok()?;
+            lookup_services_file(&services, &name, protocol.and_then(services_protocol))
+        })
+}
+
+/// The protocol column `/etc/services` lists a protocol's ports under, or
+/// `None` for protocols it has no entries for.
+fn services_protocol(protocol: &Protocol) -> Option<&'static str> {
+    match protocol {
+        Protocol::Tcp => Some("tcp"),
+        Protocol::Udp | Protocol::Quic => Some("udp"),
+        Protocol::Sctp => Some("sctp"),
+        Protocol::Icmp | Protocol::Other(_) => None,
+    }
+}
+
+/// Finds `name` (or one of its aliases) in `/etc/services`-formatted text,
+/// among the entries for `protocol` if given.
+fn lookup_services_file(content: &str, name: &str, protocol: Option<&str>) -> Option<PortRange> {
+    content.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let service = fields.next()?;
+            let (port, entry_protocol) = fields.next()?.split_once('/')?;
+            if protocol.is_some_and(|protocol| !entry_protocol.eq_ignore_ascii_case(protocol)) {
+                return None;
+            }
+            let mut names = std::iter::once(service).chain(fields);
+            if names.any(|n| n.eq_ignore_ascii_case(name)) {
+                let port = port.parse().ok()?;
+                Some(PortRange { start: port, end: port })
+            } else {
+                None
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(range: PortRange) -> (u16, u16) {
+        (range.start, range.end)
+    }
+
+    #[test]
+    fn test_builtin_service_names() {
+        assert_eq!(ports(parse_port_spec("https", None).unwrap()), (443, 443));
+        assert_eq!(ports(parse_port_spec("SIP", None).unwrap()), (5060, 5061));
+        assert_eq!(ports(parse_port_spec("dns", None).unwrap()), (53, 53));
+    }
+
+    #[test]
+    fn test_numeric_specs() {
+        assert_eq!(ports(parse_port_spec("8080", None).unwrap()), (8080, 8080));
+        assert_eq!(ports(parse_port_spec("10000-20000", None).unwrap()), (10000, 20000));
+        assert!(parse_port_spec("no-such-service", None).is_err());
+    }
+
+    #[test]
+    fn test_services_file_lookup() {
+        let content = "# comment\nhttp-alt\t8080/tcp\twebcache # WWW caching service\nxmpp-client 5222/tcp jabber-client\n";
+        assert_eq!(ports(lookup_services_file(content, "http-alt", None).unwrap()), (8080, 8080));
+        assert_eq!(ports(lookup_services_file(content, "jabber-client", None).unwrap()), (5222, 5222));
+        assert!(lookup_services_file(content, "comment", None).is_none());
+    }
+
+    #[test]
+    fn test_services_file_lookup_filtered_by_protocol() {
+        let content = "kerberos-adm\t749/tcp\nkerberos-iv\t750/udp\tkerberos-adm\n";
+        assert_eq!(ports(lookup_services_file(content, "kerberos-adm", Some("tcp")).unwrap()), (749, 749));
+        assert_eq!(ports(lookup_services_file(content, "kerberos-adm", Some("udp")).unwrap()), (750, 750));
+        assert!(lookup_services_file(content, "kerberos-iv", Some("tcp")).is_none());
+    }
+} 
@@ -0,0 +1,223 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds of the delay histogram buckets, in ms. Delays above the last
+/// bound fall into an overflow bucket.
+const DELAY_BUCKETS_MS: [f64; 12] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0];
+
+/// Counters updated on the scheduling hot path.
+#[derive(Debug, Default)]
+pub struct SchedulerStats {
+    packets_scheduled: AtomicU64,
+    packets_shed: AtomicU64,
+    packets_expired: AtomicU64,
+    packets_policy_dropped: AtomicU64,
+    packets_shaped: AtomicU64,
+    packets_unroutable: AtomicU64,
+    shadow_decisions: AtomicU64,
+    shadow_divergences: AtomicU64,
+    /// Delay distribution per QoS class, keyed by rule name.
+    class_delays: DashMap<String, ClassDelays>,
+}
+
+#[derive(Debug, Default)]
+struct ClassDelays {
+    packets: AtomicU64,
+    within_sla: AtomicU64,
+    /// Packet counts per `DELAY_BUCKETS_MS` bucket, then the overflow bucket.
+    buckets: [AtomicU64; DELAY_BUCKETS_MS.len() + 1],
+}
+
+impl ClassDelays {
+    /// Upper bound of the bucket holding the `percentile` (0-100) delay;
+    /// infinite if it is in the overflow bucket.
+    fn percentile(&self, percentile: f64) -> Option<f64> {
+        let packets = self.packets.load(Ordering::Relaxed);
+        if packets == 0 {
+            return None;
+        }
+
+        let rank = (percentile / 100.0 * packets as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Some(DELAY_BUCKETS_MS.get(index).copied().unwrap_or(f64::INFINITY));
+            }
+        }
+        Some(f64::INFINITY)
+    }
+
+    fn snapshot(&self) -> ClassSlaSnapshot {
+        ClassSlaSnapshot {
+            packets: self.packets.load(Ordering::Relaxed),
+            within_sla: self.within_sla.load(Ordering::Relaxed),
+            p50_ms: self.percentile(50.0),
+            p99_ms: self.percentile(99.0),
+        }
+    }
+}
+
+/// Point-in-time copy of `SchedulerStats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub packets_scheduled: u64,
+    pub packets_shed: u64,
+    /// Dropped for outliving their class's `max_age_ms`.
+    pub packets_expired: u64,
+    /// Dropped because their policy route's link was down.
+    pub packets_policy_dropped: u64,
+    /// Dropped for arriving at a shaped link while their class's queue
+    /// there was full.
+    pub packets_shaped: u64,
+    /// Dropped because every link was down, drained or at its in-flight cap.
+    pub packets_unroutable: u64,
+    pub shadow_decisions: u64,
+    pub shadow_divergences: u64,
+    /// Keyed by QoS rule name.
+    pub class_sla: HashMap<String, ClassSlaSnapshot>,
+}
+
+/// Delay of one QoS class's packets against its `latency_threshold`.
+/// Percentiles are bucket upper bounds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClassSlaSnapshot {
+    pub packets: u64,
+    pub within_sla: u64,
+    pub p50_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+impl SchedulerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_scheduled(&self) {
+        self.packets_scheduled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records packets dropped by load shedding.
+    pub fn record_shed(&self, packets: u64) {
+        self.packets_shed.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    /// Records a packet dropped for missing its deadline.
+    pub fn record_expired(&self) {
+        self.packets_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_policy_drop(&self) {
+        self.packets_policy_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_shaped(&self) {
+        self.packets_shaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a packet dropped for lack of any link to send it on.
+    pub fn record_unroutable(&self) {
+        self.packets_unroutable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a shadow selector decision and whether it disagreed with the
+    /// primary selector.
+    pub fn record_shadow(&self, diverged: bool) {
+        self.shadow_decisions.fetch_add(1, Ordering::Relaxed);
+        if diverged {
+            self.shadow_divergences.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the end-to-end delay of a packet matching QoS rule `class`,
+    /// counting it within SLA if it met `latency_threshold_ms` (always, when
+    /// the rule has no threshold).
+    pub fn record_class_delay(&self, class: &str, delay_ms: f64, latency_threshold_ms: Option<u64>) {
+        // Look up before inserting so the hot path doesn't allocate the key
+        let delays = match self.class_delays.get(class) {
+            Some(delays) => delays,
+            None => self.class_delays.entry(class.to_string()).or_default().downgrade(),
+        };
+
+        let bucket = DELAY_BUCKETS_MS.iter()
+            .position(|bound| delay_ms <= *bound)
+            .unwrap_or(DELAY_BUCKETS_MS.len());
+        delays.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        delays.packets.fetch_add(1, Ordering::Relaxed);
+        if latency_threshold_ms.map_or(true, |threshold| delay_ms <= threshold as f64) {
+            delays.within_sla.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Fraction of the class's packets that met its latency SLA; 1.0 before
+    /// any were recorded.
+    pub fn sla_compliance(&self, rule_name: &str) -> f64 {
+        self.class_delays.get(rule_name)
+            .map(|delays| {
+                let packets = delays.packets.load(Ordering::Relaxed);
+                if packets == 0 {
+                    return 1.0;
+                }
+                delays.within_sla.load(Ordering::Relaxed) as f64 / packets as f64
+            })
+            .unwrap_or(1.0)
+    }
+
+    /// Delay in ms at or under which `percentile` (0-100) of the class's
+    /// packets arrived, to histogram bucket resolution.
+    pub fn delay_percentile(&self, rule_name: &str, percentile: f64) -> Option<f64> {
+        self.class_delays.get(rule_name).and_then(|delays| delays.percentile(percentile))
+    }
+
+    pub fn shadow_divergences(&self) -> u64 {
+        self.shadow_divergences.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            packets_scheduled: self.packets_scheduled.load(Ordering::Relaxed),
+            packets_shed: self.packets_shed.load(Ordering::Relaxed),
+            packets_expired: self.packets_expired.load(Ordering::Relaxed),
+            packets_policy_dropped: self.packets_policy_dropped.load(Ordering::Relaxed),
+            packets_shaped: self.packets_shaped.load(Ordering::Relaxed),
+            packets_unroutable: self.packets_unroutable.load(Ordering::Relaxed),
+            shadow_decisions: self.shadow_decisions.load(Ordering::Relaxed),
+            shadow_divergences: self.shadow_divergences.load(Ordering::Relaxed),
+            class_sla: self.class_delays.iter()
+                .map(|entry| (entry.key().clone(), entry.value().snapshot()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sla_compliance_per_class() {
+        let stats = SchedulerStats::new();
+        for delay_ms in [4.0, 8.0, 12.0, 19.0, 20.0, 25.0, 30.0, 45.0] {
+            stats.record_class_delay("voip", delay_ms, Some(20));
+        }
+        for delay_ms in [150.0, 900.0] {
+            stats.record_class_delay("bulk", delay_ms, None);
+        }
+
+        // 5 of 8 voip packets stayed within 20ms
+        assert_eq!(stats.sla_compliance("voip"), 0.625);
+        assert_eq!(stats.sla_compliance("bulk"), 1.0);
+        assert_eq!(stats.sla_compliance("video"), 1.0);
+
+        assert_eq!(stats.delay_percentile("voip", 50.0), Some(20.0));
+        assert_eq!(stats.delay_percentile("voip", 99.0), Some(50.0));
+        assert_eq!(stats.delay_percentile("video", 50.0), None);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.class_sla["voip"].packets, 8);
+        assert_eq!(snapshot.class_sla["voip"].within_sla, 5);
+        assert_eq!(snapshot.class_sla["bulk"].p99_ms, Some(1000.0));
+    }
+}
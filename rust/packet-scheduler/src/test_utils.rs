@@ -0,0 +1,106 @@
+use crate::config::LinkConfig;
+use crate::scheduler::{LinkSelector, Packet, ScheduledPacket};
+use crate::transport::PacketTransport;
+use crate::LinkMetrics;
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+pub use sdwan_common::clock::MockClock;
+
+/// A `LinkSelector` that returns a scripted sequence of links, for tests that
+/// need to control routing decisions.
+pub struct MockLinkSelector {
+    script: Mutex<VecDeque<String>>,
+    calls: Mutex<usize>,
+}
+
+impl MockLinkSelector {
+    pub fn new(links: &[&str]) -> Self {
+        Self {
+            script: Mutex::new(links.iter().map(|l| l.to_string()).collect()),
+            calls: Mutex::new(0),
+        }
+    }
+
+    /// Number of times `select_link` has been called.
+    pub fn calls(&self) -> usize {
+        *self.calls.lock()
+    }
+}
+
+#[async_trait]
+impl LinkSelector for MockLinkSelector {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    async fn select_link(&self, _packet: &Packet, _metrics: &HashMap<String, LinkMetrics>) -> Result<String> {
+        *self.calls.lock() += 1;
+        self.script
+            .lock()
+            .pop_front()
+            .ok_or_else(|| anyhow::anyhow!("MockLinkSelector script exhausted"))
+    }
+}
+
+/// A `PacketTransport` that records sent packets and fails sends on the
+/// configured links.
+pub struct MockTransport {
+    failing_links: HashSet<String>,
+    sent: Mutex<Vec<(String, u64)>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::failing(&[])
+    }
+
+    pub fn failing(links: &[&str]) -> Self {
+        Self {
+            failing_links: links.iter().map(|l| l.to_string()).collect(),
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// `(link_name, sequence_number)` of every successfully sent packet.
+    pub fn sent(&self) -> Vec<(String, u64)> {
+        self.sent.lock().clone()
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl PacketTransport for MockTransport {
+    async fn send(&self, packet: &ScheduledPacket) -> Result<()> {
+        if self.failing_links.contains(&packet.link_name) {
+            return Err(anyhow::anyhow!("Link {} is unreachable", packet.link_name));
+        }
+        self.sent.lock().push((packet.link_name.clone(), packet.sequence_number));
+        Ok(())
+    }
+}
+
+/// A link definition with placeholder interface and capacity values.
+pub fn link_config(name: &str, failover_group: Option<&str>) -> LinkConfig {
+    LinkConfig {
+        name: name.to_string(),
+        interface: name.to_string(),
+        weight: 1.0,
+        max_bandwidth: 100_000_000,
+        min_latency: 10,
+        failover_group: failover_group.map(|g| g.to_string()),
+        failover_tier: 0,
+        source_address: None,
+        time_multipliers: Vec::new(),
+        mtu: None,
+        max_in_flight: None,
+        in_flight_timeout_ms: 2000,
+    }
+}
@@ -0,0 +1,511 @@
+use crate::config::{Config, LinkConfig, TunnelConfig};
+use crate::fragment::{self, Reassembler};
+#[cfg(feature = "encryption")]
+use crate::crypto::TunnelCipher;
+use crate::metrics_client::UnderlayClient;
+use crate::scheduler::ScheduledPacket;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+/// IP and UDP header bytes each tunnel datagram carries on the wire.
+const IPV4_UDP_OVERHEAD: usize = 20 + 8;
+const IPV6_UDP_OVERHEAD: usize = 40 + 8;
+
+/// Acks are the bare 8-byte big-endian sequence number of a received frame.
+const ACK_LEN: usize = 8;
+
+/// Sends scheduled packets out over the link chosen by the scheduler.
+#[async_trait]
+pub trait PacketTransport {
+    async fn send(&self, packet: &ScheduledPacket) -> Result<()>;
+}
+
+/// Tunnels packets to a single peer over UDP, with one socket per link.
+pub struct UdpTunnelTransport {
+    sockets: HashMap<String, UdpSocket>,
+    peer: SocketAddr,
+    /// Path MTU per link; links without one send frames whole.
+    mtus: HashMap<String, usize>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<TunnelCipher>,
+}
+
+impl UdpTunnelTransport {
+    pub async fn bind(links: &[LinkConfig], peer: SocketAddr) -> Result<Self> {
+        let mut sockets = HashMap::new();
+        for link in links {
+            let socket = bind_link_socket(link, peer)?;
+            sockets.insert(link.name.clone(), UdpSocket::from_std(socket.into())?);
+        }
+        let mtus = links.iter()
+            .filter_map(|link| link.mtu.map(|mtu| (link.name.clone(), mtu)))
+            .collect();
+
+        Ok(Self {
+            sockets,
+            peer,
+            mtus,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        })
+    }
+
+    /// Binds every configured link and enables encryption if the `tunnel`
+    /// section asks for it.
+    pub async fn from_config(config: &Config, peer: SocketAddr) -> Result<Self> {
+        #[allow(unused_mut)]
+        let mut transport = Self::bind(&config.links, peer).await?;
+
+        if let Some(ref tunnel) = config.tunnel {
+            #[cfg(feature = "encryption")]
+            {
+                transport.cipher = TunnelCipher::from_config(tunnel)?;
+            }
+            #[cfg(not(feature = "encryption"))]
+            if tunnel.encrypt {
+                return Err(anyhow::anyhow!("tunnel.encrypt requires the `encryption` feature"));
+            }
+        }
+
+        Ok(transport)
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn with_cipher(mut self, cipher: TunnelCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    pub fn local_addr(&self, link_name: &str) -> Option<SocketAddr> {
+        self.sockets.get(link_name).and_then(|s| s.local_addr().ok())
+    }
+
+    /// Largest tunnel datagram that fits the link's path MTU.
+    fn max_datagram(&self, link_name: &str) -> Option<usize> {
+        let overhead = if self.peer.is_ipv6() { IPV6_UDP_OVERHEAD } else { IPV4_UDP_OVERHEAD };
+        self.mtus.get(link_name).map(|mtu| mtu.saturating_sub(overhead))
+    }
+
+    /// Frames a packet for the wire: the 8-byte big-endian sequence number
+    /// followed by the payload.
+    pub fn encode(packet: &ScheduledPacket) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(8 + packet.packet.data.len());
+        frame.extend_from_slice(&packet.sequence_number.to_be_bytes());
+        frame.extend_from_slice(&packet.packet.data);
+        frame
+    }
+
+    /// Splits a plaintext frame built by `encode` into sequence number and
+    /// payload.
+    pub fn decode(frame: &[u8]) -> Result<(u64, Vec<u8>)> {
+        if frame.len() < 8 {
+            return Err(anyhow::anyhow!("Tunnel frame too short: {} bytes", frame.len()));
+        }
+        let sequence_number = u64::from_be_bytes(frame[..8].try_into()?);
+        Ok((sequence_number, frame[8..].to_vec()))
+    }
+
+    /// Waits for the far end's `UdpTunnelReceiver` to acknowledge a frame
+    /// sent on the link, returning its sequence number.
+    pub async fn recv_ack(&self, link_name: &str) -> Result<u64> {
+        let socket = self
+            .sockets
+            .get(link_name)
+            .ok_or_else(|| anyhow::anyhow!("No transport socket for link {}", link_name))?;
+
+        let mut buf = [0u8; ACK_LEN];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            if from != self.peer || len != ACK_LEN {
+                debug!("Ignoring {} byte datagram from {} on link {}", len, from, link_name);
+                continue;
+            }
+            return Ok(u64::from_be_bytes(buf));
+        }
+    }
+
+    fn frame(&self, packet: &ScheduledPacket) -> Result<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(ref cipher) = self.cipher {
+            return cipher.seal(packet.sequence_number, &packet.packet.data);
+        }
+        Ok(Self::encode(packet))
+    }
+}
+
+#[async_trait]
+impl PacketTransport for UdpTunnelTransport {
+    async fn send(&self, packet: &ScheduledPacket) -> Result<()> {
+        let socket = self
+            .sockets
+            .get(&packet.link_name)
+            .ok_or_else(|| anyhow::anyhow!("No transport socket for link {}", packet.link_name))?;
+
+        let frame = self.frame(packet)?;
+        let max_datagram = self.max_datagram(&packet.link_name).unwrap_or(usize::MAX);
+        for datagram in fragment::split(&frame, max_datagram)? {
+            socket.send_to(&datagram, self.peer).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Receiving end of a `UdpTunnelTransport`. With a cipher configured every
+/// frame must decrypt and authenticate; anything else is rejected.
+pub struct UdpTunnelReceiver {
+    socket: UdpSocket,
+    reassembler: Mutex<Reassembler>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<TunnelCipher>,
+}
+
+impl UdpTunnelReceiver {
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let timeout = Duration::from_millis(crate::config::DEFAULT_REASSEMBLY_TIMEOUT_MS);
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            reassembler: Mutex::new(Reassembler::new(timeout)),
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        })
+    }
+
+    /// Binds `addr` and applies the `tunnel` section: its reassembly timeout
+    /// and window and, if it asks for encryption, its cipher.
+    pub async fn from_config(config: &Config, addr: SocketAddr) -> Result<Self> {
+        let mut receiver = Self::bind(addr).await?;
+        if let Some(ref tunnel) = config.tunnel {
+            let timeout = tunnel.reassembly_timeout_ms.unwrap_or(crate::config::DEFAULT_REASSEMBLY_TIMEOUT_MS);
+            let reassembler = Reassembler::new(Duration::from_millis(timeout))
+                .with_window(tunnel.reassembly_window);
+            receiver.reassembler = Mutex::new(reassembler);
+            #[cfg(feature = "encryption")]
+            {
+                receiver.cipher = TunnelCipher::from_config(tunnel)?;
+            }
+            #[cfg(not(feature = "encryption"))]
+            if tunnel.encrypt {
+                return Err(anyhow::anyhow!("tunnel.encrypt requires the `encryption` feature"));
+            }
+        }
+        Ok(receiver)
+    }
+
+    /// Frames a fragmented frame may fall behind the newest before it is
+    /// dropped; see `Reassembler::with_window`. Other reassembly settings,
+    /// such as the sequence number width, are kept.
+    pub fn with_reassembly_window(mut self, window: u64) -> Self {
+        let reassembler = self.reassembler.into_inner();
+        self.reassembler = Mutex::new(reassembler.with_window(window));
+        self
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn with_cipher(mut self, cipher: TunnelCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    pub fn reassembly_timeout(&self) -> Duration {
+        self.reassembler.lock().timeout()
+    }
+
+    /// Changes how long fragmented frames wait for their missing fragments,
+    /// e.g. to follow the reorder window as link latencies change.
+    pub fn set_reassembly_timeout(&self, timeout: Duration) {
+        self.reassembler.lock().set_timeout(timeout);
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Waits for the next complete frame, reassembling fragmented ones, and
+    /// returns its sequence number and payload. Each frame is acknowledged
+    /// to the link socket it came from, for `max_in_flight` accounting.
+    pub async fn recv(&self) -> Result<(u64, Vec<u8>)> {
+        let mut buf = vec![0u8; 65536];
+        let (frame, from) = loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await?;
+            let now = Instant::now();
+            let mut reassembler = self.reassembler.lock();
+            let expired = reassembler.expire(now);
+            if expired > 0 {
+                debug!("Dropped {} frames with missing fragments", expired);
+            }
+            if let Some(frame) = reassembler.push(from, &buf[..len], now)? {
+                break (frame, from);
+            }
+        };
+
+        let (sequence_number, payload) = self.open(&frame)?;
+        self.reassembler.lock().confirm(from, sequence_number);
+        if let Err(e) = self.socket.send_to(&sequence_number.to_be_bytes(), from).await {
+            debug!("Failed to acknowledge frame {} to {}: {}", sequence_number, from, e);
+        }
+        Ok((sequence_number, payload))
+    }
+
+    fn open(&self, frame: &[u8]) -> Result<(u64, Vec<u8>)> {
+        #[cfg(feature = "encryption")]
+        if let Some(ref cipher) = self.cipher {
+            return cipher.open(frame);
+        }
+        UdpTunnelTransport::decode(frame)
+    }
+}
+
+/// The tunnel's far end: `tunnel.peer` if set, else the first peer the
+/// underlay manager's discovery has found, at `tunnel.port`. Asks the
+/// manager again every `retry` until a peer turns up.
+pub async fn tunnel_peer(tunnel: &TunnelConfig, underlay: &UnderlayClient, retry: Duration) -> Result<SocketAddr> {
+    if let Some(ref peer) = tunnel.peer {
+        return peer.parse().with_context(|| format!("Invalid tunnel.peer {}", peer));
+    }
+    loop {
+        match underlay.list_peers().await {
+            Ok(peers) => {
+                if let Some(peer) = peers.first() {
+                    let endpoint: SocketAddr = peer.endpoint.parse()
+                        .with_context(|| format!("Peer {} has an invalid endpoint {}", peer.node_id, peer.endpoint))?;
+                    let addr = SocketAddr::new(endpoint.ip(), tunnel.port);
+                    info!("Tunneling to discovered peer {} at {}", peer.node_id, addr);
+                    return Ok(addr);
+                }
+                debug!("No tunnel peer discovered yet");
+            }
+            Err(e) => warn!("Can't list discovered peers: {}", e),
+        }
+        tokio::time::sleep(retry).await;
+    }
+}
+
+/// Creates a non-blocking UDP socket for a link. With a `source_address` the
+/// socket is bound to it; on Linux we also try `SO_BINDTODEVICE` on the link's
+/// interface, which needs CAP_NET_RAW, so failure is only logged.
+fn bind_link_socket(link: &LinkConfig, peer: SocketAddr) -> Result<Socket> {
+    let source_ip = match link.source_address {
+        Some(ref addr) => addr
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid source_address {} for link {}", addr, link.name))?,
+        None if peer.is_ipv6() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let local = SocketAddr::new(source_ip, 0);
+
+    let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))?;
+
+    #[cfg(target_os = "linux")]
+    if let Err(e) = socket.bind_device(Some(link.interface.as_bytes())) {
+        debug!("SO_BINDTODEVICE {} not applied: {}", link.interface, e);
+    }
+
+    socket
+        .bind(&SockAddr::from(local))
+        .with_context(|| format!("Failed to bind link {} to {}", link.name, local))?;
+    socket.set_nonblocking(true)?;
+    debug!("Link {} transport bound to {}", link.name, local);
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Packet;
+    use chrono::Utc;
+
+    fn link(name: &str, source_address: Option<&str>) -> LinkConfig {
+        LinkConfig {
+            name: name.to_string(),
+            interface: "lo".to_string(),
+            weight: 1.0,
+            max_bandwidth: 100_000_000,
+            min_latency: 10,
+            failover_group: None,
+            failover_tier: 0,
+            source_address: source_address.map(|s| s.to_string()),
+            time_multipliers: Vec::new(),
+            mtu: None,
+            max_in_flight: None,
+            in_flight_timeout_ms: 2000,
+        }
+    }
+
+    fn scheduled(link_name: &str, sequence_number: u64) -> ScheduledPacket {
+        ScheduledPacket {
+            packet: Packet {
+                id: 1,
+                data: vec![0xab; 32],
+                priority: 5,
+                source_ip: "192.168.1.100".to_string(),
+                dest_ip: "192.168.1.200".to_string(),
+                protocol: crate::protocol::Protocol::Udp,
+                source_port: None,
+                dest_port: None,
+                dscp: None,
+                icmp_type: None,
+                icmp_code: None,
+                vlan_id: None,
+                inner_vlan_id: None,
+                timestamp: Utc::now(),
+            },
+            link_name: link_name.to_string(),
+            sequence_number,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_peer_waits_for_discovery() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let underlay = UnderlayClient::new(&listener.local_addr().unwrap().to_string(), Duration::from_secs(1));
+        tokio::spawn(async move {
+            let replies = [
+                r#"{"result": {"peers": []}}"#,
+                r#"{"result": {"peers": [{"node_id": "branch-02", "endpoint": "10.0.0.2:9093"}]}}"#,
+            ];
+            for reply in replies {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let request = BufReader::new(reader).lines().next_line().await.unwrap().unwrap();
+                assert!(request.contains("list_peers"), "{}", request);
+                writer.write_all(format!("{}\n", reply).as_bytes()).await.unwrap();
+            }
+        });
+
+        let tunnel = TunnelConfig::default();
+        let peer = tunnel_peer(&tunnel, &underlay, Duration::from_millis(10)).await.unwrap();
+        assert_eq!(peer, "10.0.0.2:47192".parse().unwrap());
+
+        // A configured peer doesn't need the manager
+        let tunnel = TunnelConfig { peer: Some("192.0.2.1:5000".to_string()), ..TunnelConfig::default() };
+        let peer = tunnel_peer(&tunnel, &underlay, Duration::from_millis(10)).await.unwrap();
+        assert_eq!(peer, "192.0.2.1:5000".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_transport_sends_from_source_address() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = receiver.local_addr().unwrap();
+        let transport = UdpTunnelTransport::bind(&[link("wan0", Some("127.0.0.1"))], peer)
+            .await
+            .unwrap();
+        assert_eq!(transport.local_addr("wan0").unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        transport.send(&scheduled("wan0", 42)).await.unwrap();
+
+        let mut buf = [0u8; 128];
+        let (len, from) = receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, transport.local_addr("wan0").unwrap());
+        assert_eq!(u64::from_be_bytes(buf[..8].try_into().unwrap()), 42);
+        // Unfragmented: offset 0, no more fragments
+        assert_eq!(&buf[8..12], &[0; 4]);
+        assert_eq!(&buf[12..len], &[0xab; 32][..]);
+    }
+
+    #[tokio::test]
+    async fn test_receiver_decodes_frames() {
+        let receiver = UdpTunnelReceiver::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let transport = UdpTunnelTransport::bind(&[link("wan0", None)], receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        transport.send(&scheduled("wan0", 7)).await.unwrap();
+
+        let (sequence_number, payload) = receiver.recv().await.unwrap();
+        assert_eq!(sequence_number, 7);
+        assert_eq!(payload, vec![0xab; 32]);
+    }
+
+    #[tokio::test]
+    async fn test_receiver_acknowledges_frames() {
+        let receiver = UdpTunnelReceiver::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let transport = UdpTunnelTransport::bind(&[link("wan0", Some("127.0.0.1"))], receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        transport.send(&scheduled("wan0", 7)).await.unwrap();
+        receiver.recv().await.unwrap();
+
+        let acked = tokio::time::timeout(Duration::from_secs(1), transport.recv_ack("wan0")).await.unwrap();
+        assert_eq!(acked.unwrap(), 7);
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[tokio::test]
+    async fn test_encrypt_requires_feature() {
+        let mut config = Config::default();
+        config.tunnel = Some(crate::config::TunnelConfig { encrypt: true, ..Default::default() });
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        assert!(UdpTunnelTransport::from_config(&config, peer).await.is_err());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encrypted_round_trip() {
+        let key = [0x42; 32];
+        let receiver = UdpTunnelReceiver::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_cipher(TunnelCipher::new(key));
+        let transport = UdpTunnelTransport::bind(&[link("wan0", None)], receiver.local_addr().unwrap())
+            .await
+            .unwrap()
+            .with_cipher(TunnelCipher::new(key));
+
+        transport.send(&scheduled("wan0", 9)).await.unwrap();
+
+        let (sequence_number, payload) = receiver.recv().await.unwrap();
+        assert_eq!(sequence_number, 9);
+        assert_eq!(payload, vec![0xab; 32]);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_receiver_rejects_plaintext_when_encrypted() {
+        let receiver = UdpTunnelReceiver::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_cipher(TunnelCipher::new([0x42; 32]));
+        let transport = UdpTunnelTransport::bind(&[link("wan0", None)], receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        transport.send(&scheduled("wan0", 9)).await.unwrap();
+        assert!(receiver.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_packet_fragmented_to_mtu() {
+        let receiver = UdpTunnelReceiver::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut wan0 = link("wan0", None);
+        wan0.mtu = Some(1500);
+        let transport = UdpTunnelTransport::bind(&[wan0], receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut packet = scheduled("wan0", 11);
+        packet.packet.data = (0..4000).map(|i| i as u8).collect();
+        transport.send(&packet).await.unwrap();
+
+        let (sequence_number, payload) = receiver.recv().await.unwrap();
+        assert_eq!(sequence_number, 11);
+        assert_eq!(payload, packet.packet.data);
+    }
+
+    #[tokio::test]
+    async fn test_transport_unknown_link() {
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let transport = UdpTunnelTransport::bind(&[link("wan0", None)], peer).await.unwrap();
+        assert!(transport.send(&scheduled("wan1", 1)).await.is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::config::BandwidthLimit;
+use sdwan_common::units::RawValue;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub use sdwan_common::units::{duration_ms, option_duration_ms, parse_duration_ms};
+
+/// Parses a rate such as `"100Mbps"` into bits per second. The unit is
+/// required, as it is for `bandwidth_limit`: a bare number is rejected
+/// rather than guessed to be bits, bytes or megabits per second.
+pub fn parse_bandwidth_bps(s: &str) -> Result<u64, String> {
+    s.parse::<BandwidthLimit>().map(|limit| limit.bits_per_sec())
+}
+
+fn bandwidth_from_raw(raw: RawValue) -> Result<u64, String> {
+    match raw {
+        RawValue::Integer(n) => Err(format!("Bandwidth {} needs a unit, e.g. \"{}Mbps\"; Bps, Kbps or Mbps", n, n)),
+        RawValue::Text(s) => parse_bandwidth_bps(&s),
+    }
+}
+
+fn bandwidth_to_string(bps: u64) -> Result<String, String> {
+    BandwidthLimit::from_bits_per_sec(bps)
+        .map(String::from)
+        .ok_or_else(|| format!("Bandwidth of {} bits per second can't be written in Bps, Kbps or Mbps", bps))
+}
+
+/// `#[serde(with)]` for bits-per-second fields written as a rate such as
+/// `"100Mbps"`. Values are written back the same way.
+pub mod bandwidth_bps {
+    use super::*;
+    use serde::ser::Error as _;
+
+    pub fn serialize<S: Serializer>(bps: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&bandwidth_to_string(*bps).map_err(S::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        bandwidth_from_raw(RawValue::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// `bandwidth_bps` for optional fields, which then also need `#[serde(default)]`.
+pub mod option_bandwidth_bps {
+    use super::*;
+    use serde::ser::Error as _;
+
+    pub fn serialize<S: Serializer>(bps: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bps {
+            Some(bps) => serializer.serialize_some(&bandwidth_to_string(*bps).map_err(S::Error::custom)?),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        Option::<RawValue>::deserialize(deserializer)?
+            .map(bandwidth_from_raw)
+            .transpose()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Settings {
+        #[serde(with = "duration_ms")]
+        interval: u64,
+        #[serde(default, with = "option_duration_ms")]
+        timeout: Option<u64>,
+        #[serde(with = "bandwidth_bps")]
+        bandwidth: u64,
+    }
+
+    #[test]
+    fn test_bandwidth_needs_a_unit() {
+        let settings: Settings = serde_yaml::from_str("interval: 5s\ntimeout: 250\nbandwidth: 100Mbps\n").unwrap();
+        assert_eq!((settings.interval, settings.timeout, settings.bandwidth), (5_000, Some(250), 100_000_000));
+        let bytes: Settings = serde_yaml::from_str("interval: 1m\nbandwidth: 1000Bps\n").unwrap();
+        assert_eq!((bytes.timeout, bytes.bandwidth), (None, 8_000));
+
+        // A bare number could be meant as bits, bytes or megabits
+        let error = serde_yaml::from_str::<Settings>("interval: 5\nbandwidth: 100\n").unwrap_err();
+        assert!(error.to_string().contains("needs a unit, e.g. \"100Mbps\""));
+        assert!(serde_yaml::from_str::<Settings>("interval: 5\nbandwidth: \"100000000\"\n").is_err());
+        assert!(serde_yaml::from_str::<Settings>("interval: 5\nbandwidth: 100mbps\n").is_err());
+        assert!(serde_yaml::from_str::<Settings>("interval: 5 parsecs\nbandwidth: 1Mbps\n").is_err());
+    }
+
+    #[test]
+    fn test_bandwidth_written_back_with_units() {
+        let settings = |bandwidth| Settings { interval: 1_000, timeout: None, bandwidth };
+        let written = |bandwidth| serde_yaml::to_string(&settings(bandwidth)).unwrap();
+        assert!(written(100_000_000).contains("bandwidth: 100Mbps"));
+        assert!(written(64_000).contains("bandwidth: 64Kbps"));
+        assert!(written(12_000_008).contains("bandwidth: 1500001Bps"));
+        assert!(serde_yaml::to_string(&settings(12)).is_err());
+
+        let yaml = written(2_500_000);
+        assert_eq!(serde_yaml::from_str::<Settings>(&yaml).unwrap().bandwidth, 2_500_000);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::scheduler::Packet;
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+/// Per-worker packet queues fed from the shared intake queue.
+///
+/// A worker first drains its own local queue. When that is empty it moves a
+/// chunk of the shared intake into its local queue, and when the intake is
+/// empty too it steals from a sibling's local queue, so one busy worker's
+/// backlog gets spread across idle ones.
+pub struct WorkQueues {
+    intake: Receiver<Packet>,
+    locals: Vec<(Sender<Packet>, Receiver<Packet>)>,
+    batch_size: usize,
+}
+
+impl WorkQueues {
+    pub fn new(intake: Receiver<Packet>, workers: usize, batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        // Each refill grabs two batches so there's something left to steal
+        let locals = (0..workers.max(1)).map(|_| bounded(batch_size * 2)).collect();
+
+        Self { intake, locals, batch_size }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.locals.len()
+    }
+
+    /// Packets waiting in the intake and all local queues.
+    pub fn depth(&self) -> usize {
+        self.intake.len() + self.locals.iter().map(|(_, local)| local.len()).sum::<usize>()
+    }
+
+    /// Returns up to `batch_size` packets for `worker`, or an empty batch if
+    /// there's no work anywhere.
+    pub fn next_batch(&self, worker: usize) -> Vec<Packet> {
+        let (local_sender, local) = &self.locals[worker];
+
+        let batch = self.take_batch(local);
+        if !batch.is_empty() {
+            return batch;
+        }
+
+        for packet in self.intake.try_iter().take(self.batch_size * 2) {
+            if let Err(e) = local_sender.try_send(packet) {
+                // Only this worker refills its queue, so this shouldn't
+                // happen, but never drop a packet on the floor
+                return vec![e.into_inner()];
+            }
+        }
+        let batch = self.take_batch(local);
+        if !batch.is_empty() {
+            return batch;
+        }
+
+        (1..self.locals.len())
+            .map(|offset| &self.locals[(worker + offset) % self.locals.len()].1)
+            .map(|sibling| self.take_batch(sibling))
+            .find(|batch| !batch.is_empty())
+            .unwrap_or_default()
+    }
+
+    fn take_batch(&self, queue: &Receiver<Packet>) -> Vec<Packet> {
+        queue.try_iter().take(self.batch_size).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Protocol;
+    use chrono::Utc;
+
+    fn packet(id: u64) -> Packet {
+        Packet {
+            id,
+            data: vec![0u8; 64],
+            priority: 5,
+            source_ip: "192.168.1.10".to_string(),
+            dest_ip: "10.0.0.1".to_string(),
+            protocol: Protocol::Tcp,
+            source_port: None,
+            dest_port: None,
+            dscp: None,
+            icmp_type: None,
+            icmp_code: None,
+            vlan_id: None,
+            inner_vlan_id: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn ids(batch: &[Packet]) -> Vec<u64> {
+        batch.iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn test_worker_refills_from_intake_in_batches() {
+        let (sender, receiver) = bounded(100);
+        for id in 0..10 {
+            sender.send(packet(id)).unwrap();
+        }
+        let queues = WorkQueues::new(receiver, 2, 4);
+
+        assert_eq!(ids(&queues.next_batch(0)), vec![0, 1, 2, 3]);
+        assert_eq!(ids(&queues.next_batch(0)), vec![4, 5, 6, 7]);
+        assert_eq!(ids(&queues.next_batch(0)), vec![8, 9]);
+        assert!(queues.next_batch(0).is_empty());
+    }
+
+    #[test]
+    fn test_idle_worker_steals_from_sibling() {
+        let (sender, receiver) = bounded(100);
+        for id in 0..8 {
+            sender.send(packet(id)).unwrap();
+        }
+        let queues = WorkQueues::new(receiver, 2, 4);
+
+        // Worker 0 takes all 8 into its local queue and works on the first 4
+        assert_eq!(ids(&queues.next_batch(0)), vec![0, 1, 2, 3]);
+        // The intake is now empty, so worker 1 steals the rest
+        assert_eq!(ids(&queues.next_batch(1)), vec![4, 5, 6, 7]);
+        assert!(queues.next_batch(0).is_empty());
+    }
+} 
\ No newline at end of file
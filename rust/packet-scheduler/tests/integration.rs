@@ -0,0 +1,146 @@
+//! The scheduler and the underlay manager running together, talking over
+//! the manager's RPC listener as they do when deployed.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use packet_scheduler::scheduler::{Packet, PacketScheduler, ScheduledPacket};
+use packet_scheduler::transport::PacketTransport;
+use packet_scheduler::{LinkMetrics, Protocol};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use underlay_manager::UnderlayManagerServer;
+
+/// Records the link each packet was sent on.
+#[derive(Default)]
+struct RecordingTransport {
+    sent: Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl PacketTransport for RecordingTransport {
+    async fn send(&self, packet: &ScheduledPacket) -> Result<()> {
+        self.sent.lock().push(packet.link_name.clone());
+        Ok(())
+    }
+}
+
+fn packet(id: u64) -> Packet {
+    Packet {
+        id,
+        data: vec![0u8; 200],
+        priority: 5,
+        source_ip: "192.168.1.10".to_string(),
+        dest_ip: "10.0.0.1".to_string(),
+        protocol: Protocol::Udp,
+        source_port: None,
+        dest_port: None,
+        dscp: None,
+        icmp_type: None,
+        icmp_code: None,
+        vlan_id: None,
+        inner_vlan_id: None,
+        timestamp: chrono::Utc::now(),
+    }
+}
+
+/// A manager probing `interfaces` every 200ms, serving on an ephemeral
+/// port. Returns the scheduler's `--underlay-endpoint` for it.
+async fn start_underlay(interfaces: &[(&str, bool)]) -> (Arc<UnderlayManagerServer>, String) {
+    let mut config = underlay_manager::Config::default();
+    let template = config.interfaces[0].clone();
+    config.interfaces = interfaces.iter()
+        .map(|(name, bandwidth_test_enabled)| underlay_manager::config::InterfaceConfig {
+            name: name.to_string(),
+            probe_interval: 200,
+            bandwidth_test_enabled: *bandwidth_test_enabled,
+            ..template.clone()
+        })
+        .collect();
+    config.probes.bandwidth_test_duration = 100;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let server = Arc::new(UnderlayManagerServer::new(config));
+    tokio::spawn({
+        let server = server.clone();
+        async move { server.start_on(listener).await }
+    });
+    (server, endpoint)
+}
+
+/// A running scheduler over links `lo` and `wan-down` taking metrics from
+/// the manager at `endpoint`.
+async fn start_scheduler(endpoint: String, transport: Arc<RecordingTransport>) -> Arc<PacketScheduler> {
+    let config = serde_yaml::from_str(
+        "links:\n  - {name: lo, interface: lo, weight: 1.0, max_bandwidth: 100Mbps, min_latency: 1}\n  - {name: wan-down, interface: wan-down, weight: 1.0, max_bandwidth: 100Mbps, min_latency: 1}\n",
+    ).unwrap();
+    let mut scheduler = PacketScheduler::new(config, endpoint).await.unwrap();
+    scheduler.set_transport(transport);
+    let scheduler = Arc::new(scheduler);
+    tokio::spawn(scheduler.clone().run());
+    scheduler
+}
+
+/// The first metrics the scheduler receives from the manager.
+async fn wait_for_metrics(scheduler: &PacketScheduler) -> HashMap<String, LinkMetrics> {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let metrics = scheduler.debug_state().metrics;
+            if !metrics.is_empty() {
+                return metrics;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await.expect("no metrics arrived from the underlay manager")
+}
+
+/// Enqueues `count` packets and returns the links they were sent on.
+async fn send_packets(scheduler: &PacketScheduler, transport: &RecordingTransport, count: u64) -> Vec<String> {
+    for id in 0..count {
+        scheduler.enqueue(packet(id)).unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while (transport.sent.lock().len() as u64) < count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("packets were not sent");
+    transport.sent.lock().clone()
+}
+
+#[tokio::test]
+async fn test_scheduler_selects_link_from_live_underlay_metrics() {
+    // The manager doesn't monitor wan-down, so it never has metrics
+    let (underlay, endpoint) = start_underlay(&[("lo", true)]).await;
+    let transport = Arc::new(RecordingTransport::default());
+    let scheduler = start_scheduler(endpoint, transport.clone()).await;
+
+    let metrics = wait_for_metrics(&scheduler).await;
+    assert_eq!(metrics.keys().collect::<Vec<_>>(), ["lo"]);
+    assert!(metrics["lo"].bandwidth_mbps > 0.0);
+
+    let sent = send_packets(&scheduler, &transport, 10).await;
+    assert!(sent.iter().all(|link| link == "lo"));
+
+    scheduler.stop();
+    underlay.stop();
+}
+
+#[tokio::test]
+async fn test_link_without_bandwidth_test_still_carries_traffic() {
+    let (underlay, endpoint) = start_underlay(&[("lo", false)]).await;
+    let transport = Arc::new(RecordingTransport::default());
+    let scheduler = start_scheduler(endpoint, transport.clone()).await;
+
+    let metrics = wait_for_metrics(&scheduler).await;
+    assert!(!metrics["lo"].bandwidth_measured);
+    assert!(!metrics["lo"].is_down());
+
+    let sent = send_packets(&scheduler, &transport, 10).await;
+    assert!(sent.iter().all(|link| link == "lo"));
+
+    scheduler.stop();
+    underlay.stop();
+}
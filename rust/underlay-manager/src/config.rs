@@ -1,87 +1,647 @@
+use sdwan_common::config_file::{load_yaml, process_env, EnvLookup};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use crate::reflector::ReflectorKey;
 
+/// The `probes` and `server` sections, and settings within them, may be left
+/// out and take the value from `Config::default()`, so configs written before
+/// a setting existed keep parsing.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub interfaces: Vec<InterfaceConfig>,
+    #[serde(default)]
     pub probes: ProbeConfig,
+    #[serde(default)]
     pub server: ServerConfig,
+    /// Announce this manager and discover peers over link-local multicast.
+    pub discovery: Option<DiscoveryConfig>,
+    /// POST a JSON notice to this webhook when a link turns healthy or
+    /// unhealthy.
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InterfaceConfig {
     pub name: String,
     pub enabled: bool,
+    #[serde(with = "crate::units::duration_ms")]
     pub probe_interval: u64,
+    /// ms between lightweight liveness checks (a single latency probe), so
+    /// a dead link is noticed well before its next full probe. Unset relies
+    /// on full probes alone.
+    #[serde(default, with = "crate::units::option_duration_ms")]
+    pub liveness_interval: Option<u64>,
     pub icmp_enabled: bool,
     pub udp_enabled: bool,
     pub bandwidth_test_enabled: bool,
+    /// Local address probe sockets bind to, so probes leave through this
+    /// interface even on multi-homed hosts.
+    pub source_address: Option<String>,
+    /// 802.1Q VLAN ID; probes then bind to the `<name>.<vlan_id>` subinterface.
+    pub vlan_id: Option<u16>,
+    /// Linux VRF device the interface is enslaved to. Probe sockets are
+    /// bound to it so they take the VRF's routes.
+    pub vrf: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ProbeConfig {
+    #[serde(with = "crate::units::duration_ms")]
     pub icmp_timeout: u64,
+    #[serde(with = "crate::units::duration_ms")]
     pub udp_timeout: u64,
+    #[serde(with = "crate::units::duration_ms")]
     pub bandwidth_test_duration: u64,
     pub packet_size: usize,
     pub probe_count: usize,
+    /// Fill pattern for UDP probe datagrams and reflector uploads.
+    pub payload_pattern: PayloadPattern,
+    /// Interfaces the scheduler isn't using are probed this many times less
+    /// often, so their recovery is still detected.
+    pub idle_probe_multiplier: u32,
+    /// Weight in (0, 1] of the newest UDP probe batch in the smoothed packet
+    /// loss; lower values follow sustained loss rather than single bad
+    /// batches. 1 reports each batch's loss as measured.
+    pub loss_alpha: f64,
+    /// Number of recent probe cycles the reliability (uptime) ratio covers.
+    pub reliability_window: usize,
+    /// Minimum instantaneous health score for a probe cycle to count as up.
+    pub healthy_threshold: f64,
+    /// How many interfaces are probed at once.
+    pub max_concurrent_probes: usize,
+    /// Extra attempts for a failed ICMP, UDP or bandwidth probe before
+    /// falling back to the interface's last-known values.
+    pub probe_retries: u32,
+    /// Wait before the first retry of a failed probe, doubling for each
+    /// further retry, so a struggling target isn't hammered.
+    #[serde(with = "crate::units::duration_ms")]
+    pub retry_backoff_ms: u64,
+    /// Longest wait between retries.
+    #[serde(with = "crate::units::duration_ms")]
+    pub max_retry_backoff_ms: u64,
+    /// After this many failed probe cycles in a row an interface is probed
+    /// less often, doubling its interval with each further failure. 0 keeps
+    /// probing at the normal cadence.
+    pub circuit_breaker_threshold: u32,
+    /// Most a failing interface's probe interval is stretched by.
+    pub circuit_breaker_max_multiplier: u32,
+    /// DSCP codepoint (0-63) marked on outgoing UDP probes, TCP connect
+    /// probes and reflector tests, to reveal whether a carrier treats
+    /// marked traffic differently.
+    pub probe_dscp: Option<u8>,
+    /// `host:port` whose TCP connect time stands in for ICMP latency when
+    /// this process isn't allowed to send ICMP. Unset keeps using ICMP.
+    pub tcp_probe_target: Option<String>,
+    /// Independent `host:port` targets (e.g. the gateway and a public
+    /// anchor) whose TCP connect times measure latency in place of ICMP,
+    /// so one unreachable target doesn't have to mean a dead link.
+    pub probe_targets: Vec<String>,
+    /// Which target failures fail a latency probe.
+    pub target_failure_policy: TargetFailurePolicy,
+    /// Start the latency probe (`probe_targets` or ICMP), UDP and TCP
+    /// connect (to `tcp_probe_target`) probes together and take the first
+    /// to succeed as proof the link is alive, instead of probing one type
+    /// after another with retries. A link where one type is filtered is then
+    /// neither slow to confirm nor mistaken for a dead one, and a dead link
+    /// fails after a single timeout.
+    pub race_probes: bool,
+    /// `host:port` of a bandwidth reflector (`underlay-manager reflector`)
+    /// to measure upload and download throughput against separately.
+    pub bandwidth_reflector: Option<String>,
+    /// `host:port` of a UDP echo responder, such as a reflector, which
+    /// echoes UDP on its listen port. UDP probes measuring jitter and loss
+    /// are sent to it; unset skips them.
+    pub udp_probe_target: Option<String>,
+    /// Key shared with the reflector (its `--psk-file`), as 64 hex
+    /// characters, authenticating bandwidth tests and UDP probes. Required
+    /// with `bandwidth_reflector`; UDP probes are sent unsigned without it.
+    pub reflector_psk: Option<String>,
+    /// Targets measured from every interface after each full probe, so the
+    /// scheduler can pick the best link for a destination rather than
+    /// overall when links peer differently.
+    pub destination_probes: Vec<DestinationProbe>,
+}
+
+/// A `host:port` standing in for the destinations in `prefix`; the TCP
+/// connect time to it is the link's latency towards them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DestinationProbe {
+    /// CIDR prefix such as `203.0.113.0/24`.
+    pub prefix: String,
+    pub target: String,
+}
+
+fn default_idle_probe_multiplier() -> u32 {
+    4
+}
+
+fn default_loss_alpha() -> f64 {
+    0.3
+}
+
+fn default_reliability_window() -> usize {
+    20
+}
+
+fn default_healthy_threshold() -> f64 {
+    0.3
+}
+
+fn default_max_concurrent_probes() -> usize {
+    8
+}
+
+fn default_probe_retries() -> u32 {
+    2
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_max_retry_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_circuit_breaker_threshold() -> u32 {
+    3
+}
+
+fn default_circuit_breaker_max_multiplier() -> u32 {
+    8
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadPattern {
+    #[default]
+    Zeros,
+    Random,
+    Incrementing,
+}
+
+/// When a latency probe against several `probe_targets` counts as failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetFailurePolicy {
+    /// Only when every target fails: one target being down is blamed on
+    /// the target, not the link.
+    #[default]
+    All,
+    /// As soon as any target fails.
+    Any,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ServerConfig {
     pub grpc_port: u16,
+    #[serde(with = "crate::units::duration_ms")]
     pub metrics_interval: u64,
     pub max_connections: usize,
+    /// Minimum change (relative for latency, jitter and bandwidth; absolute
+    /// for loss and reliability) before an interface is included in a
+    /// metrics diff.
+    pub metrics_diff_threshold: f64,
+    /// Alert when fewer links than this pass the healthy threshold; unset
+    /// disables the check. Links never probed successfully count as
+    /// unhealthy once two of the longest probe intervals have passed.
+    pub min_healthy_links: Option<usize>,
+}
+
+fn default_metrics_diff_threshold() -> f64 {
+    0.05
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryConfig {
+    /// Link-local multicast group and port announcements go to.
+    #[serde(default = "default_discovery_group")]
+    pub group: String,
+    /// Local IPv4 address of the interface to announce and listen on;
+    /// unset leaves the choice to the kernel.
+    pub interface_address: Option<String>,
+    /// Name announced to peers; defaults to a random ID per start.
+    pub node_id: Option<String>,
+    #[serde(default = "default_announce_interval", with = "crate::units::duration_ms")]
+    pub announce_interval_ms: u64,
+    /// Peers not heard from for this long are dropped from the peer list.
+    #[serde(default = "default_peer_timeout", with = "crate::units::duration_ms")]
+    pub peer_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// `http://host:port/path` the notices are POSTed to.
+    pub url: String,
+    /// Per-attempt limit on connecting, sending and reading the response.
+    #[serde(default = "default_webhook_timeout", with = "crate::units::duration_ms")]
+    pub timeout_ms: u64,
+    /// Further attempts after a failed delivery before the notice is dropped.
+    #[serde(default = "default_webhook_max_retries")]
+    pub max_retries: u32,
+    /// Wait before the first retry; doubles with each further one.
+    #[serde(default = "default_webhook_retry_backoff", with = "crate::units::duration_ms")]
+    pub retry_backoff_ms: u64,
+}
+
+fn default_webhook_timeout() -> u64 {
+    5000
+}
+
+fn default_webhook_max_retries() -> u32 {
+    5
+}
+
+fn default_webhook_retry_backoff() -> u64 {
+    1000
+}
+
+fn default_discovery_group() -> String {
+    "224.0.0.190:47190".to_string()
+}
+
+fn default_announce_interval() -> u64 {
+    5000
+}
+
+fn default_peer_timeout() -> u64 {
+    15000
 }
 
 impl Config {
+    /// Loads and validates a config file. String values may reference
+    /// environment variables as `${NAME}`, see
+    /// `sdwan_common::config_file::expand_env_vars`.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&content)?;
+        Self::from_file_with_env(path, &process_env)
+    }
+
+    /// `from_file` with variables looked up in `env` instead of the process
+    /// environment.
+    pub fn from_file_with_env<P: AsRef<Path>>(path: P, env: EnvLookup) -> Result<Self> {
+        let config: Config = load_yaml(path.as_ref(), env)?;
+        config.validate()?;
         Ok(config)
     }
 
-    pub fn default() -> Self {
+    pub fn validate(&self) -> Result<()> {
+        self.probes.validate()?;
+        if let Some(ref discovery) = self.discovery {
+            discovery.validate()?;
+        }
+        if let Some(ref webhook) = self.webhook {
+            webhook.validate()?;
+        }
+        let mut names = std::collections::HashSet::new();
+        for interface in &self.interfaces {
+            interface.validate()?;
+            // Metrics are keyed by name, so a second entry would silently
+            // replace the first
+            if !names.insert(interface.name.as_str()) {
+                return Err(anyhow::anyhow!("Duplicate interface name {}", interface.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
         Config {
             interfaces: vec![
                 InterfaceConfig {
                     name: "eth0".to_string(),
                     enabled: true,
                     probe_interval: 5000,
+                    liveness_interval: None,
                     icmp_enabled: true,
                     udp_enabled: true,
                     bandwidth_test_enabled: true,
+                    source_address: None,
+                    vlan_id: None,
+                    vrf: None,
                 },
                 InterfaceConfig {
                     name: "eth1".to_string(),
                     enabled: true,
                     probe_interval: 5000,
+                    liveness_interval: None,
                     icmp_enabled: true,
                     udp_enabled: true,
                     bandwidth_test_enabled: true,
+                    source_address: None,
+                    vlan_id: None,
+                    vrf: None,
                 },
             ],
-            probes: ProbeConfig {
-                icmp_timeout: 1000,
-                udp_timeout: 2000,
-                bandwidth_test_duration: 10000,
-                packet_size: 1500,
-                probe_count: 10,
-            },
-            server: ServerConfig {
-                grpc_port: 9093,
-                metrics_interval: 1000,
-                max_connections: 100,
-            },
+            probes: ProbeConfig::default(),
+            server: ServerConfig::default(),
+            discovery: None,
+            webhook: None,
+        }
+    }
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            icmp_timeout: 1000,
+            udp_timeout: 2000,
+            bandwidth_test_duration: 10000,
+            packet_size: 1500,
+            probe_count: 10,
+            payload_pattern: PayloadPattern::Zeros,
+            idle_probe_multiplier: default_idle_probe_multiplier(),
+            loss_alpha: default_loss_alpha(),
+            reliability_window: default_reliability_window(),
+            healthy_threshold: default_healthy_threshold(),
+            max_concurrent_probes: default_max_concurrent_probes(),
+            probe_retries: default_probe_retries(),
+            retry_backoff_ms: default_retry_backoff_ms(),
+            max_retry_backoff_ms: default_max_retry_backoff_ms(),
+            circuit_breaker_threshold: default_circuit_breaker_threshold(),
+            circuit_breaker_max_multiplier: default_circuit_breaker_max_multiplier(),
+            probe_dscp: None,
+            tcp_probe_target: None,
+            probe_targets: Vec::new(),
+            target_failure_policy: TargetFailurePolicy::default(),
+            race_probes: false,
+            bandwidth_reflector: None,
+            udp_probe_target: None,
+            reflector_psk: None,
+            destination_probes: Vec::new(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            grpc_port: 9093,
+            metrics_interval: 1000,
+            max_connections: 100,
+            metrics_diff_threshold: default_metrics_diff_threshold(),
+            min_healthy_links: None,
+        }
+    }
+}
+
+const VALID_VLAN_IDS: std::ops::RangeInclusive<u16> = 1..=4094;
+
+/// Probe payload sizes from a minimal probe up to a jumbo frame.
+const VALID_PACKET_SIZES: std::ops::RangeInclusive<usize> = 64..=9000;
+
+impl ProbeConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.probe_count == 0 {
+            return Err(anyhow::anyhow!("probes.probe_count must be at least 1"));
+        }
+        if !VALID_PACKET_SIZES.contains(&self.packet_size) {
+            return Err(anyhow::anyhow!(
+                "probes.packet_size {} is outside the valid range {}-{} bytes",
+                self.packet_size, VALID_PACKET_SIZES.start(), VALID_PACKET_SIZES.end()
+            ));
+        }
+        for (name, value) in [
+            ("icmp_timeout", self.icmp_timeout),
+            ("udp_timeout", self.udp_timeout),
+            ("bandwidth_test_duration", self.bandwidth_test_duration),
+        ] {
+            if value == 0 {
+                return Err(anyhow::anyhow!("probes.{} must be greater than 0 ms", name));
+            }
+        }
+        if !(self.loss_alpha > 0.0 && self.loss_alpha <= 1.0) {
+            return Err(anyhow::anyhow!("probes.loss_alpha {} is outside the valid range (0, 1]", self.loss_alpha));
+        }
+        if let Some(dscp) = self.probe_dscp.filter(|dscp| *dscp > 63) {
+            return Err(anyhow::anyhow!("probes.probe_dscp {} is outside the valid range 0-63", dscp));
+        }
+        // Only the form is checked: resolving here would make loading the
+        // config depend on DNS, see `resolve_targets`
+        for (field, target) in self.targets() {
+            check_host_port(field, target)?;
+        }
+        for destination in &self.destination_probes {
+            check_prefix(&destination.prefix)?;
+        }
+        if self.reflector_key()?.is_none() && self.bandwidth_reflector.is_some() {
+            return Err(anyhow::anyhow!("probes.bandwidth_reflector needs probes.reflector_psk"));
+        }
+        Ok(())
+    }
+}
+
+impl ProbeConfig {
+    /// Every configured `host:port` target with the field it came from.
+    fn targets(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.tcp_probe_target.iter().map(|target| ("tcp_probe_target", target.as_str()))
+            .chain(self.probe_targets.iter().map(|target| ("probe_targets", target.as_str())))
+            .chain(self.bandwidth_reflector.iter().map(|target| ("bandwidth_reflector", target.as_str())))
+            .chain(self.udp_probe_target.iter().map(|target| ("udp_probe_target", target.as_str())))
+            .chain(self.destination_probes.iter().map(|destination| ("destination_probes", destination.target.as_str())))
+    }
+
+    /// Resolves every configured target, failing on the first that doesn't
+    /// resolve. For preflight checks; probes resolve their own at startup.
+    pub fn resolve_targets(&self) -> Result<()> {
+        for (field, target) in self.targets() {
+            resolve_one(field, target)?;
         }
+        Ok(())
+    }
+
+    pub fn tcp_probe_addr(&self) -> Result<Option<SocketAddr>> {
+        resolve("tcp_probe_target", self.tcp_probe_target.as_deref())
+    }
+
+    pub fn probe_target_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.probe_targets.iter()
+            .map(|target| resolve_one("probe_targets", target))
+            .collect()
+    }
+
+    pub fn bandwidth_reflector_addr(&self) -> Result<Option<SocketAddr>> {
+        resolve("bandwidth_reflector", self.bandwidth_reflector.as_deref())
+    }
+
+    pub fn udp_probe_addr(&self) -> Result<Option<SocketAddr>> {
+        resolve("udp_probe_target", self.udp_probe_target.as_deref())
     }
+
+    /// Each destination prefix with its resolved target.
+    pub fn destination_probe_addrs(&self) -> Result<Vec<(String, SocketAddr)>> {
+        self.destination_probes.iter()
+            .map(|destination| Ok((destination.prefix.clone(), resolve_one("destination_probes", &destination.target)?)))
+            .collect()
+    }
+
+    pub fn reflector_key(&self) -> Result<Option<ReflectorKey>> {
+        self.reflector_psk.as_deref()
+            .map(|psk| ReflectorKey::from_hex(psk).context("probes.reflector_psk"))
+            .transpose()
+    }
+}
+
+fn check_host_port(field: &str, target: &str) -> Result<()> {
+    match target.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(anyhow::anyhow!("probes.{} {} is not a host:port", field, target)),
+    }
+}
+
+fn check_prefix(prefix: &str) -> Result<()> {
+    let valid = match prefix.split_once('/') {
+        Some((addr, len)) => match (addr.parse::<IpAddr>(), len.parse::<u8>()) {
+            (Ok(IpAddr::V4(_)), Ok(len)) => len <= 32,
+            (Ok(IpAddr::V6(_)), Ok(len)) => len <= 128,
+            _ => false,
+        },
+        None => false,
+    };
+    if !valid {
+        return Err(anyhow::anyhow!("probes.destination_probes prefix {} is not a CIDR prefix", prefix));
+    }
+    Ok(())
+}
+
+fn resolve(field: &str, target: Option<&str>) -> Result<Option<SocketAddr>> {
+    target.map(|target| resolve_one(field, target)).transpose()
+}
+
+fn resolve_one(field: &str, target: &str) -> Result<SocketAddr> {
+    target.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| anyhow::anyhow!("probes.{} {} is not a resolvable host:port", field, target))
+}
+
+impl InterfaceConfig {
+    /// The kernel device probes bind to. A `vlan_id` on a parent interface
+    /// selects its `<name>.<vlan_id>` subinterface; names that already are
+    /// subinterfaces are used as-is.
+    pub fn device_name(&self) -> String {
+        match self.vlan_id {
+            Some(vlan_id) if parse_vlan_subinterface(&self.name).is_none() => {
+                format!("{}.{}", self.name, vlan_id)
+            }
+            _ => self.name.clone(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let name_vlan = parse_vlan_subinterface(&self.name).map(|(_, vlan_id)| vlan_id);
+
+        for vlan_id in name_vlan.iter().chain(self.vlan_id.iter()) {
+            if !VALID_VLAN_IDS.contains(vlan_id) {
+                return Err(anyhow::anyhow!(
+                    "Interface {}: VLAN ID {} is outside the valid range 1-4094",
+                    self.name, vlan_id
+                ));
+            }
+        }
+
+        if let (Some(name_vlan), Some(vlan_id)) = (name_vlan, self.vlan_id) {
+            if name_vlan != vlan_id {
+                return Err(anyhow::anyhow!(
+                    "Interface {}: vlan_id {} doesn't match the subinterface name",
+                    self.name, vlan_id
+                ));
+            }
+        }
+
+        if self.vrf.as_deref() == Some("") {
+            return Err(anyhow::anyhow!("Interface {}: vrf must name a VRF device", self.name));
+        }
+
+        if self.liveness_interval == Some(0) {
+            return Err(anyhow::anyhow!("Interface {}: liveness_interval must be greater than 0 ms", self.name));
+        }
+
+        Ok(())
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.group_addr()?;
+        self.interface_addr()?;
+        if self.announce_interval_ms == 0 {
+            return Err(anyhow::anyhow!("discovery.announce_interval_ms must be greater than 0 ms"));
+        }
+        if self.peer_timeout_ms <= self.announce_interval_ms {
+            return Err(anyhow::anyhow!("discovery.peer_timeout_ms must be longer than announce_interval_ms"));
+        }
+        Ok(())
+    }
+
+    pub fn group_addr(&self) -> Result<std::net::SocketAddrV4> {
+        let group: std::net::SocketAddrV4 = self.group.parse()
+            .map_err(|e| anyhow::anyhow!("discovery.group {} is not an IPv4 address and port: {}", self.group, e))?;
+        if !group.ip().is_multicast() {
+            return Err(anyhow::anyhow!("discovery.group {} is not a multicast address", self.group));
+        }
+        Ok(group)
+    }
+
+    pub fn interface_addr(&self) -> Result<std::net::Ipv4Addr> {
+        match self.interface_address {
+            Some(ref addr) => addr.parse()
+                .map_err(|e| anyhow::anyhow!("discovery.interface_address {} is not an IPv4 address: {}", addr, e)),
+            None => Ok(std::net::Ipv4Addr::UNSPECIFIED),
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.endpoint()?;
+        if self.timeout_ms == 0 {
+            return Err(anyhow::anyhow!("webhook.timeout_ms must be greater than 0 ms"));
+        }
+        Ok(())
+    }
+
+    /// The `host:port` to connect to and the request path. Only plain HTTP
+    /// is supported; the port defaults to 80.
+    pub fn endpoint(&self) -> Result<(String, String)> {
+        let rest = self.url.strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("webhook.url {} must start with http://", self.url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(anyhow::anyhow!("webhook.url {} has no host", self.url));
+        }
+        let authority = if authority.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']')) {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok((authority, path.to_string()))
+    }
+}
+
+/// Splits a VLAN subinterface name such as `eth0.100` into its parent
+/// interface and VLAN ID. Returns `None` for names without a numeric suffix.
+pub fn parse_vlan_subinterface(name: &str) -> Option<(&str, u16)> {
+    let (parent, vlan) = name.rsplit_once('.')?;
+    if parent.is_empty() {
+        return None;
+    }
+    vlan.parse().ok().map(|vlan_id| (parent, vlan_id))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
 
     #[test]
     fn test_config_serialization() {
@@ -90,4 +650,207 @@ mod tests {
         let deserialized: Config = serde_yaml::from_str(&yaml).unwrap();
         assert_eq!(config.server.grpc_port, deserialized.server.grpc_port);
     }
+
+    #[test]
+    fn test_minimal_config_takes_defaults() {
+        let config: Config = serde_yaml::from_str(r#"
+interfaces:
+  - name: "wan0"
+    enabled: true
+    probe_interval: 5000
+    icmp_enabled: true
+    udp_enabled: true
+    bandwidth_test_enabled: false
+probes:
+  icmp_timeout: 500
+"#).unwrap();
+        let defaults = Config::default();
+
+        assert_eq!(config.probes.icmp_timeout, 500);
+        assert_eq!(config.probes.probe_count, defaults.probes.probe_count);
+        assert_eq!(config.probes.max_concurrent_probes, defaults.probes.max_concurrent_probes);
+        assert_eq!(config.server.grpc_port, defaults.server.grpc_port);
+        assert_eq!(config.server.metrics_diff_threshold, defaults.server.metrics_diff_threshold);
+        assert!(config.interfaces[0].vlan_id.is_none() && config.discovery.is_none());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_env_vars_expanded_on_load() {
+        let env = |name: &str| (name == "UNDERLAY_TEST_IFACE").then(|| "wan7".to_string());
+        let path = std::env::temp_dir().join(format!("underlay-config-{}.yml", uuid::Uuid::new_v4()));
+        let write_and_load = |grpc_port: &str| {
+            fs::write(&path, format!(
+                "interfaces:\n  - {{name: \"${{UNDERLAY_TEST_IFACE}}\", enabled: true, probe_interval: 5000, icmp_enabled: true, udp_enabled: true, bandwidth_test_enabled: false}}\nserver:\n  grpc_port: {}\n  metrics_interval: 1000\n  max_connections: 10\n",
+                grpc_port
+            )).unwrap();
+            Config::from_file_with_env(&path, &env)
+        };
+
+        let config = write_and_load("${UNDERLAY_TEST_UNSET:-50061}").unwrap();
+        assert_eq!(config.interfaces[0].name, "wan7");
+        assert_eq!(config.server.grpc_port, 50061);
+
+        let result = write_and_load("${UNDERLAY_TEST_UNSET}");
+        fs::remove_file(&path).unwrap();
+        assert!(format!("{:#}", result.unwrap_err()).contains("UNDERLAY_TEST_UNSET is not set"));
+    }
+
+    #[test]
+    fn test_durations_accept_units() {
+        let config: Config = serde_yaml::from_str(r#"
+interfaces:
+  - name: "wan0"
+    enabled: true
+    probe_interval: 5s
+    liveness_interval: "500ms"
+    icmp_enabled: true
+    udp_enabled: true
+    bandwidth_test_enabled: false
+probes:
+  icmp_timeout: 1s
+  udp_timeout: 2000
+"#).unwrap();
+
+        assert_eq!(config.interfaces[0].probe_interval, 5_000);
+        assert_eq!(config.interfaces[0].liveness_interval, Some(500));
+        assert_eq!((config.probes.icmp_timeout, config.probes.udp_timeout), (1_000, 2_000));
+        assert!(serde_yaml::from_str::<ProbeConfig>("icmp_timeout: 1 second\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_vlan_subinterface() {
+        assert_eq!(parse_vlan_subinterface("eth0.100"), Some(("eth0", 100)));
+        assert_eq!(parse_vlan_subinterface("bond0.4094"), Some(("bond0", 4094)));
+        assert_eq!(parse_vlan_subinterface("eth0"), None);
+        assert_eq!(parse_vlan_subinterface("br.lan"), None);
+        assert_eq!(parse_vlan_subinterface(".100"), None);
+    }
+
+    #[test]
+    fn test_vlan_device_name() {
+        let mut interface = Config::default().interfaces[0].clone();
+        assert_eq!(interface.device_name(), "eth0");
+
+        interface.vlan_id = Some(100);
+        assert_eq!(interface.device_name(), "eth0.100");
+
+        interface.name = "eth0.100".to_string();
+        assert_eq!(interface.device_name(), "eth0.100");
+    }
+
+    #[test]
+    fn test_vlan_id_validation() {
+        let mut interface = Config::default().interfaces[0].clone();
+        interface.vlan_id = Some(100);
+        assert!(interface.validate().is_ok());
+
+        interface.vlan_id = Some(0);
+        assert!(interface.validate().is_err());
+        interface.vlan_id = Some(4095);
+        assert!(interface.validate().is_err());
+
+        interface.vlan_id = None;
+        interface.name = "eth0.4095".to_string();
+        assert!(interface.validate().is_err());
+
+        interface.name = "eth0.100".to_string();
+        interface.vlan_id = Some(200);
+        assert!(interface.validate().is_err());
+    }
+
+    #[test]
+    fn test_duplicate_interface_names_rejected() {
+        let mut config = Config::default();
+        let mut duplicate = config.interfaces[0].clone();
+        duplicate.source_address = Some("192.0.2.10".to_string());
+        config.interfaces.push(duplicate);
+
+        let error = config.validate().unwrap_err().to_string();
+        assert_eq!(error, format!("Duplicate interface name {}", config.interfaces[0].name));
+    }
+
+
+    fn probe_error(update: impl FnOnce(&mut ProbeConfig)) -> String {
+        let mut config = Config::default();
+        update(&mut config.probes);
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_default_probe_config_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_targets_checked_without_resolving() {
+        assert!(probe_error(|probes| probes.tcp_probe_target = Some("203.0.113.1".to_string())).contains("not a host:port"));
+        assert!(probe_error(|probes| probes.probe_targets = vec![":443".to_string()]).contains("probe_targets"));
+
+        // Unresolvable names load, and fail the preflight resolution instead
+        let mut config = Config::default();
+        config.probes.udp_probe_target = Some("probe.invalid:47191".to_string());
+        assert!(config.validate().is_ok());
+        assert!(config.probes.resolve_targets().unwrap_err().to_string().contains("udp_probe_target"));
+
+        config.probes.udp_probe_target = Some("127.0.0.1:47191".to_string());
+        assert!(config.probes.resolve_targets().is_ok());
+    }
+
+    #[test]
+    fn test_destination_probes_validated() {
+        let destination = |prefix: &str, target: &str| DestinationProbe { prefix: prefix.to_string(), target: target.to_string() };
+        let mut config = Config::default();
+        config.probes.destination_probes = vec![destination("203.0.113.0/24", "203.0.113.1:443"), destination("2001:db8::/32", "[2001:db8::1]:443")];
+        assert!(config.validate().is_ok());
+
+        assert!(probe_error(|probes| probes.destination_probes = vec![destination("203.0.113.0/33", "203.0.113.1:443")]).contains("not a CIDR prefix"));
+        assert!(probe_error(|probes| probes.destination_probes = vec![destination("203.0.113.0", "203.0.113.1:443")]).contains("not a CIDR prefix"));
+        assert!(probe_error(|probes| probes.destination_probes = vec![destination("203.0.113.0/24", "203.0.113.1")]).contains("destination_probes"));
+    }
+
+    #[test]
+    fn test_zero_probe_count_rejected() {
+        assert!(probe_error(|probes| probes.probe_count = 0).contains("probe_count"));
+    }
+
+    #[test]
+    fn test_packet_size_out_of_range_rejected() {
+        assert!(probe_error(|probes| probes.packet_size = 0).contains("packet_size 0"));
+        assert!(probe_error(|probes| probes.packet_size = 65535).contains("packet_size 65535"));
+    }
+
+    #[test]
+    fn test_probe_dscp_out_of_range_rejected() {
+        assert!(probe_error(|probes| probes.probe_dscp = Some(64)).contains("probe_dscp 64"));
+        let mut config = Config::default();
+        config.probes.probe_dscp = Some(46);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_zero_timeouts_rejected() {
+        assert!(probe_error(|probes| probes.icmp_timeout = 0).contains("icmp_timeout"));
+        assert!(probe_error(|probes| probes.udp_timeout = 0).contains("udp_timeout"));
+        assert!(probe_error(|probes| probes.bandwidth_test_duration = 0).contains("bandwidth_test_duration"));
+    }
+
+    #[test]
+    fn test_webhook_endpoint() {
+        let webhook = |url: &str| WebhookConfig {
+            url: url.to_string(),
+            timeout_ms: default_webhook_timeout(),
+            max_retries: default_webhook_max_retries(),
+            retry_backoff_ms: default_webhook_retry_backoff(),
+        };
+        assert_eq!(webhook("http://10.0.0.5:8080/hooks/links").endpoint().unwrap(), ("10.0.0.5:8080".to_string(), "/hooks/links".to_string()));
+        assert_eq!(webhook("http://alerts.example.com").endpoint().unwrap(), ("alerts.example.com:80".to_string(), "/".to_string()));
+        assert_eq!(webhook("http://[2001:db8::1]/x").endpoint().unwrap(), ("[2001:db8::1]:80".to_string(), "/x".to_string()));
+        assert!(webhook("https://alerts.example.com/").validate().is_err());
+        assert!(webhook("http:///path").validate().is_err());
+
+        let config: Config = serde_yaml::from_str("interfaces: []\nwebhook:\n  url: http://127.0.0.1:9000/\n  retry_backoff_ms: 2s\n").unwrap();
+        let webhook = config.webhook.unwrap();
+        assert_eq!((webhook.timeout_ms, webhook.max_retries, webhook.retry_backoff_ms), (5000, 5, 2000));
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,219 @@
+use crate::config::DiscoveryConfig;
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Largest announcement we expect; anything longer is truncated and ignored.
+const MAX_ANNOUNCEMENT_SIZE: usize = 512;
+
+/// What a manager multicasts about itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Announcement {
+    node_id: String,
+    grpc_port: u16,
+}
+
+/// A manager heard on the discovery group.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub node_id: String,
+    /// The announcement's source address with the gRPC port it advertised.
+    pub endpoint: SocketAddr,
+    pub last_seen: Instant,
+}
+
+/// Announces this manager on a link-local multicast group and keeps the list
+/// of peers heard there, which the scheduler uses as tunnel endpoints.
+pub struct Discovery {
+    node_id: String,
+    grpc_port: u16,
+    group: SocketAddrV4,
+    interface: Ipv4Addr,
+    announce_interval: Duration,
+    peer_timeout: Duration,
+    peers: DashMap<String, Peer>,
+}
+
+impl Discovery {
+    pub fn new(config: &DiscoveryConfig, grpc_port: u16) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            node_id: config.node_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            grpc_port,
+            group: config.group_addr()?,
+            interface: config.interface_addr()?,
+            announce_interval: Duration::from_millis(config.announce_interval_ms),
+            peer_timeout: Duration::from_millis(config.peer_timeout_ms),
+            peers: DashMap::new(),
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Peers heard within the peer timeout, sorted by node ID.
+    pub fn peers(&self) -> Vec<Peer> {
+        let now = Instant::now();
+        let mut peers: Vec<Peer> = self.peers.iter()
+            .filter(|peer| now.duration_since(peer.last_seen) < self.peer_timeout)
+            .map(|peer| peer.value().clone())
+            .collect();
+        peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        peers
+    }
+
+    /// Announces every `announce_interval_ms` and records the peers heard in
+    /// between. Runs until dropped or the socket fails.
+    pub async fn run(&self) -> Result<()> {
+        let socket = self.bind()?;
+        info!("Discovery announcing {} on {}", self.node_id, self.group);
+
+        let mut announce = tokio::time::interval(self.announce_interval);
+        let mut buf = [0u8; MAX_ANNOUNCEMENT_SIZE];
+        loop {
+            tokio::select! {
+                _ = announce.tick() => {
+                    self.announce(&socket).await?;
+                    self.expire(Instant::now());
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let (len, source) = received?;
+                    self.handle(&buf[..len], source, Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Joins the group with a socket shared with any other manager on this
+    /// host. Announcements are looped back so those managers hear them too,
+    /// and never leave the local link.
+    fn bind(&self) -> Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+
+        let local = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.group.port()));
+        socket.bind(&SockAddr::from(local))
+            .with_context(|| format!("Failed to bind discovery socket to {}", local))?;
+        socket.join_multicast_v4(self.group.ip(), &self.interface)
+            .with_context(|| format!("Failed to join discovery group {} on {}", self.group.ip(), self.interface))?;
+        if !self.interface.is_unspecified() {
+            socket.set_multicast_if_v4(&self.interface)?;
+        }
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(1)?;
+
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    async fn announce(&self, socket: &UdpSocket) -> Result<()> {
+        let announcement = serde_json::to_vec(&Announcement {
+            node_id: self.node_id.clone(),
+            grpc_port: self.grpc_port,
+        })?;
+        socket.send_to(&announcement, self.group).await
+            .with_context(|| format!("Failed to announce to {}", self.group))?;
+        Ok(())
+    }
+
+    /// Records the announcement in `datagram`, ignoring our own and anything
+    /// that doesn't parse. Returns whether it came from a new peer.
+    fn handle(&self, datagram: &[u8], source: SocketAddr, now: Instant) -> bool {
+        let announcement: Announcement = match serde_json::from_slice(datagram) {
+            Ok(announcement) => announcement,
+            Err(e) => {
+                debug!("Ignoring malformed announcement from {}: {}", source, e);
+                return false;
+            }
+        };
+        if announcement.node_id == self.node_id {
+            return false;
+        }
+
+        let endpoint = SocketAddr::new(source.ip(), announcement.grpc_port);
+        let previous = self.peers.insert(announcement.node_id.clone(), Peer {
+            node_id: announcement.node_id.clone(),
+            endpoint,
+            last_seen: now,
+        });
+        if previous.is_none() {
+            info!("Discovered peer {} at {}", announcement.node_id, endpoint);
+        }
+        previous.is_none()
+    }
+
+    fn expire(&self, now: Instant) {
+        self.peers.retain(|node_id, peer| {
+            let alive = now.duration_since(peer.last_seen) < self.peer_timeout;
+            if !alive {
+                info!("Peer {} at {} timed out", node_id, peer.endpoint);
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn discovery(node_id: &str, grpc_port: u16) -> Arc<Discovery> {
+        let config = DiscoveryConfig {
+            group: "224.0.0.190:47191".to_string(),
+            interface_address: Some("127.0.0.1".to_string()),
+            node_id: Some(node_id.to_string()),
+            announce_interval_ms: 50,
+            peer_timeout_ms: 1000,
+        };
+        Arc::new(Discovery::new(&config, grpc_port).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_two_managers_discover_each_other() {
+        let a = discovery("node-a", 9101);
+        let b = discovery("node-b", 9102);
+        let tasks: Vec<_> = [a.clone(), b.clone()].into_iter()
+            .map(|discovery| tokio::spawn(async move { discovery.run().await }))
+            .collect();
+
+        let discovered = tokio::time::timeout(Duration::from_secs(5), async {
+            while a.peers().is_empty() || b.peers().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }).await;
+        tasks.iter().for_each(|task| task.abort());
+        discovered.expect("managers did not discover each other");
+
+        let (a_peers, b_peers) = (a.peers(), b.peers());
+        assert_eq!(a_peers.len(), 1);
+        assert_eq!(a_peers[0].node_id, "node-b");
+        assert_eq!(a_peers[0].endpoint, "127.0.0.1:9102".parse().unwrap());
+        assert_eq!(b_peers.len(), 1);
+        assert_eq!(b_peers[0].node_id, "node-a");
+        assert_eq!(b_peers[0].endpoint, "127.0.0.1:9101".parse().unwrap());
+    }
+
+    #[test]
+    fn test_peers_time_out() {
+        let discovery = discovery("node-a", 9101);
+        let source = "127.0.0.1:47191".parse().unwrap();
+        let heard = Instant::now();
+        assert!(discovery.handle(br#"{"node_id":"node-b","grpc_port":9102}"#, source, heard));
+        assert!(!discovery.handle(br#"{"node_id":"node-a","grpc_port":9101}"#, source, heard));
+        assert!(!discovery.handle(b"not json", source, heard));
+
+        discovery.expire(heard + Duration::from_millis(500));
+        assert_eq!(discovery.peers.len(), 1);
+        discovery.expire(heard + Duration::from_millis(1000));
+        assert!(discovery.peers.is_empty());
+    }
+}
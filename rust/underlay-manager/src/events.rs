@@ -0,0 +1,83 @@
+use crate::LinkMetrics;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// A link crossing the healthy threshold, with the metrics that moved it.
+#[derive(Debug, Clone)]
+pub struct LinkEvent {
+    pub link_name: String,
+    pub healthy: bool,
+    pub metrics: LinkMetrics,
+    /// When the transition was seen.
+    pub at: DateTime<Utc>,
+}
+
+/// Fans link health changes out to every interested task.
+pub type EventBus = sdwan_common::event_bus::EventBus<LinkEvent>;
+
+/// Remembers whether each link was last seen healthy, turning a stream of
+/// metrics snapshots into health transitions.
+pub struct HealthTracker {
+    threshold: f64,
+    healthy: HashMap<String, bool>,
+}
+
+impl HealthTracker {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            healthy: HashMap::new(),
+        }
+    }
+
+    /// Events for the links whose health changed since the last snapshot.
+    /// A link's first sample only sets its starting state. Events are
+    /// stamped `now`.
+    pub fn observe(&mut self, metrics: &HashMap<String, LinkMetrics>, now: DateTime<Utc>) -> Vec<LinkEvent> {
+        let mut events = Vec::new();
+        for (link_name, link_metrics) in metrics {
+            let healthy = link_metrics.is_healthy(self.threshold);
+            if self.healthy.insert(link_name.clone(), healthy).is_some_and(|previous| previous != healthy) {
+                events.push(LinkEvent {
+                    link_name: link_name.clone(),
+                    healthy,
+                    metrics: link_metrics.clone(),
+                    at: now,
+                });
+            }
+        }
+        events.sort_by(|a, b| a.link_name.cmp(&b.link_name));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(links: &[(&str, bool)]) -> HashMap<String, LinkMetrics> {
+        links.iter()
+            .map(|(name, carrier_up)| {
+                let mut metrics = LinkMetrics::new();
+                metrics.latency_ms = 10.0;
+                metrics.bandwidth_mbps = 100.0;
+                metrics.carrier_up = *carrier_up;
+                (name.to_string(), metrics)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_only_transitions_are_reported() {
+        let mut tracker = HealthTracker::new(0.3);
+        let now = Utc::now();
+        assert!(tracker.observe(&snapshot(&[("eth0", true), ("eth1", false)]), now).is_empty());
+        assert!(tracker.observe(&snapshot(&[("eth0", true), ("eth1", false)]), now).is_empty());
+
+        let events = tracker.observe(&snapshot(&[("eth0", false), ("eth1", true)]), now);
+        let changes: Vec<(&str, bool)> = events.iter().map(|event| (event.link_name.as_str(), event.healthy)).collect();
+        assert_eq!(changes, vec![("eth0", false), ("eth1", true)]);
+        assert!(!events[0].metrics.carrier_up);
+        assert_eq!(events[0].at, now);
+    }
+} 
\ No newline at end of file
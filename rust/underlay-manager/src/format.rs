@@ -0,0 +1,87 @@
+use crate::metrics::MetricsSnapshot;
+use anyhow::Result;
+use clap::ValueEnum;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+    Table,
+}
+
+pub fn format_snapshot(snapshot: &MetricsSnapshot, format: OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(snapshot)?),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(snapshot)?),
+        OutputFormat::Table => Ok(format_table(snapshot)),
+    }
+}
+
+fn format_table(snapshot: &MetricsSnapshot) -> String {
+    let mut names: Vec<&String> = snapshot.link_metrics.keys().collect();
+    names.sort();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<16} {:>12} {:>12} {:>8} {:>16}",
+        "INTERFACE", "LATENCY(ms)", "JITTER(ms)", "LOSS(%)", "BANDWIDTH(Mbps)"
+    );
+    for name in names {
+        let m = &snapshot.link_metrics[name];
+        let _ = writeln!(
+            out,
+            "{:<16} {:>12.2} {:>12.2} {:>8.2} {:>16.2}",
+            name,
+            m.latency_ms,
+            m.jitter_ms,
+            m.packet_loss * 100.0,
+            m.bandwidth_mbps
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LinkMetrics;
+
+    fn snapshot() -> MetricsSnapshot {
+        let mut snapshot = MetricsSnapshot::new();
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 12.5;
+        metrics.jitter_ms = 1.25;
+        metrics.packet_loss = 0.02;
+        metrics.bandwidth_mbps = 95.0;
+        snapshot.link_metrics.insert("eth0".to_string(), metrics);
+        snapshot
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let json = format_snapshot(&snapshot(), OutputFormat::Json).unwrap();
+        let parsed: MetricsSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.link_metrics["eth0"].latency_ms, 12.5);
+        assert_eq!(parsed.link_metrics["eth0"].bandwidth_mbps, 95.0);
+    }
+
+    #[test]
+    fn test_yaml_round_trip() {
+        let yaml = format_snapshot(&snapshot(), OutputFormat::Yaml).unwrap();
+        let parsed: MetricsSnapshot = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(parsed.link_metrics["eth0"].jitter_ms, 1.25);
+        assert_eq!(parsed.link_metrics["eth0"].packet_loss, 0.02);
+    }
+
+    #[test]
+    fn test_table_renders_fields() {
+        let table = format_snapshot(&snapshot(), OutputFormat::Table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("INTERFACE"));
+        let fields: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(fields, vec!["eth0", "12.50", "1.25", "2.00", "95.00"]);
+    }
+}
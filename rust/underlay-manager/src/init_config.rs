@@ -0,0 +1,80 @@
+use crate::config::Config;
+use anyhow::Result;
+use sdwan_common::init_config::{render_annotated, FieldDocs};
+
+const FIELD_DOCS: &FieldDocs = &[
+    ("interfaces", "underlay interfaces to probe"),
+    ("interfaces.name", "interface name, e.g. eth0"),
+    ("interfaces.enabled", "probe this interface"),
+    ("interfaces.probe_interval", "ms between probe cycles"),
+    ("interfaces.liveness_interval", "optional, ms between single latency checks that catch hard failures between probe cycles"),
+    ("interfaces.icmp_enabled", "measure latency and loss with ICMP echo"),
+    ("interfaces.udp_enabled", "measure jitter with UDP probes"),
+    ("interfaces.bandwidth_test_enabled", "run periodic bandwidth tests"),
+    ("interfaces.source_address", "optional, local address probes bind to on multi-homed hosts"),
+    ("interfaces.vlan_id", "optional, 802.1Q VLAN; probes use the <name>.<vlan_id> subinterface"),
+    ("interfaces.vrf", "optional, Linux VRF device probe sockets bind to (needs CAP_NET_RAW)"),
+    ("probes", "probe parameters shared by all interfaces"),
+    ("probes.icmp_timeout", "ms to wait for an ICMP echo reply"),
+    ("probes.udp_timeout", "ms to wait for a UDP probe reply"),
+    ("probes.bandwidth_test_duration", "ms each bandwidth test runs"),
+    ("probes.packet_size", "probe payload bytes, 64 to 9000"),
+    ("probes.probe_count", "probes sent per measurement"),
+    ("probes.payload_pattern", "zeros, random or incrementing"),
+    ("probes.idle_probe_multiplier", "interfaces the scheduler isn't using are probed this many times less often"),
+    ("probes.loss_alpha", "weight in (0, 1] of the newest probe batch in the smoothed packet loss; 1 disables smoothing"),
+    ("probes.reliability_window", "probe cycles the reliability ratio covers"),
+    ("probes.healthy_threshold", "minimum health score for a cycle to count as up"),
+    ("probes.max_concurrent_probes", "interfaces probed at once"),
+    ("probes.probe_retries", "extra attempts before falling back to last-known values"),
+    ("probes.retry_backoff_ms", "ms before the first retry, doubling for each further retry"),
+    ("probes.max_retry_backoff_ms", "longest ms between retries"),
+    ("probes.circuit_breaker_threshold", "failed probe cycles in a row before an interface is probed less often; 0 disables"),
+    ("probes.circuit_breaker_max_multiplier", "most a failing interface's probe interval is stretched by"),
+    ("probes.probe_dscp", "optional, DSCP codepoint (0-63) marked on probe packets, e.g. 46 for EF"),
+    ("probes.tcp_probe_target", "optional, host:port whose TCP connect time measures latency when ICMP isn't permitted"),
+    ("probes.probe_targets", "host:port targets whose TCP connect times measure latency instead of ICMP"),
+    ("probes.target_failure_policy", "all: a probe fails only when every target does; any: when one does"),
+    ("probes.race_probes", "run ICMP, UDP and TCP connect probes at once; the first success proves the link alive"),
+    ("probes.bandwidth_reflector", "optional, host:port of a bandwidth reflector measuring upload and download separately"),
+    ("probes.udp_probe_target", "optional, host:port of a UDP echo responder (e.g. a reflector) for jitter and loss probes"),
+    ("probes.reflector_psk", "optional, 64 hex characters shared with the reflector's --psk-file; required with bandwidth_reflector"),
+    ("probes.destination_probes", "list of {prefix, target}: latency to the host:port target via each link stands for the CIDR prefix"),
+    ("server", "gRPC metrics server"),
+    ("server.grpc_port", "port the metrics service listens on"),
+    ("server.metrics_interval", "ms between metrics updates pushed to clients"),
+    ("server.max_connections", "concurrent client connections"),
+    ("server.metrics_diff_threshold", "minimum change before an interface appears in a metrics diff"),
+    ("server.min_healthy_links", "optional, alert when fewer links than this are healthy"),
+    ("discovery", "optional, link-local multicast peer discovery: group, interface_address, node_id, announce_interval_ms, peer_timeout_ms"),
+    ("webhook", "optional, POST link health changes as JSON: url, timeout_ms, max_retries, retry_backoff_ms"),
+];
+
+/// Renders `Config::default()` as YAML with a comment on every field, as a
+/// starting point for a new configuration file.
+pub fn starter_config() -> Result<String> {
+    render_annotated(&Config::default(), "Underlay manager configuration", FIELD_DOCS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdwan_common::init_config::undocumented_fields;
+
+    #[test]
+    fn test_starter_config_parses_back() {
+        let yaml = starter_config().unwrap();
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        config.validate().unwrap();
+        assert_eq!(serde_yaml::to_value(&config).unwrap(), serde_yaml::to_value(Config::default()).unwrap());
+
+        assert!(yaml.contains("  - name: eth0  # interface name"));
+        assert!(yaml.contains("    enabled: true  # probe this interface"));
+    }
+
+    #[test]
+    fn test_every_field_is_documented() {
+        let missing = undocumented_fields(&Config::default(), FIELD_DOCS).unwrap();
+        assert!(missing.is_empty(), "undocumented fields: {:?}", missing);
+    }
+}
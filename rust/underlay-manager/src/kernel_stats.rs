@@ -0,0 +1,106 @@
+use crate::LinkMetrics;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+const SYSFS_NET: &str = "/sys/class/net";
+
+/// Passive interface state as the kernel reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelStats {
+    /// Negotiated link rate; `None` when the driver doesn't report one
+    /// (virtual interfaces, or no link).
+    pub speed_mbps: Option<u64>,
+    pub carrier_up: bool,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+impl KernelStats {
+    pub fn apply(&self, metrics: &mut LinkMetrics) {
+        metrics.link_speed_mbps = self.speed_mbps;
+        metrics.carrier_up = self.carrier_up;
+        metrics.rx_dropped = self.rx_dropped;
+        metrics.tx_dropped = self.tx_dropped;
+    }
+}
+
+/// Reads interface speed, carrier and drop counters from sysfs, the same
+/// values ethtool and netlink report, without needing extra privileges.
+pub struct KernelStatsCollector {
+    root: PathBuf,
+}
+
+impl KernelStatsCollector {
+    pub fn new() -> Self {
+        Self::with_root(SYSFS_NET)
+    }
+
+    /// Reads from `root` instead of `/sys/class/net`.
+    pub fn with_root<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn read(&self, device: &str) -> Result<KernelStats> {
+        let dir = self.root.join(device);
+        if !dir.is_dir() {
+            return Err(anyhow::anyhow!("Interface {} not found in {}", device, self.root.display()));
+        }
+        let attribute = |name: &str| fs::read_to_string(dir.join(name)).map(|value| value.trim().to_string());
+        let counter = |name: &str| -> Result<u64> {
+            let value = attribute(name).with_context(|| format!("Failed to read {} of {}", name, device))?;
+            value.parse().with_context(|| format!("Invalid {} {:?} for {}", name, value, device))
+        };
+
+        Ok(KernelStats {
+            // Unreadable (EINVAL while down) or -1 when unknown
+            speed_mbps: attribute("speed").ok().and_then(|speed| speed.parse().ok()),
+            // Reading carrier fails while the interface is administratively down
+            carrier_up: attribute("carrier").is_ok_and(|carrier| carrier == "1"),
+            rx_dropped: counter("statistics/rx_dropped")?,
+            tx_dropped: counter("statistics/tx_dropped")?,
+        })
+    }
+}
+
+impl Default for KernelStatsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reads_loopback_stats() {
+        let stats = KernelStatsCollector::new().read("lo").unwrap();
+        assert!(stats.carrier_up);
+        // Loopback has no negotiated rate
+        assert_eq!(stats.speed_mbps, None);
+    }
+
+    #[test]
+    fn test_down_carrier_marks_link_unhealthy() {
+        let root = std::env::temp_dir().join(format!("kernel-stats-{}", std::process::id()));
+        let dir = root.join("wan0");
+        fs::create_dir_all(dir.join("statistics")).unwrap();
+        for (name, value) in [("speed", "1000"), ("carrier", "0"), ("statistics/rx_dropped", "3"), ("statistics/tx_dropped", "7")] {
+            fs::write(dir.join(name), format!("{}\n", value)).unwrap();
+        }
+
+        let stats = KernelStatsCollector::with_root(&root).read("wan0").unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(stats, KernelStats { speed_mbps: Some(1000), carrier_up: false, rx_dropped: 3, tx_dropped: 7 });
+
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 5.0;
+        metrics.bandwidth_mbps = 500.0;
+        assert!(metrics.is_healthy(0.3));
+        stats.apply(&mut metrics);
+        assert!(!metrics.is_healthy(0.3));
+        assert!(KernelStatsCollector::with_root(&root).read("wan0").is_err());
+    }
+}
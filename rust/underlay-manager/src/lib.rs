@@ -1,9 +1,25 @@
 pub mod config;
+pub mod discovery;
+pub mod events;
+pub mod format;
+pub mod init_config;
+pub mod kernel_stats;
 pub mod server;
 pub mod probe;
+pub mod reflector;
 pub mod metrics;
+pub mod preflight;
 pub mod proto;
+pub mod schedule;
+pub mod socket;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+#[cfg(feature = "snmp")]
+pub mod snmp;
+pub mod tcp_probe;
+pub mod webhook;
 
+pub use sdwan_common::{clock, log_limit, runtime, supervisor, units};
 pub use config::Config;
 pub use server::UnderlayManagerServer;
 pub use probe::NetworkProbe;
@@ -1,6 +1,13 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use underlay_manager::format::{format_snapshot, OutputFormat};
+use underlay_manager::init_config::starter_config;
+use underlay_manager::metrics::MetricsSnapshot;
+use underlay_manager::preflight;
+use underlay_manager::reflector::{serve_reflector, serve_udp_echo, ReflectorKey};
+use underlay_manager::runtime::{build_runtime, RuntimeFlavor};
 use underlay_manager::server::UnderlayManagerServer;
 use underlay_manager::config::Config;
+use underlay_manager::NetworkProbe;
 use tracing::{info, error};
 
 #[derive(Parser)]
@@ -14,19 +21,95 @@ struct Args {
     #[arg(short, long, default_value = "info")]
     log_level: String,
 
+    /// Tokio runtime flavor; current-thread has the smallest footprint
+    #[arg(long, value_enum, default_value = "multi-thread")]
+    runtime: RuntimeFlavor,
+
+    /// Worker threads of the multi-threaded runtime [default: one per core];
+    /// rejected with --runtime current-thread
+    #[arg(long)]
+    worker_threads: Option<usize>,
+
     /// gRPC server port
     #[arg(long, default_value = "9093")]
     port: u16,
+
+    /// Also push metrics as StatsD gauges and timers to this host:port
+    #[cfg(feature = "statsd")]
+    #[arg(long)]
+    statsd: Option<String>,
+
+    /// Also answer SNMP requests for the metrics on this address
+    #[cfg(feature = "snmp")]
+    #[arg(long)]
+    snmp: Option<String>,
+
+    /// SNMP community clients must present
+    #[cfg(feature = "snmp")]
+    #[arg(long, default_value = "public")]
+    snmp_community: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Probe all interfaces once and print the metrics
+    DumpMetrics {
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Probe every configured interface once and exit non-zero if any
+    /// enabled interface is unreachable
+    Preflight,
+    /// Print a commented starter configuration with the default settings
+    InitConfig,
+    /// Answer other managers' bandwidth tests, so they can measure upload
+    /// and download separately, and echo their UDP probes
+    Reflector {
+        /// Address to accept bandwidth tests (TCP) and UDP probes on. Give
+        /// a routable address to serve other hosts
+        #[arg(long, default_value = "127.0.0.1:47191")]
+        listen: String,
+        /// File holding the key shared with managers' probes.reflector_psk,
+        /// as 64 hex characters; requests not signed with it are refused
+        #[arg(long)]
+        psk_file: String,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    build_runtime(args.runtime, args.worker_threads)?.block_on(run(args))
+}
 
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_env_filter(&args.log_level)
-        .init();
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+
+    // Needs no configuration file, so runs before one is loaded
+    if let Some(Command::InitConfig) = args.command {
+        print!("{}", starter_config()?);
+        return Ok(());
+    }
+
+    // Initialize logging; subcommands keep stdout for their own output
+    let subscriber = tracing_subscriber::fmt().with_env_filter(&args.log_level);
+    if args.command.is_some() {
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
+
+    // Needs no configuration file either
+    if let Some(Command::Reflector { ref listen, ref psk_file }) = args.command {
+        let key = ReflectorKey::from_hex(std::fs::read_to_string(psk_file)?.trim())?;
+        let listener = tokio::net::TcpListener::bind(listen).await?;
+        let echo = tokio::net::UdpSocket::bind(listen).await?;
+        info!("Bandwidth reflector listening on {}", listen);
+        tokio::try_join!(serve_reflector(listener, key.clone()), serve_udp_echo(echo, key))?;
+        return Ok(());
+    }
 
     info!("Starting SD-WAN Underlay Manager");
 
@@ -34,8 +117,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::from_file(&args.config)?;
     info!("Loaded configuration from {}", args.config);
 
+    match args.command {
+        Some(Command::DumpMetrics { format }) => {
+            let probe = NetworkProbe::new(config);
+            let mut snapshot = MetricsSnapshot::new();
+            snapshot.link_metrics = probe.probe_all_interfaces().await?;
+            print!("{}", format_snapshot(&snapshot, format)?);
+            return Ok(());
+        }
+        Some(Command::Preflight) => {
+            if let Err(e) = config.probes.resolve_targets() {
+                error!("Preflight failed: {}", e);
+                std::process::exit(1);
+            }
+            let probe = NetworkProbe::new(config.clone());
+            let results = preflight::run_preflight(&probe, &config).await;
+            print!("{}", preflight::render_report(&results));
+            std::process::exit(preflight::exit_code(&results));
+        }
+        Some(Command::InitConfig) | Some(Command::Reflector { .. }) | None => {}
+    }
+
     // Create and start the gRPC server
     let server = UnderlayManagerServer::new(config);
+    #[cfg(feature = "statsd")]
+    let server = {
+        let mut server = server;
+        if let Some(ref target) = args.statsd {
+            server.set_statsd_exporter(underlay_manager::statsd::StatsdExporter::connect(target)?);
+            info!("Pushing metrics to StatsD at {}", target);
+        }
+        server
+    };
+    #[cfg(feature = "snmp")]
+    let server = {
+        let mut server = server;
+        if let Some(ref listen) = args.snmp {
+            server.set_snmp_exporter(underlay_manager::snmp::SnmpExporter::bind(listen, &args.snmp_community).await?);
+            info!("Serving metrics over SNMP on {}", listen);
+        }
+        server
+    };
     info!("Underlay manager server initialized on port {}", args.port);
 
     // Start the server
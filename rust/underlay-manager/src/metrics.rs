@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LinkMetrics {
@@ -7,46 +8,368 @@ pub struct LinkMetrics {
     pub jitter_ms: f64,
     pub packet_loss: f64,
     pub bandwidth_mbps: f64,
+    /// Throughput in each direction, when measured against a reflector.
+    /// Many WAN links are asymmetric, which `bandwidth_mbps` alone hides.
+    #[serde(default)]
+    pub bandwidth_up_mbps: Option<f64>,
+    #[serde(default)]
+    pub bandwidth_down_mbps: Option<f64>,
     pub timestamp: DateTime<Utc>,
+    /// Fraction of recent probe cycles in which the link was healthy.
+    #[serde(default = "default_reliability")]
+    pub reliability: f64,
+    /// How much latency rises while the link is saturated by the bandwidth
+    /// test. Large values make a link a poor fit for interactive traffic.
+    #[serde(default)]
+    pub bufferbloat_ms: f64,
+    /// Negotiated link rate reported by the kernel, if known.
+    #[serde(default)]
+    pub link_speed_mbps: Option<u64>,
+    /// Whether the kernel sees a carrier. A link without one is unhealthy
+    /// whatever its last probe measured.
+    #[serde(default = "default_carrier_up")]
+    pub carrier_up: bool,
+    /// Kernel drop counters since the interface came up.
+    #[serde(default)]
+    pub rx_dropped: u64,
+    #[serde(default)]
+    pub tx_dropped: u64,
+    /// Set when every latency probe failed and these are the link's
+    /// last-known measurements, still stamped with when they were taken.
+    #[serde(default)]
+    pub stale: bool,
+    /// Cleared when no bandwidth test ran for the link, so a zero
+    /// `bandwidth_mbps` means "unknown" rather than "no capacity".
+    #[serde(default = "default_bandwidth_measured")]
+    pub bandwidth_measured: bool,
+}
+
+fn default_reliability() -> f64 {
+    1.0
+}
+
+fn default_carrier_up() -> bool {
+    true
+}
+
+fn default_bandwidth_measured() -> bool {
+    true
 }
 
 impl LinkMetrics {
+    /// Empty metrics stamped with the system clock.
     pub fn new() -> Self {
+        Self::at(Utc::now())
+    }
+
+    /// Empty metrics stamped `timestamp`, for callers holding a `Clock`.
+    pub fn at(timestamp: DateTime<Utc>) -> Self {
         Self {
             latency_ms: 0.0,
             jitter_ms: 0.0,
             packet_loss: 0.0,
             bandwidth_mbps: 0.0,
-            timestamp: Utc::now(),
+            bandwidth_up_mbps: None,
+            bandwidth_down_mbps: None,
+            timestamp,
+            reliability: default_reliability(),
+            bufferbloat_ms: 0.0,
+            link_speed_mbps: None,
+            carrier_up: default_carrier_up(),
+            rx_dropped: 0,
+            tx_dropped: 0,
+            stale: false,
+            bandwidth_measured: default_bandwidth_measured(),
         }
     }
     
     pub fn health_score(&self) -> f64 {
+        if !self.carrier_up {
+            return 0.0;
+        }
+        
         let latency_score = 1.0 / (1.0 + self.latency_ms);
-        let bandwidth_score = (self.bandwidth_mbps / 1000.0).min(1.0);
+        // Unmeasured bandwidth scores halfway rather than as no capacity
+        let bandwidth_score = if self.bandwidth_measured {
+            (self.bandwidth_mbps / 1000.0).min(1.0)
+        } else {
+            0.5
+        };
         let loss_score = 1.0 - self.packet_loss;
         
-        (latency_score + bandwidth_score + loss_score) / 3.0
+        // A link that keeps flapping scores lower than a steady one with the
+        // same instantaneous measurements
+        (latency_score + bandwidth_score + loss_score) / 3.0 * self.reliability
     }
     
+    /// Stale metrics are never healthy: nothing on the link answered.
     pub fn is_healthy(&self, threshold: f64) -> bool {
-        self.health_score() >= threshold
+        !self.stale && self.health_score() >= threshold
+    }
+    
+    /// Combines another measurement of the same link, e.g. an on-demand probe
+    /// or a second probe target. Keeps the best latency seen and the worst
+    /// jitter, loss, reliability and bufferbloat, so a problem spotted by
+    /// either source isn't hidden, and a lost carrier from either; bandwidth
+    /// and kernel counters come from the newer sample.
+    pub fn merge(&mut self, other: &LinkMetrics) {
+        if other.timestamp > self.timestamp {
+            self.bandwidth_mbps = other.bandwidth_mbps;
+            self.bandwidth_up_mbps = other.bandwidth_up_mbps;
+            self.bandwidth_down_mbps = other.bandwidth_down_mbps;
+            self.bandwidth_measured = other.bandwidth_measured;
+            self.timestamp = other.timestamp;
+            self.link_speed_mbps = other.link_speed_mbps;
+            self.rx_dropped = other.rx_dropped;
+            self.tx_dropped = other.tx_dropped;
+        }
+        self.latency_ms = self.latency_ms.min(other.latency_ms);
+        self.jitter_ms = self.jitter_ms.max(other.jitter_ms);
+        self.packet_loss = self.packet_loss.max(other.packet_loss);
+        self.reliability = self.reliability.min(other.reliability);
+        self.bufferbloat_ms = self.bufferbloat_ms.max(other.bufferbloat_ms);
+        self.carrier_up = self.carrier_up && other.carrier_up;
+    }
+    
+    /// Whether any measurement moved by more than `threshold`: relative
+    /// change for latency, jitter and bandwidth, absolute change for the
+    /// loss and reliability fractions.
+    pub fn changed_beyond(&self, other: &LinkMetrics, threshold: f64) -> bool {
+        let relative = |a: f64, b: f64| (a - b).abs() / a.abs().max(b.abs()).max(f64::EPSILON);
+        
+        self.carrier_up != other.carrier_up
+            || self.bandwidth_measured != other.bandwidth_measured
+            || relative(self.latency_ms, other.latency_ms) > threshold
+            || relative(self.jitter_ms, other.jitter_ms) > threshold
+            || relative(self.bandwidth_mbps, other.bandwidth_mbps) > threshold
+            || (self.packet_loss - other.packet_loss).abs() > threshold
+            || (self.reliability - other.reliability).abs() > threshold
+    }
+}
+
+impl Default for LinkMetrics {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetricsSnapshot {
-    pub link_metrics: std::collections::HashMap<String, LinkMetrics>,
+    pub link_metrics: HashMap<String, LinkMetrics>,
     pub timestamp: DateTime<Utc>,
 }
 
 impl MetricsSnapshot {
+    /// An empty snapshot stamped with the system clock.
     pub fn new() -> Self {
+        Self::at(Utc::now())
+    }
+
+    /// An empty snapshot stamped `timestamp`, for callers holding a `Clock`.
+    pub fn at(timestamp: DateTime<Utc>) -> Self {
+        Self {
+            link_metrics: HashMap::new(),
+            timestamp,
+        }
+    }
+}
+
+impl Default for MetricsSnapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rolling record of whether each link was healthy over its last `window`
+/// probe cycles.
+pub struct ReliabilityTracker {
+    window: usize,
+    history: HashMap<String, VecDeque<bool>>,
+}
+
+impl ReliabilityTracker {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            history: HashMap::new(),
+        }
+    }
+
+    /// Records one probe cycle and returns the link's updated uptime ratio.
+    pub fn record(&mut self, link_name: &str, healthy: bool) -> f64 {
+        let history = self.history.entry(link_name.to_string()).or_default();
+        if history.len() == self.window {
+            history.pop_front();
+        }
+        history.push_back(healthy);
+        self.reliability(link_name)
+    }
+
+    /// Uptime ratio for a link; links with no history are assumed reliable.
+    pub fn reliability(&self, link_name: &str) -> f64 {
+        match self.history.get(link_name) {
+            Some(history) if !history.is_empty() => {
+                history.iter().filter(|healthy| **healthy).count() as f64 / history.len() as f64
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+/// Packet loss smoothed per link with an exponentially weighted moving
+/// average over probe batches, so one bad batch of a few probes doesn't make
+/// a link look lossy.
+pub struct LossEstimator {
+    /// Weight of the newest batch, in (0, 1]; 1 keeps only the latest.
+    alpha: f64,
+    estimates: HashMap<String, f64>,
+}
+
+impl LossEstimator {
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha: alpha.clamp(f64::EPSILON, 1.0),
+            estimates: HashMap::new(),
+        }
+    }
+
+    /// Folds one batch's loss ratio into the link's estimate and returns it.
+    /// A link's first batch is taken as-is, and so is a batch that lost
+    /// everything: a dead link must read as down at once, not after the
+    /// average has crept up to it.
+    pub fn record(&mut self, link_name: &str, batch_loss: f64) -> f64 {
+        let alpha = self.alpha;
+        let estimate = self.estimates.entry(link_name.to_string())
+            .and_modify(|estimate| {
+                if batch_loss >= 1.0 {
+                    *estimate = 1.0;
+                } else {
+                    *estimate += alpha * (batch_loss - *estimate);
+                }
+            })
+            .or_insert(batch_loss);
+        *estimate
+    }
+
+    pub fn estimate(&self, link_name: &str) -> Option<f64> {
+        self.estimates.get(link_name).copied()
+    }
+}
+
+/// Change in whether enough links are healthy to keep the site redundant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedundancyAlert {
+    Lost { healthy: usize, required: usize },
+    Restored { healthy: usize, required: usize },
+}
+
+/// Watches the number of healthy links against `min_healthy_links`,
+/// reporting only when it crosses the minimum so an outage alerts once.
+pub struct RedundancyMonitor {
+    min_healthy_links: usize,
+    threshold: f64,
+    lost: bool,
+}
+
+impl RedundancyMonitor {
+    pub fn new(min_healthy_links: usize, threshold: f64) -> Self {
+        Self {
+            min_healthy_links,
+            threshold,
+            lost: false,
+        }
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    pub fn check(&mut self, metrics: &HashMap<String, LinkMetrics>) -> Option<RedundancyAlert> {
+        let healthy = metrics.values().filter(|metrics| metrics.is_healthy(self.threshold)).count();
+        let required = self.min_healthy_links;
+        match (self.lost, healthy < required) {
+            (false, true) => {
+                self.lost = true;
+                Some(RedundancyAlert::Lost { healthy, required })
+            }
+            (true, false) => {
+                self.lost = false;
+                Some(RedundancyAlert::Restored { healthy, required })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Latest metrics per interface, versioned so clients can fetch only the
+/// interfaces that changed since the version they last saw.
+pub struct MetricsCache {
+    version: u64,
+    /// Changes at or below this (see `LinkMetrics::changed_beyond`) don't
+    /// bump an interface's version.
+    threshold: f64,
+    entries: HashMap<String, CachedMetrics>,
+}
+
+struct CachedMetrics {
+    latest: LinkMetrics,
+    /// Value as of `version`. Changes are measured against it rather than
+    /// `latest`, so small drifts still add up to a new version.
+    published: LinkMetrics,
+    version: u64,
+}
+
+impl MetricsCache {
+    pub fn new(threshold: f64) -> Self {
         Self {
-            link_metrics: std::collections::HashMap::new(),
-            timestamp: Utc::now(),
+            version: 0,
+            threshold,
+            entries: HashMap::new(),
         }
     }
+
+    /// Stores new metrics for an interface, bumping the cache version if the
+    /// interface is new or changed beyond the threshold. Returns whether it did.
+    pub fn insert(&mut self, interface_name: String, metrics: LinkMetrics) -> bool {
+        match self.entries.get_mut(&interface_name) {
+            Some(entry) if !metrics.changed_beyond(&entry.published, self.threshold) => {
+                entry.latest = metrics;
+                false
+            }
+            _ => {
+                self.version += 1;
+                self.entries.insert(interface_name, CachedMetrics {
+                    latest: metrics.clone(),
+                    published: metrics,
+                    version: self.version,
+                });
+                true
+            }
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, interface_name: &str) -> Option<&LinkMetrics> {
+        self.entries.get(interface_name).map(|entry| &entry.latest)
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, LinkMetrics> {
+        self.entries.iter()
+            .map(|(name, entry)| (name.clone(), entry.latest.clone()))
+            .collect()
+    }
+
+    /// Interfaces whose metrics changed after `version`, with their latest
+    /// values. Version 0 returns everything.
+    pub fn changed_since(&self, version: u64) -> HashMap<String, LinkMetrics> {
+        self.entries.iter()
+            .filter(|(_, entry)| entry.version > version)
+            .map(|(name, entry)| (name.clone(), entry.latest.clone()))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -73,4 +396,195 @@ mod tests {
         
         assert!(metrics.is_healthy(0.5));
     }
+    
+    #[test]
+    fn test_unmeasured_bandwidth_scores_neutral() {
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 5.0;
+        let zero = metrics.health_score();
+        metrics.bandwidth_measured = false;
+        
+        assert!(metrics.health_score() > zero);
+        metrics.bandwidth_mbps = 1000.0;
+        metrics.bandwidth_measured = true;
+        assert!(metrics.health_score() > zero);
+    }
+    
+    #[test]
+    fn test_reliability_window_rolls() {
+        let mut tracker = ReliabilityTracker::new(4);
+        assert_eq!(tracker.reliability("eth0"), 1.0);
+        
+        for healthy in [false, true, true, true] {
+            tracker.record("eth0", healthy);
+        }
+        assert_eq!(tracker.reliability("eth0"), 0.75);
+        assert_eq!(tracker.record("eth0", true), 1.0);
+    }
+    
+    #[test]
+    fn test_flapping_link_scores_below_stable_link() {
+        let mut tracker = ReliabilityTracker::new(10);
+        for cycle in 0..10 {
+            tracker.record("stable", true);
+            tracker.record("flapping", cycle % 2 == 0);
+        }
+        
+        let mut stable = LinkMetrics::new();
+        stable.latency_ms = 10.0;
+        stable.bandwidth_mbps = 100.0;
+        let mut flapping = stable.clone();
+        stable.reliability = tracker.reliability("stable");
+        flapping.reliability = tracker.reliability("flapping");
+        
+        assert_eq!(flapping.reliability, 0.5);
+        assert!(stable.health_score() > flapping.health_score());
+    }
+    
+    #[test]
+    fn test_cache_only_reports_changed_interfaces() {
+        let mut cache = MetricsCache::new(0.1);
+        let mut eth0 = LinkMetrics::new();
+        eth0.latency_ms = 10.0;
+        eth0.bandwidth_mbps = 100.0;
+        cache.insert("eth0".to_string(), eth0.clone());
+        cache.insert("eth1".to_string(), eth0.clone());
+        let seen = cache.version();
+        assert_eq!(cache.changed_since(0).len(), 2);
+        
+        // Within the threshold: no new version
+        let mut jittered = eth0.clone();
+        jittered.latency_ms = 10.5;
+        assert!(!cache.insert("eth0".to_string(), jittered));
+        assert!(cache.changed_since(seen).is_empty());
+        
+        let mut degraded = eth0.clone();
+        degraded.latency_ms = 40.0;
+        assert!(cache.insert("eth1".to_string(), degraded));
+        let diff = cache.changed_since(seen);
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff["eth1"].latency_ms, 40.0);
+        assert_eq!(cache.snapshot()["eth0"].latency_ms, 10.5);
+    }
+    
+    #[test]
+    fn test_small_drifts_accumulate() {
+        let mut cache = MetricsCache::new(0.1);
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 10.0;
+        cache.insert("eth0".to_string(), metrics.clone());
+        
+        metrics.latency_ms = 10.8;
+        assert!(!cache.insert("eth0".to_string(), metrics.clone()));
+        metrics.latency_ms = 11.6;
+        assert!(cache.insert("eth0".to_string(), metrics));
+    }
+    
+    fn measured(latency_ms: f64, packet_loss: f64, bandwidth_mbps: f64, age_secs: i64) -> LinkMetrics {
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = latency_ms;
+        metrics.jitter_ms = latency_ms / 10.0;
+        metrics.packet_loss = packet_loss;
+        metrics.bandwidth_mbps = bandwidth_mbps;
+        metrics.timestamp = Utc::now() - chrono::Duration::seconds(age_secs);
+        metrics
+    }
+    
+    #[test]
+    fn test_merge_combines_fields() {
+        let mut background = measured(20.0, 0.01, 100.0, 5);
+        let on_demand = measured(12.0, 0.05, 80.0, 0);
+        background.merge(&on_demand);
+        
+        assert_eq!(background.latency_ms, 12.0);
+        assert_eq!(background.jitter_ms, 2.0);
+        assert_eq!(background.packet_loss, 0.05);
+        assert_eq!(background.bandwidth_mbps, 80.0);
+        assert_eq!(background.timestamp, on_demand.timestamp);
+    }
+    
+    #[test]
+    fn test_merge_keeps_newer_bandwidth_and_timestamp() {
+        let mut fresh = measured(15.0, 0.0, 90.0, 0);
+        let timestamp = fresh.timestamp;
+        let mut stale = measured(10.0, 0.0, 200.0, 30);
+        stale.reliability = 0.8;
+        fresh.merge(&stale);
+        
+        assert_eq!(fresh.bandwidth_mbps, 90.0);
+        assert_eq!(fresh.timestamp, timestamp);
+        assert_eq!(fresh.latency_ms, 10.0);
+        assert_eq!(fresh.reliability, 0.8);
+    }
+
+    #[test]
+    fn test_redundancy_alert_fires_below_minimum() {
+        let link = |healthy: bool| {
+            let mut metrics = LinkMetrics::new();
+            metrics.latency_ms = 5.0;
+            metrics.bandwidth_mbps = 500.0;
+            metrics.carrier_up = healthy;
+            metrics
+        };
+        let mut monitor = RedundancyMonitor::new(2, 0.3);
+        let mut links: HashMap<String, LinkMetrics> = ["eth0", "eth1", "eth2"].iter()
+            .map(|name| (name.to_string(), link(true)))
+            .collect();
+        assert_eq!(monitor.check(&links), None);
+
+        // Exactly at the minimum is still redundant
+        links.insert("eth0".to_string(), link(false));
+        assert_eq!(monitor.check(&links), None);
+
+        links.insert("eth1".to_string(), link(false));
+        assert_eq!(monitor.check(&links), Some(RedundancyAlert::Lost { healthy: 1, required: 2 }));
+        assert!(monitor.is_lost());
+        // No repeat while it stays lost
+        assert_eq!(monitor.check(&links), None);
+
+        links.insert("eth1".to_string(), link(true));
+        assert_eq!(monitor.check(&links), Some(RedundancyAlert::Restored { healthy: 2, required: 2 }));
+        assert!(!monitor.is_lost());
+    }
+
+    #[test]
+    fn test_single_bad_batch_moves_loss_estimate_partially() {
+        let mut estimator = LossEstimator::new(0.25);
+        for _ in 0..10 {
+            estimator.record("eth0", 0.0);
+        }
+        // One batch of 10 probes loses 4
+        let estimate = estimator.record("eth0", 0.4);
+        assert!((estimate - 0.1).abs() < 1e-9, "{}", estimate);
+        // and the estimate falls back as clean batches follow
+        assert!(estimator.record("eth0", 0.0) < estimate);
+        
+        // Sustained loss is followed
+        for _ in 0..30 {
+            estimator.record("eth1", 0.4);
+        }
+        assert!((estimator.estimate("eth1").unwrap() - 0.4).abs() < 1e-9);
+        
+        let mut unsmoothed = LossEstimator::new(1.0);
+        unsmoothed.record("eth0", 0.0);
+        assert_eq!(unsmoothed.record("eth0", 0.4), 0.4);
+    }
+
+    #[test]
+    fn test_dead_link_down_within_one_batch() {
+        let mut estimator = LossEstimator::new(0.25);
+        for _ in 0..10 {
+            estimator.record("eth0", 0.0);
+        }
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 5.0;
+        metrics.bandwidth_mbps = 100.0;
+        metrics.packet_loss = estimator.record("eth0", 1.0);
+
+        // Total loss is what the scheduler treats as down
+        assert_eq!(metrics.packet_loss, 1.0);
+        assert!(!metrics.is_healthy(0.1));
+        // and recovery is smoothed as usual
+        assert!(estimator.record("eth0", 0.0) > 0.5);
+    }
 } 
\ No newline at end of file
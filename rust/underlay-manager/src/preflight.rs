@@ -0,0 +1,126 @@
+use crate::{Config, NetworkProbe};
+use std::fmt::Write;
+
+#[derive(Debug, Clone)]
+pub struct PreflightResult {
+    pub interface: String,
+    pub enabled: bool,
+    pub reachable: bool,
+    pub latency_ms: Option<f64>,
+    pub packet_loss: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// Probes every enabled interface once. Disabled interfaces are reported but
+/// not probed.
+pub async fn run_preflight(probe: &NetworkProbe, config: &Config) -> Vec<PreflightResult> {
+    let mut results = Vec::new();
+
+    for interface in &config.interfaces {
+        let mut result = PreflightResult {
+            interface: interface.name.clone(),
+            enabled: interface.enabled,
+            reachable: false,
+            latency_ms: None,
+            packet_loss: None,
+            error: None,
+        };
+
+        if interface.enabled {
+            match probe.probe_interface(&interface.name).await {
+                Ok(metrics) => {
+                    result.reachable = metrics.packet_loss < 1.0;
+                    result.latency_ms = Some(metrics.latency_ms);
+                    result.packet_loss = Some(metrics.packet_loss);
+                }
+                Err(e) => result.error = Some(e.to_string()),
+            }
+        }
+
+        results.push(result);
+    }
+
+    results
+}
+
+pub fn render_report(results: &[PreflightResult]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<16} {:<6} {:>12} {:>8}  DETAIL", "INTERFACE", "STATUS", "LATENCY(ms)", "LOSS(%)");
+
+    for result in results {
+        let status = match (result.enabled, result.reachable) {
+            (false, _) => "SKIP",
+            (true, true) => "PASS",
+            (true, false) => "FAIL",
+        };
+        let latency = result.latency_ms.map_or("-".to_string(), |l| format!("{:.2}", l));
+        let loss = result.packet_loss.map_or("-".to_string(), |l| format!("{:.2}", l * 100.0));
+        let _ = writeln!(
+            out,
+            "{:<16} {:<6} {:>12} {:>8}  {}",
+            result.interface,
+            status,
+            latency,
+            loss,
+            result.error.as_deref().unwrap_or("")
+        );
+    }
+
+    out
+}
+
+/// Process exit code for a preflight run: non-zero if any enabled interface
+/// is unreachable.
+pub fn exit_code(results: &[PreflightResult]) -> i32 {
+    if results.iter().any(|r| r.enabled && !r.reachable) {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_preflight_passes_for_reachable_interfaces() {
+        let config = Config::default();
+        let probe = NetworkProbe::new(config.clone());
+
+        let results = run_preflight(&probe, &config).await;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.reachable));
+        assert_eq!(exit_code(&results), 0);
+        assert!(render_report(&results).contains("PASS"));
+    }
+
+    #[tokio::test]
+    async fn test_preflight_fails_when_interface_cannot_be_probed() {
+        let mut config = Config::default();
+        // Only UDP probing is enabled, and its socket can't be bound
+        config.interfaces[1].icmp_enabled = false;
+        config.interfaces[1].bandwidth_test_enabled = false;
+        config.interfaces[1].source_address = Some("not-an-ip".to_string());
+        let probe = NetworkProbe::new(config.clone());
+
+        let results = run_preflight(&probe, &config).await;
+        assert!(results[0].reachable);
+        assert!(!results[1].reachable);
+        assert!(results[1].error.is_some());
+        assert_eq!(exit_code(&results), 1);
+        assert!(render_report(&results).lines().any(|l| l.starts_with("eth1") && l.contains("FAIL")));
+    }
+
+    #[tokio::test]
+    async fn test_preflight_skips_disabled_interfaces() {
+        let mut config = Config::default();
+        config.interfaces[1].enabled = false;
+        config.interfaces[1].source_address = Some("not-an-ip".to_string());
+        let probe = NetworkProbe::new(config.clone());
+
+        let results = run_preflight(&probe, &config).await;
+        assert!(!results[1].enabled);
+        assert_eq!(exit_code(&results), 0);
+    }
+}
@@ -1,49 +1,344 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::{InterfaceConfig, PayloadPattern, ProbeConfig};
+use crate::reflector::{ReflectorClient, ReflectorKey, Throughput, DATAGRAM_AUTH_LEN};
+use crate::socket::connect_udp_probe_socket;
+use crate::tcp_probe::{icmp_permitted, LatencyProbe, MultiTargetProbe, TcpConnectProbe};
+use crate::metrics::{LossEstimator, ReliabilityTracker};
 use crate::{Config, LinkMetrics};
 use anyhow::Result;
+use parking_lot::Mutex;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use chrono::Utc;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, FuturesUnordered, StreamExt};
+use std::future::Future;
+use rand::RngCore;
 use tokio::time::Instant;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 pub struct NetworkProbe {
     config: Config,
+    reliability: Mutex<ReliabilityTracker>,
+    /// Smooths the UDP probes' per-batch loss.
+    loss: Mutex<LossEstimator>,
+    /// Latest successful measurement per interface, used when a probe type
+    /// keeps failing.
+    last_known: Mutex<HashMap<String, LinkMetrics>>,
+    latency_probe: LatencyProbe,
+    /// Measures each direction separately when `probes.bandwidth_reflector`
+    /// is set.
+    reflector: Option<ReflectorClient>,
+    /// Stamps each measurement.
+    clock: Arc<dyn Clock>,
+    /// TCP connect probe to `probes.tcp_probe_target`, entered in the race
+    /// when `probes.race_probes` is set.
+    race_tcp: Option<TcpConnectProbe>,
+    /// UDP echo responder at `probes.udp_probe_target`; UDP probes are
+    /// skipped without one.
+    udp_target: Option<SocketAddr>,
+    /// Signs UDP probes, so a reflector echoes them.
+    reflector_key: Option<ReflectorKey>,
+    /// TCP connect probes standing in for each of
+    /// `probes.destination_probes`, with their destination prefix.
+    destination_probes: Vec<(String, TcpConnectProbe)>,
 }
 
 impl NetworkProbe {
+    /// Measures latency against `probes.probe_targets` when any are set,
+    /// else with ICMP if this process may, otherwise with TCP connects to
+    /// `probes.tcp_probe_target` when one is configured.
     pub fn new(config: Config) -> Self {
-        Self { config }
+        let timeout = Duration::from_millis(config.probes.icmp_timeout);
+        let targets = config.probes.probe_target_addrs().unwrap_or_else(|e| {
+            warn!("Probe targets unusable: {}", e);
+            Vec::new()
+        });
+        let latency_probe = match config.probes.tcp_probe_addr() {
+            _ if !targets.is_empty() => {
+                info!("Measuring latency against {} probe targets", targets.len());
+                LatencyProbe::Targets(MultiTargetProbe::new(&targets, timeout, config.probes.target_failure_policy)
+                    .with_dscp(config.probes.probe_dscp))
+            }
+            _ if icmp_permitted() => LatencyProbe::Icmp,
+            Ok(Some(target)) => {
+                info!("ICMP not permitted, measuring latency with TCP connects to {}", target);
+                LatencyProbe::TcpConnect(TcpConnectProbe::new(target, timeout).with_dscp(config.probes.probe_dscp))
+            }
+            Ok(None) => {
+                warn!("ICMP not permitted and no probes.tcp_probe_target set; latency probes may fail");
+                LatencyProbe::Icmp
+            }
+            Err(e) => {
+                warn!("ICMP not permitted and TCP fallback unusable: {}", e);
+                LatencyProbe::Icmp
+            }
+        };
+        Self::with_latency_probe(config, latency_probe)
+    }
+
+    pub fn with_latency_probe(config: Config, latency_probe: LatencyProbe) -> Self {
+        let reliability = Mutex::new(ReliabilityTracker::new(config.probes.reliability_window));
+        // Each direction gets half the test duration
+        let direction_duration = Duration::from_millis(config.probes.bandwidth_test_duration / 2);
+        let reflector_key = config.probes.reflector_key().unwrap_or_else(|e| {
+            warn!("Reflector PSK unusable: {}", e);
+            None
+        });
+        let reflector = match (config.probes.bandwidth_reflector_addr(), reflector_key.clone()) {
+            (Ok(Some(target)), Some(key)) => {
+                Some(ReflectorClient::new(target, direction_duration, key).with_dscp(config.probes.probe_dscp))
+            }
+            (Ok(Some(_)), None) => {
+                warn!("Bandwidth reflector set without probes.reflector_psk, measuring combined bandwidth");
+                None
+            }
+            (Ok(None), _) => None,
+            (Err(e), _) => {
+                warn!("Bandwidth reflector unusable, measuring combined bandwidth: {}", e);
+                None
+            }
+        };
+        let loss = Mutex::new(LossEstimator::new(config.probes.loss_alpha));
+        let race_tcp = match config.probes.tcp_probe_addr() {
+            Ok(Some(target)) if config.probes.race_probes => {
+                Some(TcpConnectProbe::new(target, Duration::from_millis(config.probes.icmp_timeout))
+                    .with_dscp(config.probes.probe_dscp))
+            }
+            _ => None,
+        };
+        let udp_target = config.probes.udp_probe_addr().unwrap_or_else(|e| {
+            warn!("UDP probe target unusable, skipping UDP probes: {}", e);
+            None
+        });
+        let timeout = Duration::from_millis(config.probes.icmp_timeout);
+        let destination_probes = config.probes.destination_probe_addrs()
+            .unwrap_or_else(|e| {
+                warn!("Destination probes unusable: {}", e);
+                Vec::new()
+            })
+            .into_iter()
+            .map(|(prefix, target)| (prefix, TcpConnectProbe::new(target, timeout).with_dscp(config.probes.probe_dscp)))
+            .collect();
+        Self {
+            config,
+            reliability,
+            loss,
+            last_known: Mutex::new(HashMap::new()),
+            latency_probe,
+            reflector,
+            clock: Arc::new(SystemClock),
+            race_tcp,
+            udp_target,
+            reflector_key,
+            destination_probes,
+        }
     }
 
+    /// Stamps measurements with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Probes an interface and folds the result into its rolling uptime
+    /// ratio; a failed probe, or stale metrics, count as an unhealthy cycle.
     pub async fn probe_interface(&self, interface_name: &str) -> Result<LinkMetrics> {
-        let mut metrics = LinkMetrics::new();
+        let result = self.measure_interface(interface_name).await;
+        let healthy = result.as_ref()
+            .is_ok_and(|metrics| metrics.is_healthy(self.config.probes.healthy_threshold));
+        let reliability = self.reliability.lock().record(interface_name, healthy);
+        
+        result.map(|mut metrics| {
+            metrics.reliability = reliability;
+            metrics
+        })
+    }
+
+    /// A single latency probe with no retries, for detecting hard failures
+    /// between full probes. Doesn't count towards the uptime ratio.
+    pub async fn check_liveness(&self, interface_name: &str) -> Result<f64> {
+        if self.config.probes.race_probes {
+            return Ok(self.race_probes(interface_name).await?.1);
+        }
+        self.latency_probe(interface_name).await
+    }
+
+    /// Latency from the interface to each `probes.destination_probes`
+    /// target, keyed by its destination prefix. Measured once, without
+    /// retries; a failed target is left out.
+    pub async fn probe_destinations(&self, interface_name: &str) -> HashMap<String, f64> {
+        let interface = self.interface_config(interface_name);
+        let results = futures::future::join_all(
+            self.destination_probes.iter().map(|(_, probe)| probe.measure(interface))
+        ).await;
+        self.destination_probes.iter()
+            .zip(results)
+            .filter_map(|((prefix, _), result)| match result {
+                Ok(latency) => Some((prefix.clone(), latency)),
+                Err(e) => {
+                    debug!("Destination probe for {} via {} failed: {}", prefix, interface_name, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Starts every enabled probe type at once and returns the first to
+    /// succeed, with its latency.
+    async fn race_probes(&self, interface_name: &str) -> Result<(ProbeKind, f64)> {
+        let interface = self.interface_config(interface_name);
+        let mut probes: Vec<(ProbeKind, BoxFuture<'_, Result<f64>>)> = Vec::new();
+        if interface.map_or(true, |i| i.icmp_enabled) {
+            let kind = match self.latency_probe {
+                LatencyProbe::Icmp => ProbeKind::Icmp,
+                LatencyProbe::TcpConnect(_) => ProbeKind::TcpConnect,
+                LatencyProbe::Targets(_) => ProbeKind::Targets,
+            };
+            probes.push((kind, self.latency_probe(interface_name).boxed()));
+        }
+        if self.udp_probes_enabled(interface) {
+            let udp = self.udp_probe(interface_name).map(|result| result.map(|(latency, _, _)| latency));
+            probes.push((ProbeKind::Udp, udp.boxed()));
+        }
+        // Already entered as the latency probe when it fell back to TCP
+        let tcp_entered = matches!(self.latency_probe, LatencyProbe::TcpConnect(_));
+        if let Some(tcp) = self.race_tcp.as_ref().filter(|_| !tcp_entered) {
+            probes.push((ProbeKind::TcpConnect, tcp.measure(interface).boxed()));
+        }
+        let (kind, latency) = first_success(probes).await
+            .map_err(|e| anyhow::anyhow!("No probe succeeded for {}: {}", interface_name, e))?;
+        debug!("{:?} probe answered first for {}: {}ms", kind, interface_name, latency);
+        Ok((kind, latency))
+    }
+
+    async fn measure_interface(&self, interface_name: &str) -> Result<LinkMetrics> {
+        let mut metrics = LinkMetrics::at(self.clock.now());
+        let interface = self.interface_config(interface_name);
+        let retries = self.config.probes.probe_retries;
+        let backoff = RetryBackoff::from_config(&self.config.probes);
+        let last_known = self.last_known.lock().get(interface_name).cloned();
+        let mut reachable = false;
+        
+        // Racing replaces the ICMP and UDP tests, and a link where every
+        // probe type failed is reported at once rather than after each
+        // one's retries
+        let raced = self.config.probes.race_probes;
+        if raced {
+            match self.race_probes(interface_name).await {
+                Ok((_, latency)) => {
+                    metrics.latency_ms = latency;
+                    reachable = true;
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    if let Some(ref last) = last_known {
+                        metrics.latency_ms = last.latency_ms;
+                    }
+                }
+            }
+        }
         
         // ICMP ping test
-        if let Ok(latency) = self.icmp_probe(interface_name).await {
-            metrics.latency_ms = latency;
+        if !raced && interface.map_or(true, |i| i.icmp_enabled) {
+            match with_retries(retries, backoff, || self.latency_probe(interface_name)).await {
+                Ok(latency) => {
+                    metrics.latency_ms = latency;
+                    reachable = true;
+                }
+                Err(e) => {
+                    warn!("ICMP probe failed for {} after {} retries: {}", interface_name, retries, e);
+                    if let Some(ref last) = last_known {
+                        metrics.latency_ms = last.latency_ms;
+                    }
+                }
+            }
         }
         
         // UDP probe test
-        if let Ok((latency, jitter, loss)) = self.udp_probe(interface_name).await {
-            metrics.latency_ms = latency;
-            metrics.jitter_ms = jitter;
-            metrics.packet_loss = loss;
+        if !raced && self.udp_probes_enabled(interface) {
+            match with_retries(retries, backoff, || self.udp_probe(interface_name)).await {
+                Ok((latency, jitter, loss)) => {
+                    metrics.latency_ms = latency;
+                    metrics.jitter_ms = jitter;
+                    metrics.packet_loss = self.loss.lock().record(interface_name, loss);
+                    reachable = true;
+                }
+                Err(e) => {
+                    warn!("UDP probe failed for {} after {} retries: {}", interface_name, retries, e);
+                    if let Some(ref last) = last_known {
+                        metrics.jitter_ms = last.jitter_ms;
+                        metrics.packet_loss = last.packet_loss;
+                    }
+                }
+            }
         }
         
-        // Bandwidth test
-        if let Ok(bandwidth) = self.bandwidth_probe(interface_name).await {
-            metrics.bandwidth_mbps = bandwidth;
+        // Nothing answered: report what was last seen rather than dropping
+        // the link from the metrics, marked so consumers can tell
+        if !reachable {
+            let Some(mut last) = last_known else {
+                return Err(anyhow::anyhow!("No ICMP or UDP probe succeeded for {}", interface_name));
+            };
+            warn!("No ICMP or UDP probe succeeded for {}, reporting last-known metrics from {}", interface_name, last.timestamp);
+            last.stale = true;
+            return Ok(last);
         }
         
-        metrics.timestamp = Utc::now();
+        // Bandwidth test, with latency sampled while the link is loaded
+        if interface.map_or(true, |i| i.bandwidth_test_enabled) {
+            match with_retries(retries, backoff, || self.bandwidth_probe(interface_name)).await {
+                Ok((bandwidth, directional, loaded_latencies)) => {
+                    metrics.bandwidth_mbps = bandwidth;
+                    metrics.bandwidth_up_mbps = directional.map(|throughput| throughput.up_mbps);
+                    metrics.bandwidth_down_mbps = directional.map(|throughput| throughput.down_mbps);
+                    metrics.bufferbloat_ms = bufferbloat_ms(metrics.latency_ms, &loaded_latencies);
+                }
+                Err(e) => {
+                    warn!("Bandwidth probe failed for {} after {} retries: {}", interface_name, retries, e);
+                    if let Some(ref last) = last_known {
+                        metrics.bandwidth_mbps = last.bandwidth_mbps;
+                        metrics.bandwidth_up_mbps = last.bandwidth_up_mbps;
+                        metrics.bandwidth_down_mbps = last.bandwidth_down_mbps;
+                        metrics.bufferbloat_ms = last.bufferbloat_ms;
+                        metrics.bandwidth_measured = last.bandwidth_measured;
+                    } else {
+                        metrics.bandwidth_measured = false;
+                    }
+                }
+            }
+        } else {
+            metrics.bandwidth_measured = false;
+        }
+        
+        metrics.timestamp = self.clock.now();
+        self.last_known.lock().insert(interface_name.to_string(), metrics.clone());
         Ok(metrics)
     }
 
+    async fn latency_probe(&self, interface_name: &str) -> Result<f64> {
+        match self.latency_probe {
+            LatencyProbe::Icmp => self.icmp_probe(interface_name).await,
+            LatencyProbe::TcpConnect(ref probe) => {
+                let latency = probe.measure(self.interface_config(interface_name)).await?;
+                debug!("TCP connect probe for {}: {}ms", interface_name, latency);
+                Ok(latency)
+            }
+            LatencyProbe::Targets(ref probe) => {
+                let latency = probe.measure(self.interface_config(interface_name)).await?;
+                debug!("Probe targets for {}: {}ms", interface_name, latency);
+                Ok(latency)
+            }
+        }
+    }
+
     async fn icmp_probe(&self, interface_name: &str) -> Result<f64> {
         // Simulate ICMP ping
         let start = Instant::now();
         
-        // TODO: Implement actual ICMP ping using pnet
+        // TODO: Implement actual ICMP ping using pnet, marking the socket
+        // with `probes.probe_dscp` via `set_probe_dscp`
         tokio::time::sleep(Duration::from_millis(10)).await;
         
         let latency = start.elapsed().as_millis() as f64;
@@ -52,29 +347,64 @@ impl NetworkProbe {
         Ok(latency)
     }
 
+    fn udp_probes_enabled(&self, interface: Option<&InterfaceConfig>) -> bool {
+        self.udp_target.is_some() && interface.map_or(true, |i| i.udp_enabled)
+    }
+
+    /// Sends `probe_count` datagrams to `probes.udp_probe_target` at once
+    /// and times their echoes, returning the average latency, the jitter
+    /// and the fraction lost. Fails when no echo comes back within
+    /// `udp_timeout`.
     async fn udp_probe(&self, interface_name: &str) -> Result<(f64, f64, f64)> {
         let probe_config = &self.config.probes;
-        let mut latencies = Vec::new();
-        let mut lost_packets = 0;
+        let target = self.udp_target.ok_or_else(|| anyhow::anyhow!("No probes.udp_probe_target set"))?;
         
-        for i in 0..probe_config.probe_count {
-            let start = Instant::now();
-            
-            // Simulate UDP probe
-            tokio::time::sleep(Duration::from_millis(5 + (i % 3) as u64)).await;
-            
-            let latency = start.elapsed().as_millis() as f64;
-            latencies.push(latency);
-            
-            // Simulate packet loss
-            if i % 100 == 0 {
-                lost_packets += 1;
+        // Bind to the interface's source address so probes don't follow the default route
+        let socket = connect_udp_probe_socket(target, self.interface_config(interface_name), probe_config.probe_dscp)?;
+        // The sequence number overwrites the start of the payload
+        let mut packet = build_payload(probe_config.payload_pattern, probe_config.packet_size);
+        
+        let mut sent_at = Vec::with_capacity(probe_config.probe_count);
+        for seq in 0..probe_config.probe_count {
+            packet[..8].copy_from_slice(&(seq as u64).to_be_bytes());
+            let mut datagram = packet.clone();
+            if let Some(ref key) = self.reflector_key {
+                key.sign_datagram(&mut datagram)?;
+            }
+            sent_at.push(Instant::now());
+            socket.send(&datagram).await?;
+        }
+        
+        // Echoes can come back in any order, so each is matched to its probe
+        // by sequence number
+        let mut latencies: Vec<Option<f64>> = vec![None; probe_config.probe_count];
+        let mut received = 0;
+        let mut reply = vec![0u8; probe_config.packet_size + DATAGRAM_AUTH_LEN];
+        let deadline = Instant::now() + Duration::from_millis(probe_config.udp_timeout);
+        while received < probe_config.probe_count {
+            let Ok(len) = tokio::time::timeout_at(deadline, socket.recv(&mut reply)).await else {
+                break;
+            };
+            if len? < 8 {
+                continue;
+            }
+            let seq = u64::from_be_bytes(reply[..8].try_into()?) as usize;
+            match latencies.get_mut(seq) {
+                Some(latency) if latency.is_none() => {
+                    *latency = Some(sent_at[seq].elapsed().as_secs_f64() * 1000.0);
+                    received += 1;
+                }
+                _ => {}
             }
         }
         
+        let latencies: Vec<f64> = latencies.into_iter().flatten().collect();
+        if latencies.is_empty() {
+            return Err(anyhow::anyhow!("No UDP echoes from {} within {}ms", target, probe_config.udp_timeout));
+        }
         let avg_latency = latencies.iter().sum::<f64>() / latencies.len() as f64;
         let jitter = self.calculate_jitter(&latencies);
-        let loss_rate = lost_packets as f64 / probe_config.probe_count as f64;
+        let loss_rate = 1.0 - received as f64 / probe_config.probe_count as f64;
         
         debug!("UDP probe for {}: latency={}ms, jitter={}ms, loss={}%", 
                interface_name, avg_latency, jitter, loss_rate * 100.0);
@@ -82,11 +412,43 @@ impl NetworkProbe {
         Ok((avg_latency, jitter, loss_rate))
     }
 
-    async fn bandwidth_probe(&self, interface_name: &str) -> Result<f64> {
+    /// Runs the throughput test while repeatedly probing latency over the
+    /// same link, returning the bandwidth, its split by direction when
+    /// measured against a reflector, and the latencies seen under load.
+    async fn bandwidth_probe(&self, interface_name: &str) -> Result<(f64, Option<Throughput>, Vec<f64>)> {
+        let throughput = self.throughput_test(interface_name);
+        tokio::pin!(throughput);
+        
+        let mut loaded_latencies = Vec::new();
+        let (bandwidth, directional) = loop {
+            tokio::select! {
+                bandwidth = &mut throughput => break bandwidth?,
+                latency = self.latency_probe(interface_name) => {
+                    if let Ok(latency) = latency {
+                        loaded_latencies.push(latency);
+                    }
+                }
+            }
+        };
+        
+        debug!("Latency under load for {}: {:?}", interface_name, loaded_latencies);
+        Ok((bandwidth, directional, loaded_latencies))
+    }
+
+    /// Against a reflector, the combined figure is the slower direction.
+    async fn throughput_test(&self, interface_name: &str) -> Result<(f64, Option<Throughput>)> {
+        if let Some(ref reflector) = self.reflector {
+            let payload = build_payload(self.config.probes.payload_pattern, self.config.probes.packet_size);
+            let throughput = reflector.measure(self.interface_config(interface_name), &payload).await?;
+            debug!("Bandwidth probe for {}: {:.1} Mbps up, {:.1} Mbps down",
+                   interface_name, throughput.up_mbps, throughput.down_mbps);
+            return Ok((throughput.up_mbps.min(throughput.down_mbps), Some(throughput)));
+        }
+        
         // Simulate bandwidth test
         let start = Instant::now();
         
-        // TODO: Implement actual bandwidth measurement
+        // TODO: Implement actual bandwidth measurement without a reflector
         tokio::time::sleep(Duration::from_millis(100)).await;
         
         let _duration = start.elapsed().as_millis() as f64;
@@ -94,7 +456,11 @@ impl NetworkProbe {
         
         debug!("Bandwidth probe for {}: {} Mbps", interface_name, bandwidth);
         
-        Ok(bandwidth)
+        Ok((bandwidth, None))
+    }
+
+    fn interface_config(&self, interface_name: &str) -> Option<&InterfaceConfig> {
+        self.config.interfaces.iter().find(|i| i.name == interface_name)
     }
 
     fn calculate_jitter(&self, latencies: &[f64]) -> f64 {
@@ -110,24 +476,42 @@ impl NetworkProbe {
         jitter_sum / (latencies.len() - 1) as f64
     }
 
+    /// Probes the given interfaces, up to `max_concurrent_probes` at a time,
+    /// returning results in completion order.
+    pub async fn probe_interfaces(&self, interface_names: &[String]) -> Vec<(String, Result<LinkMetrics>)> {
+        // Boxed so the spawned server loop's future stays provably Send
+        let probes: Vec<BoxFuture<'_, (String, Result<LinkMetrics>)>> = interface_names.iter()
+            .map(|name| async move { (name.clone(), self.probe_interface(name).await) }.boxed())
+            .collect();
+        probe_concurrently(probes, self.config.probes.max_concurrent_probes).await
+    }
+
+    /// Liveness checks for the given interfaces, up to
+    /// `max_concurrent_probes` at a time, returning results in completion
+    /// order.
+    pub async fn check_liveness_of(&self, interface_names: &[String]) -> Vec<(String, Result<f64>)> {
+        let checks: Vec<BoxFuture<'_, (String, Result<f64>)>> = interface_names.iter()
+            .map(|name| async move { (name.clone(), self.check_liveness(name).await) }.boxed())
+            .collect();
+        probe_concurrently(checks, self.config.probes.max_concurrent_probes).await
+    }
+
     pub async fn probe_all_interfaces(&self) -> Result<HashMap<String, LinkMetrics>> {
         let mut metrics = HashMap::new();
+        let enabled: Vec<String> = self.config.interfaces.iter()
+            .filter(|interface| interface.enabled)
+            .map(|interface| interface.name.clone())
+            .collect();
         
-        for interface in &self.config.interfaces {
-            if !interface.enabled {
-                continue;
-            }
-            
-            match self.probe_interface(&interface.name).await {
+        for (interface_name, result) in self.probe_interfaces(&enabled).await {
+            match result {
                 Ok(metric) => {
-                    let latency = metric.latency_ms;
-                    let bandwidth = metric.bandwidth_mbps;
-                    metrics.insert(interface.name.clone(), metric);
                     info!("Probed interface {}: latency={}ms, bandwidth={}Mbps", 
-                          interface.name, latency, bandwidth);
+                          interface_name, metric.latency_ms, metric.bandwidth_mbps);
+                    metrics.insert(interface_name, metric);
                 }
                 Err(e) => {
-                    error!("Failed to probe interface {}: {}", interface.name, e);
+                    error!("Failed to probe interface {}: {}", interface_name, e);
                 }
             }
         }
@@ -136,9 +520,130 @@ impl NetworkProbe {
     }
 }
 
+/// Builds a probe payload of `size` bytes filled according to `pattern`.
+pub fn build_payload(pattern: PayloadPattern, size: usize) -> Vec<u8> {
+    match pattern {
+        PayloadPattern::Zeros => vec![0u8; size],
+        PayloadPattern::Random => {
+            let mut payload = vec![0u8; size];
+            rand::thread_rng().fill_bytes(&mut payload);
+            payload
+        }
+        PayloadPattern::Incrementing => (0..size).map(|i| i as u8).collect(),
+    }
+}
+
+/// Runs `probes` with at most `limit` in flight, so one slow or timing-out
+/// interface doesn't hold up the rest. Results come back in completion order.
+pub async fn probe_concurrently<I>(probes: I, limit: usize) -> Vec<<I::Item as Future>::Output>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    stream::iter(probes)
+        .buffer_unordered(limit.max(1))
+        .collect()
+        .await
+}
+
+/// A probe type entered in a race by `probes.race_probes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeKind {
+    Icmp,
+    Udp,
+    TcpConnect,
+    /// The nearest of `probes.probe_targets`.
+    Targets,
+}
+
+/// Runs `probes` at once and returns the first to succeed with its latency,
+/// without waiting for the rest. Fails, listing every error, only once all
+/// of them have.
+pub async fn first_success(probes: Vec<(ProbeKind, BoxFuture<'_, Result<f64>>)>) -> Result<(ProbeKind, f64)> {
+    if probes.is_empty() {
+        return Err(anyhow::anyhow!("No probe types enabled"));
+    }
+    let mut racing: FuturesUnordered<_> = probes.into_iter()
+        .map(|(kind, probe)| probe.map(move |result| (kind, result)))
+        .collect();
+    let mut errors = Vec::new();
+    while let Some((kind, result)) = racing.next().await {
+        match result {
+            Ok(latency) => return Ok((kind, latency)),
+            Err(e) => errors.push(format!("{:?}: {}", kind, e)),
+        }
+    }
+    Err(anyhow::anyhow!("{}", errors.join("; ")))
+}
+
+/// Waits between retries of a failed probe: `initial` before the first,
+/// doubling for each further one, up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl RetryBackoff {
+    pub fn from_config(config: &ProbeConfig) -> Self {
+        Self {
+            initial: Duration::from_millis(config.retry_backoff_ms),
+            max: Duration::from_millis(config.max_retry_backoff_ms),
+        }
+    }
+
+    /// Wait before retry number `retry`, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Runs `attempt`, retrying up to `retries` more times while it fails, with
+/// `backoff` between attempts. Returns the first success or the last error.
+pub async fn with_retries<T, F, Fut>(retries: u32, backoff: RetryBackoff, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut tries = 0;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) if tries < retries => {
+                tries += 1;
+                let delay = backoff.delay(tries);
+                debug!("Probe attempt {} failed, retrying in {:?}: {}", tries, delay, e);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Latency increase under load: the median of `loaded_latencies` over the
+/// idle latency, or 0 if no samples were taken or latency didn't rise.
+pub fn bufferbloat_ms(idle_latency_ms: f64, loaded_latencies: &[f64]) -> f64 {
+    if loaded_latencies.is_empty() {
+        return 0.0;
+    }
+    
+    let mut sorted = loaded_latencies.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mid = sorted.len() / 2;
+    let median = if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    };
+    
+    (median - idle_latency_ms).max(0.0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::DestinationProbe;
     
     #[tokio::test]
     async fn test_network_probe_creation() {
@@ -146,4 +651,362 @@ mod tests {
         let probe = NetworkProbe::new(config);
         assert!(probe.probe_all_interfaces().await.is_ok());
     }
-} 
\ No newline at end of file
+    
+    #[tokio::test]
+    async fn test_tcp_connect_fallback_measures_latency() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let probe = NetworkProbe::with_latency_probe(
+            Config::default(),
+            LatencyProbe::TcpConnect(TcpConnectProbe::new(target, Duration::from_secs(1))),
+        );
+        
+        let latency = probe.latency_probe("lo").await.unwrap();
+        assert!((0.0..1000.0).contains(&latency));
+    }
+    
+    #[test]
+    fn test_payload_patterns() {
+        let zeros = build_payload(PayloadPattern::Zeros, 1500);
+        assert_eq!(zeros.len(), 1500);
+        assert!(zeros.iter().all(|&b| b == 0));
+        
+        let incrementing = build_payload(PayloadPattern::Incrementing, 300);
+        assert_eq!(incrementing.len(), 300);
+        assert_eq!(&incrementing[..3], &[0, 1, 2]);
+        assert_eq!(incrementing[255], 255);
+        assert_eq!(incrementing[256], 0);
+        
+        let random = build_payload(PayloadPattern::Random, 1500);
+        assert_eq!(random.len(), 1500);
+        assert!(random.iter().any(|&b| b != 0));
+        assert_ne!(random, build_payload(PayloadPattern::Random, 1500));
+    }
+    
+    #[test]
+    fn test_payload_pattern_from_config() {
+        let probes: crate::config::ProbeConfig = serde_yaml::from_str(
+            "icmp_timeout: 1000\nudp_timeout: 2000\nbandwidth_test_duration: 10000\npacket_size: 64\nprobe_count: 10\npayload_pattern: incrementing\n",
+        ).unwrap();
+        assert_eq!(probes.payload_pattern, PayloadPattern::Incrementing);
+    }
+    
+    #[test]
+    fn test_bufferbloat_from_latency_under_load() {
+        // 10ms idle; queues fill once the bandwidth test ramps up
+        let loaded = [12.0, 45.0, 61.0, 58.0, 60.0];
+        assert_eq!(bufferbloat_ms(10.0, &loaded), 48.0);
+        
+        // A well-behaved link barely moves under load
+        assert_eq!(bufferbloat_ms(10.0, &[10.0, 11.0, 12.0, 11.0]), 1.0);
+        assert_eq!(bufferbloat_ms(10.0, &[9.0, 9.5]), 0.0);
+        assert_eq!(bufferbloat_ms(10.0, &[]), 0.0);
+    }
+    
+    #[tokio::test]
+    async fn test_bandwidth_probe_samples_latency_under_load() {
+        let probe = NetworkProbe::new(Config::default());
+        let (bandwidth, directional, loaded_latencies) = probe.bandwidth_probe("eth0").await.unwrap();
+        assert!(bandwidth > 0.0);
+        assert!(directional.is_none());
+        assert!(!loaded_latencies.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_measurements_stamped_by_injected_clock() {
+        let mut config = Config::default();
+        config.probes.bandwidth_test_duration = 100;
+        let start = chrono::DateTime::parse_from_rfc3339("2026-10-16T09:30:00+00:00").unwrap().with_timezone(&chrono::Utc);
+        let clock = Arc::new(crate::clock::MockClock::new(start));
+        let probe = NetworkProbe::new(config).with_clock(clock.clone());
+        
+        assert_eq!(probe.probe_interface("lo").await.unwrap().timestamp, start);
+        clock.advance(chrono::Duration::seconds(5));
+        assert_eq!(probe.probe_interface("lo").await.unwrap().timestamp, start + chrono::Duration::seconds(5));
+    }
+    
+    #[tokio::test]
+    async fn test_reflector_measures_up_and_down_separately() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.probes.bandwidth_reflector = Some(listener.local_addr().unwrap().to_string());
+        config.probes.reflector_psk = Some(PSK.to_string());
+        config.probes.bandwidth_test_duration = 400;
+        tokio::spawn(crate::reflector::serve_reflector(listener, ReflectorKey::from_hex(PSK).unwrap()));
+        
+        let metrics = NetworkProbe::new(config).probe_interface("lo").await.unwrap();
+        let (up, down) = (metrics.bandwidth_up_mbps.unwrap(), metrics.bandwidth_down_mbps.unwrap());
+        assert!(up > 0.0 && down > 0.0);
+        assert_eq!(metrics.bandwidth_mbps, up.min(down));
+    }
+    
+    #[tokio::test]
+    async fn test_slow_interface_does_not_block_others() {
+        let names: Vec<String> = ["wan0", "slow0", "wan1", "wan2"].iter().map(|n| n.to_string()).collect();
+        let start = Instant::now();
+        
+        let probes = names.iter().map(|name| async move {
+            let delay = if name == "slow0" { 500 } else { 10 };
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            (name.clone(), start.elapsed())
+        });
+        let results = probe_concurrently(probes, 4).await;
+        
+        assert_eq!(results.len(), 4);
+        assert_eq!(results.last().unwrap().0, "slow0");
+        for (name, elapsed) in &results[..3] {
+            assert!(*elapsed < Duration::from_millis(250), "{} took {:?}", name, elapsed);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_concurrency_limit_respected() {
+        let names: Vec<String> = (0..6).map(|i| format!("wan{}", i)).collect();
+        let in_flight = std::sync::atomic::AtomicUsize::new(0);
+        let peak = std::sync::atomic::AtomicUsize::new(0);
+        
+        let probes = names.iter().map(|_| async {
+            let now = in_flight.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            peak.fetch_max(now, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            in_flight.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        });
+        probe_concurrently(probes, 2).await;
+        
+        assert_eq!(peak.into_inner(), 2);
+    }
+    
+    const NO_BACKOFF: RetryBackoff = RetryBackoff { initial: Duration::ZERO, max: Duration::ZERO };
+    
+    #[tokio::test]
+    async fn test_failed_probe_retried() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let result = with_retries(2, NO_BACKOFF, || async {
+            match attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => Err(anyhow::anyhow!("dropped")),
+                _ => Ok(12.5),
+            }
+        }).await;
+        
+        assert_eq!(result.unwrap(), 12.5);
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_probe_fails_once_retries_exhausted() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let result: Result<f64> = with_retries(2, NO_BACKOFF, || async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(anyhow::anyhow!("dropped"))
+        }).await;
+        
+        assert!(result.is_err());
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retries_back_off_exponentially_up_to_cap() {
+        let backoff = RetryBackoff { initial: Duration::from_millis(20), max: Duration::from_millis(60) };
+        let attempts = Mutex::new(Vec::new());
+        let result: Result<f64> = with_retries(4, backoff, || async {
+            attempts.lock().push(Instant::now());
+            Err(anyhow::anyhow!("dropped"))
+        }).await;
+        assert!(result.is_err());
+        
+        let attempts = attempts.into_inner();
+        let gaps: Vec<Duration> = attempts.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        for (gap, expected) in gaps.iter().zip([20, 40, 60, 60]) {
+            let expected = Duration::from_millis(expected);
+            assert!(*gap >= expected && *gap < expected + Duration::from_millis(50), "gaps were {:?}", gaps);
+        }
+        assert_eq!(backoff.delay(1), Duration::from_millis(20));
+        assert_eq!(backoff.delay(3), Duration::from_millis(60));
+        assert_eq!(backoff.delay(40), Duration::from_millis(60));
+    }
+
+    #[tokio::test]
+    async fn test_race_won_by_tcp_when_icmp_blocked() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpConnectProbe::new(listener.local_addr().unwrap(), Duration::from_secs(1));
+        // Filtered ICMP: no reply until the timeout
+        let icmp = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Err(anyhow::anyhow!("ICMP echo timed out"))
+        };
+
+        let start = Instant::now();
+        let (kind, latency) = first_success(vec![
+            (ProbeKind::Icmp, icmp.boxed()),
+            (ProbeKind::TcpConnect, tcp.measure(None).boxed()),
+        ]).await.unwrap();
+        assert_eq!(kind, ProbeKind::TcpConnect);
+        assert!(latency < 1000.0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_race_fails_only_when_every_probe_fails() {
+        let failing = |message: &'static str| async move { Err::<f64, _>(anyhow::anyhow!(message)) }.boxed();
+        let error = first_success(vec![
+            (ProbeKind::Icmp, failing("filtered")),
+            (ProbeKind::Udp, failing("no reply")),
+        ]).await.unwrap_err().to_string();
+        assert!(error.contains("Icmp: filtered") && error.contains("Udp: no reply"));
+        assert!(first_success(Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_liveness_raced_against_tcp_target() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.probes.race_probes = true;
+        config.probes.tcp_probe_target = Some(listener.local_addr().unwrap().to_string());
+        config.interfaces[0].name = "lo".to_string();
+        config.interfaces[0].icmp_enabled = false;
+        config.interfaces[0].udp_enabled = false;
+        let probe = NetworkProbe::new(config);
+
+        let latency = probe.check_liveness("lo").await.unwrap();
+        assert!((0.0..1000.0).contains(&latency));
+    }
+
+    #[tokio::test]
+    async fn test_liveness_checked_for_every_due_interface() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let probe = NetworkProbe::with_latency_probe(
+            Config::default(),
+            LatencyProbe::TcpConnect(TcpConnectProbe::new(listener.local_addr().unwrap(), Duration::from_secs(1))),
+        );
+
+        // Not configured, so the checks aren't bound to a device
+        let names = vec!["wan0".to_string(), "wan1".to_string()];
+        let mut results: Vec<(String, bool)> = probe.check_liveness_of(&names).await.into_iter()
+            .map(|(name, result)| (name, result.is_ok()))
+            .collect();
+        results.sort();
+        assert_eq!(results, vec![("wan0".to_string(), true), ("wan1".to_string(), true)]);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_link_reports_stale_last_known_metrics() {
+        let mut config = Config::default();
+        config.interfaces[0].name = "lo".to_string();
+        config.interfaces[0].icmp_enabled = false;
+        config.interfaces[0].udp_enabled = false;
+        let probe = NetworkProbe::new(config);
+        assert!(probe.probe_interface("lo").await.is_err());
+
+        let mut last = LinkMetrics::new();
+        last.latency_ms = 12.0;
+        last.bandwidth_mbps = 80.0;
+        probe.last_known.lock().insert("lo".to_string(), last.clone());
+
+        let metrics = probe.probe_interface("lo").await.unwrap();
+        assert!(metrics.stale);
+        assert_eq!(metrics.latency_ms, 12.0);
+        assert_eq!(metrics.bandwidth_mbps, 80.0);
+        assert_eq!(metrics.timestamp, last.timestamp);
+        // Counted as an unhealthy cycle
+        assert!(metrics.reliability < 1.0);
+    }
+
+    const PSK: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn udp_probed_loopback(target: SocketAddr) -> Config {
+        let mut config = Config::default();
+        config.probes.udp_probe_target = Some(target.to_string());
+        config.probes.reflector_psk = Some(PSK.to_string());
+        config.probes.udp_timeout = 200;
+        config.interfaces[0].name = "lo".to_string();
+        config.interfaces[0].source_address = Some("127.0.0.1".to_string());
+        config.interfaces[0].bandwidth_test_enabled = false;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_udp_probe_times_echoes() {
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = udp_probed_loopback(echo.local_addr().unwrap());
+        tokio::spawn(crate::reflector::serve_udp_echo(echo, ReflectorKey::from_hex(PSK).unwrap()));
+        let probe = NetworkProbe::new(config);
+
+        let (latency, jitter, loss) = probe.udp_probe("lo").await.unwrap();
+        assert!((0.0..200.0).contains(&latency));
+        assert!(jitter >= 0.0);
+        assert_eq!(loss, 0.0);
+        // Bandwidth test disabled: reported as unknown, not as zero capacity
+        let metrics = probe.probe_interface("lo").await.unwrap();
+        assert!(!metrics.bandwidth_measured);
+    }
+
+    #[tokio::test]
+    async fn test_udp_probes_carry_payload_pattern() {
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = udp_probed_loopback(echo.local_addr().unwrap());
+        config.probes.probe_count = 1;
+        config.probes.packet_size = 300;
+        config.probes.payload_pattern = PayloadPattern::Incrementing;
+        let received = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (len, peer) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], peer).await.unwrap();
+            buf[..len].to_vec()
+        });
+
+        NetworkProbe::new(config).udp_probe("lo").await.unwrap();
+        let datagram = received.await.unwrap();
+        assert_eq!(datagram.len(), 300 + DATAGRAM_AUTH_LEN);
+        assert_eq!(&datagram[..8], &0u64.to_be_bytes());
+        assert_eq!(&datagram[8..300], &build_payload(PayloadPattern::Incrementing, 300)[8..]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_probe_fails_without_echoes() {
+        // Bound but never answers
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let probe = NetworkProbe::new(udp_probed_loopback(silent.local_addr().unwrap()));
+        assert!(probe.udp_probe("lo").await.is_err());
+
+        let mut config = udp_probed_loopback(silent.local_addr().unwrap());
+        config.probes.udp_probe_target = None;
+        let probe = NetworkProbe::new(config);
+        assert!(probe.udp_probe("lo").await.is_err());
+        assert!(!probe.udp_probes_enabled(probe.interface_config("lo")));
+    }
+
+    #[tokio::test]
+    async fn test_race_enters_probe_targets() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.probes.race_probes = true;
+        config.probes.probe_targets = vec![listener.local_addr().unwrap().to_string()];
+        config.interfaces[0].name = "lo".to_string();
+        config.interfaces[0].udp_enabled = false;
+        config.interfaces[0].bandwidth_test_enabled = false;
+        let probe = NetworkProbe::new(config);
+
+        let (kind, latency) = probe.race_probes("lo").await.unwrap();
+        assert_eq!(kind, ProbeKind::Targets);
+        assert!((0.0..1000.0).contains(&latency));
+        assert!(probe.probe_interface("lo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_destination_targets_probed_per_prefix() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.probes.icmp_timeout = 200;
+        config.interfaces[0].name = "lo".to_string();
+        config.probes.destination_probes = vec![
+            DestinationProbe { prefix: "203.0.113.0/24".to_string(), target: listener.local_addr().unwrap().to_string() },
+            // Documentation range: the connect fails or times out
+            DestinationProbe { prefix: "198.51.100.0/24".to_string(), target: "192.0.2.1:9".to_string() },
+        ];
+        let probe = NetworkProbe::new(config);
+
+        let latencies = probe.probe_destinations("lo").await;
+        assert!((0.0..200.0).contains(&latencies["203.0.113.0/24"]));
+        assert!(!latencies.contains_key("198.51.100.0/24"));
+    }
+}
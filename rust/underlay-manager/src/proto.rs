@@ -1,6 +1,7 @@
 // Protocol buffer definitions for underlay manager
 // This will be used for gRPC communication with other components
 
+use crate::LinkMetrics;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,10 +18,43 @@ pub struct ProbeResponse {
     pub jitter_ms: f64,
     pub packet_loss: f64,
     pub bandwidth_mbps: f64,
+    #[serde(default)]
+    pub bandwidth_up_mbps: Option<f64>,
+    #[serde(default)]
+    pub bandwidth_down_mbps: Option<f64>,
+    /// False when no bandwidth test ran, so `bandwidth_mbps` is unknown.
+    #[serde(default = "default_bandwidth_measured")]
+    pub bandwidth_measured: bool,
+    pub reliability: f64,
+    pub bufferbloat_ms: f64,
     pub timestamp: String,
     pub status: String,
 }
 
+fn default_bandwidth_measured() -> bool {
+    true
+}
+
+impl ProbeResponse {
+    pub fn from_metrics(interface_name: &str, metrics: &LinkMetrics) -> Self {
+        Self {
+            interface_name: interface_name.to_string(),
+            latency_ms: metrics.latency_ms,
+            jitter_ms: metrics.jitter_ms,
+            // Clients treat total loss as down
+            packet_loss: if metrics.carrier_up { metrics.packet_loss } else { 1.0 },
+            bandwidth_mbps: metrics.bandwidth_mbps,
+            bandwidth_up_mbps: metrics.bandwidth_up_mbps,
+            bandwidth_down_mbps: metrics.bandwidth_down_mbps,
+            bandwidth_measured: metrics.bandwidth_measured,
+            reliability: metrics.reliability,
+            bufferbloat_ms: metrics.bufferbloat_ms,
+            timestamp: metrics.timestamp.to_rfc3339(),
+            status: if metrics.carrier_up { "ok" } else { "no_carrier" }.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetricsRequest {
     pub interface_names: Vec<String>,
@@ -32,9 +66,145 @@ pub struct MetricsResponse {
     pub timestamp: String,
 }
 
+/// Asks for interfaces whose metrics changed after `since_version`; 0 asks
+/// for everything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsDiffRequest {
+    pub since_version: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsDiffResponse {
+    /// Cache version the diff brings the client up to.
+    pub version: u64,
+    pub metrics: Vec<ProbeResponse>,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerListRequest {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub node_id: String,
+    /// gRPC endpoint of the peer's manager, as `ip:port`.
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerListResponse {
+    pub peers: Vec<PeerInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationMetricsRequest {}
+
+/// An interface's metrics towards the destinations in `prefix`, measured
+/// against that prefix's `probes.destination_probes` target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationMetric {
+    pub prefix: String,
+    pub metrics: ProbeResponse,
+}
+
+/// Every interface's latest successful measurement towards each prefix.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationMetricsResponse {
+    pub metrics: Vec<DestinationMetric>,
+    pub timestamp: String,
+}
+
+/// A call to the manager's RPC listener: one JSON object per line, such as
+/// `{"method": "metrics_diff", "params": {"since_version": 0}}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum RpcRequest {
+    MetricsDiff(MetricsDiffRequest),
+    ListPeers(PeerListRequest),
+    SetIdle(SetIdleRequest),
+    DestinationMetrics(DestinationMetricsRequest),
+}
+
+/// The line answering an `RpcRequest`: `{"result": ...}` with the method's
+/// response, or `{"error": "..."}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcReply {
+    Result(serde_json::Value),
+    Error(String),
+}
+
+/// The scheduler's view of whether it is using an interface; idle ones are
+/// probed `probes.idle_probe_multiplier` times less often.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetIdleRequest {
+    pub interface_name: String,
+    pub idle: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetIdleResponse {}
+
 // Service trait for gRPC communication
 #[async_trait::async_trait]
 pub trait UnderlayService {
     async fn probe_interface(&self, request: ProbeRequest) -> Result<ProbeResponse, Box<dyn std::error::Error>>;
     async fn get_metrics(&self, request: MetricsRequest) -> Result<MetricsResponse, Box<dyn std::error::Error>>;
+}
+
+/// Incremental metrics for clients tracking many interfaces.
+#[async_trait::async_trait]
+pub trait MetricsDiffService {
+    async fn get_metrics_diff(&self, request: MetricsDiffRequest) -> Result<MetricsDiffResponse, Box<dyn std::error::Error>>;
+}
+
+/// Peers found by discovery, for the scheduler's tunnel endpoints.
+#[async_trait::async_trait]
+pub trait PeerService {
+    async fn list_peers(&self, request: PeerListRequest) -> Result<PeerListResponse, Box<dyn std::error::Error>>;
+}
+
+/// Per-destination metrics, for choosing a link by where traffic goes.
+#[async_trait::async_trait]
+pub trait DestinationMetricsService {
+    async fn get_destination_metrics(&self, request: DestinationMetricsRequest) -> Result<DestinationMetricsResponse, Box<dyn std::error::Error>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The diff as the packet scheduler's proto tests parse it; keep the two
+    /// sides in step when changing `ProbeResponse`.
+    const WIRE_METRICS_DIFF: &str = include_str!("../../testdata/metrics_diff.json");
+
+    #[test]
+    fn test_rpc_wire_format() {
+        let request: RpcRequest = serde_json::from_str(r#"{"method": "metrics_diff", "params": {"since_version": 3}}"#).unwrap();
+        assert!(matches!(request, RpcRequest::MetricsDiff(MetricsDiffRequest { since_version: 3 })));
+        assert!(serde_json::from_str::<RpcRequest>(r#"{"method": "reboot", "params": {}}"#).is_err());
+
+        let reply = serde_json::to_string(&RpcReply::Error("no such interface".to_string())).unwrap();
+        assert_eq!(reply, r#"{"error":"no such interface"}"#);
+    }
+
+    #[test]
+    fn test_metrics_diff_wire_format() {
+        let diff: MetricsDiffResponse = serde_json::from_str(WIRE_METRICS_DIFF).unwrap();
+        assert_eq!(diff.version, 7);
+
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 12.5;
+        metrics.jitter_ms = 1.5;
+        metrics.packet_loss = 0.01;
+        metrics.bandwidth_mbps = 95.0;
+        metrics.bandwidth_up_mbps = Some(40.0);
+        metrics.bandwidth_down_mbps = Some(95.0);
+        metrics.reliability = 0.95;
+        metrics.bufferbloat_ms = 30.0;
+        metrics.timestamp = chrono::DateTime::parse_from_rfc3339("2026-10-16T09:30:00+00:00").unwrap().into();
+        let written = serde_json::to_value(ProbeResponse::from_metrics("eth0", &metrics)).unwrap();
+        let fixture: serde_json::Value = serde_json::from_str(WIRE_METRICS_DIFF).unwrap();
+        assert_eq!(written, fixture["metrics"][0]);
+    }
 } 
\ No newline at end of file
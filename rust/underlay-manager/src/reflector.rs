@@ -0,0 +1,353 @@
+use crate::config::InterfaceConfig;
+use crate::socket::bind_tcp_probe_socket;
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// The client streams to the reflector, which reports what it received.
+const UPLOAD: u8 = b'U';
+/// The reflector streams to the client.
+const DOWNLOAD: u8 = b'D';
+/// Longest test a reflector agrees to run, so a bad request can't tie it up.
+const MAX_TEST_DURATION: Duration = Duration::from_secs(60);
+/// Time allowed beyond the test itself for connecting and the report.
+const RESPONSE_GRACE: Duration = Duration::from_secs(5);
+const DOWNLOAD_CHUNK: usize = 64 * 1024;
+/// Largest UDP probe echoed, enough for a jumbo-frame `packet_size`.
+const MAX_DATAGRAM: usize = 9000 + DATAGRAM_AUTH_LEN;
+/// Pause after a failed accept, so running out of file descriptors doesn't
+/// spin the loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Bytes an authenticated UDP probe carries beyond its body: a nonce and
+/// a tag.
+pub const DATAGRAM_AUTH_LEN: usize = NONCE_LEN + TAG_LEN;
+
+/// Pre-shared key proving requests come from a manager allowed to use the
+/// reflector, so it can't be borrowed to flood other hosts. Requests carry a
+/// Poly1305 tag, ChaCha20-Poly1305 over an empty message with the request as
+/// associated data.
+#[derive(Clone)]
+pub struct ReflectorKey(ChaCha20Poly1305);
+
+impl ReflectorKey {
+    /// Parses 64 hex characters.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        if hex.len() != 64 {
+            return Err(anyhow::anyhow!("Reflector PSK must be 64 hex characters, got {}", hex.len()));
+        }
+        // Checked up front so slicing below stays on character boundaries
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(anyhow::anyhow!("Reflector PSK is not valid hex"));
+        }
+
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .context("Reflector PSK is not valid hex")?;
+        }
+        Ok(Self(ChaCha20Poly1305::new(Key::from_slice(&key))))
+    }
+
+    fn tag(&self, nonce: &[u8; NONCE_LEN], data: &[u8]) -> Result<Vec<u8>> {
+        self.0.encrypt(Nonce::from_slice(nonce), Payload { msg: &[], aad: data })
+            .map_err(|_| anyhow::anyhow!("Failed to authenticate reflector request"))
+    }
+
+    fn verify(&self, nonce: &[u8], data: &[u8], tag: &[u8]) -> bool {
+        nonce.len() == NONCE_LEN && self.0.decrypt(Nonce::from_slice(nonce), Payload { msg: tag, aad: data }).is_ok()
+    }
+
+    /// Appends a fresh nonce and the tag over `datagram` to it.
+    pub fn sign_datagram(&self, datagram: &mut Vec<u8>) -> Result<()> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let tag = self.tag(&nonce, datagram)?;
+        datagram.extend_from_slice(&nonce);
+        datagram.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Whether `datagram` ends in a valid nonce and tag for the rest of it.
+    pub fn verify_datagram(&self, datagram: &[u8]) -> bool {
+        let Some(body_len) = datagram.len().checked_sub(DATAGRAM_AUTH_LEN) else {
+            return false;
+        };
+        let (body, auth) = datagram.split_at(body_len);
+        self.verify(&auth[..NONCE_LEN], body, &auth[NONCE_LEN..])
+    }
+}
+
+/// Measured throughput in each direction, in Mbps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    pub up_mbps: f64,
+    pub down_mbps: f64,
+}
+
+fn mbps(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 * 8.0 / elapsed.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+}
+
+/// Answers bandwidth tests from other managers' probes. Failed accepts are
+/// logged and retried.
+///
+/// Each connection opens with a random 12-byte challenge from the
+/// reflector. The client answers with a command byte, the test duration in
+/// ms as a big-endian u32, and the tag over both under `key` with the
+/// challenge as nonce; a connection that fails the check is closed. For an
+/// upload the reflector drains the connection until the client shuts its
+/// side, then replies with the bytes received and the microseconds that
+/// took, both big-endian u64. For a download it sends data for the duration
+/// and closes.
+pub async fn serve_reflector(listener: TcpListener, key: ReflectorKey) -> Result<()> {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept bandwidth test: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let key = key.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reflect(stream, &key).await {
+                debug!("Bandwidth test from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Echoes UDP datagrams back to their sender, answering other managers'
+/// UDP jitter and loss probes. Only datagrams signed under `key` (see
+/// `ReflectorKey::sign_datagram`) are echoed, whole.
+pub async fn serve_udp_echo(socket: UdpSocket, key: ReflectorKey) -> Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("UDP echo receive failed: {}", e);
+                continue;
+            }
+        };
+        if !key.verify_datagram(&buf[..len]) {
+            debug!("Dropped unauthenticated UDP probe from {}", peer);
+            continue;
+        }
+        if let Err(e) = socket.send_to(&buf[..len], peer).await {
+            debug!("UDP echo to {} failed: {}", peer, e);
+        }
+    }
+}
+
+async fn reflect(mut stream: TcpStream, key: &ReflectorKey) -> Result<()> {
+    let mut challenge = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    stream.write_all(&challenge).await?;
+
+    let mut request = [0u8; 5 + TAG_LEN];
+    tokio::time::timeout(RESPONSE_GRACE, stream.read_exact(&mut request)).await
+        .context("No bandwidth test request")??;
+    let (request, tag) = request.split_at(5);
+    if !key.verify(&challenge, request, tag) {
+        return Err(anyhow::anyhow!("Bandwidth test request failed authentication"));
+    }
+    let duration_ms = u32::from_be_bytes([request[1], request[2], request[3], request[4]]);
+    let duration = Duration::from_millis(u64::from(duration_ms)).min(MAX_TEST_DURATION);
+
+    match request[0] {
+        UPLOAD => {
+            let start = Instant::now();
+            let (mut reader, mut writer) = stream.split();
+            let received = tokio::time::timeout(duration + RESPONSE_GRACE, tokio::io::copy(&mut reader, &mut tokio::io::sink()))
+                .await
+                .context("Upload ran past its duration")??;
+            let elapsed = start.elapsed().as_micros() as u64;
+
+            let mut report = [0u8; 16];
+            report[..8].copy_from_slice(&received.to_be_bytes());
+            report[8..].copy_from_slice(&elapsed.to_be_bytes());
+            writer.write_all(&report).await?;
+        }
+        DOWNLOAD => {
+            let chunk = vec![0u8; DOWNLOAD_CHUNK];
+            let deadline = Instant::now() + duration;
+            while Instant::now() < deadline {
+                stream.write_all(&chunk).await?;
+            }
+            stream.shutdown().await?;
+        }
+        command => return Err(anyhow::anyhow!("Unknown bandwidth test command {:#04x}", command)),
+    }
+    Ok(())
+}
+
+/// Measures throughput to and from a reflector over TCP, each direction for
+/// `duration`.
+pub struct ReflectorClient {
+    target: SocketAddr,
+    duration: Duration,
+    key: ReflectorKey,
+    dscp: Option<u8>,
+}
+
+impl ReflectorClient {
+    pub fn new(target: SocketAddr, duration: Duration, key: ReflectorKey) -> Self {
+        Self { target, duration, key, dscp: None }
+    }
+
+    /// Marks the test traffic with `dscp`.
+    pub fn with_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    /// Upload, then download, from the interface's source address.
+    pub async fn measure(&self, interface: Option<&InterfaceConfig>, payload: &[u8]) -> Result<Throughput> {
+        let up_mbps = self.upload(interface, payload).await?;
+        let down_mbps = self.download(interface).await?;
+        Ok(Throughput { up_mbps, down_mbps })
+    }
+
+    /// Mbps the reflector received while `payload` was sent repeatedly.
+    pub async fn upload(&self, interface: Option<&InterfaceConfig>, payload: &[u8]) -> Result<f64> {
+        let test = async {
+            let mut stream = self.start(interface, UPLOAD).await?;
+            let deadline = Instant::now() + self.duration;
+            while Instant::now() < deadline {
+                stream.write_all(payload).await?;
+            }
+            stream.shutdown().await?;
+
+            let mut report = [0u8; 16];
+            stream.read_exact(&mut report).await?;
+            let received = u64::from_be_bytes(report[..8].try_into()?);
+            let elapsed = Duration::from_micros(u64::from_be_bytes(report[8..].try_into()?));
+            Ok(mbps(received, elapsed))
+        };
+        self.bounded(test).await
+    }
+
+    /// Mbps received while the reflector streamed to us.
+    pub async fn download(&self, interface: Option<&InterfaceConfig>) -> Result<f64> {
+        let test = async {
+            let mut stream = self.start(interface, DOWNLOAD).await?;
+            let start = Instant::now();
+            let received = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+            if received == 0 {
+                return Err(anyhow::anyhow!("Bandwidth reflector {} sent nothing", self.target));
+            }
+            Ok(mbps(received, start.elapsed()))
+        };
+        self.bounded(test).await
+    }
+
+    async fn start(&self, interface: Option<&InterfaceConfig>, command: u8) -> Result<TcpStream> {
+        let socket = bind_tcp_probe_socket(self.target, interface, self.dscp)?;
+        let mut stream = socket.connect(self.target).await
+            .with_context(|| format!("Failed to reach bandwidth reflector {}", self.target))?;
+        let mut challenge = [0u8; NONCE_LEN];
+        stream.read_exact(&mut challenge).await
+            .with_context(|| format!("No challenge from bandwidth reflector {}", self.target))?;
+
+        let duration_ms = u32::try_from(self.duration.as_millis()).unwrap_or(u32::MAX);
+        let mut request = vec![command];
+        request.extend_from_slice(&duration_ms.to_be_bytes());
+        let tag = self.key.tag(&challenge, &request)?;
+        request.extend_from_slice(&tag);
+        stream.write_all(&request).await?;
+        Ok(stream)
+    }
+
+    async fn bounded<F: std::future::Future<Output = Result<f64>>>(&self, test: F) -> Result<f64> {
+        tokio::time::timeout(self.duration + RESPONSE_GRACE, test).await
+            .map_err(|_| anyhow::anyhow!("Bandwidth test against {} timed out", self.target))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const OTHER_PSK: &str = "ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key(hex: &str) -> ReflectorKey {
+        ReflectorKey::from_hex(hex).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_both_directions_measured_against_loopback_reflector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(serve_reflector(listener, key(PSK)));
+
+        let client = ReflectorClient::new(target, Duration::from_millis(200), key(PSK));
+        let throughput = client.measure(None, &[0xa5; 1400]).await.unwrap();
+        assert!(throughput.up_mbps > 0.0, "{:?}", throughput);
+        assert!(throughput.down_mbps > 0.0, "{:?}", throughput);
+    }
+
+    #[tokio::test]
+    async fn test_reflector_refuses_other_keys() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(serve_reflector(listener, key(PSK)));
+
+        let client = ReflectorClient::new(target, Duration::from_millis(200), key(OTHER_PSK));
+        assert!(client.download(None).await.is_err());
+        assert!(client.upload(None, &[0xa5; 1400]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_signed_udp_probes_echoed() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(serve_udp_echo(echo, key(PSK)));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 128];
+
+        let mut forged = b"probe 0".to_vec();
+        key(OTHER_PSK).sign_datagram(&mut forged).unwrap();
+        client.send_to(b"probe 0", target).await.unwrap();
+        client.send_to(&forged, target).await.unwrap();
+
+        let mut signed = b"probe 1".to_vec();
+        key(PSK).sign_datagram(&mut signed).unwrap();
+        client.send_to(&signed, target).await.unwrap();
+        let (len, from) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], &signed[..]);
+        assert_eq!(from, target);
+    }
+
+    #[test]
+    fn test_reflector_key_parsing() {
+        assert!(ReflectorKey::from_hex(PSK).is_ok());
+        assert!(ReflectorKey::from_hex("abcd").is_err());
+        assert!(ReflectorKey::from_hex(&"zz".repeat(32)).is_err());
+
+        let mut datagram = b"probe".to_vec();
+        key(PSK).sign_datagram(&mut datagram).unwrap();
+        assert_eq!(datagram.len(), 5 + DATAGRAM_AUTH_LEN);
+        assert!(key(PSK).verify_datagram(&datagram));
+        datagram[0] ^= 1;
+        assert!(!key(PSK).verify_datagram(&datagram));
+        assert!(!key(PSK).verify_datagram(b"short"));
+    }
+
+    #[test]
+    fn test_mbps() {
+        assert_eq!(mbps(1_250_000, Duration::from_secs(1)), 10.0);
+        assert_eq!(mbps(1_250_000, Duration::from_millis(500)), 20.0);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::Config;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Tracks when each enabled interface is next due for probing, and for a
+/// liveness check if it has a `liveness_interval`.
+///
+/// Interfaces the scheduler isn't currently using are marked idle and probed
+/// at a reduced cadence rather than not at all, so a recovered link is still
+/// noticed and can be reselected. Interfaces whose probes keep failing are
+/// backed off the same way, so a dead target isn't probed at full rate.
+/// Liveness checks are cheap and keep their cadence in both cases.
+pub struct ProbeSchedule {
+    intervals: HashMap<String, Duration>,
+    next_due: HashMap<String, Instant>,
+    liveness_intervals: HashMap<String, Duration>,
+    liveness_next_due: HashMap<String, Instant>,
+    idle: HashSet<String>,
+    idle_multiplier: u32,
+    /// Failed probe cycles in a row, per interface.
+    consecutive_failures: HashMap<String, u32>,
+    breaker_threshold: u32,
+    breaker_max_multiplier: u32,
+}
+
+impl ProbeSchedule {
+    /// Every enabled interface starts out due immediately.
+    pub fn new(config: &Config, now: Instant) -> Self {
+        let intervals: HashMap<String, Duration> = config.interfaces.iter()
+            .filter(|i| i.enabled)
+            .map(|i| (i.name.clone(), Duration::from_millis(i.probe_interval)))
+            .collect();
+        let next_due = intervals.keys().map(|name| (name.clone(), now)).collect();
+        // The first full probe already shows whether the link is alive
+        let liveness_intervals: HashMap<String, Duration> = config.interfaces.iter()
+            .filter(|i| i.enabled)
+            .filter_map(|i| Some((i.name.clone(), Duration::from_millis(i.liveness_interval?))))
+            .collect();
+        let liveness_next_due = liveness_intervals.iter()
+            .map(|(name, interval)| (name.clone(), now + *interval))
+            .collect();
+
+        Self {
+            intervals,
+            next_due,
+            liveness_intervals,
+            liveness_next_due,
+            idle: HashSet::new(),
+            idle_multiplier: config.probes.idle_probe_multiplier.max(1),
+            consecutive_failures: HashMap::new(),
+            breaker_threshold: config.probes.circuit_breaker_threshold,
+            breaker_max_multiplier: config.probes.circuit_breaker_max_multiplier.max(1),
+        }
+    }
+
+    /// Like `new`, but each interface's first probe is due at a random point
+    /// within its interval, so interfaces sharing an interval don't all
+    /// probe at once every cycle.
+    pub fn staggered<R: Rng>(config: &Config, now: Instant, rng: &mut R) -> Self {
+        let mut schedule = Self::new(config, now);
+        for (name, due) in schedule.next_due.iter_mut() {
+            let interval = schedule.intervals[name];
+            if !interval.is_zero() {
+                *due = now + interval.mul_f64(rng.gen_range(0.0..1.0));
+            }
+        }
+        schedule
+    }
+
+    /// Interfaces whose probe is due at `now`, sorted by name.
+    pub fn due(&self, now: Instant) -> Vec<String> {
+        due_at(&self.next_due, now)
+    }
+
+    /// Interfaces whose liveness check is due at `now`, sorted by name.
+    pub fn liveness_due(&self, now: Instant) -> Vec<String> {
+        due_at(&self.liveness_next_due, now)
+    }
+
+    /// A full probe also tells whether the link is alive, so it pushes the
+    /// next liveness check back too.
+    pub fn mark_probed(&mut self, interface_name: &str, now: Instant) {
+        if let Some(interval) = self.interval(interface_name) {
+            self.next_due.insert(interface_name.to_string(), now + interval);
+        }
+        self.mark_liveness_checked(interface_name, now);
+    }
+
+    /// Counts a probe cycle's outcome; call before `mark_probed` so the next
+    /// probe is scheduled with the backoff it earned.
+    pub fn record_probe_result(&mut self, interface_name: &str, success: bool) {
+        if success {
+            self.consecutive_failures.remove(interface_name);
+        } else {
+            *self.consecutive_failures.entry(interface_name.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// How many times less often the interface is probed for failing:
+    /// 2 once `circuit_breaker_threshold` cycles in a row failed, doubling
+    /// with each further failure, up to `circuit_breaker_max_multiplier`.
+    pub fn failure_multiplier(&self, interface_name: &str) -> u32 {
+        let failures = self.consecutive_failures.get(interface_name).copied().unwrap_or(0);
+        if self.breaker_threshold == 0 || failures < self.breaker_threshold {
+            return 1;
+        }
+        2u32.saturating_pow(failures - self.breaker_threshold + 1).min(self.breaker_max_multiplier)
+    }
+
+    pub fn mark_liveness_checked(&mut self, interface_name: &str, now: Instant) {
+        if let Some(interval) = self.liveness_intervals.get(interface_name) {
+            self.liveness_next_due.insert(interface_name.to_string(), now + *interval);
+        }
+    }
+
+    /// Makes the interface's full probe due at `now`, e.g. once a liveness
+    /// check finds a link that was failing reachable again.
+    pub fn probe_now(&mut self, interface_name: &str, now: Instant) {
+        if let Some(due) = self.next_due.get_mut(interface_name) {
+            *due = (*due).min(now);
+        }
+    }
+
+    /// Marks an interface idle (probed `idle_multiplier` times less often) or
+    /// active again. Reactivating pulls its next probe forward to the normal
+    /// cadence.
+    pub fn set_idle(&mut self, interface_name: &str, idle: bool, now: Instant) {
+        if idle {
+            self.idle.insert(interface_name.to_string());
+        } else if self.idle.remove(interface_name) {
+            if let (Some(interval), Some(due)) = (self.interval(interface_name), self.next_due.get_mut(interface_name)) {
+                *due = (*due).min(now + interval);
+            }
+        }
+    }
+
+    pub fn is_idle(&self, interface_name: &str) -> bool {
+        self.idle.contains(interface_name)
+    }
+
+    /// Time until the next full probe is due, or `None` if nothing is
+    /// scheduled.
+    pub fn next_due_in(&self, now: Instant) -> Option<Duration> {
+        self.next_due.values().min().map(|due| due.saturating_duration_since(now))
+    }
+
+    /// Time until the next liveness check is due, or `None` if no interface
+    /// has a `liveness_interval`.
+    pub fn next_liveness_due_in(&self, now: Instant) -> Option<Duration> {
+        self.liveness_next_due.values().min().map(|due| due.saturating_duration_since(now))
+    }
+
+    /// Whether any interface gets liveness checks.
+    pub fn has_liveness_checks(&self) -> bool {
+        !self.liveness_intervals.is_empty()
+    }
+
+    fn interval(&self, interface_name: &str) -> Option<Duration> {
+        let interval = *self.intervals.get(interface_name)? * self.failure_multiplier(interface_name);
+        if self.idle.contains(interface_name) {
+            Some(interval * self.idle_multiplier)
+        } else {
+            Some(interval)
+        }
+    }
+}
+
+fn due_at(next_due: &HashMap<String, Instant>, now: Instant) -> Vec<String> {
+    let mut due: Vec<String> = next_due.iter()
+        .filter(|(_, due)| **due <= now)
+        .map(|(name, _)| name.clone())
+        .collect();
+    due.sort();
+    due
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_interfaces_due_at_start_then_after_interval() {
+        let start = Instant::now();
+        let mut schedule = ProbeSchedule::new(&Config::default(), start);
+        assert_eq!(schedule.due(start), vec!["eth0", "eth1"]);
+
+        schedule.mark_probed("eth0", start);
+        schedule.mark_probed("eth1", start);
+        assert!(schedule.due(start + Duration::from_millis(4999)).is_empty());
+        assert_eq!(schedule.due(start + Duration::from_millis(5000)).len(), 2);
+    }
+
+    #[test]
+    fn test_idle_interface_probed_at_reduced_cadence() {
+        let start = Instant::now();
+        let mut schedule = ProbeSchedule::new(&Config::default(), start);
+        schedule.set_idle("eth1", true, start);
+        schedule.mark_probed("eth0", start);
+        schedule.mark_probed("eth1", start);
+
+        // Default multiplier is 4: eth1 is next due after 20s instead of 5s
+        assert_eq!(schedule.due(start + Duration::from_secs(5)), vec!["eth0"]);
+        assert_eq!(schedule.due(start + Duration::from_secs(20)), vec!["eth0", "eth1"]);
+    }
+
+    #[test]
+    fn test_reactivated_interface_returns_to_normal_cadence() {
+        let start = Instant::now();
+        let mut schedule = ProbeSchedule::new(&Config::default(), start);
+        schedule.set_idle("eth1", true, start);
+        schedule.mark_probed("eth1", start);
+
+        schedule.set_idle("eth1", false, start + Duration::from_secs(1));
+        assert!(!schedule.is_idle("eth1"));
+        assert_eq!(schedule.due(start + Duration::from_secs(6)), vec!["eth0", "eth1"]);
+    }
+
+    #[test]
+    fn test_disabled_interfaces_not_scheduled() {
+        let mut config = Config::default();
+        config.interfaces[1].enabled = false;
+        let start = Instant::now();
+        let schedule = ProbeSchedule::new(&config, start);
+        assert_eq!(schedule.due(start), vec!["eth0"]);
+    }
+
+    #[test]
+    fn test_staggered_start_spreads_probes_over_the_interval() {
+        let mut config = Config::default();
+        for name in ["eth2", "eth3"] {
+            let mut interface = config.interfaces[0].clone();
+            interface.name = name.to_string();
+            config.interfaces.push(interface);
+        }
+        let start = Instant::now();
+        let interval = Duration::from_millis(5000);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let schedule = ProbeSchedule::staggered(&config, start, &mut rng);
+
+        let mut offsets: Vec<Duration> = schedule.next_due.values().map(|due| *due - start).collect();
+        offsets.sort();
+        offsets.dedup();
+        assert_eq!(offsets.len(), 4, "probes start together: {:?}", offsets);
+        assert!(offsets.iter().all(|offset| *offset < interval));
+
+        // The first probes come due one at a time across the interval
+        let due_counts: Vec<usize> = offsets.iter().map(|offset| schedule.due(start + *offset).len()).collect();
+        assert_eq!(due_counts, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_liveness_checked_more_often_than_full_probes() {
+        let mut config = Config::default();
+        config.interfaces[0].liveness_interval = Some(500);
+        config.interfaces[1].enabled = false;
+        let start = Instant::now();
+        let mut schedule = ProbeSchedule::new(&config, start);
+
+        // Step through 10s the way the probe and liveness loops do
+        let (mut probes, mut liveness_checks) = (0, 0);
+        let mut now = start;
+        while now < start + Duration::from_secs(10) {
+            for name in schedule.due(now) {
+                probes += 1;
+                schedule.mark_probed(&name, now);
+            }
+            for name in schedule.liveness_due(now) {
+                liveness_checks += 1;
+                schedule.mark_liveness_checked(&name, now);
+            }
+            let next = schedule.next_due_in(now).into_iter().chain(schedule.next_liveness_due_in(now)).min();
+            now += next.unwrap().max(Duration::from_millis(1));
+        }
+        assert_eq!(probes, 2);
+        // Every 500ms, except where a full probe stood in for one
+        assert_eq!(liveness_checks, 18);
+    }
+
+    #[test]
+    fn test_probe_now_pulls_full_probe_forward() {
+        let start = Instant::now();
+        let mut schedule = ProbeSchedule::new(&Config::default(), start);
+        schedule.mark_probed("eth0", start);
+        let later = start + Duration::from_secs(1);
+        assert!(!schedule.due(later).contains(&"eth0".to_string()));
+
+        schedule.probe_now("eth0", later);
+        assert!(schedule.due(later).contains(&"eth0".to_string()));
+    }
+
+    #[test]
+    fn test_persistently_failing_interface_probed_less_often() {
+        let mut config = Config::default();
+        config.interfaces[1].enabled = false;
+        let mut now = Instant::now();
+        let mut schedule = ProbeSchedule::new(&config, now);
+        let mut intervals = Vec::new();
+        for _ in 0..7 {
+            schedule.record_probe_result("eth0", false);
+            schedule.mark_probed("eth0", now);
+            let interval = schedule.next_due_in(now).unwrap();
+            intervals.push(interval.as_secs());
+            now += interval;
+        }
+        // Default threshold 3, capped at 8x the 5s interval
+        assert_eq!(intervals, vec![5, 5, 10, 20, 40, 40, 40]);
+        
+        // One success restores the normal cadence
+        schedule.record_probe_result("eth0", true);
+        schedule.mark_probed("eth0", now);
+        assert_eq!(schedule.next_due_in(now), Some(Duration::from_secs(5)));
+    }
+}
@@ -1,67 +1,584 @@
+use crate::clock::{Clock, SystemClock};
+use crate::discovery::{Discovery, Peer};
+use crate::events::{EventBus, HealthTracker};
+use crate::kernel_stats::{KernelStats, KernelStatsCollector};
+use crate::log_limit::RateLimitedLogger;
+use crate::metrics::{MetricsCache, RedundancyAlert, RedundancyMonitor};
+use crate::proto::{
+    DestinationMetric, DestinationMetricsRequest, DestinationMetricsResponse, DestinationMetricsService,
+    MetricsDiffRequest, MetricsDiffResponse, MetricsDiffService, PeerInfo, PeerListRequest, PeerListResponse,
+    PeerService, ProbeResponse, RpcReply, RpcRequest, SetIdleResponse,
+};
+use crate::schedule::ProbeSchedule;
+#[cfg(feature = "snmp")]
+use crate::snmp::SnmpExporter;
+#[cfg(feature = "statsd")]
+use crate::statsd::StatsdExporter;
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::webhook::WebhookNotifier;
 use crate::{Config, NetworkProbe, LinkMetrics};
 use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::{FuturesUnordered, StreamExt};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+/// Upper bound on how long the probe loop sleeps, so idle-state changes are
+/// picked up promptly.
+const MAX_SCHEDULE_WAIT: Duration = Duration::from_secs(1);
+
+/// Minimum time between repeats of the same probe or export error log.
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct UnderlayManagerServer {
     config: Config,
     probe: Arc<NetworkProbe>,
-    metrics_cache: Arc<RwLock<HashMap<String, LinkMetrics>>>,
+    metrics_cache: Arc<RwLock<MetricsCache>>,
+    /// Each interface's metrics towards each `probes.destination_probes`
+    /// prefix, keyed by interface and prefix.
+    destination_metrics: Arc<RwLock<HashMap<(String, String), LinkMetrics>>>,
+    schedule: Arc<RwLock<ProbeSchedule>>,
+    discovery: Option<Arc<Discovery>>,
+    /// Where link health changes are published.
+    events: EventBus,
+    webhook: Option<Arc<WebhookNotifier>>,
+    /// Set while fewer than `min_healthy_links` links are healthy.
+    redundancy_lost: Arc<AtomicBool>,
+    #[cfg(feature = "statsd")]
+    statsd: Option<Arc<StatsdExporter>>,
+    #[cfg(feature = "snmp")]
+    snmp: Option<Arc<SnmpExporter>>,
+    /// Stamps metrics, diffs and link events.
+    clock: Arc<dyn Clock>,
+    shutdown: CancellationToken,
 }
 
 impl UnderlayManagerServer {
     pub fn new(config: Config) -> Self {
-        let probe = Arc::new(NetworkProbe::new(config.clone()));
-        let metrics_cache = Arc::new(RwLock::new(HashMap::new()));
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// Stamps everything it reports with `clock` instead of the system clock.
+    pub fn with_clock(config: Config, clock: Arc<dyn Clock>) -> Self {
+        let probe = Arc::new(NetworkProbe::new(config.clone()).with_clock(clock.clone()));
+        let metrics_cache = Arc::new(RwLock::new(MetricsCache::new(config.server.metrics_diff_threshold)));
+        let schedule = Arc::new(RwLock::new(ProbeSchedule::staggered(&config, Instant::now(), &mut rand::thread_rng())));
+        let discovery = config.discovery.as_ref().and_then(|discovery| {
+            match Discovery::new(discovery, config.server.grpc_port) {
+                Ok(discovery) => Some(Arc::new(discovery)),
+                Err(e) => {
+                    error!("Peer discovery disabled: {}", e);
+                    None
+                }
+            }
+        });
+        let webhook = config.webhook.as_ref().and_then(|webhook| {
+            match WebhookNotifier::new(webhook) {
+                Ok(notifier) => Some(Arc::new(notifier)),
+                Err(e) => {
+                    error!("Webhook notifications disabled: {}", e);
+                    None
+                }
+            }
+        });
         
         Self {
             config,
             probe,
             metrics_cache,
+            destination_metrics: Arc::new(RwLock::new(HashMap::new())),
+            schedule,
+            discovery,
+            events: EventBus::new(),
+            webhook,
+            redundancy_lost: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "statsd")]
+            statsd: None,
+            #[cfg(feature = "snmp")]
+            snmp: None,
+            clock,
+            shutdown: CancellationToken::new(),
         }
     }
 
     pub async fn start(&self, addr: String) -> Result<()> {
-        info!("Starting Underlay Manager server on {} with {} interfaces", addr, self.config.interfaces.len());
+        let listener = TcpListener::bind(&addr).await?;
+        self.start_on(listener).await
+    }
+
+    /// Like `start`, answering RPC clients on an already bound `listener`.
+    pub async fn start_on(&self, listener: TcpListener) -> Result<()> {
+        info!("Starting Underlay Manager server on {} with {} interfaces", listener.local_addr()?, self.config.interfaces.len());
         
         // Start metrics collection in background
         let probe = self.probe.clone();
         let metrics_cache = self.metrics_cache.clone();
+        let destination_metrics = self.destination_metrics.clone();
+        let schedule = self.schedule.clone();
+        
+        let devices: Vec<(String, String)> = self.config.interfaces.iter()
+            .filter(|interface| interface.enabled)
+            .map(|interface| (interface.name.clone(), interface.device_name()))
+            .collect();
+        let min_healthy_links = self.config.server.min_healthy_links;
+        let healthy_threshold = self.config.probes.healthy_threshold;
+        let redundancy_lost = self.redundancy_lost.clone();
+        let redundancy_grace = redundancy_grace(&self.config);
+        let events = self.events.clone();
+        let clock = self.clock.clone();
         
-        tokio::spawn(async move {
-            loop {
-                match probe.probe_all_interfaces().await {
-                    Ok(metrics) => {
-                        let mut cache = metrics_cache.write().await;
-                        *cache = metrics;
-                        debug!("Updated metrics cache with {} interfaces", cache.len());
+        let probe_task = supervise("probe loop", RestartPolicy::default(), self.shutdown.clone(), move || {
+            let probe = probe.clone();
+            let metrics_cache = metrics_cache.clone();
+            let destination_metrics = destination_metrics.clone();
+            let schedule = schedule.clone();
+            let devices = devices.clone();
+            let redundancy_lost = redundancy_lost.clone();
+            let events = events.clone();
+            let clock = clock.clone();
+            async move {
+                let collector = KernelStatsCollector::new();
+                let log_limit = RateLimitedLogger::new(LOG_INTERVAL);
+                let mut redundancy = min_healthy_links.map(|min| RedundancyMonitor::new(min, healthy_threshold));
+                let mut health = HealthTracker::new(healthy_threshold);
+                let started = Instant::now();
+                loop {
+                    // Kernel state is cheap to read, so it's checked on every
+                    // pass rather than when a probe is due
+                    let kernel_stats = read_kernel_stats(&collector, &devices);
+                    apply_kernel_stats(&mut *metrics_cache.write().await, &kernel_stats, clock.now());
+                    
+                    let due = schedule.read().await.due(Instant::now());
+                    for (interface_name, result) in probe.probe_interfaces(&due).await {
+                        schedule.write().await.record_probe_result(&interface_name, result.as_ref().is_ok_and(|metrics| !metrics.stale));
+                        match result {
+                            Ok(mut metrics) => {
+                                if let Some(stats) = kernel_stats.get(&interface_name) {
+                                    stats.apply(&mut metrics);
+                                }
+                                let latencies = probe.probe_destinations(&interface_name).await;
+                                apply_destination_latencies(&mut *destination_metrics.write().await, &interface_name, &metrics, latencies);
+                                metrics_cache.write().await.insert(interface_name.clone(), metrics);
+                                debug!("Updated metrics for interface {}", interface_name);
+                            }
+                            Err(e) => {
+                                if let Some(suppressed) = log_limit.check(&interface_name) {
+                                    error!("Failed to probe interface {}: {}{}", interface_name, e, suppressed);
+                                }
+                            }
+                        }
+                        schedule.write().await.mark_probed(&interface_name, Instant::now());
                     }
-                    Err(e) => {
-                        error!("Failed to collect metrics: {}", e);
+                    
+                    let metrics = metrics_cache.read().await.snapshot();
+                    for event in health.observe(&metrics, clock.now()) {
+                        info!("Link {} is now {}", event.link_name, if event.healthy { "healthy" } else { "unhealthy" });
+                        events.publish(event);
                     }
+                    
+                    if let Some(ref mut redundancy) = redundancy {
+                        if redundancy_checkable(&metrics, devices.len(), started.elapsed(), redundancy_grace) {
+                            check_redundancy(redundancy, &metrics);
+                            redundancy_lost.store(redundancy.is_lost(), Ordering::Relaxed);
+                        }
+                    }
+                    
+                    let wait = schedule.read().await
+                        .next_due_in(Instant::now())
+                        .unwrap_or(MAX_SCHEDULE_WAIT)
+                        .min(MAX_SCHEDULE_WAIT);
+                    tokio::time::sleep(wait).await;
                 }
-                
-                tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
             }
         });
 
-        // TODO: Implement actual gRPC server
-        // For now, just keep the server running
+        // Liveness checks run on their own cadence, so a full probe timing
+        // out on a dead link doesn't delay spotting other dead links
+        if self.schedule.read().await.has_liveness_checks() {
+            let probe = self.probe.clone();
+            let metrics_cache = self.metrics_cache.clone();
+            let schedule = self.schedule.clone();
+            supervise("liveness checks", RestartPolicy::default(), self.shutdown.clone(), move || {
+                let probe = probe.clone();
+                let metrics_cache = metrics_cache.clone();
+                let schedule = schedule.clone();
+                async move {
+                    loop {
+                        let due = schedule.read().await.liveness_due(Instant::now());
+                        for (interface_name, result) in probe.check_liveness_of(&due).await {
+                            if let Err(ref e) = result {
+                                debug!("Liveness check failed for {}: {}", interface_name, e);
+                            }
+                            let recovered = apply_liveness(&mut *metrics_cache.write().await, &interface_name, result.is_ok());
+                            let mut schedule = schedule.write().await;
+                            schedule.mark_liveness_checked(&interface_name, Instant::now());
+                            if recovered {
+                                schedule.probe_now(&interface_name, Instant::now());
+                            }
+                        }
+
+                        // Capped since full probes push liveness checks back
+                        let wait = schedule.read().await
+                            .next_liveness_due_in(Instant::now())
+                            .unwrap_or(MAX_SCHEDULE_WAIT)
+                            .min(MAX_SCHEDULE_WAIT);
+                        tokio::time::sleep(wait).await;
+                    }
+                }
+            });
+        }
+
+        if let Some(ref discovery) = self.discovery {
+            let discovery = discovery.clone();
+            supervise("discovery", RestartPolicy::default(), self.shutdown.clone(), move || {
+                let discovery = discovery.clone();
+                async move { discovery.run().await }
+            });
+        }
+
+        if let Some(ref webhook) = self.webhook {
+            let webhook = webhook.clone();
+            let events = self.events.clone();
+            supervise("webhook", RestartPolicy::default(), self.shutdown.clone(), move || {
+                let webhook = webhook.clone();
+                let events = events.subscribe();
+                async move { webhook.run(events).await }
+            });
+        }
+
+        #[cfg(feature = "statsd")]
+        if let Some(ref statsd) = self.statsd {
+            let statsd = statsd.clone();
+            let metrics_cache = self.metrics_cache.clone();
+            let interval = Duration::from_millis(self.config.server.metrics_interval);
+            supervise("statsd export", RestartPolicy::default(), self.shutdown.clone(), move || {
+                let statsd = statsd.clone();
+                let metrics_cache = metrics_cache.clone();
+                async move {
+                    let log_limit = RateLimitedLogger::new(LOG_INTERVAL);
+                    loop {
+                        tokio::time::sleep(interval).await;
+                        let metrics = metrics_cache.read().await.snapshot();
+                        if let Err(e) = statsd.export(&metrics) {
+                            if let Some(suppressed) = log_limit.check("statsd") {
+                                error!("StatsD export failed: {}{}", e, suppressed);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        #[cfg(feature = "snmp")]
+        if let Some(ref snmp) = self.snmp {
+            let snmp = snmp.clone();
+            let metrics_cache = self.metrics_cache.clone();
+            supervise("snmp agent", RestartPolicy::default(), self.shutdown.clone(), move || {
+                let snmp = snmp.clone();
+                let metrics_cache = metrics_cache.clone();
+                async move { snmp.serve(metrics_cache).await }
+            });
+        }
+
+        // Run until stopped or the probe loop keeps failing
+        tokio::select! {
+            result = probe_task => result?,
+            result = self.serve_rpc(listener) => result,
+        }
+    }
+
+    /// Answers RPC clients, up to `server.max_connections` at a time.
+    async fn serve_rpc(&self, listener: TcpListener) -> Result<()> {
+        let mut connections = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("Failed to accept RPC client: {}", e);
+                            continue;
+                        }
+                    };
+                    if connections.len() >= self.config.server.max_connections {
+                        warn!("Refusing RPC client {}: {} connections open", peer, connections.len());
+                        continue;
+                    }
+                    connections.push(self.serve_connection(stream, peer));
+                }
+                Some(()) = connections.next(), if !connections.is_empty() => {}
+            }
+        }
+    }
+
+    /// Answers each request line from one client until it disconnects.
+    async fn serve_connection(&self, stream: TcpStream, peer: SocketAddr) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(e) => {
+                    debug!("RPC client {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let mut reply = serde_json::to_vec(&self.handle_rpc(&line).await).expect("RPC replies serialize");
+            reply.push(b'\n');
+            if let Err(e) = writer.write_all(&reply).await {
+                debug!("RPC client {} failed: {}", peer, e);
+                return;
+            }
+        }
+    }
+
+    async fn handle_rpc(&self, line: &str) -> RpcReply {
+        let request = match serde_json::from_str::<RpcRequest>(line) {
+            Ok(request) => request,
+            Err(e) => return RpcReply::Error(format!("Invalid request: {}", e)),
+        };
+        let result = match request {
+            RpcRequest::MetricsDiff(request) => self.get_metrics_diff(request).await
+                .map(|response| serde_json::to_value(response).expect("responses serialize")),
+            RpcRequest::ListPeers(request) => self.list_peers(request).await
+                .map(|response| serde_json::to_value(response).expect("responses serialize")),
+            RpcRequest::DestinationMetrics(request) => self.get_destination_metrics(request).await
+                .map(|response| serde_json::to_value(response).expect("responses serialize")),
+            RpcRequest::SetIdle(request) => match self.set_interface_idle(&request.interface_name, request.idle).await {
+                Ok(()) => Ok(serde_json::to_value(SetIdleResponse {}).expect("responses serialize")),
+                Err(e) => Err(e.into()),
+            },
+        };
+        match result {
+            Ok(result) => RpcReply::Result(result),
+            Err(e) => RpcReply::Error(e.to_string()),
         }
     }
 
+    /// Stops the probe loop, making `start` return.
+    pub fn stop(&self) {
+        self.shutdown.cancel();
+    }
+
     pub async fn get_metrics(&self) -> Result<HashMap<String, LinkMetrics>> {
-        let cache = self.metrics_cache.read().await;
-        Ok(cache.clone())
+        Ok(self.metrics_cache.read().await.snapshot())
     }
 
+    /// Probes the interface now and returns the result. It is merged into
+    /// the interface's cached metrics, so a problem it spots is reported
+    /// without waiting for the next background probe, which replaces them.
     pub async fn probe_interface(&self, interface_name: &str) -> Result<LinkMetrics> {
-        self.probe.probe_interface(interface_name).await
+        let metrics = self.probe.probe_interface(interface_name).await?;
+        let mut cache = self.metrics_cache.write().await;
+        let merged = match cache.get(interface_name) {
+            Some(cached) => {
+                let mut merged = cached.clone();
+                merged.merge(&metrics);
+                merged
+            }
+            None => metrics.clone(),
+        };
+        cache.insert(interface_name.to_string(), merged);
+        Ok(metrics)
+    }
+
+    /// Also pushes the metrics to StatsD every `metrics_interval`.
+    #[cfg(feature = "statsd")]
+    pub fn set_statsd_exporter(&mut self, exporter: StatsdExporter) {
+        self.statsd = Some(Arc::new(exporter));
+    }
+
+    /// Also answers SNMP GET and GETNEXT requests for the metrics.
+    #[cfg(feature = "snmp")]
+    pub fn set_snmp_exporter(&mut self, exporter: SnmpExporter) {
+        self.snmp = Some(Arc::new(exporter));
+    }
+
+    /// Link health changes seen by the probe loop.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Whether fewer than `server.min_healthy_links` links are healthy.
+    /// Always false when the minimum isn't configured.
+    pub fn redundancy_lost(&self) -> bool {
+        self.redundancy_lost.load(Ordering::Relaxed)
+    }
+
+    /// Peers found by discovery; empty when discovery is off.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.discovery.as_ref().map(|discovery| discovery.peers()).unwrap_or_default()
+    }
+
+    /// Called with the scheduler's view of which links it isn't selecting.
+    /// Idle interfaces keep being probed, just less often.
+    pub async fn set_interface_idle(&self, interface_name: &str, idle: bool) -> Result<()> {
+        if !self.config.interfaces.iter().any(|i| i.name == interface_name) {
+            return Err(anyhow::anyhow!("Interface {} not found in configuration", interface_name));
+        }
+        self.schedule.write().await.set_idle(interface_name, idle, Instant::now());
+        Ok(())
+    }
+}
+
+/// How long after startup links that were never probed start counting
+/// as unhealthy: two of the longest probe intervals, so every link had its
+/// staggered first probe and a retry.
+fn redundancy_grace(config: &Config) -> Duration {
+    let longest = config.interfaces.iter()
+        .filter(|interface| interface.enabled)
+        .map(|interface| interface.probe_interval)
+        .max()
+        .unwrap_or(0);
+    Duration::from_millis(longest) * 2
+}
+
+/// Whether redundancy can be judged yet. Links not yet probed would
+/// otherwise count as unhealthy right after startup, so until every link
+/// has reported the check waits out `grace`; after that a link that never
+/// answered counts as unhealthy rather than holding the check off forever.
+fn redundancy_checkable(metrics: &HashMap<String, LinkMetrics>, links: usize, since_start: Duration, grace: Duration) -> bool {
+    metrics.len() >= links || since_start >= grace
+}
+
+fn check_redundancy(monitor: &mut RedundancyMonitor, metrics: &HashMap<String, LinkMetrics>) {
+    match monitor.check(metrics) {
+        Some(RedundancyAlert::Lost { healthy, required }) => {
+            warn!("Redundancy lost: {} of {} required links healthy", healthy, required);
+        }
+        Some(RedundancyAlert::Restored { healthy, required }) => {
+            info!("Redundancy restored: {} of {} required links healthy", healthy, required);
+        }
+        None => {}
+    }
+}
+
+/// Kernel stats of each `(interface, device)` pair that could be read.
+fn read_kernel_stats(collector: &KernelStatsCollector, devices: &[(String, String)]) -> HashMap<String, KernelStats> {
+    devices.iter()
+        .filter_map(|(interface_name, device)| match collector.read(device) {
+            Ok(stats) => Some((interface_name.clone(), stats)),
+            Err(e) => {
+                debug!("No kernel stats for {}: {}", interface_name, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Folds fresh kernel stats into the cached metrics, so a lost carrier
+/// marks its link unhealthy without waiting for the next probe. Links seen
+/// for the first time are stamped `now`.
+fn apply_kernel_stats(cache: &mut MetricsCache, kernel_stats: &HashMap<String, KernelStats>, now: DateTime<Utc>) {
+    for (interface_name, stats) in kernel_stats {
+        let metrics = match cache.get(interface_name) {
+            Some(metrics) => metrics.clone(),
+            // Nothing to report until the first probe, unless it's down
+            None if stats.carrier_up => continue,
+            None => LinkMetrics::at(now),
+        };
+        if metrics.carrier_up != stats.carrier_up {
+            info!("Interface {} carrier {}", interface_name, if stats.carrier_up { "up" } else { "down" });
+        }
+        let mut updated = metrics;
+        stats.apply(&mut updated);
+        cache.insert(interface_name.clone(), updated);
+    }
+}
+
+/// Marks an interface that failed its liveness check as losing everything,
+/// so the scheduler stops using it without waiting for the next full probe.
+/// Returns whether a link seen losing everything answered again, meaning
+/// it deserves a full probe now.
+fn apply_liveness(cache: &mut MetricsCache, interface_name: &str, alive: bool) -> bool {
+    let Some(metrics) = cache.get(interface_name) else {
+        // Nothing to report until the first full probe
+        return false;
+    };
+    let total_loss = metrics.packet_loss >= 1.0;
+    if alive {
+        return total_loss;
+    }
+    if !total_loss {
+        warn!("Interface {} failed its liveness check", interface_name);
+        let mut updated = metrics.clone();
+        updated.packet_loss = 1.0;
+        cache.insert(interface_name.to_string(), updated);
+    }
+    false
+}
+
+/// Replaces the interface's per-destination metrics with its latest
+/// `metrics`, each with the latency measured towards that destination.
+/// Prefixes whose probe failed are dropped rather than left stale.
+fn apply_destination_latencies(
+    destinations: &mut HashMap<(String, String), LinkMetrics>,
+    interface_name: &str,
+    metrics: &LinkMetrics,
+    latencies: HashMap<String, f64>,
+) {
+    destinations.retain(|(name, _), _| name != interface_name);
+    for (prefix, latency_ms) in latencies {
+        let mut towards = metrics.clone();
+        towards.latency_ms = latency_ms;
+        destinations.insert((interface_name.to_string(), prefix), towards);
+    }
+}
+
+#[async_trait]
+impl MetricsDiffService for UnderlayManagerServer {
+    async fn get_metrics_diff(&self, request: MetricsDiffRequest) -> Result<MetricsDiffResponse, Box<dyn std::error::Error>> {
+        let cache = self.metrics_cache.read().await;
+        let mut metrics: Vec<ProbeResponse> = cache.changed_since(request.since_version).iter()
+            .map(|(name, metrics)| ProbeResponse::from_metrics(name, metrics))
+            .collect();
+        metrics.sort_by(|a, b| a.interface_name.cmp(&b.interface_name));
+        
+        Ok(MetricsDiffResponse {
+            version: cache.version(),
+            metrics,
+            timestamp: self.clock.now().to_rfc3339(),
+        })
+    }
+}
+
+#[async_trait]
+impl DestinationMetricsService for UnderlayManagerServer {
+    async fn get_destination_metrics(&self, _request: DestinationMetricsRequest) -> Result<DestinationMetricsResponse, Box<dyn std::error::Error>> {
+        let destinations = self.destination_metrics.read().await;
+        let mut metrics: Vec<DestinationMetric> = destinations.iter()
+            .map(|((interface_name, prefix), metrics)| DestinationMetric {
+                prefix: prefix.clone(),
+                metrics: ProbeResponse::from_metrics(interface_name, metrics),
+            })
+            .collect();
+        metrics.sort_by(|a, b| (&a.metrics.interface_name, &a.prefix).cmp(&(&b.metrics.interface_name, &b.prefix)));
+        
+        Ok(DestinationMetricsResponse {
+            metrics,
+            timestamp: self.clock.now().to_rfc3339(),
+        })
+    }
+}
+
+#[async_trait]
+impl PeerService for UnderlayManagerServer {
+    async fn list_peers(&self, _request: PeerListRequest) -> Result<PeerListResponse, Box<dyn std::error::Error>> {
+        let peers = self.peers().into_iter()
+            .map(|peer| PeerInfo { node_id: peer.node_id, endpoint: peer.endpoint.to_string() })
+            .collect();
+        Ok(PeerListResponse { peers })
     }
 }
 
@@ -75,4 +592,233 @@ mod tests {
         let server = UnderlayManagerServer::new(config);
         assert!(server.get_metrics().await.is_ok());
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_set_interface_idle() {
+        let server = UnderlayManagerServer::new(Config::default());
+        server.set_interface_idle("eth1", true).await.unwrap();
+        assert!(server.schedule.read().await.is_idle("eth1"));
+        assert!(server.set_interface_idle("wlan9", true).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_metrics_diff_sends_only_changed_interfaces() {
+        let server = UnderlayManagerServer::new(Config::default());
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 10.0;
+        metrics.bandwidth_mbps = 100.0;
+        {
+            let mut cache = server.metrics_cache.write().await;
+            cache.insert("eth0".to_string(), metrics.clone());
+            cache.insert("eth1".to_string(), metrics.clone());
+        }
+        
+        let full = server.get_metrics_diff(MetricsDiffRequest { since_version: 0 }).await.unwrap();
+        assert_eq!(full.metrics.len(), 2);
+        
+        metrics.packet_loss = 0.2;
+        server.metrics_cache.write().await.insert("eth1".to_string(), metrics);
+        let diff = server.get_metrics_diff(MetricsDiffRequest { since_version: full.version }).await.unwrap();
+        assert_eq!(diff.metrics.len(), 1);
+        assert_eq!(diff.metrics[0].interface_name, "eth1");
+        assert!(diff.version > full.version);
+        
+        let empty = server.get_metrics_diff(MetricsDiffRequest { since_version: diff.version }).await.unwrap();
+        assert!(empty.metrics.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_diff_stamped_by_injected_clock() {
+        let start = "2024-05-01T12:00:00Z".parse().unwrap();
+        let clock = Arc::new(crate::clock::MockClock::new(start));
+        let server = UnderlayManagerServer::with_clock(Config::default(), clock.clone());
+        clock.advance(chrono::Duration::seconds(90));
+
+        let diff = server.get_metrics_diff(MetricsDiffRequest { since_version: 0 }).await.unwrap();
+        assert_eq!(diff.timestamp, "2024-05-01T12:01:30+00:00");
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_probe_merged_into_cache() {
+        let mut config = Config::default();
+        config.probes.bandwidth_test_duration = 100;
+        let server = UnderlayManagerServer::new(config);
+        let mut background = LinkMetrics::at(Utc::now() - chrono::Duration::seconds(10));
+        background.latency_ms = 10_000.0;
+        background.packet_loss = 0.4;
+        server.metrics_cache.write().await.insert("lo".to_string(), background);
+        
+        let on_demand = server.probe_interface("lo").await.unwrap();
+        let cached = server.get_metrics().await.unwrap()["lo"].clone();
+        // The loss the background probe saw isn't hidden, the better latency
+        // and newer timestamp are taken
+        assert_eq!(cached.packet_loss, 0.4);
+        assert_eq!(cached.latency_ms, on_demand.latency_ms);
+        assert_eq!(cached.timestamp, on_demand.timestamp);
+    }
+    
+    #[tokio::test]
+    async fn test_start_returns_when_stopped() {
+        let server = Arc::new(UnderlayManagerServer::new(Config::default()));
+        let running = tokio::spawn({
+            let server = server.clone();
+            async move { server.start("127.0.0.1:0".to_string()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!running.is_finished());
+
+        server.stop();
+        tokio::time::timeout(Duration::from_secs(1), running).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_metrics_diff_served_over_rpc() {
+        let mut config = Config::default();
+        config.interfaces.clear();
+        let server = Arc::new(UnderlayManagerServer::new(config));
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 10.0;
+        server.metrics_cache.write().await.insert("wan0".to_string(), metrics);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let running = tokio::spawn({
+            let server = server.clone();
+            async move { server.start_on(listener).await }
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut replies = BufReader::new(reader).lines();
+        writer.write_all(b"{\"method\": \"metrics_diff\", \"params\": {\"since_version\": 0}}\n").await.unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&replies.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(reply["result"]["version"], 1);
+        assert_eq!(reply["result"]["metrics"][0]["interface_name"], "wan0");
+
+        writer.write_all(b"{\"method\": \"reboot\"}\n").await.unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&replies.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(reply["error"].as_str().unwrap().starts_with("Invalid request"));
+
+        server.stop();
+        tokio::time::timeout(Duration::from_secs(1), running).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peers_listed_over_rpc() {
+        let server = UnderlayManagerServer::new(Config::default());
+        let RpcReply::Result(result) = server.handle_rpc(r#"{"method": "list_peers", "params": {}}"#).await else {
+            panic!("list_peers failed");
+        };
+        assert_eq!(result, serde_json::json!({"peers": []}));
+    }
+
+    #[tokio::test]
+    async fn test_destination_metrics_replaced_each_probe() {
+        let server = UnderlayManagerServer::new(Config::default());
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 10.0;
+        metrics.packet_loss = 0.02;
+        {
+            let mut destinations = server.destination_metrics.write().await;
+            let latencies = HashMap::from([("203.0.113.0/24".to_string(), 80.0), ("198.51.100.0/24".to_string(), 30.0)]);
+            apply_destination_latencies(&mut destinations, "eth0", &metrics, latencies);
+            apply_destination_latencies(&mut destinations, "eth1", &metrics, HashMap::from([("203.0.113.0/24".to_string(), 20.0)]));
+        }
+
+        let RpcReply::Result(result) = server.handle_rpc(r#"{"method": "destination_metrics", "params": {}}"#).await else {
+            panic!("destination_metrics failed");
+        };
+        let response: DestinationMetricsResponse = serde_json::from_value(result).unwrap();
+        let listed: Vec<_> = response.metrics.iter()
+            .map(|metric| (metric.metrics.interface_name.as_str(), metric.prefix.as_str(), metric.metrics.latency_ms))
+            .collect();
+        assert_eq!(listed, [("eth0", "198.51.100.0/24", 30.0), ("eth0", "203.0.113.0/24", 80.0), ("eth1", "203.0.113.0/24", 20.0)]);
+        // The rest of each measurement is the link's own
+        assert_eq!(response.metrics[0].metrics.packet_loss, 0.02);
+
+        // A destination whose probe failed is dropped, not left stale
+        apply_destination_latencies(&mut *server.destination_metrics.write().await, "eth0", &metrics,
+            HashMap::from([("198.51.100.0/24".to_string(), 35.0)]));
+        let destinations = server.destination_metrics.read().await;
+        assert!(!destinations.contains_key(&("eth0".to_string(), "203.0.113.0/24".to_string())));
+        assert_eq!(destinations[&("eth0".to_string(), "198.51.100.0/24".to_string())].latency_ms, 35.0);
+        assert_eq!(destinations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_idle_state_set_over_rpc() {
+        let server = UnderlayManagerServer::new(Config::default());
+        let reply = server.handle_rpc(r#"{"method": "set_idle", "params": {"interface_name": "eth1", "idle": true}}"#).await;
+        assert!(matches!(reply, RpcReply::Result(_)));
+        assert!(server.schedule.read().await.is_idle("eth1"));
+
+        let reply = server.handle_rpc(r#"{"method": "set_idle", "params": {"interface_name": "wlan9", "idle": true}}"#).await;
+        assert!(matches!(reply, RpcReply::Error(e) if e.contains("wlan9")));
+    }
+
+    #[test]
+    fn test_lost_carrier_published_before_next_probe() {
+        let mut cache = MetricsCache::new(0.05);
+        let mut probed = LinkMetrics::new();
+        probed.latency_ms = 5.0;
+        probed.bandwidth_mbps = 500.0;
+        cache.insert("eth0".to_string(), probed);
+        let version = cache.version();
+        
+        let stats = |carrier_up| KernelStats { speed_mbps: Some(1000), carrier_up, rx_dropped: 0, tx_dropped: 0 };
+        let now: DateTime<Utc> = "2024-05-01T12:00:00Z".parse().unwrap();
+        apply_kernel_stats(&mut cache, &HashMap::from([("eth0".to_string(), stats(true))]), now);
+        assert_eq!(cache.version(), version);
+        
+        // eth1 hasn't been probed yet, but a down carrier is still news
+        apply_kernel_stats(&mut cache, &HashMap::from([
+            ("eth0".to_string(), stats(false)),
+            ("eth1".to_string(), stats(false)),
+        ]), now);
+        let changed = cache.changed_since(version);
+        assert_eq!(changed.len(), 2);
+        assert!(changed.values().all(|metrics| !metrics.is_healthy(0.1)));
+        assert_eq!(changed["eth1"].timestamp, now);
+    }
+
+    #[test]
+    fn test_failed_liveness_check_published_before_next_probe() {
+        let mut cache = MetricsCache::new(0.05);
+        assert!(!apply_liveness(&mut cache, "eth0", false));
+        assert!(cache.get("eth0").is_none());
+        
+        let mut probed = LinkMetrics::new();
+        probed.latency_ms = 5.0;
+        probed.bandwidth_mbps = 500.0;
+        cache.insert("eth0".to_string(), probed);
+        let version = cache.version();
+        assert!(!apply_liveness(&mut cache, "eth0", true));
+        assert_eq!(cache.version(), version);
+        
+        assert!(!apply_liveness(&mut cache, "eth0", false));
+        // Total loss is what the scheduler treats as down
+        assert_eq!(cache.get("eth0").unwrap().packet_loss, 1.0);
+        assert!(cache.version() > version);
+        
+        // Answering again calls for a full probe to measure it properly
+        assert!(apply_liveness(&mut cache, "eth0", true));
+    }
+
+    #[test]
+    fn test_never_probed_link_counts_as_unhealthy_after_grace() {
+        let config = Config::default();
+        let grace = redundancy_grace(&config);
+        assert_eq!(grace, Duration::from_millis(config.interfaces[0].probe_interval * 2));
+
+        // eth1 never answered
+        let metrics = HashMap::from([("eth0".to_string(), LinkMetrics::new())]);
+        assert!(!redundancy_checkable(&metrics, 2, grace / 2, grace));
+        assert!(redundancy_checkable(&metrics, 2, grace, grace));
+
+        let mut monitor = RedundancyMonitor::new(2, 0.3);
+        let mut healthy = LinkMetrics::new();
+        healthy.latency_ms = 5.0;
+        healthy.bandwidth_mbps = 100.0;
+        let metrics = HashMap::from([("eth0".to_string(), healthy)]);
+        assert_eq!(monitor.check(&metrics), Some(RedundancyAlert::Lost { healthy: 1, required: 2 }));
+    }
+}
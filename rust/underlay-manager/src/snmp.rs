@@ -0,0 +1,411 @@
+use crate::metrics::MetricsCache;
+use crate::LinkMetrics;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use tracing::debug;
+
+/// Enterprise subtree the link table is published under by default. 32473
+/// is the private enterprise number reserved for documentation (RFC 5612).
+pub const DEFAULT_BASE_OID: &[u32] = &[1, 3, 6, 1, 4, 1, 32473, 1];
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+const PDU_GET: u8 = 0xa0;
+const PDU_GET_NEXT: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+
+const VERSION_1: i64 = 0;
+const VERSION_2C: i64 = 1;
+/// SNMPv1's error for an object the agent doesn't have.
+const NO_SUCH_NAME: i64 = 2;
+
+/// Read-only SNMP agent (v1 and v2c, GET and GETNEXT) publishing the
+/// metrics cache as a table under `<base>.1.1.<column>.<row>`, one row per
+/// interface in name order. SNMP has no floating point, so columns are
+/// integers in finer units:
+///
+/// | column | value |
+/// |--------|-------|
+/// | 1 | interface name |
+/// | 2 | latency, microseconds |
+/// | 3 | jitter, microseconds |
+/// | 4 | packet loss, parts per million |
+/// | 5 | bandwidth, kbit/s |
+/// | 6 | reliability, parts per million |
+pub struct SnmpExporter {
+    socket: UdpSocket,
+    community: String,
+    base_oid: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Text(String),
+    Gauge(u32),
+}
+
+impl SnmpExporter {
+    pub async fn bind(listen: &str, community: &str) -> Result<Self> {
+        let socket = UdpSocket::bind(listen).await
+            .with_context(|| format!("Failed to bind SNMP agent to {}", listen))?;
+        Ok(Self {
+            socket,
+            community: community.to_string(),
+            base_oid: DEFAULT_BASE_OID.to_vec(),
+        })
+    }
+
+    pub fn with_base_oid(mut self, base_oid: &[u32]) -> Self {
+        self.base_oid = base_oid.to_vec();
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Answers requests from the current contents of `metrics_cache` until
+    /// the socket fails. Malformed requests and ones with the wrong
+    /// community are dropped, as agents do.
+    pub async fn serve(&self, metrics_cache: Arc<RwLock<MetricsCache>>) -> Result<()> {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, peer) = self.socket.recv_from(&mut buf).await?;
+            let metrics = metrics_cache.read().await.snapshot();
+            match self.respond(&buf[..len], &metrics) {
+                Some(response) => {
+                    self.socket.send_to(&response, peer).await?;
+                }
+                None => debug!("Ignoring SNMP request from {}", peer),
+            }
+        }
+    }
+
+    fn respond(&self, request: &[u8], metrics: &HashMap<String, LinkMetrics>) -> Option<Vec<u8>> {
+        let (TAG_SEQUENCE, message, _) = read_tlv(request)? else { return None };
+        let (version, rest) = read_integer(message)?;
+        if version != VERSION_1 && version != VERSION_2C {
+            return None;
+        }
+        let (TAG_OCTET_STRING, community, rest) = read_tlv(rest)? else { return None };
+        if community != self.community.as_bytes() {
+            return None;
+        }
+        let (pdu_type, pdu, _) = read_tlv(rest)?;
+        if pdu_type != PDU_GET && pdu_type != PDU_GET_NEXT {
+            return None;
+        }
+        let (request_id, rest) = read_integer(pdu)?;
+        let (_, rest) = read_integer(rest)?;
+        let (_, rest) = read_integer(rest)?;
+        let (TAG_SEQUENCE, mut bindings, _) = read_tlv(rest)? else { return None };
+
+        let table = self.table(metrics);
+        let mut error = (0, 0);
+        let mut encoded = Vec::new();
+        let mut index = 0;
+        while !bindings.is_empty() {
+            let (TAG_SEQUENCE, binding, rest) = read_tlv(bindings)? else { return None };
+            bindings = rest;
+            index += 1;
+            let (TAG_OID, oid, _) = read_tlv(binding)? else { return None };
+            let oid = decode_oid(oid)?;
+
+            let found = if pdu_type == PDU_GET {
+                table.iter().find(|(candidate, _)| *candidate == oid)
+            } else {
+                table.iter().find(|(candidate, _)| *candidate > oid)
+            };
+            let (oid, value) = match found {
+                Some((oid, value)) => (oid.clone(), encode_value(value)),
+                None if version == VERSION_1 => {
+                    // v1 has no exception values: the whole request fails
+                    if error.0 == 0 {
+                        error = (NO_SUCH_NAME, index);
+                    }
+                    (oid, tlv(TAG_NULL, &[]))
+                }
+                None if pdu_type == PDU_GET => (oid, tlv(TAG_NO_SUCH_OBJECT, &[])),
+                None => (oid, tlv(TAG_END_OF_MIB_VIEW, &[])),
+            };
+            encoded.extend(tlv(TAG_SEQUENCE, &[tlv(TAG_OID, &encode_oid(&oid)), value].concat()));
+        }
+
+        let pdu = [
+            encode_integer(request_id),
+            encode_integer(error.0),
+            encode_integer(error.1),
+            tlv(TAG_SEQUENCE, &encoded),
+        ].concat();
+        let message = [
+            encode_integer(version),
+            tlv(TAG_OCTET_STRING, self.community.as_bytes()),
+            tlv(PDU_RESPONSE, &pdu),
+        ].concat();
+        Some(tlv(TAG_SEQUENCE, &message))
+    }
+
+    /// Every object the agent serves, in OID order.
+    fn table(&self, metrics: &HashMap<String, LinkMetrics>) -> Vec<(Vec<u32>, Value)> {
+        let mut names: Vec<&String> = metrics.keys().collect();
+        names.sort();
+        let gauge = |value: f64| Value::Gauge(value.clamp(0.0, u32::MAX as f64).round() as u32);
+
+        let mut table = Vec::new();
+        for (row, name) in names.iter().enumerate() {
+            let link = &metrics[*name];
+            let columns = [
+                Value::Text(name.to_string()),
+                gauge(link.latency_ms * 1000.0),
+                gauge(link.jitter_ms * 1000.0),
+                gauge(link.packet_loss * 1_000_000.0),
+                gauge(link.bandwidth_mbps * 1000.0),
+                gauge(link.reliability * 1_000_000.0),
+            ];
+            for (column, value) in columns.into_iter().enumerate() {
+                let mut oid = self.base_oid.clone();
+                oid.extend([1, 1, column as u32 + 1, row as u32 + 1]);
+                table.push((oid, value));
+            }
+        }
+        table.sort_by(|a, b| a.0.cmp(&b.0));
+        table
+    }
+}
+
+/// Splits one BER element off the front of `data`: its tag, its contents
+/// and whatever follows.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets].iter().fold(0usize, |len, &byte| (len << 8) | byte as usize);
+        (len, &rest[octets..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn read_integer(data: &[u8]) -> Option<(i64, &[u8])> {
+    let (TAG_INTEGER, contents, rest) = read_tlv(data)? else { return None };
+    if contents.is_empty() || contents.len() > 8 {
+        return None;
+    }
+    let sign = if contents[0] & 0x80 != 0 { -1i64 } else { 0 };
+    let value = contents.iter().fold(sign, |value, &byte| (value << 8) | byte as i64);
+    Some((value, rest))
+}
+
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if contents.len() < 0x80 {
+        encoded.push(contents.len() as u8);
+    } else {
+        let len = (contents.len() as u32).to_be_bytes();
+        let skip = len.iter().take_while(|&&byte| byte == 0).count();
+        encoded.push(0x80 | (4 - skip) as u8);
+        encoded.extend(&len[skip..]);
+    }
+    encoded.extend(contents);
+    encoded
+}
+
+fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Minimal two's complement: drop leading bytes that only repeat the sign
+    let mut start = 0;
+    while start < 7 {
+        let redundant = (bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    tlv(TAG_INTEGER, &bytes[start..])
+}
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Text(text) => tlv(TAG_OCTET_STRING, text.as_bytes()),
+        Value::Gauge(gauge) => {
+            // Unsigned, so a leading zero keeps the top bit clear
+            let bytes = (*gauge as u64).to_be_bytes();
+            let skip = bytes.iter().take(7).take_while(|&&byte| byte == 0).count();
+            let skip = if bytes[skip] & 0x80 != 0 { skip - 1 } else { skip };
+            tlv(TAG_GAUGE32, &bytes[skip..])
+        }
+    }
+}
+
+fn decode_oid(contents: &[u8]) -> Option<Vec<u32>> {
+    let (&first, rest) = contents.split_first()?;
+    let top = (first / 40).min(2);
+    let mut oid = vec![top as u32, (first - 40 * top) as u32];
+    let mut arc: u32 = 0;
+    for &byte in rest {
+        arc = arc.checked_mul(128)? | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            oid.push(arc);
+            arc = 0;
+        }
+    }
+    Some(oid)
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut encoded = vec![(oid[0] * 40 + oid.get(1).copied().unwrap_or(0)) as u8];
+    for &arc in oid.iter().skip(2) {
+        let mut groups = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            groups.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        encoded.extend(groups.iter().rev());
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn request(pdu_type: u8, community: &str, request_id: i64, oids: &[Vec<u32>]) -> Vec<u8> {
+        let bindings: Vec<u8> = oids.iter()
+            .flat_map(|oid| tlv(TAG_SEQUENCE, &[tlv(TAG_OID, &encode_oid(oid)), tlv(TAG_NULL, &[])].concat()))
+            .collect();
+        let pdu = [encode_integer(request_id), encode_integer(0), encode_integer(0), tlv(TAG_SEQUENCE, &bindings)].concat();
+        let message = [encode_integer(VERSION_2C), tlv(TAG_OCTET_STRING, community.as_bytes()), tlv(pdu_type, &pdu)].concat();
+        tlv(TAG_SEQUENCE, &message)
+    }
+
+    /// `(oid, tag, contents)` of a returned variable binding.
+    type Binding = (Vec<u32>, u8, Vec<u8>);
+
+    /// The request id and each binding.
+    fn parse_response(response: &[u8]) -> (i64, Vec<Binding>) {
+        let (TAG_SEQUENCE, message, _) = read_tlv(response).unwrap() else { panic!("not a sequence") };
+        let (_, rest) = read_integer(message).unwrap();
+        let (_, _, rest) = read_tlv(rest).unwrap();
+        let (PDU_RESPONSE, pdu, _) = read_tlv(rest).unwrap() else { panic!("not a response") };
+        let (request_id, rest) = read_integer(pdu).unwrap();
+        let (error_status, rest) = read_integer(rest).unwrap();
+        assert_eq!(error_status, 0);
+        let (_, rest) = read_integer(rest).unwrap();
+        let (_, mut bindings, _) = read_tlv(rest).unwrap();
+
+        let mut values = Vec::new();
+        while !bindings.is_empty() {
+            let (_, binding, rest) = read_tlv(bindings).unwrap();
+            bindings = rest;
+            let (_, oid, value) = read_tlv(binding).unwrap();
+            let (tag, contents, _) = read_tlv(value).unwrap();
+            values.push((decode_oid(oid).unwrap(), tag, contents.to_vec()));
+        }
+        (request_id, values)
+    }
+
+    fn column(column: u32, row: u32) -> Vec<u32> {
+        [DEFAULT_BASE_OID, &[1, 1, column, row]].concat()
+    }
+
+    fn gauge(contents: &[u8]) -> u32 {
+        contents.iter().fold(0, |value, &byte| (value << 8) | byte as u32)
+    }
+
+    async fn agent() -> (Arc<SnmpExporter>, std::net::UdpSocket) {
+        let mut cache = MetricsCache::new(0.1);
+        let mut wan0 = LinkMetrics::new();
+        wan0.latency_ms = 12.5;
+        wan0.packet_loss = 0.02;
+        wan0.bandwidth_mbps = 250.0;
+        let mut wan1 = LinkMetrics::new();
+        wan1.latency_ms = 40.0;
+        cache.insert("wan1".to_string(), wan1);
+        cache.insert("wan0".to_string(), wan0);
+
+        let exporter = Arc::new(SnmpExporter::bind("127.0.0.1:0", "monitor").await.unwrap());
+        let serving = exporter.clone();
+        tokio::spawn(async move { serving.serve(Arc::new(RwLock::new(cache))).await });
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(exporter.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        (exporter, client)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_returns_link_metrics() {
+        let (_agent, client) = agent().await;
+        let oids = [column(1, 1), column(2, 1), column(4, 1), column(5, 1), column(2, 2), column(9, 1)];
+        client.send(&request(PDU_GET, "monitor", 0x1234_5678, &oids)).unwrap();
+
+        let mut buf = [0u8; 1500];
+        let len = client.recv(&mut buf).unwrap();
+        let (request_id, values) = parse_response(&buf[..len]);
+        assert_eq!(request_id, 0x1234_5678);
+        assert_eq!(values.iter().map(|(oid, _, _)| oid.clone()).collect::<Vec<_>>(), oids);
+
+        // Rows follow interface name order
+        assert_eq!((values[0].1, values[0].2.as_slice()), (TAG_OCTET_STRING, b"wan0".as_slice()));
+        assert_eq!((values[1].1, gauge(&values[1].2)), (TAG_GAUGE32, 12_500));
+        assert_eq!(gauge(&values[2].2), 20_000);
+        assert_eq!(gauge(&values[3].2), 250_000);
+        assert_eq!(gauge(&values[4].2), 40_000);
+        assert_eq!(values[5].1, TAG_NO_SUCH_OBJECT);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_next_walks_table() {
+        let (_agent, client) = agent().await;
+        let mut buf = [0u8; 1500];
+
+        client.send(&request(PDU_GET_NEXT, "monitor", 1, &[DEFAULT_BASE_OID.to_vec(), column(1, 2), column(6, 2)])).unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        let (_, values) = parse_response(&buf[..len]);
+        assert_eq!((values[0].0.clone(), values[0].2.as_slice()), (column(1, 1), b"wan0".as_slice()));
+        assert_eq!(values[1].0, column(2, 1));
+        assert_eq!(values[2].1, TAG_END_OF_MIB_VIEW);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wrong_community_ignored() {
+        let (_agent, client) = agent().await;
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        client.send(&request(PDU_GET, "public", 1, &[column(2, 1)])).unwrap();
+        assert!(client.recv(&mut [0u8; 1500]).is_err());
+    }
+
+    #[test]
+    fn test_ber_round_trips() {
+        for value in [0, 1, 127, 128, 255, 256, -1, -128, -129, i64::from(i32::MAX)] {
+            let encoded = encode_integer(value);
+            assert_eq!(read_integer(&encoded).unwrap().0, value);
+        }
+        let oid = vec![1, 3, 6, 1, 4, 1, 32473, 1, 1, 1, 200_000, 3];
+        assert_eq!(decode_oid(&encode_oid(&oid)).unwrap(), oid);
+
+        let long = tlv(TAG_OCTET_STRING, &[7u8; 300]);
+        assert_eq!(&long[..4], &[TAG_OCTET_STRING, 0x82, 0x01, 0x2c]);
+        assert_eq!(read_tlv(&long).unwrap().1.len(), 300);
+    }
+} 
\ No newline at end of file
@@ -0,0 +1,191 @@
+use crate::config::InterfaceConfig;
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::{TcpSocket, UdpSocket};
+use tracing::debug;
+
+/// Creates a UDP probe socket pinned to the given interface.
+///
+/// If the interface has a `source_address` the socket is bound to it, which
+/// makes the kernel pick the route for that address. On Linux the socket is
+/// also bound to the interface's device with `SO_BINDTODEVICE`, see
+/// `bind_interface_device`. Outgoing packets are marked with `dscp` when
+/// given.
+pub fn bind_probe_socket(interface: &InterfaceConfig, dscp: Option<u8>) -> Result<Socket> {
+    let source_ip = match interface.source_address {
+        Some(ref addr) => addr.parse::<IpAddr>().with_context(|| {
+            format!("Invalid source_address {} for interface {}", addr, interface.name)
+        })?,
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let local = SocketAddr::new(source_ip, 0);
+
+    let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(dscp) = dscp {
+        set_probe_dscp(&socket, local.is_ipv6(), dscp)?;
+    }
+
+    bind_interface_device(SockRef::from(&socket), interface)?;
+
+    socket
+        .bind(&SockAddr::from(local))
+        .with_context(|| format!("Failed to bind probe socket for {} to {}", interface.name, local))?;
+    debug!("Probe socket for {} bound to {}", interface.name, local);
+
+    Ok(socket)
+}
+
+/// Creates a UDP probe socket connected to `target`, from the interface's
+/// probe socket when it has one (see `bind_probe_socket`) and otherwise
+/// from any local address.
+pub fn connect_udp_probe_socket(target: SocketAddr, interface: Option<&InterfaceConfig>, dscp: Option<u8>) -> Result<UdpSocket> {
+    let socket = match interface {
+        Some(interface) => bind_probe_socket(interface, dscp)?,
+        None => {
+            let socket = Socket::new(Domain::for_address(target), Type::DGRAM, Some(Protocol::UDP))?;
+            if let Some(dscp) = dscp {
+                set_probe_dscp(&socket, target.is_ipv6(), dscp)?;
+            }
+            socket
+        }
+    };
+    socket.set_nonblocking(true)?;
+    socket.connect(&SockAddr::from(target))
+        .with_context(|| format!("Failed to connect probe socket to {}", target))?;
+    Ok(UdpSocket::from_std(std::net::UdpSocket::from(socket))?)
+}
+
+/// Creates a TCP socket for connecting to `target` from the interface's
+/// source address, when it has one, and device, marked with `dscp` when
+/// given.
+pub fn bind_tcp_probe_socket(target: SocketAddr, interface: Option<&InterfaceConfig>, dscp: Option<u8>) -> Result<TcpSocket> {
+    let socket = if target.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+    if let Some(dscp) = dscp {
+        set_probe_dscp(&SockRef::from(&socket), target.is_ipv6(), dscp)?;
+    }
+    if let Some(interface) = interface {
+        if let Some(ref addr) = interface.source_address {
+            let source: IpAddr = addr.parse()
+                .with_context(|| format!("Invalid source_address {} for interface {}", addr, interface.name))?;
+            socket.bind(SocketAddr::new(source, 0))?;
+        }
+        bind_interface_device(SockRef::from(&socket), interface)?;
+    }
+    Ok(socket)
+}
+
+/// Binds a probe socket to the interface's VRF device, so it takes the VRF's
+/// routes, or else to the interface (or its VLAN subinterface). Binding needs
+/// CAP_NET_RAW; without a VRF that is only logged, since probes still leave
+/// by the source address, but a probe outside its VRF would measure the
+/// wrong path.
+#[cfg(target_os = "linux")]
+fn bind_interface_device(socket: SockRef<'_>, interface: &InterfaceConfig) -> Result<()> {
+    match interface.vrf {
+        Some(ref vrf) => socket.bind_device(Some(vrf.as_bytes()))
+            .with_context(|| format!("Failed to bind probe socket for {} into VRF {}", interface.name, vrf)),
+        None => {
+            let device = interface.device_name();
+            if let Err(e) = socket.bind_device(Some(device.as_bytes())) {
+                debug!("SO_BINDTODEVICE {} not applied: {}", device, e);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_interface_device(_socket: SockRef<'_>, interface: &InterfaceConfig) -> Result<()> {
+    match interface.vrf {
+        Some(ref vrf) => Err(anyhow::anyhow!("Interface {}: VRF {} needs Linux", interface.name, vrf)),
+        None => Ok(()),
+    }
+}
+
+/// Marks packets sent on `socket` with `dscp` in the IPv4 TOS byte or the
+/// IPv6 traffic class, leaving the ECN bits clear.
+pub fn set_probe_dscp(socket: &Socket, ipv6: bool, dscp: u8) -> Result<()> {
+    let tos = u32::from(dscp) << 2;
+    if !ipv6 {
+        socket.set_tos(tos).with_context(|| format!("Failed to set probe DSCP {}", dscp))?;
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    socket.set_tclass_v6(tos).with_context(|| format!("Failed to set probe DSCP {}", dscp))?;
+    #[cfg(not(target_os = "linux"))]
+    debug!("Probe DSCP {} not applied to IPv6 socket on this platform", dscp);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(source_address: Option<&str>) -> InterfaceConfig {
+        InterfaceConfig {
+            name: "lo".to_string(),
+            enabled: true,
+            probe_interval: 5000,
+            liveness_interval: None,
+            icmp_enabled: true,
+            udp_enabled: true,
+            bandwidth_test_enabled: false,
+            source_address: source_address.map(|s| s.to_string()),
+            vlan_id: None,
+            vrf: None,
+        }
+    }
+
+    #[test]
+    fn test_probe_socket_bound_to_source_address() {
+        let socket = bind_probe_socket(&interface(Some("127.0.0.1")), None).unwrap();
+        let local = socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(local.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_ne!(local.port(), 0);
+    }
+
+    #[test]
+    fn test_probe_socket_invalid_source_address() {
+        assert!(bind_probe_socket(&interface(Some("not-an-ip")), None).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_probe_socket_marks_dscp() {
+        // EF (46) occupies the upper six bits of the TOS byte
+        let socket = bind_probe_socket(&interface(Some("127.0.0.1")), Some(46)).unwrap();
+        assert_eq!(socket.tos().unwrap(), 0xb8);
+
+        let unmarked = bind_probe_socket(&interface(Some("127.0.0.1")), None).unwrap();
+        assert_eq!(unmarked.tos().unwrap(), 0);
+
+        let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9);
+        let tcp = bind_tcp_probe_socket(target, None, Some(46)).unwrap();
+        assert_eq!(SockRef::from(&tcp).tos().unwrap(), 0xb8);
+        let udp = connect_udp_probe_socket(target, None, Some(46)).unwrap();
+        assert_eq!(SockRef::from(&udp).tos().unwrap(), 0xb8);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_probe_sockets_bound_into_vrf() {
+        let mut unknown = interface(Some("127.0.0.1"));
+        unknown.vrf = Some("vrf-missing0".to_string());
+        assert!(bind_probe_socket(&unknown, None).is_err());
+
+        // Loopback stands in for a VRF device
+        let mut in_vrf = interface(Some("127.0.0.1"));
+        in_vrf.vrf = Some("lo".to_string());
+        let Ok(socket) = bind_probe_socket(&in_vrf, None) else {
+            // SO_BINDTODEVICE needs CAP_NET_RAW
+            return;
+        };
+        assert_eq!(socket.device().unwrap().as_deref(), Some(&b"lo"[..]));
+
+        let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9);
+        let tcp = bind_tcp_probe_socket(target, Some(&in_vrf), None).unwrap();
+        assert_eq!(SockRef::from(&tcp).device().unwrap().as_deref(), Some(&b"lo"[..]));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::LinkMetrics;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+const DEFAULT_PREFIX: &str = "underlay";
+
+/// Pushes link metrics to a StatsD server over UDP: delays as timers, the
+/// rest as gauges, named `<prefix>.<interface>.<metric>`.
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl StatsdExporter {
+    pub fn connect(target: &str) -> Result<Self> {
+        let server = target.to_socket_addrs()
+            .with_context(|| format!("Invalid StatsD address {}", target))?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("StatsD address {} did not resolve", target))?;
+        let local: SocketAddr = if server.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse()?;
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)
+            .with_context(|| format!("Failed to connect to StatsD server {}", server))?;
+
+        Ok(Self {
+            socket,
+            prefix: DEFAULT_PREFIX.to_string(),
+        })
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Sends one datagram per interface holding all of its metrics.
+    pub fn export(&self, metrics: &HashMap<String, LinkMetrics>) -> Result<()> {
+        for (interface, metrics) in metrics {
+            let datagram = self.lines(interface, metrics).join("\n");
+            self.socket.send(datagram.as_bytes())
+                .with_context(|| format!("Failed to send StatsD metrics for {}", interface))?;
+        }
+        Ok(())
+    }
+
+    fn lines(&self, interface: &str, metrics: &LinkMetrics) -> Vec<String> {
+        // Dots separate StatsD name segments, so VLAN subinterfaces like
+        // eth0.100 would otherwise split into two levels
+        let name = format!("{}.{}", self.prefix, interface.replace('.', "_"));
+        [
+            ("latency_ms", metrics.latency_ms, "ms"),
+            ("jitter_ms", metrics.jitter_ms, "ms"),
+            ("bufferbloat_ms", metrics.bufferbloat_ms, "ms"),
+            ("packet_loss", metrics.packet_loss, "g"),
+            ("bandwidth_mbps", metrics.bandwidth_mbps, "g"),
+            ("reliability", metrics.reliability, "g"),
+        ]
+        .iter()
+        .map(|(metric, value, kind)| format!("{}.{}:{}|{}", name, metric, value, kind))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_sent_as_statsd_lines() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+        let exporter = StatsdExporter::connect(&server.local_addr().unwrap().to_string()).unwrap();
+
+        let mut link = LinkMetrics::new();
+        link.latency_ms = 12.5;
+        link.jitter_ms = 1.5;
+        link.packet_loss = 0.01;
+        link.bandwidth_mbps = 100.0;
+        link.reliability = 0.9;
+        let metrics = HashMap::from([("eth0.100".to_string(), link)]);
+        exporter.export(&metrics).unwrap();
+
+        let mut buf = [0u8; 1500];
+        let len = server.recv(&mut buf).unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&buf[..len]).unwrap().lines().collect();
+        assert_eq!(lines, [
+            "underlay.eth0_100.latency_ms:12.5|ms",
+            "underlay.eth0_100.jitter_ms:1.5|ms",
+            "underlay.eth0_100.bufferbloat_ms:0|ms",
+            "underlay.eth0_100.packet_loss:0.01|g",
+            "underlay.eth0_100.bandwidth_mbps:100|g",
+            "underlay.eth0_100.reliability:0.9|g",
+        ]);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::config::{InterfaceConfig, TargetFailurePolicy};
+use crate::log_limit::RateLimitedLogger;
+use crate::socket::bind_tcp_probe_socket;
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// How latency is measured on this host.
+pub enum LatencyProbe {
+    Icmp,
+    /// Used where ICMP sockets need privileges we don't have.
+    TcpConnect(TcpConnectProbe),
+    /// TCP connects to each of `probes.probe_targets`.
+    Targets(MultiTargetProbe),
+}
+
+/// Whether this process may send ICMP echo requests: through a raw socket,
+/// or on Linux an unprivileged ping socket (`net.ipv4.ping_group_range`).
+pub fn icmp_permitted() -> bool {
+    if Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).is_ok() {
+        return true;
+    }
+    #[cfg(target_os = "linux")]
+    if Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4)).is_ok() {
+        return true;
+    }
+    false
+}
+
+/// Measures latency as the time to open a TCP connection to `target`. A
+/// refusal takes one round trip too, so a closed port works as well as an
+/// open one; only a timeout counts as failure. Needs no privileges.
+pub struct TcpConnectProbe {
+    target: SocketAddr,
+    timeout: Duration,
+    dscp: Option<u8>,
+}
+
+impl TcpConnectProbe {
+    pub fn new(target: SocketAddr, timeout: Duration) -> Self {
+        Self { target, timeout, dscp: None }
+    }
+
+    /// Marks the probe's packets with `dscp`.
+    pub fn with_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    /// Connect time in ms, from the interface's source address when it has
+    /// one.
+    pub async fn measure(&self, interface: Option<&InterfaceConfig>) -> Result<f64> {
+        let socket = bind_tcp_probe_socket(self.target, interface, self.dscp)?;
+        let start = Instant::now();
+        match tokio::time::timeout(self.timeout, socket.connect(self.target)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => {}
+            Ok(Err(e)) => return Err(e).with_context(|| format!("TCP probe to {} failed", self.target)),
+            Err(_) => return Err(anyhow::anyhow!("TCP probe to {} timed out after {:?}", self.target, self.timeout)),
+        }
+        Ok(start.elapsed().as_secs_f64() * 1000.0)
+    }
+}
+
+/// How often a link that keeps losing some of its probe targets is logged.
+const FAILED_TARGET_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Measures latency against several independent targets at once, so a
+/// failed target (say, a gateway that stopped answering) can be told apart
+/// from a failed link.
+pub struct MultiTargetProbe {
+    targets: Vec<TcpConnectProbe>,
+    policy: TargetFailurePolicy,
+    log_limit: RateLimitedLogger,
+}
+
+impl MultiTargetProbe {
+    pub fn new(targets: &[SocketAddr], timeout: Duration, policy: TargetFailurePolicy) -> Self {
+        Self {
+            targets: targets.iter().map(|target| TcpConnectProbe::new(*target, timeout)).collect(),
+            policy,
+            log_limit: RateLimitedLogger::new(FAILED_TARGET_LOG_INTERVAL),
+        }
+    }
+
+    /// Marks every target's probe packets with `dscp`.
+    pub fn with_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.targets = self.targets.into_iter().map(|target| target.with_dscp(dscp)).collect();
+        self
+    }
+
+    /// Connect time in ms to the nearest target that answered.
+    pub async fn measure(&self, interface: Option<&InterfaceConfig>) -> Result<f64> {
+        let results = futures::future::join_all(self.targets.iter().map(|target| target.measure(interface))).await;
+        let link = interface.map_or("default route", |interface| interface.name.as_str());
+        combine_target_results(results, self.policy, link, &self.log_limit)
+    }
+}
+
+/// The lowest latency among the targets that answered: the targets sit at
+/// different distances, so an average would jump whenever one of them stops
+/// answering, while the nearest bounds the link's own delay.
+fn combine_target_results(results: Vec<Result<f64>>, policy: TargetFailurePolicy, link: &str, log_limit: &RateLimitedLogger) -> Result<f64> {
+    let total = results.len();
+    let mut latencies = Vec::new();
+    let mut errors = Vec::new();
+    for result in results {
+        match result {
+            Ok(latency) => latencies.push(latency),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if latencies.is_empty() {
+        return Err(anyhow::anyhow!("All {} probe targets failed: {}", total, errors.join("; ")));
+    }
+    if !errors.is_empty() {
+        if policy == TargetFailurePolicy::Any {
+            return Err(anyhow::anyhow!("{} of {} probe targets failed: {}", errors.len(), total, errors.join("; ")));
+        }
+        // The link reached the others, so the target is the likelier fault
+        if let Some(suppressed) = log_limit.check(link) {
+            warn!("{} of {} probe targets failed via {}, link still up: {}{}", errors.len(), total, link, errors.join("; "), suppressed);
+        }
+    }
+    Ok(latencies.into_iter().fold(f64::INFINITY, f64::min))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connect_time_measured_for_open_and_closed_ports() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let probe = TcpConnectProbe::new(open, Duration::from_secs(1));
+        let latency = probe.measure(None).await.unwrap();
+        assert!((0.0..1000.0).contains(&latency));
+
+        // Nothing listens once the listener is gone, so the connect is refused
+        drop(listener);
+        let probe = TcpConnectProbe::new(open, Duration::from_secs(1));
+        assert!(probe.measure(None).await.is_ok());
+    }
+
+    #[test]
+    fn test_all_policy_fails_only_when_every_target_fails() {
+        let failed = || Err(anyhow::anyhow!("timed out"));
+        let all = TargetFailurePolicy::All;
+        let log_limit = RateLimitedLogger::new(FAILED_TARGET_LOG_INTERVAL);
+        assert_eq!(combine_target_results(vec![Ok(10.0), failed(), Ok(30.0)], all, "eth0", &log_limit).unwrap(), 10.0);
+        assert!(combine_target_results(vec![failed(), failed()], all, "eth0", &log_limit).is_err());
+    }
+
+    #[test]
+    fn test_any_policy_fails_on_one_target() {
+        let failed = || Err(anyhow::anyhow!("timed out"));
+        let any = TargetFailurePolicy::Any;
+        let log_limit = RateLimitedLogger::new(FAILED_TARGET_LOG_INTERVAL);
+        assert_eq!(combine_target_results(vec![Ok(10.0), Ok(30.0)], any, "eth0", &log_limit).unwrap(), 10.0);
+        assert!(combine_target_results(vec![Ok(10.0), failed()], any, "eth0", &log_limit).is_err());
+    }
+
+    #[test]
+    fn test_losing_the_far_target_keeps_latency() {
+        let failed = || Err(anyhow::anyhow!("timed out"));
+        let log_limit = RateLimitedLogger::new(FAILED_TARGET_LOG_INTERVAL);
+        let all = TargetFailurePolicy::All;
+        let both = combine_target_results(vec![Ok(10.0), Ok(80.0)], all, "eth0", &log_limit).unwrap();
+        let near_only = combine_target_results(vec![Ok(10.0), failed()], all, "eth0", &log_limit).unwrap();
+        assert_eq!(both, near_only);
+    }
+} 
\ No newline at end of file
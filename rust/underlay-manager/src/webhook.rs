@@ -0,0 +1,209 @@
+use crate::config::WebhookConfig;
+use crate::events::LinkEvent;
+use anyhow::{Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::time::Duration;
+use tracing::{debug, error, warn};
+
+/// POSTs a JSON notice to a webhook for every link health change, retrying
+/// failed deliveries with exponential backoff.
+pub struct WebhookNotifier {
+    /// `host:port` to connect to, also sent as the `Host` header.
+    authority: String,
+    path: String,
+    timeout: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: &WebhookConfig) -> Result<Self> {
+        let (authority, path) = config.endpoint()?;
+        Ok(Self {
+            authority,
+            path,
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_retries: config.max_retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+        })
+    }
+
+    /// Delivers each event in turn until the bus closes. A notice that still
+    /// fails after every retry is logged and dropped.
+    pub async fn run(&self, mut events: Receiver<LinkEvent>) -> Result<()> {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Err(e) = self.notify(&event).await {
+                        error!("Webhook notice for {} dropped: {}", event.link_name, e);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => warn!("Webhook fell behind, {} link events missed", missed),
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    /// Sends one notice, retrying up to `max_retries` times.
+    pub async fn notify(&self, event: &LinkEvent) -> Result<()> {
+        let body = serde_json::to_vec(&payload(event))?;
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.post(&body).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.max_retries => {
+                    attempt += 1;
+                    debug!("Webhook delivery failed ({}), retry {} in {:?}", e, attempt, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<()> {
+        let exchange = async {
+            let mut stream = TcpStream::connect(&self.authority).await
+                .with_context(|| format!("Failed to connect to webhook {}", self.authority))?;
+            let head = format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                self.path, self.authority, body.len(),
+            );
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(body).await?;
+
+            // Only the status matters, so the body isn't waited for
+            let mut response = BufReader::new(stream);
+            let mut status_line = String::new();
+            response.read_line(&mut status_line).await?;
+            let status = status_line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| anyhow::anyhow!("Webhook sent no HTTP status line"))?;
+            if !(200..300).contains(&status) {
+                return Err(anyhow::anyhow!("Webhook answered HTTP {}", status));
+            }
+            let mut header = String::new();
+            while response.read_line(&mut header).await? > 0 && !header.trim_end().is_empty() {
+                header.clear();
+            }
+            Ok(())
+        };
+        tokio::time::timeout(self.timeout, exchange).await
+            .map_err(|_| anyhow::anyhow!("Webhook {} timed out", self.authority))?
+    }
+}
+
+/// The JSON body sent for `event`.
+pub fn payload(event: &LinkEvent) -> serde_json::Value {
+    serde_json::json!({
+        "link": event.link_name,
+        "state": if event.healthy { "healthy" } else { "unhealthy" },
+        "timestamp": event.at,
+        "metrics": event.metrics,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LinkMetrics;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn notifier(addr: std::net::SocketAddr) -> WebhookNotifier {
+        WebhookNotifier::new(&WebhookConfig {
+            url: format!("http://{}/hooks/links", addr),
+            timeout_ms: 2000,
+            max_retries: 3,
+            retry_backoff_ms: 10,
+        }).unwrap()
+    }
+
+    fn link_down() -> LinkEvent {
+        let mut metrics = LinkMetrics::new();
+        metrics.latency_ms = 12.5;
+        metrics.carrier_up = false;
+        let at = "2024-05-01T12:00:00Z".parse().unwrap();
+        LinkEvent { link_name: "eth0".to_string(), healthy: false, metrics, at }
+    }
+
+    /// Accepts one request, answers it with `status` and returns its request
+    /// line and body.
+    async fn receive(listener: &TcpListener, status: &str) -> (String, serde_json::Value) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        let (head_len, content_length) = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&request[..end]).to_string();
+                let length = head.lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                break (end + 4, length);
+            }
+        };
+        while request.len() < head_len + content_length {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).await.unwrap();
+
+        let request_line = String::from_utf8_lossy(&request).lines().next().unwrap().to_string();
+        (request_line, serde_json::from_slice(&request[head_len..]).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_state_change_posted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let notifier = notifier(listener.local_addr().unwrap());
+        let event = link_down();
+        let (delivered, (request_line, body)) = tokio::join!(notifier.notify(&event), receive(&listener, "204 No Content"));
+        delivered.unwrap();
+
+        assert_eq!(request_line, "POST /hooks/links HTTP/1.1");
+        assert_eq!(body["link"], "eth0");
+        assert_eq!(body["state"], "unhealthy");
+        assert_eq!(body["timestamp"], "2024-05-01T12:00:00Z");
+        assert_eq!(body["metrics"]["latency_ms"], 12.5);
+        assert_eq!(body["metrics"]["carrier_up"], false);
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let notifier = notifier(listener.local_addr().unwrap());
+        let event = link_down();
+        let receiver = async {
+            receive(&listener, "503 Service Unavailable").await;
+            receive(&listener, "500 Internal Server Error").await;
+            receive(&listener, "200 OK").await
+        };
+        let (delivered, (_, body)) = tokio::join!(notifier.notify(&event), receiver);
+        delivered.unwrap();
+        assert_eq!(body["link"], "eth0");
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let notifier = notifier(listener.local_addr().unwrap());
+        let event = link_down();
+        let receiver = async {
+            for _ in 0..4 {
+                receive(&listener, "500 Internal Server Error").await;
+            }
+        };
+        let (delivered, ()) = tokio::join!(notifier.notify(&event), receiver);
+        assert!(delivered.unwrap_err().to_string().contains("HTTP 500"));
+    }
+} 
\ No newline at end of file